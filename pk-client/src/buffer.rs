@@ -1,7 +1,153 @@
 use pk_common::piece_table::*;
 use pk_common::protocol;
-use pk_common::Direction;
+use pk_common::{CharClass, CharClassify, Direction};
 use std::path::PathBuf;
+use std::ops::Range;
+
+/// How the indentation of a line must compare to the current line's for `Buffer::next_line_at_indent`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum IndentRelation {
+    Less, LessOrEqual, Equal, Greater
+}
+
+impl IndentRelation {
+    fn holds(self, width: usize, current: usize) -> bool {
+        match self {
+            IndentRelation::Less => width < current,
+            IndentRelation::LessOrEqual => width <= current,
+            IndentRelation::Equal => width == current,
+            IndentRelation::Greater => width > current
+        }
+    }
+}
+
+/// how many columns `c` takes up on screen when it starts in column `col`. Tabs go to the next tab
+/// stop, `tabstop` columns apart, and everything else is as wide as Unicode says, so East Asian
+/// wide characters take two columns and combining marks take none. Other control characters take
+/// one. This is what both the renderer and the cursor motions measure columns with
+pub fn display_width(c: char, col: usize, tabstop: usize) -> usize {
+    use unicode_width::UnicodeWidthChar;
+    match c {
+        '\t' => tabstop - col % tabstop,
+        c => c.width().unwrap_or(1)
+    }
+}
+
+/// the width of the leading whitespace of a line with tabs `tabstop` columns apart, or None if
+/// the line is blank
+fn indent_width(ln: &str, tabstop: usize) -> Option<usize> {
+    if ln.trim().is_empty() { None } else { Some(whitespace_width(ln, tabstop)) }
+}
+
+/// comment leaders that are repeated at the start of each line when a comment is reflowed
+const COMMENT_LEADERS: &[&str] = &["///", "//!", "//", "#", "--", ";"];
+
+/// how many columns `s` takes up on screen when it starts in column `col`
+fn str_width(s: &str, col: usize, tabstop: usize) -> usize {
+    s.chars().fold(col, |col, c| col + display_width(c, col, tabstop)) - col
+}
+
+/// how many columns a run of spaces and tabs at the start of a line takes up, with tabs that are
+/// `tabstop` columns apart
+fn whitespace_width(ws: &str, tabstop: usize) -> usize {
+    ws.chars().take_while(|c| *c == ' ' || *c == '\t')
+        .fold(0, |col, c| col + if c == '\t' { tabstop - col % tabstop } else { 1 })
+}
+
+/// split a line into the prefix that starts each line of its paragraph, which is its indentation
+/// and any comment leader along with the spaces after it, and the text after that
+fn line_prefix(ln: &str) -> (&str, &str) {
+    let indent = ln.len() - ln.trim_start().len();
+    let leader = COMMENT_LEADERS.iter().find(|l| ln[indent..].starts_with(*l)).map_or(0, |l| l.len());
+    let spaces = if leader > 0 {
+        ln[indent+leader..].chars().take_while(|c| *c == ' ').count()
+    } else { 0 };
+    ln.split_at(indent + leader + spaces)
+}
+
+/// greedily fill lines no wider than `textwidth` with `words`, starting each one with `prefix`.
+/// Words that are too long on their own get a line to themselves
+fn fill_paragraph(out: &mut String, prefix: &str, words: &[&str], textwidth: usize, tabstop: usize) {
+    let mut col = None;
+    for w in words {
+        col = match col {
+            Some(c) if c + 1 + str_width(w, c+1, tabstop) <= textwidth => {
+                out.push(' ');
+                Some(c + 1)
+            },
+            c => {
+                if c.is_some() { out.push('\n'); }
+                out.push_str(prefix);
+                Some(str_width(prefix, 0, tabstop))
+            }
+        }.map(|c| { out.push_str(w); c + str_width(w, c, tabstop) });
+    }
+    if col.is_some() { out.push('\n'); }
+}
+
+/// rewrap each paragraph of `text` to `textwidth` columns. Paragraphs are separated by blank lines
+/// and by changes in indentation or comment leader, which are kept at the start of every line
+fn reflow_text(text: &str, textwidth: usize, tabstop: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut para: Option<(&str, Vec<&str>)> = None;
+    for ln in text.lines() {
+        let (prefix, rest) = line_prefix(ln);
+        let blank = rest.trim().is_empty();
+        if blank || para.as_ref().map_or(false, |(p, _)| *p != prefix) {
+            if let Some((p, words)) = para.take() {
+                fill_paragraph(&mut out, p, &words, textwidth, tabstop);
+            }
+        }
+        if blank {
+            out.push_str(ln.trim_end());
+            out.push('\n');
+        } else {
+            para.get_or_insert_with(|| (prefix, Vec::new())).1.extend(rest.split_whitespace());
+        }
+    }
+    if let Some((p, words)) = para {
+        fill_paragraph(&mut out, p, &words, textwidth, tabstop);
+    }
+    if !text.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+/// how a line changes the nesting of brackets, as the number of closing brackets it starts with
+/// and how many more brackets it opens than it closes. Brackets in strings and comments don't count
+fn bracket_balance(ln: &str) -> (usize, isize) {
+    let ln = ln.trim_start();
+    let leading = ln.chars().take_while(|c| matches!(c, '}' | ')' | ']')).count();
+    let mut balance = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut prev = None;
+    for c in ln.chars() {
+        if in_string {
+            if escaped { escaped = false; }
+            else if c == '\\' { escaped = true; }
+            else if c == '"' { in_string = false; }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '/' if prev == Some('/') => break,
+                '{' | '(' | '[' => balance += 1,
+                '}' | ')' | ']' => balance -= 1,
+                _ => {}
+            }
+        }
+        prev = Some(c);
+    }
+    (leading, balance)
+}
+
+/// A range of lines that can be collapsed to show as a single line
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Fold {
+    pub lines: Range<usize>,
+    pub closed: bool
+}
 
 pub struct Buffer {
     pub text: PieceTable,
@@ -10,12 +156,42 @@ pub struct Buffer {
     pub file_id: protocol::FileId,
     pub format: protocol::TextFormat,
     pub version: usize,
+    /// the text the server has for `version`, which local edits are made on top of
+    pub synced_text: String,
+    /// the most recent action on the text when it was last synced with the server
+    pub last_saved_action_id: usize,
+    /// true if the buffer isn't backed by a file on a server, so it never gets synced
+    pub scratch: bool,
+    /// true if the buffer is only for looking at, like the view `:diff` opens
+    pub read_only: bool,
     pub currently_in_conflict: bool,
+    /// where the cursor is, which should be moved with `set_cursor` to keep it valid
     pub cursor_index: usize,
     pub highlights: Option<Vec<crate::piece_table_render::Highlight>>,
     pub last_highlighted_action_id: usize,
+    /// syntax highlighting of the text as of `last_highlighted_action_id`, to pick up from after an edit
+    pub syntax_cache: Option<crate::syntax_highlight::HighlightCache>,
+    /// options that override the configuration for this buffer
+    pub options: crate::config::BufferOptions,
+    /// how many columns apart tab stops are when measuring columns, which is the resolved
+    /// `tabstop` option as of the last time the buffer was drawn
+    pub tabstop: usize,
     pub current_query: Option<String>,
-    pub last_char_query: Option<(char, bool, Direction)>
+    /// true if `current_query` only matches whole words, as it does after `*` or `#`
+    pub query_whole_word: bool,
+    /// the matches of `current_query` as of the last time highlights were computed
+    pub search_matches: Vec<Range<usize>>,
+    /// the matches of a pattern along with the pattern and the most recent action on the text
+    /// when they were found, so counting them for the status line doesn't search every frame
+    pub match_count_cache: Option<(String, usize, Vec<Range<usize>>)>,
+    /// the most recent action on the text when the current search was made, as long as its
+    /// matches should be highlighted
+    pub search_highlight_action: Option<usize>,
+    pub last_char_query: Option<(char, bool, Direction)>,
+    /// folds sorted by start line, with enclosing folds before the folds they contain
+    pub folds: Vec<Fold>,
+    /// the lines visible in the active pane the last time it showed this buffer
+    pub viewport: Range<usize>
 }
 
 impl Buffer {
@@ -23,121 +199,240 @@ impl Buffer {
         Buffer {
             text: PieceTable::with_text(s),
             version: 0, file_id: protocol::FileId(0), cursor_index: 0,
+            synced_text: s.into(),
+            last_saved_action_id: 0,
+            scratch: false,
+            read_only: false,
             server_name: "".into(),
             path: "".into(), currently_in_conflict: false, format: protocol::TextFormat::default(),
             highlights: None,
             last_highlighted_action_id: 0,
+            syntax_cache: None,
+            options: crate::config::BufferOptions::default(),
+            tabstop: crate::config::DEFAULT_TABSTOP,
             current_query: None,
-            last_char_query: None
+            query_whole_word: false,
+            search_matches: Vec::new(),
+            match_count_cache: None,
+            search_highlight_action: None,
+            last_char_query: None,
+            folds: Vec::new(),
+            viewport: 0..0
         }
     }
 
+    /// an error if the buffer is read-only, for anything about to edit it
+    pub fn check_writable(&self) -> Result<(), crate::Error> {
+        if self.read_only {
+            Err(crate::Error::InvalidCommand("buffer is read-only".into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// an empty buffer without a file, until one is attached with `:saveas`
+    pub fn scratch() -> Buffer {
+        let mut b = Buffer::with_text("");
+        b.scratch = true;
+        b
+    }
+
     pub fn from_server(server_name: String, path: PathBuf, file_id: protocol::FileId, contents: String, version: usize, format: protocol::TextFormat) -> Buffer {
         Buffer {
             text: PieceTable::with_text(&contents),
             file_id, version, cursor_index: 0,
+            synced_text: contents,
+            last_saved_action_id: 0,
+            scratch: false,
+            read_only: false,
             server_name, path,
             currently_in_conflict: false, format,
             highlights: None,
             last_highlighted_action_id: 0,
+            syntax_cache: None,
+            options: crate::config::BufferOptions::default(),
+            tabstop: crate::config::DEFAULT_TABSTOP,
             current_query: None,
-            last_char_query: None
+            query_whole_word: false,
+            search_matches: Vec::new(),
+            match_count_cache: None,
+            search_highlight_action: None,
+            last_char_query: None,
+            folds: Vec::new(),
+            viewport: 0..0
         }
     }
 
-    pub fn sense_indent_level(&self, at: usize, config: &crate::config::Config) -> usize {
-        let mut i = self.current_start_of_line(at);
-        let mut indent_level = 0;
-        if config.softtab {
-            let mut space_counter = 0;
-            loop {
-                match self.text.char_at(i) {
-                    Some(' ') => {
-                        space_counter += 1;
-                        if space_counter == 4 {
-                            space_counter = 0;
-                            indent_level += 1;
-                        }
-                    },
-                    Some('\t') => indent_level += 1,
-                    Some(_) | None => break,
-                }
-                i += 1;
+    /// move the cursor to `index`, clamped to the end of the text and moved back to the start of
+    /// the character it lands in the middle of, so that it is always somewhere valid. An index on
+    /// a line hidden by a closed fold moves to the start of the fold instead. Commands and motions
+    /// should move the cursor through this instead of setting `cursor_index`
+    pub fn set_cursor(&mut self, index: usize) {
+        let mut index = self.clamp_index(index);
+        if !self.folds.is_empty() {
+            let line = self.line_for_index(index);
+            if let Some(f) = self.closed_fold_containing(line).filter(|f| f.start != line) {
+                index = self.index_for_line(f.start);
             }
+        }
+        self.cursor_index = index;
+    }
+
+    /// where the cursor is, checked in debug builds to be somewhere `set_cursor` could have put it
+    fn cursor(&self) -> usize {
+        debug_assert!(self.text.is_char_boundary(self.cursor_index), "cursor at {} is out of place", self.cursor_index);
+        self.cursor_index
+    }
+
+    /// `index` clamped to the end of the text and moved back to the start of the character it
+    /// lands in the middle of
+    pub fn clamp_index(&self, index: usize) -> usize {
+        let mut index = index.min(self.text.len());
+        while !self.text.is_char_boundary(index) {
+            index -= 1;
+        }
+        index
+    }
+
+    /// the index of the character after the one at `at`, or the end of the text
+    pub fn next_char_index(&self, at: usize) -> usize {
+        if at >= self.text.len() { return self.text.len(); }
+        at + self.text.chars(at).next().map_or(1, char::len_utf8)
+    }
+
+    /// the index of the character before the one at `at`, or 0 at the start of the text
+    pub fn prev_char_index(&self, at: usize) -> usize {
+        let mut index = at.min(self.text.len()).saturating_sub(1);
+        while !self.text.is_char_boundary(index) {
+            index -= 1;
+        }
+        index
+    }
+
+    /// true if the text has changed since it was last synced, which never matters for scratch buffers
+    pub fn is_modified(&self) -> bool {
+        !self.scratch && self.last_saved_action_id != self.text.most_recent_action_id()
+    }
+
+    /// change the line ending the file is written with. The text always uses `\n` so only the
+    /// format changes, but the buffer counts as modified until it's synced again
+    pub fn set_line_ending(&mut self, line_ending: protocol::LineEnding) {
+        if self.format.line_ending != line_ending || self.format.mixed_line_endings {
+            self.format.line_ending = line_ending;
+            self.format.mixed_line_endings = false;
+            self.last_saved_action_id = usize::MAX;
+        }
+    }
+
+    /// record that the text as it is now has been synced
+    pub fn mark_saved(&mut self) {
+        self.last_saved_action_id = self.text.most_recent_action_id();
+    }
+
+    /// bring in the changes the server made to the text since `synced_text` while keeping the
+    /// ones made here, which conflicted when syncing. The server's edits are transformed to apply
+    /// after the local ones, with the server's insertions going first when both insert at the
+    /// same place. The local edits still need to be synced afterwards
+    pub fn merge_server_text(&mut self, server_text: String, server_version: usize) {
+        use pk_common::transform::{self, Edit};
+        let local = Edit::between(&self.synced_text, &self.text.text());
+        let incoming = Edit::between(&self.synced_text, &server_text);
+        let (incoming, _) = transform::transform(&incoming, &local, true);
+        for e in incoming.iter() {
+            e.apply(&mut self.text);
+        }
+        self.text.merge_last_actions(incoming.len());
+        self.set_cursor(transform::map_index(self.cursor(), &incoming));
+        self.synced_text = server_text;
+        self.version = server_version;
+        if local.is_empty() {
+            self.mark_saved();
         } else {
-            loop {
-                match self.text.char_at(i) {
-                    Some('\t') => indent_level += 1,
-                    Some(_) | None => break
-                }
-                i += 1;
-            }
+            self.last_saved_action_id = usize::MAX;
         }
-        indent_level
     }
 
-    pub fn indent_with_mutator(&mut self, ins: &mut crate::piece_table::TableMutator, count: usize, config: &crate::config::Config) -> usize {
-        if count == 0 { return 0; }
+    /// the leading spaces and tabs of the line containing `at`
+    fn leading_whitespace(&self, at: usize) -> String {
+        let start = self.current_start_of_line(at);
+        self.text.chars(start).take_while(|c| *c == ' ' || *c == '\t').collect()
+    }
+
+    /// how many levels the line containing `at` is indented, counting a tab as reaching the
+    /// next tabstop no matter whether tabs or spaces are being inserted
+    pub fn sense_indent_level(&self, at: usize, config: &crate::config::TextOptions) -> usize {
+        whitespace_width(&self.leading_whitespace(at), config.tabstop) / config.tabstop
+    }
+
+    /// shift the indentation of the lines in `text` so that its first non-blank line is indented
+    /// as much as the line containing `at`, keeping the indentation of the others relative to it
+    pub fn auto_indent(&self, text: &str, at: usize, config: &crate::config::TextOptions) -> String {
+        let width = |ln: &str| whitespace_width(ln, config.tabstop);
+        let target = width(&self.leading_whitespace(at));
+        let first = match text.lines().find(|ln| !ln.trim().is_empty()) {
+            Some(ln) => width(ln),
+            None => return text.into()
+        };
+        text.split('\n').map(|ln| {
+            if ln.trim().is_empty() { return ln.to_owned(); }
+            let new_width = (width(ln) + target).saturating_sub(first);
+            Buffer::indent_text(new_width, config) + ln.trim_start_matches(|c| c == ' ' || c == '\t')
+        }).collect::<Vec<_>>().join("\n")
+    }
+
+    /// the text that indents by `width` columns, with as many tabs as fit and then spaces unless
+    /// only spaces are inserted
+    fn indent_text(width: usize, config: &crate::config::TextOptions) -> String {
         if config.softtab {
-            for _ in 0..(count*config.tabstop) {
-                ins.push_char(&mut self.text, ' ');
-            }
-            count * config.tabstop
+            " ".repeat(width)
         } else {
-            for _ in 0..count {
-                ins.push_char(&mut self.text, '\t');
-            }
-            count
+            "\t".repeat(width / config.tabstop) + &" ".repeat(width % config.tabstop)
         }
     }
 
-    pub fn indent(&mut self, at: usize, count: usize, config: &crate::config::Config) -> usize {
+    /// indent by `count` levels at the mutator's position, returning the number of characters inserted
+    pub fn indent_with_mutator(&mut self, ins: &mut crate::piece_table::TableMutator, count: usize, config: &crate::config::TextOptions) -> usize {
+        let text = Buffer::indent_text(count * config.tabstop, config);
+        for c in text.chars() {
+            ins.push_char(&mut self.text, c);
+        }
+        text.len()
+    }
+
+    /// insert `text` at `index`, or at the end of the buffer if `index` is past it, returning the
+    /// index just after the inserted text. This is how commands add text to a buffer, rather than
+    /// going to the piece table directly
+    pub fn insert_at(&mut self, index: usize, text: &str) -> usize {
+        let index = index.min(self.text.len());
+        self.text.insert_range(text, index);
+        index + text.len()
+    }
+
+    /// indent the line that starts at `at` by `count` levels, returning the number of characters inserted.
+    /// Inserting whole levels at the start of the line widens it by exactly that much even if the
+    /// existing indentation mixes tabs and spaces
+    pub fn indent(&mut self, at: usize, count: usize, config: &crate::config::TextOptions) -> usize {
         if count == 0 { return 0; }
-        if config.softtab {
-            let mut ins = self.text.insert_mutator(at);
-            for _ in 0..(count*config.tabstop) {
-                ins.push_char(&mut self.text, ' ');
-            }
-            ins.finish(&mut self.text);
-            count * config.tabstop
-        } else {
-            for _ in 0..count {
-                self.text.insert_range("\t", at);
-            }
-            count
-        }
-    }
-
-    pub fn undent(&mut self, at: usize, count: usize, config: &crate::config::Config) {
-        if count == 0 { return; }
-        if config.softtab { 
-            let mut spaces_left = count * config.tabstop;
-            loop {
-                match self.text.char_at(at) {
-                    Some(c) if c.is_whitespace() => {
-                        self.text.delete_range(at, at+1);
-                        spaces_left -= 1;
-                    }
-                    Some('\t') => {
-                        self.text.delete_range(at, at+1);
-                        spaces_left -= config.tabstop;
-                    }
-                    Some(_) | None => break
-                }
-                if spaces_left == 0 { break; }
-            }
-        } else {
-            for _ in 0..count {
-                match self.text.char_at(at) {
-                    Some('\t') => {
-                        self.text.delete_range(at, at+1);
-                    }
-                    Some(_) | None => break
-                }
-            }
+        let text = Buffer::indent_text(count * config.tabstop, config);
+        self.insert_at(at, &text) - at
+    }
+
+    /// remove `count` levels of indentation from the line that starts at `at`, or all of it if
+    /// there is less than that, returning the number of characters removed.
+    /// Leading whitespace is removed until the rest is no wider than it should be, so spaces in
+    /// front of a tab are removed along with it
+    pub fn undent(&mut self, at: usize, count: usize, config: &crate::config::TextOptions) -> usize {
+        if count == 0 { return 0; }
+        let ws: Vec<char> = self.text.chars(at).take_while(|c| *c == ' ' || *c == '\t').collect();
+        let width = |cs: &[char]| whitespace_width(&cs.iter().collect::<String>(), config.tabstop);
+        let target = width(&ws).saturating_sub(count * config.tabstop);
+        let removed = (0..=ws.len()).find(|k| width(&ws[*k..]) <= target).unwrap_or(ws.len());
+        if removed > 0 {
+            self.text.delete_range(at, at + removed);
         }
+        removed
     }
-    
+
     //prev line\nthis is a line\nnext line
     //^LLL       ^CSoL           ^NL
 
@@ -151,8 +446,58 @@ impl Buffer {
             .unwrap_or(0)
     }
 
+    /// the screen column, counting from zero, where the character at `index` starts
     pub fn column_for_index(&self, index: usize) -> usize {
-        index - self.current_start_of_line(index)
+        let start = self.current_start_of_line(index);
+        if start >= self.text.len() { return 0; }
+        let mut ix = start;
+        let mut col = 0;
+        for c in self.text.chars(start) {
+            if ix >= index || c == '\n' { break; }
+            col += display_width(c, col, self.tabstop);
+            ix += c.len_utf8();
+        }
+        col
+    }
+
+    /// the index of the character covering the screen `column`, counting from zero, on the line
+    /// containing `at`, or the end of the line if it is shorter than that
+    pub fn index_for_column(&self, at: usize, column: usize) -> usize {
+        let start = self.current_start_of_line(at);
+        if start >= self.text.len() { return start; }
+        let mut index = start;
+        let mut col = 0;
+        for c in self.text.chars(start).take_while(|c| *c != '\n') {
+            col += display_width(c, col, self.tabstop);
+            if col > column { break; }
+            index += c.len_utf8();
+        }
+        index
+    }
+
+    /// the index of the character covering the screen `column`, counting from zero, on `line`, or
+    /// the end of the line if it is shorter than that. Lines past the end of the buffer go to the
+    /// last one
+    pub fn index_at_line_column(&self, line: usize, column: usize) -> usize {
+        let line = line.min(self.line_for_index(self.text.len()));
+        self.index_for_column(self.index_for_line(line), column)
+    }
+
+    /// the index of the character covering the 1-based screen `column` on the line containing
+    /// `at`. Columns past the end of the line go to its last character
+    pub fn goto_column(&self, at: usize, column: usize) -> usize {
+        let start = self.current_start_of_line(at);
+        if start >= self.text.len() { return start; }
+        let mut index = start;
+        let mut last = start;
+        let mut col = 0;
+        for c in self.text.chars(start).take_while(|c| *c != '\n') {
+            col += display_width(c, col, self.tabstop);
+            if col >= column { return index; }
+            last = index;
+            index += c.len_utf8();
+        }
+        last
     }
 
     pub fn line_for_index(&self, index: usize) -> usize {
@@ -176,73 +521,1492 @@ impl Buffer {
             .unwrap_or(0)
     }
     
-    pub fn set_query(&mut self, s: String) {
-        self.current_query = Some(s);
+    /// find the next line in `dir` from `line` whose indentation compares to the indentation of
+    /// `line` by `relation`, skipping blank lines. A blank `line` takes its indentation from the
+    /// closest non-blank line above it
+    pub fn next_line_at_indent(&self, line: usize, dir: Direction, relation: IndentRelation) -> Option<usize> {
+        let text = self.text.text();
+        let widths: Vec<Option<usize>> = text.split('\n').map(|ln| indent_width(ln, self.tabstop)).collect();
+        if line >= widths.len() { return None; }
+        let current = widths[0..=line].iter().rev().find_map(|w| *w).unwrap_or(0);
+        let matches = |(_, w): &(usize, &Option<usize>)| w.map_or(false, |w| relation.holds(w, current));
+        match dir {
+            Direction::Forward => widths.iter().enumerate().skip(line+1).find(matches),
+            Direction::Backward => widths[0..line].iter().enumerate().rev().find(matches)
+        }.map(|(i, _)| i)
     }
-    
-    pub fn next_query_index(&self, from: usize, direction: crate::Direction, mut wrap: bool) -> Option<usize> {
-        use crate::Direction::*;
-        if self.current_query.is_none() { return None; }
-        let mut chrs = self.text.chars(from);
-        let mut qury: Vec<char> = self.current_query.as_ref().unwrap().chars().collect();
-        if direction == Backward {
-            qury.reverse();
-        }
-        let mut qury_ix = 0;
-        let mut chrs_ix = from;
-        let mut qury_match = None;
-        //dbg!(&qury, from, direction);
-        loop {
-            //dbg!(&qury, qury_ix, chrs_ix, qury_match);
-            match match direction {
-                Forward => chrs.next(),
-                Backward => chrs.next_back()
-            } {
-                Some(c) if qury[qury_ix] == c => {
-                    //println!("found {} at {}/{}", c, qury_ix, chrs_ix);
-                    if qury_ix == 0 {
-                        qury_match = Some(chrs_ix);
-                    }
-                    qury_ix += 1;
-                    if qury_ix == qury.len() {
-                        return match direction {
-                            Forward => qury_match,
-                            Backward => Some(chrs_ix)
-                        };
-                    }
-                },
-                Some(_) => {
-                    //println!("reset at {}", chrs_ix);
-                    qury_ix = 0;
-                    qury_match = None;
-                },
-                None => {
-                    if wrap {
-                        wrap = false;
-                        chrs_ix = match direction {
-                            Forward => 0,
-                            Backward => from
-                        };
-                        chrs = self.text.chars(from);
-                    } else {
-                        break;
-                    }
+
+    /// the paragraph text object around `index` as an inclusive range covering whole lines. For
+    /// `ip` this is `count` runs of either non-blank or blank lines starting with the one the
+    /// index is in, and for `ap` each run also takes the run of the other kind after it, or the
+    /// blank lines before the paragraph if there are none after it
+    pub fn paragraph_object(&self, index: usize, count: usize, include: bool) -> Range<usize> {
+        let text = self.text.text();
+        let mut blank: Vec<bool> = text.split('\n').map(|l| l.trim().is_empty()).collect();
+        if text.ends_with('\n') { blank.pop(); }
+        if blank.is_empty() { return index+1 .. index; }
+        let n = blank.len();
+        let cur = self.line_for_index(index).min(n-1);
+        let mut start = cur;
+        while start > 0 && blank[start-1] == blank[cur] { start -= 1; }
+        let mut end = cur;
+        let runs = if include { 2 } else { 1 };
+        for i in 0..count.max(1)*runs {
+            if i > 0 {
+                if end+1 >= n { break; }
+                end += 1;
+            }
+            while end+1 < n && blank[end+1] == blank[end] { end += 1; }
+        }
+        if include && !blank[start] && !blank[end] {
+            while start > 0 && blank[start-1] { start -= 1; }
+        }
+        self.index_for_line(start) .. self.next_line_index(self.index_for_line(end)).saturating_sub(1)
+    }
+
+    /// the index of the first character of `line`
+    pub fn index_for_line(&self, line: usize) -> usize {
+        let mut ix = 0;
+        for _ in 0..line {
+            ix = self.next_line_index(ix);
+        }
+        ix
+    }
+
+    /// the index of the first non-whitespace character on `line`, or its start if it is blank,
+    /// which is where linewise commands like `dd` and `:<n>` leave the cursor
+    pub fn first_nonblank(&self, line: usize) -> usize {
+        let start = self.index_for_line(line);
+        self.text.index_of_pred(|c| !c.is_whitespace(), start)
+            .filter(|ix| *ix < self.next_line_index(start))
+            .unwrap_or(start)
+    }
+
+    /// the text of each line in `lines` with its line number, without newlines
+    pub fn lines_in_range(&self, lines: Range<usize>) -> impl Iterator<Item=(usize, std::borrow::Cow<'_, str>)> {
+        let start = self.index_for_line(lines.start);
+        lines.zip(self.text.lines(start))
+    }
+
+    /// the text of `line` without its newline
+    pub fn line_text(&self, line: usize) -> Option<std::borrow::Cow<'_, str>> {
+        self.lines_in_range(line..line+1).next().map(|(_, l)| l)
+    }
+
+    /// add a closed fold over `lines`. Folds can nest but not partially overlap, so this returns
+    /// false without adding anything if `lines` crosses the boundary of an existing fold
+    pub fn create_fold(&mut self, lines: Range<usize>) -> bool {
+        if lines.start >= lines.end { return false; }
+        if self.folds.iter().any(|f| f.lines == lines ||
+            (f.lines.start < lines.start && lines.start < f.lines.end && f.lines.end < lines.end) ||
+            (lines.start < f.lines.start && f.lines.start < lines.end && lines.end < f.lines.end)) {
+            return false;
+        }
+        let ix = self.folds.iter().position(|f| f.lines.start > lines.start ||
+            (f.lines.start == lines.start && f.lines.end < lines.end)).unwrap_or(self.folds.len());
+        self.folds.insert(ix, Fold { lines, closed: true });
+        true
+    }
+
+    /// open the outermost closed fold containing `line`, which is the one that is visible
+    pub fn open_fold(&mut self, line: usize) -> bool {
+        match self.folds.iter_mut().find(|f| f.closed && f.lines.contains(&line)) {
+            Some(f) => { f.closed = false; true },
+            None => false
+        }
+    }
+
+    /// close the innermost open fold containing `line`
+    pub fn close_fold(&mut self, line: usize) -> bool {
+        match self.folds.iter_mut().rev().find(|f| !f.closed && f.lines.contains(&line)) {
+            Some(f) => { f.closed = true; true },
+            None => false
+        }
+    }
+
+    pub fn set_all_folds(&mut self, closed: bool) {
+        for f in self.folds.iter_mut() {
+            f.closed = closed;
+        }
+    }
+
+    /// replace all folds with closed folds over each block of lines that is indented further
+    /// than the line before it. Blank lines at the end of a block are left outside of it
+    pub fn fold_by_indent(&mut self) {
+        let text = self.text.text();
+        let widths: Vec<Option<usize>> = text.split('\n').map(|ln| indent_width(ln, self.tabstop)).collect();
+        self.folds.clear();
+        // (line the block starts on, indentation of the line before it)
+        let mut open: Vec<(usize, usize)> = Vec::new();
+        let mut last: Option<(usize, usize)> = None;
+        for (ln, w) in widths.iter().enumerate() {
+            let w = match w { Some(w) => *w, None => continue };
+            if let Some((last_ln, last_w)) = last {
+                while open.last().map_or(false, |(_, ow)| w <= *ow) {
+                    let (start, _) = open.pop().unwrap();
+                    self.create_fold(start .. last_ln+1);
+                }
+                if w > last_w {
+                    open.push((ln, last_w));
                 }
             }
-            chrs_ix = match direction {
-                Forward => chrs_ix + 1,
-                Backward => if chrs_ix == 0 {
-                    if wrap { from } else { return None; }
-                } else { chrs_ix - 1 },
+            last = Some((ln, w));
+        }
+        if let Some((last_ln, _)) = last {
+            while let Some((start, _)) = open.pop() {
+                self.create_fold(start .. last_ln+1);
+            }
+        }
+    }
+
+    /// the line ranges hidden by closed folds, not counting the first line of each fold which
+    /// stays visible. These never overlap and are sorted
+    pub fn closed_folds(&self) -> Vec<Range<usize>> {
+        let mut res: Vec<Range<usize>> = Vec::new();
+        for f in self.folds.iter().filter(|f| f.closed) {
+            if res.last().map_or(false, |l| l.end >= f.lines.end) { continue; }
+            res.push(f.lines.clone());
+        }
+        res
+    }
+
+    /// the closed fold that hides `line` or shows it as its first line
+    pub fn closed_fold_containing(&self, line: usize) -> Option<Range<usize>> {
+        self.closed_folds().into_iter().find(|f| f.contains(&line))
+    }
+
+    /// the row on screen that `line` shows up on if every line were visible, accounting for lines
+    /// hidden by closed folds. Hidden lines map to the row of the fold that contains them
+    pub fn visible_line(&self, line: usize) -> usize {
+        let mut row = line;
+        for f in self.closed_folds() {
+            if f.end <= line {
+                row -= f.len() - 1;
+            } else if f.start < line {
+                row -= line - f.start;
+                break;
+            } else {
+                break;
+            }
+        }
+        row
+    }
+
+    /// the line that shows up on `row`, the inverse of `visible_line`
+    pub fn line_for_visible(&self, row: usize) -> usize {
+        let mut line = row;
+        for f in self.closed_folds() {
+            if f.start < line {
+                line += f.len() - 1;
+            } else {
+                break;
+            }
+        }
+        line
+    }
+
+    /// join the line containing `at` with the `count` lines after it like `J`, as a single undo
+    /// step. Each line break and the indentation after it become one space, except after
+    /// whitespace, before a `)` or when either line is empty. Returns where the last join happened,
+    /// which is on the space if one was inserted, or None if there was no line to join
+    pub fn join_lines(&mut self, at: usize, count: usize) -> Option<usize> {
+        let mut join_point = None;
+        let mut edits = 0;
+        for _ in 0..count {
+            let nl = match self.text.index_of('\n', at) {
+                Some(nl) => nl,
+                None => break
             };
+            let next = self.text.index_of_pred(|c| c != ' ' && c != '\t', nl+1).unwrap_or_else(|| self.text.len());
+            let before = if nl > self.current_start_of_line(at) { self.text.char_at(nl-1) } else { None };
+            let after = self.text.char_at(next);
+            let space = !matches!(before, None | Some(' ') | Some('\t')) && !matches!(after, None | Some('\n') | Some(')'));
+            self.text.delete_range(nl, next);
+            edits += 1;
+            if space {
+                self.text.insert_range(" ", nl);
+                edits += 1;
+            }
+            join_point = Some(nl);
+        }
+        self.text.merge_last_actions(edits);
+        // without a space, the join point can be past the end of the line
+        join_point.map(|ix| {
+            let past_end = matches!(self.text.char_at(ix), None | Some('\n'));
+            if past_end && ix > self.current_start_of_line(ix) { ix-1 } else { ix }
+        })
+    }
+
+    /// delete `count` lines starting at `line` like `dd`, and move the cursor to the first
+    /// non-blank of the line that takes their place. When the last line has no newline after it, the newline before
+    /// the deleted lines goes instead, so no empty line is left behind. Returns the deleted lines,
+    /// always ending in a newline, or None if `line` is past the end of the buffer
+    pub fn delete_line(&mut self, line: usize, count: usize) -> Option<String> {
+        let len = self.text.len();
+        let start = self.index_for_line(line);
+        if count == 0 || start >= len { return None; }
+        let end = self.index_for_line(line + count).min(len);
+        let mut deleted = self.text.copy_range(start, end);
+        if deleted.ends_with('\n') {
+            self.text.delete_range(start, end);
+        } else {
+            deleted.push('\n');
+            self.text.delete_range(start.saturating_sub(1), end);
+        }
+        let last_line = self.line_for_index(self.text.len().saturating_sub(1));
+        self.set_cursor(self.first_nonblank(line.min(last_line)));
+        Some(deleted)
+    }
+
+    /// replace `count` characters starting at `at` with `c` like `r`, as a single undo step. The
+    /// characters replaced stop at the end of the line. Returns the index of the last replaced
+    /// character, or None if `at` is at the end of a line
+    pub fn replace_char_count(&mut self, at: usize, c: char, count: usize) -> Option<usize> {
+        let end = self.text.index_of('\n', at).unwrap_or_else(|| self.text.len());
+        if at >= end || count == 0 { return None; }
+        let replaced: Vec<char> = self.text.copy_range(at, end).chars().take(count).collect();
+        let replacement: String = std::iter::repeat(c).take(replaced.len()).collect();
+        self.text.delete_range(at, at + replaced.iter().map(|c| c.len_utf8()).sum::<usize>());
+        let end = self.insert_at(at, &replacement);
+        self.text.merge_last_actions(2);
+        Some(end - c.len_utf8())
+    }
+
+    /// swap the character at `index` with the one after it, as a single undo step. At the end of
+    /// a line the two characters before the newline are swapped instead. Returns the new index of
+    /// the character that moved forward, or None if there aren't two characters on the line to swap
+    pub fn transpose_chars(&mut self, index: usize) -> Option<usize> {
+        let first = match self.text.char_at(index) {
+            Some('\n') | None => return None,
+            Some(_) => index
+        };
+        let first = match self.text.char_at(self.next_char_index(first)) {
+            Some('\n') | None if first > self.current_start_of_line(first) => self.prev_char_index(first),
+            Some('\n') | None => return None,
+            Some(_) => first
+        };
+        let second = self.next_char_index(first);
+        let end = self.next_char_index(second);
+        let pair: String = self.text.copy_range(first, end).chars().rev().collect();
+        self.text.delete_range(first, end);
+        self.text.insert_range(&pair, first);
+        self.text.merge_last_actions(2);
+        Some(first + (end - second))
+    }
+
+    /// swap the block of `lines` with the line before or after it, as a single undo step. The
+    /// cursor moves with the block if it is inside it. Returns the new index of the start of the
+    /// block, or None if it is already at the top or bottom of the buffer
+    pub fn move_lines(&mut self, lines: Range<usize>, direction: Direction) -> Option<usize> {
+        let line_count = self.text.text().lines().count();
+        if lines.start >= lines.end || lines.end > line_count { return None; }
+        let swapped = match direction {
+            Direction::Forward if lines.end < line_count => lines.start..lines.end+1,
+            Direction::Backward if lines.start > 0 => lines.start-1..lines.end,
+            _ => return None
+        };
+        let mut text: Vec<String> = self.lines_in_range(swapped.clone()).map(|(_, l)| l.into_owned()).collect();
+        let start = self.index_for_line(swapped.start);
+        let end = start + text.iter().map(|l| l.len() + 1).sum::<usize>() - 1;
+        let block_start = self.index_for_line(lines.start);
+        let block_len = match direction {
+            Direction::Forward => end - start - text[text.len()-1].len() - 1,
+            Direction::Backward => end - block_start
+        };
+        let new_block_start = match direction {
+            Direction::Forward => { text.rotate_right(1); block_start + text[0].len() + 1 },
+            Direction::Backward => { text.rotate_left(1); start }
+        };
+        let cursor = self.cursor();
+        self.text.delete_range(start, end);
+        self.text.insert_range(&text.join("\n"), start);
+        self.text.merge_last_actions(2);
+        if cursor >= block_start && cursor <= block_start + block_len {
+            self.set_cursor(cursor + new_block_start - block_start);
+        }
+        Some(new_block_start)
+    }
+
+    /// insert a copy of `range` after it, or before it when going backward, as a single undo step,
+    /// and put the cursor at the same place in the copy. When `linewise` is set, `range` should cover
+    /// whole lines and a newline is added if the last one doesn't have one. Returns the index of the
+    /// start of the copy
+    pub fn duplicate(&mut self, range: Range<usize>, direction: Direction, linewise: bool) -> usize {
+        let mut text = self.text.copy_range(range.start, range.end);
+        let at = match direction {
+            Direction::Forward => range.end,
+            Direction::Backward => range.start
+        };
+        let mut copy_start = at;
+        // the last line of the buffer has no newline to copy along with it
+        if linewise && !text.ends_with('\n') {
+            match direction {
+                Direction::Forward => { text.insert(0, '\n'); copy_start += 1; },
+                Direction::Backward => text.push('\n')
+            }
+        }
+        let cursor = self.cursor();
+        self.text.insert_range(&text, at);
+        self.set_cursor(if range.contains(&cursor) {
+            copy_start + cursor - range.start
+        } else { copy_start });
+        copy_start
+    }
+
+    /// rewrap the paragraphs in `lines` so that they fit in `textwidth` columns, keeping the
+    /// indentation and comment leader of each one, as a single undo step. Returns the index of the
+    /// start of the last line that was reflowed
+    pub fn reflow(&mut self, lines: Range<usize>, textwidth: usize) -> usize {
+        let start = self.index_for_line(lines.start);
+        let end = self.index_for_line(lines.end);
+        let old = self.text.copy_range(start, end);
+        let new = reflow_text(&old, textwidth, self.tabstop);
+        if new != old {
+            self.text.delete_range(start, end);
+            self.text.insert_range(&new, start);
+            self.text.merge_last_actions(2);
+        }
+        start + new.trim_end_matches('\n').rfind('\n').map_or(0, |i| i+1)
+    }
+
+    /// indent each of `lines` by how deeply it is nested in brackets, carrying on from the last
+    /// non-blank line before them, as a single undo step. Blank lines lose their whitespace, and
+    /// since the existing indentation of the lines is ignored, doing it again changes nothing.
+    /// Returns the index of the first non-blank character of the first line
+    pub fn reindent(&mut self, lines: Range<usize>, config: &crate::config::TextOptions) -> usize {
+        let start = self.index_for_line(lines.start);
+        let end = self.index_for_line(lines.end);
+        let mut depth = 0;
+        for line in (0..lines.start).rev() {
+            let ls = self.index_for_line(line);
+            let ln = self.text.copy_range(ls, self.next_line_index(ls));
+            if ln.trim().is_empty() { continue; }
+            let (leading, balance) = bracket_balance(&ln);
+            depth = (self.sense_indent_level(ls, config) + leading) as isize + balance;
+            break;
+        }
+        let old = self.text.copy_range(start, end);
+        let new = old.split('\n').map(|ln| {
+            let ln = ln.trim_start_matches(&[' ', '\t'][..]);
+            if ln.trim().is_empty() { return String::new(); }
+            let (leading, balance) = bracket_balance(ln);
+            let level = (depth - leading as isize).max(0) as usize;
+            depth = (depth + balance).max(0);
+            Buffer::indent_text(level * config.tabstop, config) + ln
+        }).collect::<Vec<_>>().join("\n");
+        if new != old {
+            self.text.delete_range(start, end);
+            if !new.is_empty() {
+                self.text.insert_range(&new, start);
+                self.text.merge_last_actions(2);
+            }
         }
-        None
+        self.first_nonblank(lines.start)
+    }
+
+    /// insert `text` at screen column `col` on each of `lines` as a single undo step, which is what
+    /// block `I` and `A` do. Lines that are too short get spaces added to reach the column, unless
+    /// there is nothing to insert
+    pub fn apply_block_edit(&mut self, lines: Range<usize>, col: usize, text: &str) {
+        if text.is_empty() { return; }
+        let last_line = self.line_for_index(self.text.len());
+        // only edits that made an action get merged, so earlier actions are left alone
+        let mut edits = 0;
+        for line in lines.rev().filter(|l| *l <= last_line) {
+            let last_action = self.text.most_recent_action_id();
+            let start = self.index_for_line(line);
+            let end = self.text.index_of('\n', start).unwrap_or_else(|| self.text.len());
+            let width = if start == end { 0 } else { self.column_for_index(end) };
+            if width < col {
+                self.text.insert_range(&format!("{}{}", " ".repeat(col - width), text), end);
+            } else {
+                let at = self.index_for_column(start, col);
+                self.text.insert_range(text, at);
+            }
+            if self.text.most_recent_action_id() != last_action {
+                edits += 1;
+            }
+        }
+        self.text.merge_last_actions(edits);
+    }
+
+    /// apply an action made on another copy of this text, like another client editing the same
+    /// file, moving the cursor and the insertion point of `tmut` along with the text around them.
+    /// The pieces of the action have to refer to text this table's sources already have. The
+    /// action isn't added to the history, so it can't be undone here, but local actions still can
+    pub fn apply_remote_action(&mut self, action: &Action, tmut: Option<&mut TableMutator>) {
+        let cursor = self.text.apply_remote_action(action, tmut, self.cursor());
+        self.set_cursor(cursor);
+    }
+
+    /// the text a Visual mode `selection` covers, which includes the characters at both of its
+    /// ends, and the text that yanking or deleting it puts in a register. Linewise selections
+    /// always end with a newline in the register, even on the last line, so that they are put as
+    /// whole lines
+    pub fn visual_selection_text(&self, selection: &Range<usize>, linewise: bool) -> (Range<usize>, String) {
+        let len = self.text.len();
+        let lo = selection.start.min(selection.end).min(len);
+        let hi = selection.start.max(selection.end);
+        let range = if linewise {
+            self.current_start_of_line(lo) .. self.next_line_index(hi).min(len)
+        } else {
+            lo .. (hi+1).min(len)
+        };
+        let mut text = self.text.copy_range(range.start, range.end);
+        if linewise && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        (range, text)
+    }
+
+    /// the first and last lines in the viewport that the cursor can be on without scrolling it,
+    /// which keeps a line of context above the cursor unless the viewport is at the top
+    pub fn viewport_lines(&self) -> (usize, usize) {
+        let first = if self.viewport.start > 0 { self.viewport.start + 1 } else { 0 };
+        let last = self.viewport.end.saturating_sub(1)
+            .min(self.line_for_index(self.text.len()));
+        (first.min(last), last)
+    }
+
+    /// insert the text from `start` to `after` bytes past the cursor again with `tmut`, the
+    /// mutator adding text at the cursor, so that it appears `count` times in total and is undone
+    /// along with it. Returns the text of a single insertion
+    pub fn repeat_insertion(&mut self, tmut: &mut TableMutator, start: usize, after: usize, count: usize) -> String {
+        let before = self.text.copy_range(start.min(self.cursor_index), self.cursor_index);
+        let after = self.text.copy_range(self.cursor_index, self.cursor_index + after);
+        if count > 1 && !(before.is_empty() && after.is_empty()) {
+            // the text after the cursor followed by the text before it finishes one copy and starts the next
+            let repeated = (after.clone() + &before).repeat(count-1);
+            tmut.push_str(&mut self.text, &repeated);
+            self.cursor_index += repeated.len();
+        }
+        before + &after
+    }
+
+    pub fn set_query(&mut self, s: String) {
+        self.current_query = Some(s);
+        self.query_whole_word = false;
+        self.search_highlight_action = Some(self.text.most_recent_action_id());
+        // search matches are highlighted, so they need to be recomputed
+        self.highlights = None;
+    }
+
+    /// stop highlighting the matches of the current search, which can still be jumped to. The
+    /// matches are left for `update_search_matches` to clear, so that it reports the change
+    pub fn clear_search_highlights(&mut self) {
+        self.search_highlight_action = None;
+        self.highlights = None;
+    }
+
+    /// recompute `search_matches` for highlighting, clearing them instead if the text has been
+    /// edited since the search was made. Returns true if the matches changed
+    pub fn update_search_matches(&mut self) -> bool {
+        if self.search_highlight_action.map_or(false, |id| id != self.text.most_recent_action_id()) {
+            self.search_highlight_action = None;
+        }
+        let matches = if self.search_highlight_action.is_some() {
+            self.query_matches()
+        } else {
+            Vec::new()
+        };
+        let changed = matches != self.search_matches;
+        self.search_matches = matches;
+        changed
+    }
+
+    /// the ranges of every match of `pattern` in the buffer, in order. Matches never overlap, and
+    /// empty matches are skipped since there is nothing in them to highlight or count
+    pub fn find_all(&self, pattern: &regex::Regex) -> Vec<Range<usize>> {
+        pattern.find_iter(&self.text.text())
+            .map(|m| m.start() .. m.end())
+            .filter(|r| r.start < r.end)
+            .collect()
+    }
+
+    /// the number of the match of `pattern` under or after the cursor, counting from one and
+    /// wrapping around to the first past the last match, and the total number of matches, or None
+    /// if there aren't any. The matches are cached until the pattern or the text changes
+    pub fn match_count(&mut self, pattern: &regex::Regex) -> Option<(usize, usize)> {
+        let action = self.text.most_recent_action_id();
+        if !self.match_count_cache.as_ref().map_or(false, |(p, a, _)| p == pattern.as_str() && *a == action) {
+            self.match_count_cache = Some((pattern.as_str().into(), action, self.find_all(pattern)));
+        }
+        let matches = &self.match_count_cache.as_ref().expect("just filled").2;
+        if matches.is_empty() { return None; }
+        let current = matches.iter().position(|m| m.end > self.cursor_index).unwrap_or(0);
+        Some((current + 1, matches.len()))
+    }
+
+    /// where the cursor is among the matches of the current search, as long as they are
+    /// highlighted, for showing like `match 3 of 7`
+    pub fn search_match_position(&mut self) -> Option<(usize, usize)> {
+        self.search_highlight_action?;
+        let pattern = self.query_regex()?;
+        self.match_count(&pattern)
+    }
+
+    /// the index of the first `c` after `from` going forward, or the closest one before it going
+    /// backward, without leaving the line `from` is on, like `f` and `F` search
+    pub fn find_char_on_line(&self, c: char, from: usize, dir: Direction) -> Option<usize> {
+        let (start, end) = match dir {
+            Direction::Forward => (self.next_char_index(from), self.text.index_of('\n', from).unwrap_or_else(|| self.text.len())),
+            Direction::Backward => (self.current_start_of_line(from), from.min(self.text.len()))
+        };
+        if start >= end { return None; }
+        let line = self.text.copy_range(start, end);
+        match dir {
+            Direction::Forward => line.find(c),
+            Direction::Backward => line.rfind(c)
+        }.map(|i| start + i)
+    }
+
+    /// the class of the character at `index`, or None past the end of the text
+    pub fn char_class_at(&self, index: usize) -> Option<CharClass> {
+        self.text.char_at(index).map(CharClassify::class)
+    }
+
+    /// the range and text of the run of characters around `index` that `is_word` accepts, or None
+    /// if it doesn't accept the character at `index`. Passing something other than the word class
+    /// picks out other kinds of words, like paths that include `/` and `.`
+    pub fn word_at(&self, index: usize, is_word: impl Fn(char) -> bool) -> Option<(Range<usize>, String)> {
+        if !self.text.char_at(index).map_or(false, &is_word) { return None; }
+        let start = self.text.last_index_of_pred(|c| !is_word(c), index).map_or(0, |i| i+1);
+        let end = self.text.index_of_pred(|c| !is_word(c), index).unwrap_or(self.text.len());
+        Some((start..end, self.text.copy_range(start, end)))
+    }
+
+    /// the word of letters, digits and underscores under the cursor, like the word `iw` selects,
+    /// or None if the cursor is on whitespace or punctuation
+    pub fn word_under_cursor(&self) -> Option<(Range<usize>, String)> {
+        self.word_at(self.cursor_index, |c| c.class() == CharClass::Regular)
+    }
+
+    /// search for the whole word under the cursor, returning it, or None if the cursor isn't on a word
+    pub fn set_word_query(&mut self) -> Option<String> {
+        let (_, word) = self.word_under_cursor()?;
+        self.set_query(word.clone());
+        self.query_whole_word = true;
+        Some(word)
+    }
+
+    /// the current search query as a regex that matches it literally
+    fn query_regex(&self) -> Option<regex::Regex> {
+        match self.current_query.as_ref() {
+            Some(q) if !q.is_empty() => Some(regex::Regex::new(&if self.query_whole_word {
+                format!(r"\b{}\b", regex::escape(q))
+            } else {
+                regex::escape(q)
+            }).expect("escaped query is valid")),
+            _ => None
+        }
+    }
+
+    /// the matches of the current search query, which is matched literally
+    pub fn query_matches(&self) -> Vec<Range<usize>> {
+        self.query_regex().map_or_else(Vec::new, |q| self.find_all(&q))
+    }
+
+    /// the start of the first match of `pattern` at or after `start` going forward, or the last one
+    /// that starts before `start` going backward. With `wrap`, the search continues from the other
+    /// end of the buffer, so the match at `start` itself is found last
+    pub fn search(&self, pattern: &regex::Regex, start: usize, direction: Direction, wrap: bool) -> Option<usize> {
+        let matches = self.find_all(pattern);
+        let found = match direction {
+            Direction::Forward => matches.iter().find(|m| m.start >= start).or_else(|| matches.first().filter(|_| wrap)),
+            Direction::Backward => matches.iter().rev().find(|m| m.start < start).or_else(|| matches.last().filter(|_| wrap))
+        };
+        found.map(|m| m.start)
+    }
+    
+    /// the start of the next match of the current search query, as `search` finds it
+    pub fn next_query_index(&self, from: usize, direction: crate::Direction, wrap: bool) -> Option<usize> {
+        self.query_regex().and_then(|q| self.search(&q, from, direction, wrap))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+
+    /// an edit made on a copy of `b`'s text, with the text it added shared with `b`
+    fn remote_edit(b: &mut Buffer, edit: impl FnOnce(&mut PieceTable)) -> Action {
+        let mut remote = PieceTable {
+            sources: b.text.sources.clone(), pieces: b.text.pieces.clone(),
+            history: Vec::new(), undone: Vec::new(), next_action_id: b.text.next_action_id
+        };
+        edit(&mut remote);
+        b.text.sources = remote.sources;
+        remote.history.pop().unwrap()
+    }
+
+    #[test]
+    fn remote_action_moves_cursor() {
+        let mut b = Buffer::with_text("hello world");
+        b.cursor_index = 6;
+        let before = remote_edit(&mut b, |t| t.insert_range("ab", 2));
+        b.apply_remote_action(&before, None);
+        assert_eq!(b.text.text(), "heabllo world");
+        assert_eq!(b.cursor_index, 8);
+        let after = remote_edit(&mut b, |t| t.insert_range("!", 13));
+        b.apply_remote_action(&after, None);
+        assert_eq!(b.text.text(), "heabllo world!");
+        assert_eq!(b.cursor_index, 8, "insertions after the cursor don't move it");
+        let around = remote_edit(&mut b, |t| t.delete_range(6, 10));
+        b.apply_remote_action(&around, None);
+        assert_eq!(b.text.text(), "heabllrld!");
+        assert_eq!(b.cursor_index, 6, "deleting the text under the cursor moves it to the start of the deletion");
+    }
+
+    #[test]
+    fn remote_action_in_repeated_text() {
+        let mut b = Buffer::with_text("x\n\n\ny");
+        b.cursor_index = 4;
+        let action = remote_edit(&mut b, |t| t.insert_range("\n", 2));
+        b.apply_remote_action(&action, None);
+        assert_eq!(b.text.text(), "x\n\n\n\ny");
+        assert_eq!(b.cursor_index, 5, "the cursor stays on the y");
+    }
+
+    #[test]
+    fn undo_after_remote_action() {
+        let mut b = Buffer::with_text("hello world");
+        b.text.insert_range(",", 5);
+        b.text.insert_range("!", 12);
+        let action = remote_edit(&mut b, |t| t.insert_range("oh ", 0));
+        b.apply_remote_action(&action, None);
+        assert_eq!(b.text.text(), "oh hello, world!");
+        b.text.undo();
+        assert_eq!(b.text.text(), "oh hello, world");
+        b.text.undo();
+        assert_eq!(b.text.text(), "oh hello world");
+        b.text.redo();
+        assert_eq!(b.text.text(), "oh hello, world");
+
+        // undoing the insertion would join "hello" and " world" back together around " big"
+        let mut b = Buffer::with_text("hello world");
+        b.text.insert_range(",", 5);
+        let action = remote_edit(&mut b, |t| t.insert_range(" big", 6));
+        b.apply_remote_action(&action, None);
+        b.text.undo();
+        assert_eq!(b.text.text(), "hello, big world", "the insertion can't be undone anymore");
+
+        // the remote action deletes the piece the local insertion made
+        let mut b = Buffer::with_text("hello world");
+        b.text.insert_range("!", 11);
+        let action = remote_edit(&mut b, |t| t.delete_range(10, 12));
+        b.apply_remote_action(&action, None);
+        assert_eq!(b.text.text(), "hello worl");
+        b.text.undo();
+        assert_eq!(b.text.text(), "hello worl");
+    }
+
+    #[test]
+    fn merge_server_text() {
+        let mut b = Buffer::from_server("local".into(), "a.txt".into(), protocol::FileId(1),
+            "one\ntwo\nthree\n".into(), 1, protocol::TextFormat::default());
+        b.text.insert_range("2", 4);
+        b.text.delete_range(5, 8);
+        b.cursor_index = 4;
+        b.merge_server_text("zero\none\ntwo\nthree!\n".into(), 3);
+        assert_eq!(b.text.text(), "zero\none\n2\nthree!\n");
+        assert_eq!(b.cursor_index, 9, "the cursor stays on the same character");
+        assert_eq!(b.version, 3);
+        assert!(b.is_modified(), "the local changes still need to be synced");
+        b.text.undo();
+        assert_eq!(b.text.text(), "one\n2\nthree\n", "the merge is one undo step");
+
+        let mut b = Buffer::from_server("local".into(), "a.txt".into(), protocol::FileId(1),
+            "abc".into(), 1, protocol::TextFormat::default());
+        b.merge_server_text("abcd".into(), 2);
+        assert_eq!(b.text.text(), "abcd");
+        assert!(!b.is_modified());
+    }
+
+    #[test]
+    fn remote_action_during_insert() {
+        let mut b = Buffer::with_text("hello world");
+        b.cursor_index = 11;
+        let mut tmut = b.text.insert_mutator(11);
+        tmut.push_str(&mut b.text, "!!");
+        b.cursor_index += 2;
+        // the remote copy doesn't have the pending insertion yet
+        let mut remote = PieceTable::with_text_and_starting_action_id("hello world", b.text.next_action_id);
+        remote.insert_range("oh, ", 0);
+        b.text.sources.extend(remote.sources.drain(1..));
+        b.apply_remote_action(&remote.history[0], Some(&mut tmut));
+        assert_eq!(b.cursor_index, 17);
+        tmut.push_char(&mut b.text, '?');
+        tmut.finish(&mut b.text);
+        assert_eq!(b.text.text(), "oh, hello world!!?");
+        b.text.undo();
+        assert_eq!(b.text.text(), "oh, hello world", "the insertion is still undone as a whole");
+
+        // the remote copy inserts at the same index as the pending insertion
+        let mut b = Buffer::with_text("hello world");
+        let mut tmut = b.text.insert_mutator(0);
+        tmut.push_str(&mut b.text, "!!");
+        b.cursor_index = 2;
+        let mut remote = PieceTable::with_text_and_starting_action_id("hello world", b.text.next_action_id);
+        remote.sources.push(String::new());
+        remote.insert_range("oh, ", 0);
+        b.text.sources.extend(remote.sources.drain(2..));
+        b.apply_remote_action(&remote.history[0], Some(&mut tmut));
+        assert_eq!(b.cursor_index, 6);
+        tmut.push_char(&mut b.text, '?');
+        assert!(!tmut.pop_char(&mut b.text));
+        tmut.push_char(&mut b.text, '?');
+        tmut.finish(&mut b.text);
+        assert_eq!(b.text.text(), "oh, !!?hello world");
+        b.text.undo();
+        assert_eq!(b.text.text(), "oh, hello world");
+
+        // the remote copy changes the piece that the pending insertion is growing
+        let mut b = Buffer::with_text("hello world");
+        let mut tmut = b.text.insert_mutator(11);
+        tmut.push_str(&mut b.text, "!!");
+        b.cursor_index = 13;
+        let mut remote = PieceTable::with_text_and_starting_action_id("hello world", b.text.next_action_id);
+        remote.delete_range(0, 6);
+        b.apply_remote_action(&remote.history[0], Some(&mut tmut));
+        assert_eq!(b.text.text(), "world", "the remote copy replaced the piece with the pending text");
+        assert_eq!(b.cursor_index, 5);
+        tmut.push_char(&mut b.text, '?');
+        assert!(!tmut.pop_char(&mut b.text));
+        assert!(tmut.pop_char(&mut b.text), "only text pushed since the remote action can be popped");
+        tmut.push_char(&mut b.text, '?');
+        tmut.finish(&mut b.text);
+        assert_eq!(b.text.text(), "world?");
+        b.text.undo();
+        assert_eq!(b.text.text(), "world");
+    }
+
+    #[test]
+    fn line_ending() {
+        let format = protocol::TextFormat { line_ending: protocol::LineEnding::CRLF, ..Default::default() };
+        let mut buf = Buffer::from_server("local".into(), "a.txt".into(), protocol::FileId(1), "a\nb\n".into(), 1, format);
+        assert_eq!(buf.format.line_ending, protocol::LineEnding::CRLF);
+        assert!(!buf.is_modified());
+        buf.set_line_ending(protocol::LineEnding::CRLF);
+        assert!(!buf.is_modified(), "nothing changed");
+        buf.set_line_ending(protocol::LineEnding::LF);
+        assert_eq!(buf.format.line_ending, protocol::LineEnding::LF);
+        assert!(buf.is_modified());
+        assert_eq!(buf.text.text(), "a\nb\n");
+    }
+    #[test]
+    fn indent_mixed_whitespace() {
+        let mut config = crate::config::Config::default().text_options();
+        config.tabstop = 4;
+        let mut buf = Buffer::with_text("\tx\n  \ty\n\t  z\n      w\n");
+        assert_eq!(buf.sense_indent_level(0, &config), 1);
+        assert_eq!(buf.sense_indent_level(4, &config), 1, "spaces before a tab still reach the tabstop");
+        assert_eq!(buf.sense_indent_level(9, &config), 1);
+        assert_eq!(buf.sense_indent_level(14, &config), 1);
+        config.softtab = false;
+        assert_eq!(buf.sense_indent_level(9, &config), 1, "spaces count without softtab too");
+
+        config.softtab = true;
+        assert_eq!(buf.indent(3, 1, &config), 4);
+        assert_eq!(buf.text.text(), "\tx\n      \ty\n\t  z\n      w\n");
+        assert_eq!(buf.undent(3, 1, &config), 3, "spaces before a tab are removed until it is back a level");
+        assert_eq!(buf.text.text(), "\tx\n   \ty\n\t  z\n      w\n");
+        assert_eq!(buf.undent(3, 1, &config), 4, "the tab and the spaces before it are one level");
+        assert_eq!(buf.text.text(), "\tx\ny\n\t  z\n      w\n");
+        assert_eq!(buf.undent(5, 1, &config), 1);
+        assert_eq!(buf.text.text(), "\tx\ny\n  z\n      w\n");
+        assert_eq!(buf.undent(9, 1, &config), 4);
+        assert_eq!(buf.text.text(), "\tx\ny\n  z\n  w\n");
+        assert_eq!(buf.undent(9, 1, &config), 2, "less than a level left");
+        assert_eq!(buf.undent(0, 1, &config), 1);
+        assert_eq!(buf.undent(0, 1, &config), 0, "nothing left to remove");
+        assert_eq!(buf.text.text(), "x\ny\n  z\nw\n");
+
+        config.softtab = false;
+        assert_eq!(buf.indent(4, 2, &config), 2);
+        assert_eq!(buf.text.text(), "x\ny\n\t\t  z\nw\n");
+        let mut buf = Buffer::with_text("      \tz");
+        assert_eq!(buf.undent(0, 1, &config), 3);
+        assert_eq!(buf.text.text(), "   \tz");
+        let mut buf = Buffer::with_text("\t\t z");
+        assert_eq!(buf.undent(0, 1, &config), 1);
+        assert_eq!(buf.text.text(), "\t z");
+        let mut buf = Buffer::with_text(" \t  z");
+        assert_eq!(buf.undent(0, 1, &config), 2);
+        assert_eq!(buf.text.text(), "  z");
+        let mut buf = Buffer::with_text("\t\t  z");
+        config.tabstop = 8;
+        assert_eq!(buf.undent(0, 1, &config), 1);
+        assert_eq!(buf.text.text(), "\t  z");
+    }
+
+    #[test]
+    fn unicode_columns() {
+        // "é" is two bytes, "日本" are three bytes and two columns each, and the "e" is followed by a combining accent
+        let buf = Buffer::with_text("café x\n日本 y\ne\u{301}z\n\tá");
+        assert_eq!(buf.column_for_index(5), 4);
+        assert_eq!(buf.column_for_index(6), 5);
+        assert_eq!(buf.column_for_index(14), 4);
+        assert_eq!(buf.column_for_index(15), 5);
+        assert_eq!(buf.column_for_index(20), 1);
+        assert_eq!(buf.column_for_index(23), 4);
+        assert_eq!(buf.index_for_column(0, 5), 6);
+        assert_eq!(buf.index_for_column(8, 3), 11, "inside a wide character");
+        assert_eq!(buf.index_for_column(8, 5), 15);
+        assert_eq!(buf.index_for_column(8, 40), 16, "past the end of the line");
+        assert_eq!(buf.index_for_column(17, 1), 20);
+        assert_eq!(buf.goto_column(8, 4), 11);
+        assert_eq!(buf.goto_column(17, 2), 20);
+        assert_eq!(Buffer::with_text("🚀x\u{483}y").column_for_index(7), 3, "widths come from Unicode, not a short list");
+        // tabs reach the buffer's tabstop
+        let mut buf = buf;
+        buf.tabstop = 8;
+        assert_eq!(buf.column_for_index(23), 8);
+        assert_eq!(buf.index_for_column(22, 5), 22, "inside the tab");
+        assert_eq!(buf.index_for_column(22, 8), 23);
+    }
+
+    #[test]
+    fn index_at_line_column() {
+        let buf = Buffer::with_text("abcdef\nab\n\n\tx\nlonger line\nend");
+        assert_eq!(buf.index_at_line_column(0, 0), 0);
+        assert_eq!(buf.index_at_line_column(0, 4), 4);
+        assert_eq!(buf.index_at_line_column(1, 1), 8);
+        assert_eq!(buf.index_at_line_column(1, 4), 9, "short line clamps to its end");
+        assert_eq!(buf.index_at_line_column(2, 3), 10, "empty line");
+        assert_eq!(buf.index_at_line_column(3, 2), 11, "inside a tab");
+        assert_eq!(buf.index_at_line_column(3, 4), 12);
+        assert_eq!(buf.index_at_line_column(4, 7), 21);
+        assert_eq!(buf.index_at_line_column(5, 1), 27);
+        assert_eq!(buf.index_at_line_column(5, 10), 29, "last line without a newline");
+        assert_eq!(buf.index_at_line_column(50, 1), 27, "past the last line");
+        let trailing = Buffer::with_text("ab\ncd\n");
+        assert_eq!(trailing.index_at_line_column(1, 1), 4);
+        assert_eq!(trailing.index_at_line_column(9, 1), 6, "the empty line after the last newline");
+    }
+
+    #[test]
+    fn set_cursor() {
+        let mut buf = Buffer::with_text("a\u{e9}b\n\u{65e5}\u{672c}");
+        buf.set_cursor(2);
+        assert_eq!(buf.cursor_index, 1, "inside a two byte character");
+        buf.set_cursor(3);
+        assert_eq!(buf.cursor_index, 3);
+        buf.set_cursor(7);
+        assert_eq!(buf.cursor_index, 5, "inside a three byte character");
+        buf.set_cursor(100);
+        assert_eq!(buf.cursor_index, 11, "past the end");
+        // the same across pieces
+        buf.insert_at(5, "\u{e9}");
+        buf.set_cursor(6);
+        assert_eq!(buf.cursor_index, 5);
+        buf.set_cursor(8);
+        assert_eq!(buf.cursor_index, 7);
+        assert_eq!(buf.next_char_index(0), 1);
+        assert_eq!(buf.next_char_index(1), 3);
+        assert_eq!(buf.next_char_index(5), 7);
+        assert_eq!(buf.next_char_index(7), 10);
+        assert_eq!(buf.next_char_index(13), 13, "at the end");
+        assert_eq!(buf.prev_char_index(3), 1);
+        assert_eq!(buf.prev_char_index(7), 5);
+        assert_eq!(buf.prev_char_index(0), 0);
+        buf.text.delete_range(0, buf.text.len());
+        buf.set_cursor(3);
+        assert_eq!(buf.cursor_index, 0, "empty buffer");
+    }
+
+    #[test]
+    fn set_cursor_in_closed_fold() {
+        let mut buf = Buffer::with_text("a\nbc\nde\nf\n");
+        assert!(buf.create_fold(1..3));
+        assert!(buf.open_fold(1));
+        buf.set_cursor(7);
+        assert_eq!(buf.cursor_index, 7, "the fold is open");
+        assert!(buf.close_fold(1));
+        buf.set_cursor(7);
+        assert_eq!(buf.cursor_index, 2, "hidden lines move to the start of the fold");
+        buf.set_cursor(3);
+        assert_eq!(buf.cursor_index, 3, "the first line of the fold is still shown");
+        buf.set_cursor(8);
+        assert_eq!(buf.cursor_index, 8);
+    }
+
+    #[test]
+    fn find_char_on_line() {
+        let buf = Buffer::with_text("a.b.c\nd.e\n");
+        assert_eq!(buf.find_char_on_line('.', 0, Direction::Forward), Some(1));
+        assert_eq!(buf.find_char_on_line('.', 1, Direction::Forward), Some(3));
+        assert_eq!(buf.find_char_on_line('.', 3, Direction::Forward), None, "the next one is on the next line");
+        assert_eq!(buf.find_char_on_line('d', 0, Direction::Forward), None);
+        assert_eq!(buf.find_char_on_line('.', 5, Direction::Forward), None, "from the newline");
+        assert_eq!(buf.find_char_on_line('.', 4, Direction::Backward), Some(3));
+        assert_eq!(buf.find_char_on_line('.', 3, Direction::Backward), Some(1));
+        assert_eq!(buf.find_char_on_line('.', 7, Direction::Backward), None, "the previous one is on the line before");
+        assert_eq!(buf.find_char_on_line('.', 8, Direction::Backward), Some(7));
+    }
+
+    #[test]
+    fn first_nonblank() {
+        let buf = Buffer::with_text("one\n    two\n\t three\n   \n\nend");
+        assert_eq!(buf.first_nonblank(0), 0);
+        assert_eq!(buf.first_nonblank(1), 8);
+        assert_eq!(buf.first_nonblank(2), 14);
+        assert_eq!(buf.first_nonblank(3), 20, "blank lines land on their start");
+        assert_eq!(buf.first_nonblank(4), 24);
+        assert_eq!(buf.first_nonblank(5), 25);
+    }
+
+    #[test]
+    fn delete_line() {
+        let mut buf = Buffer::with_text("one\ntwo\nthree\n");
+        buf.cursor_index = 5;
+        assert_eq!(buf.delete_line(0, 1).as_deref(), Some("one\n"));
+        assert_eq!(buf.text.text(), "two\nthree\n");
+        assert_eq!(buf.cursor_index, 0);
+
+        let mut buf = Buffer::with_text("one\ntwo\nthree\n");
+        assert_eq!(buf.delete_line(1, 1).as_deref(), Some("two\n"));
+        assert_eq!(buf.text.text(), "one\nthree\n");
+        assert_eq!(buf.cursor_index, 4);
+
+        let mut buf = Buffer::with_text("one\ntwo\nthree\n");
+        assert_eq!(buf.delete_line(2, 1).as_deref(), Some("three\n"));
+        assert_eq!(buf.text.text(), "one\ntwo\n");
+        assert_eq!(buf.cursor_index, 4, "moves up to the new last line");
+
+        let mut buf = Buffer::with_text("one\ntwo\nthree");
+        assert_eq!(buf.delete_line(2, 1).as_deref(), Some("three\n"));
+        assert_eq!(buf.text.text(), "one\ntwo", "the newline before goes instead");
+        assert_eq!(buf.cursor_index, 4);
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "one\ntwo\nthree");
+
+        let mut buf = Buffer::with_text("one\ntwo\nthree");
+        assert_eq!(buf.delete_line(1, 5).as_deref(), Some("two\nthree\n"));
+        assert_eq!(buf.text.text(), "one");
+        assert_eq!(buf.cursor_index, 0);
+        assert_eq!(buf.delete_line(0, 1).as_deref(), Some("one\n"));
+        assert_eq!(buf.text.text(), "");
+        assert_eq!(buf.delete_line(0, 1), None);
+        assert_eq!(Buffer::with_text("a\n").delete_line(1, 1), None, "past the last line");
+
+        let mut buf = Buffer::with_text("a\nb\nc\nd\ne\n");
+        assert!(buf.create_fold(2..4));
+        assert_eq!(buf.delete_line(4, 1).as_deref(), Some("e\n"));
+        assert_eq!(buf.cursor_index, 4, "the line the cursor moves up to is in a closed fold");
+    }
+
+    #[test]
+    fn goto_column() {
+        let buf = Buffer::with_text("abcdef\n\tx\ta\n\nab\tc");
+        assert_eq!(buf.goto_column(3, 1), 0);
+        assert_eq!(buf.goto_column(0, 4), 3);
+        assert_eq!(buf.goto_column(0, 100), 5, "past the end of the line");
+        // "\tx\ta" covers columns 1-4, 5, 6-8 and 9
+        assert_eq!(buf.goto_column(9, 1), 7);
+        assert_eq!(buf.goto_column(9, 4), 7);
+        assert_eq!(buf.goto_column(9, 5), 8);
+        assert_eq!(buf.goto_column(9, 6), 9);
+        assert_eq!(buf.goto_column(9, 9), 10);
+        assert_eq!(buf.goto_column(9, 10), 10);
+        assert_eq!(buf.goto_column(12, 3), 12, "empty line");
+        // a tab after text only goes to the next tab stop
+        assert_eq!(buf.goto_column(13, 3), 15);
+        assert_eq!(buf.goto_column(13, 5), 16);
+        assert_eq!(buf.goto_column(13, 50), 16, "last line");
+        assert_eq!(Buffer::with_text("a\n").goto_column(2, 3), 2, "empty last line");
+        // with tab stops every 8 columns, "\tx\ta" covers columns 1-8, 9, 10-16 and 17
+        let mut buf = buf;
+        buf.tabstop = 8;
+        assert_eq!(buf.goto_column(9, 8), 7);
+        assert_eq!(buf.goto_column(9, 9), 8);
+        assert_eq!(buf.goto_column(9, 16), 9);
+        assert_eq!(buf.goto_column(9, 17), 10);
+    }
+
+    #[test]
+    fn viewport_lines() {
+        let mut buf = Buffer::with_text(&"line\n".repeat(20));
+        buf.viewport = 0..10;
+        assert_eq!(buf.viewport_lines(), (0, 9));
+        buf.viewport = 5..15;
+        assert_eq!(buf.viewport_lines(), (6, 14));
+        buf.viewport = 15..25;
+        assert_eq!(buf.viewport_lines(), (16, 20), "past the end of the buffer");
+    }
+
+    #[test]
+    fn auto_indent() {
+        let buf = Buffer::with_text("a\n    b\n\tc\n");
+        let mut config = crate::config::Config::default().text_options();
+        let block = "x {\n    y\n}\n";
+        assert_eq!(buf.auto_indent(block, 0, &config), block);
+        assert_eq!(buf.auto_indent(block, 3, &config), "    x {\n        y\n    }\n");
+        assert_eq!(buf.auto_indent("        x\n\n      y\n", 3, &config), "    x\n\n  y\n", "less indented");
+        assert_eq!(buf.auto_indent("        x\n  y\n", 3, &config), "    x\ny\n", "indentation stops at nothing");
+        config.softtab = false;
+        assert_eq!(buf.auto_indent(block, 8, &config), "\tx {\n\t\ty\n\t}\n");
+        config.tabstop = 8;
+        assert_eq!(buf.auto_indent(block, 8, &config), "\tx {\n\t    y\n\t}\n");
+        assert_eq!(buf.auto_indent("\n \n", 8, &config), "\n \n", "only blank lines");
+    }
+
+    #[test]
+    fn insert_at() {
+        let mut buf = Buffer::with_text("ab");
+        assert_eq!(buf.insert_at(1, "xy"), 3);
+        assert_eq!(buf.insert_at(100, "!"), 5, "past the end is clamped");
+        assert_eq!(buf.insert_at(0, ""), 0);
+        assert_eq!(buf.text.text(), "axyb!");
+    }
+
+    #[test]
+    fn insert_at_matches_string() {
+        // a fixed xorshift so that failures can be reproduced
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut rand = move |n: usize| {
+            seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        let words = ["", "a", "\n", "piece", "two\nlines\n"];
+        for _ in 0..20 {
+            let mut buf = Buffer::with_text("start\n");
+            let mut reference = String::from("start\n");
+            let mut history = vec![reference.clone()];
+            for _ in 0..50 {
+                let at = rand(reference.len() + 2);
+                let text = words[rand(words.len())];
+                // deleting now and then splits pieces so that inserts land at their starts and ends
+                if rand(4) == 0 && at + 1 < reference.len() {
+                    buf.text.delete_range(at, at + 1);
+                    reference.remove(at);
+                } else {
+                    let end = buf.insert_at(at, text);
+                    let at = at.min(reference.len());
+                    reference.insert_str(at, text);
+                    assert_eq!(end, at + text.len());
+                    if text.is_empty() { continue; }
+                }
+                assert_eq!(buf.text.text(), reference);
+                history.push(reference.clone());
+            }
+            history.pop();
+            while let Some(previous) = history.pop() {
+                buf.text.undo();
+                assert_eq!(buf.text.text(), previous, "each edit undoes on its own");
+            }
+        }
+    }
+
+    #[test]
+    fn transpose_chars() {
+        let mut buf = Buffer::with_text("abc\nd\n\nef");
+        assert_eq!(buf.transpose_chars(0), Some(1), "line start");
+        assert_eq!(buf.text.text(), "bac\nd\n\nef");
+        assert_eq!(buf.transpose_chars(1), Some(2), "middle");
+        assert_eq!(buf.text.text(), "bca\nd\n\nef");
+        assert_eq!(buf.transpose_chars(2), Some(2), "end of line");
+        assert_eq!(buf.text.text(), "bac\nd\n\nef");
+        assert_eq!(buf.transpose_chars(3), None, "newline");
+        assert_eq!(buf.transpose_chars(4), None, "single character line");
+        assert_eq!(buf.transpose_chars(6), None, "empty line");
+        assert_eq!(buf.transpose_chars(8), Some(8), "end of buffer");
+        assert_eq!(buf.text.text(), "bac\nd\n\nfe");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "bac\nd\n\nef", "one undo step");
+
+        let mut buf = Buffer::with_text("aé€\n");
+        assert_eq!(buf.transpose_chars(0), Some(2));
+        assert_eq!(buf.text.text(), "éa€\n");
+        assert_eq!(buf.transpose_chars(3), Some(5), "end of line");
+        assert_eq!(buf.text.text(), "é€a\n");
+    }
+
+    #[test]
+    fn join_lines() {
+        let mut buf = Buffer::with_text("fn f(\n    x\n)\nab \n\tcd\n\nlast");
+        assert_eq!(buf.join_lines(0, 2), Some(7));
+        assert_eq!(buf.text.text(), "fn f( x)\nab \n\tcd\n\nlast", "no space before )");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "fn f(\n    x\n)\nab \n\tcd\n\nlast", "one undo step");
+        assert_eq!(buf.join_lines(15, 1), Some(17));
+        assert_eq!(buf.text.text(), "fn f(\n    x\n)\nab cd\n\nlast", "trailing whitespace is kept instead of adding a space");
+        assert_eq!(buf.join_lines(17, 1), Some(18));
+        assert_eq!(buf.text.text(), "fn f(\n    x\n)\nab cd\nlast", "empty lines join without a space");
+        assert_eq!(buf.join_lines(21, 1), None, "last line");
+        let mut buf = Buffer::with_text("\nx");
+        assert_eq!(buf.join_lines(0, 1), Some(0));
+        assert_eq!(buf.text.text(), "x");
+    }
+
+    #[test]
+    fn move_single_line() {
+        let mut buf = Buffer::with_text("ab\ncd\nef");
+        buf.cursor_index = 4;
+        assert_eq!(buf.move_lines(1..2, Direction::Backward), Some(0));
+        assert_eq!(buf.text.text(), "cd\nab\nef");
+        assert_eq!(buf.cursor_index, 1, "cursor moves with the line");
+        assert_eq!(buf.move_lines(0..1, Direction::Backward), None, "top of the buffer");
+        assert_eq!(buf.move_lines(0..1, Direction::Forward), Some(3));
+        assert_eq!(buf.move_lines(1..2, Direction::Forward), Some(6));
+        assert_eq!(buf.text.text(), "ab\nef\ncd", "last line has no newline");
+        assert_eq!(buf.cursor_index, 7);
+        assert_eq!(buf.move_lines(2..3, Direction::Forward), None, "bottom of the buffer");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "ab\ncd\nef", "one undo step");
+    }
+
+    #[test]
+    fn duplicate_line_down() {
+        let mut buf = Buffer::with_text("ab\ncd");
+        buf.cursor_index = 4;
+        assert_eq!(buf.duplicate(3..5, Direction::Forward, true), 6, "last line has no newline");
+        assert_eq!(buf.text.text(), "ab\ncd\ncd");
+        assert_eq!(buf.cursor_index, 7);
+        buf.cursor_index = 1;
+        assert_eq!(buf.duplicate(0..3, Direction::Forward, true), 3);
+        assert_eq!(buf.text.text(), "ab\nab\ncd\ncd");
+        assert_eq!(buf.cursor_index, 4);
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "ab\ncd\ncd", "one undo step");
+        assert_eq!(buf.duplicate(4..5, Direction::Backward, false), 4);
+        assert_eq!(buf.text.text(), "ab\ncdd\ncd", "exact span");
+    }
+
+    #[test]
+    fn move_multiple_lines() {
+        let mut buf = Buffer::with_text("a\nbb\ncc\nd\n");
+        buf.cursor_index = 0;
+        assert_eq!(buf.move_lines(1..3, Direction::Forward), Some(4));
+        assert_eq!(buf.text.text(), "a\nd\nbb\ncc\n");
+        assert_eq!(buf.cursor_index, 0, "cursor outside of the block");
+        assert_eq!(buf.move_lines(2..4, Direction::Forward), None, "trailing newline isn't a line");
+        buf.cursor_index = 8;
+        assert_eq!(buf.move_lines(2..4, Direction::Backward), Some(2));
+        assert_eq!(buf.text.text(), "a\nbb\ncc\nd\n");
+        assert_eq!(buf.cursor_index, 6);
+        assert_eq!(buf.move_lines(0..2, Direction::Backward), None);
+    }
+
+    #[test]
+    fn next_line_at_indent() {
+        let buf = Buffer::with_text(
+"fn main() {
+    let x = 3;
+    if x > 2 {
+        println!(\"hi\");
+
+        x += 1;
+    }
+    loop {
+        break;
+    }
+}
+");
+        use IndentRelation::*;
+        use Direction::*;
+        assert_eq!(buf.next_line_at_indent(1, Forward, LessOrEqual), Some(2));
+        assert_eq!(buf.next_line_at_indent(3, Forward, Less), Some(6));
+        assert_eq!(buf.next_line_at_indent(3, Forward, Equal), Some(5), "blank lines are skipped");
+        assert_eq!(buf.next_line_at_indent(2, Forward, Equal), Some(6));
+        assert_eq!(buf.next_line_at_indent(2, Forward, Greater), Some(3));
+        assert_eq!(buf.next_line_at_indent(4, Forward, Less), Some(6), "blank lines use the indentation above them");
+        assert_eq!(buf.next_line_at_indent(5, Backward, LessOrEqual), Some(3));
+        assert_eq!(buf.next_line_at_indent(8, Backward, Less), Some(7));
+        assert_eq!(buf.next_line_at_indent(7, Backward, Less), Some(0));
+        assert_eq!(buf.next_line_at_indent(1, Backward, Equal), None);
+        assert_eq!(buf.next_line_at_indent(9, Forward, Less), Some(10));
+        assert_eq!(buf.next_line_at_indent(10, Forward, LessOrEqual), None);
+        // tabs are as wide as the buffer's tabstop
+        let mut buf = Buffer::with_text("\tx\n    y\n        z\n");
+        assert_eq!(buf.next_line_at_indent(0, Forward, Equal), Some(1));
+        buf.tabstop = 8;
+        assert_eq!(buf.next_line_at_indent(0, Forward, Equal), Some(2));
+    }
+
+    fn fold_sample() -> Buffer {
+        Buffer::with_text(
+"fn a() {
+    if x {
+        y();
+        z();
+    }
+    w();
+}
+
+fn b() {
+    q();
+}
+")
+    }
+
+    #[test]
+    fn create_folds() {
+        let mut buf = fold_sample();
+        assert!(buf.create_fold(1..5));
+        assert!(buf.create_fold(0..7));
+        assert!(buf.create_fold(2..4));
+        assert!(!buf.create_fold(1..5), "duplicate fold");
+        assert!(!buf.create_fold(3..6), "crosses the end of 1..5");
+        assert!(!buf.create_fold(6..9), "crosses the end of 0..7");
+        assert!(!buf.create_fold(4..4));
+        assert_eq!(buf.folds.iter().map(|f| f.lines.clone()).collect::<Vec<_>>(), vec![0..7, 1..5, 2..4]);
+    }
+
+    #[test]
+    fn nested_folds() {
+        let mut buf = fold_sample();
+        buf.create_fold(0..7);
+        buf.create_fold(1..5);
+        buf.create_fold(2..4);
+        assert_eq!(buf.closed_folds(), vec![0..7]);
+        // opens from the outside in
+        assert!(buf.open_fold(3));
+        assert_eq!(buf.closed_folds(), vec![1..5]);
+        assert!(buf.open_fold(3));
+        assert_eq!(buf.closed_folds(), vec![2..4]);
+        assert!(buf.open_fold(3));
+        assert!(!buf.open_fold(3));
+        assert_eq!(buf.closed_folds(), vec![]);
+        // closes from the inside out
+        assert!(buf.close_fold(3));
+        assert_eq!(buf.closed_folds(), vec![2..4]);
+        assert!(buf.close_fold(3));
+        assert_eq!(buf.closed_folds(), vec![1..5]);
+        assert!(buf.close_fold(5));
+        assert_eq!(buf.closed_folds(), vec![0..7]);
+        assert!(!buf.close_fold(8));
+        buf.set_all_folds(false);
+        assert_eq!(buf.closed_folds(), vec![]);
+        buf.set_all_folds(true);
+        assert_eq!(buf.closed_fold_containing(4), Some(0..7));
+        assert_eq!(buf.closed_fold_containing(7), None);
+    }
+
+    #[test]
+    fn indent_folds() {
+        let mut buf = fold_sample();
+        buf.fold_by_indent();
+        assert_eq!(buf.folds.iter().map(|f| f.lines.clone()).collect::<Vec<_>>(), vec![1..6, 2..4, 9..10]);
+    }
+
+    #[test]
+    fn visible_lines() {
+        let mut buf = fold_sample();
+        buf.create_fold(1..6);
+        buf.create_fold(2..4);
+        buf.create_fold(9..10);
+        buf.open_fold(1);
+        // 0 1 2 [3] 4 5 6 7 8 9
+        let rows = (0..11).map(|l| buf.visible_line(l)).collect::<Vec<_>>();
+        assert_eq!(rows, vec![0, 1, 2, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!((0..10).map(|r| buf.line_for_visible(r)).collect::<Vec<_>>(), vec![0, 1, 2, 4, 5, 6, 7, 8, 9, 10]);
+        buf.close_fold(1);
+        // 0 1 [2 3 4 5] 6 7 8 9
+        let rows = (0..11).map(|l| buf.visible_line(l)).collect::<Vec<_>>();
+        assert_eq!(rows, vec![0, 1, 1, 1, 1, 1, 2, 3, 4, 5, 6]);
+        assert_eq!((0..7).map(|r| buf.line_for_visible(r)).collect::<Vec<_>>(), vec![0, 1, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn find_all_matches() {
+        let buf = Buffer::with_text("abc xabc abcabc\nab abc");
+        let re = regex::Regex::new("abc").unwrap();
+        assert_eq!(buf.find_all(&re), vec![0..3, 5..8, 9..12, 12..15, 19..22]);
+        assert_eq!(buf.find_all(&regex::Regex::new("q").unwrap()), vec![]);
+        // empty matches are skipped
+        assert_eq!(buf.find_all(&regex::Regex::new("b*").unwrap()), vec![1..2, 6..7, 10..11, 13..14, 17..18, 20..21]);
+        // matches do not overlap
+        let buf = Buffer::with_text("aaaa");
+        assert_eq!(buf.find_all(&regex::Regex::new("aa").unwrap()), vec![0..2, 2..4]);
+    }
+
+    #[test]
+    fn modified_flag() {
+        let mut buf = Buffer::with_text("abc");
+        assert!(!buf.is_modified());
+        buf.text.insert_range("x", 0);
+        assert!(buf.is_modified());
+        buf.mark_saved();
+        assert!(!buf.is_modified());
+        buf.text.insert_range("y", 0);
+        assert!(buf.is_modified());
+        // undoing back to the saved text is not a change
+        buf.text.undo();
+        assert!(!buf.is_modified());
+        buf.text.undo();
+        assert!(buf.is_modified());
+        // a sync that started before an edit only saves the text up to the edit
+        let synced = buf.text.most_recent_action_id();
+        buf.text.insert_range("z", 0);
+        buf.last_saved_action_id = synced;
+        assert!(buf.is_modified());
+    }
+
+    #[test]
+    fn lines_in_range() {
+        let mut buf = Buffer::with_text("alpha\nbeta\ngamma\n");
+        buf.text.insert_range("del", 8);
+        buf.text.insert_range("\nzeta\n", buf.text.len());
+        let text = buf.text.text();
+        let expected: Vec<(usize, &str)> = text.lines().enumerate().skip(1).take(3).collect();
+        assert_eq!(buf.lines_in_range(1..4).map(|(i, l)| (i, l.into_owned())).collect::<Vec<_>>(),
+            expected.into_iter().map(|(i, l)| (i, l.to_owned())).collect::<Vec<_>>());
+        assert_eq!(buf.lines_in_range(0..100).count(), text.lines().count());
+        assert_eq!(buf.line_text(1).as_deref(), Some("bedelta"));
+        assert_eq!(buf.line_text(5), None);
+    }
+
+    #[test]
+    fn scratch_never_modified() {
+        let mut buf = Buffer::scratch();
+        assert_eq!(buf.text.text(), "");
+        buf.text.insert_range("abc", 0);
+        assert!(!buf.is_modified());
+        buf.scratch = false;
+        assert!(buf.is_modified());
+    }
+
+    #[test]
+    fn search_highlights_clear() {
+        let mut buf = Buffer::with_text("ab ab ab");
+        buf.set_query("ab".into());
+        assert!(buf.update_search_matches());
+        assert_eq!(buf.search_matches, vec![0..2, 3..5, 6..8]);
+        assert!(!buf.update_search_matches(), "nothing has changed");
+        buf.clear_search_highlights();
+        assert!(buf.update_search_matches());
+        assert!(buf.search_matches.is_empty());
+        assert_eq!(buf.next_query_index(1, Direction::Forward, false), Some(3), "the query is kept");
+        // a new search highlights again
+        buf.set_query("b".into());
+        assert!(buf.update_search_matches());
+        assert_eq!(buf.search_matches.len(), 3);
+    }
+
+    #[test]
+    fn search_highlights_clear_on_edit() {
+        let mut buf = Buffer::with_text("ab ab ab");
+        buf.set_query("ab".into());
+        buf.update_search_matches();
+        assert_eq!(buf.search_matches.len(), 3);
+        buf.text.insert_range("ab ", 0);
+        assert!(buf.update_search_matches());
+        assert!(buf.search_matches.is_empty());
+        assert_eq!(buf.search_highlight_action, None);
+        // undoing is an edit too
+        buf.set_query("ab".into());
+        buf.update_search_matches();
+        assert_eq!(buf.search_matches.len(), 4);
+        buf.text.undo();
+        buf.update_search_matches();
+        assert!(buf.search_matches.is_empty());
+    }
+
+    #[test]
+    fn search_match_count() {
+        let mut buf = Buffer::with_text("x.y x.y x.y");
+        buf.set_query("x.y".into());
+        buf.update_search_matches();
+        assert_eq!(buf.search_matches, vec![0..3, 4..7, 8..11]);
+        assert_eq!(buf.search_match_position(), Some((1, 3)));
+        buf.cursor_index = 6;
+        assert_eq!(buf.search_match_position(), Some((2, 3)));
+        buf.cursor_index = 10;
+        assert_eq!(buf.search_match_position(), Some((3, 3)));
+        buf.set_query("z".into());
+        buf.update_search_matches();
+        assert_eq!(buf.search_match_position(), None);
+        buf.clear_search_highlights();
+        buf.set_query("x.y".into());
+        buf.clear_search_highlights();
+        assert_eq!(buf.search_match_position(), None, "only while highlighted");
+    }
+
+    #[test]
+    fn match_count() {
+        let mut buf = Buffer::with_text("ab cd ab\nef ab gh");
+        let ab = regex::Regex::new("ab").unwrap();
+        assert_eq!(buf.match_count(&ab), Some((1, 3)), "on the first match");
+        buf.cursor_index = 1;
+        assert_eq!(buf.match_count(&ab), Some((1, 3)), "inside a match");
+        buf.cursor_index = 2;
+        assert_eq!(buf.match_count(&ab), Some((2, 3)), "between matches counts the next one");
+        buf.cursor_index = 10;
+        assert_eq!(buf.match_count(&ab), Some((3, 3)));
+        buf.cursor_index = 13;
+        assert_eq!(buf.match_count(&ab), Some((3, 3)));
+        buf.cursor_index = 14;
+        assert_eq!(buf.match_count(&ab), Some((1, 3)), "past the last match wraps around");
+        assert_eq!(buf.match_count(&regex::Regex::new("x").unwrap()), None);
+
+        // the cache follows edits
+        buf.cursor_index = 0;
+        buf.insert_at(0, "ab ");
+        assert_eq!(buf.match_count(&ab), Some((1, 4)));
+        buf.text.undo();
+        assert_eq!(buf.match_count(&ab), Some((1, 3)));
+    }
+
+    #[test]
+    fn search_wrap() {
+        let buf = Buffer::with_text("ab cd ab cd");
+        let ab = regex::Regex::new("ab").unwrap();
+        assert_eq!(buf.search(&ab, 1, Direction::Forward, false), Some(6));
+        assert_eq!(buf.search(&ab, 6, Direction::Forward, false), Some(6), "a match at the start counts going forward");
+        assert_eq!(buf.search(&ab, 7, Direction::Forward, false), None);
+        assert_eq!(buf.search(&ab, 7, Direction::Forward, true), Some(0));
+        assert_eq!(buf.search(&ab, 6, Direction::Backward, false), Some(0));
+        assert_eq!(buf.search(&ab, 0, Direction::Backward, false), None);
+        assert_eq!(buf.search(&ab, 0, Direction::Backward, true), Some(6));
+        // when the only match is at the cursor, wrapping comes back around to it
+        let buf = Buffer::with_text("xx ab xx");
+        assert_eq!(buf.search(&ab, 4, Direction::Forward, true), Some(3));
+        assert_eq!(buf.search(&ab, 4, Direction::Forward, false), None);
+        assert_eq!(buf.search(&ab, 3, Direction::Backward, true), Some(3));
+        assert_eq!(buf.search(&ab, 3, Direction::Backward, false), None);
+        assert_eq!(buf.search(&regex::Regex::new("q").unwrap(), 3, Direction::Forward, true), None);
+    }
+
+    #[test]
+    fn word_query() {
+        let mut buf = Buffer::with_text("foo foobar foo_ foo.");
+        buf.cursor_index = 1;
+        assert_eq!(buf.set_word_query().as_deref(), Some("foo"));
+        assert_eq!(buf.query_matches(), vec![0..3, 16..19]);
+        buf.cursor_index = 3;
+        assert_eq!(buf.set_word_query(), None);
+        assert!(buf.query_whole_word, "the last query is kept");
+        buf.set_query("foo".into());
+        assert_eq!(buf.query_matches().len(), 4);
+    }
+
+    #[test]
+    fn word_under_cursor() {
+        let mut buf = Buffer::with_text("let foo_1 = a.b;\n  src/main.rs");
+        let mut word_at = |i| { buf.cursor_index = i; buf.word_under_cursor() };
+        assert_eq!(word_at(4), Some((4..9, "foo_1".into())), "start");
+        assert_eq!(word_at(6), Some((4..9, "foo_1".into())), "middle");
+        assert_eq!(word_at(8), Some((4..9, "foo_1".into())), "end");
+        assert_eq!(word_at(0), Some((0..3, "let".into())), "start of the buffer");
+        assert_eq!(word_at(12), Some((12..13, "a".into())), "before punctuation");
+        assert_eq!(word_at(3), None, "whitespace");
+        assert_eq!(word_at(13), None, "punctuation");
+        assert_eq!(word_at(16), None, "newline");
+        assert_eq!(word_at(29), Some((28..30, "rs".into())), "end of the buffer");
+        let is_path = |c: char| c.class() == CharClass::Regular || c == '/' || c == '.';
+        assert_eq!(buf.word_at(22, is_path), Some((19..30, "src/main.rs".into())));
+    }
+
+    #[test]
+    fn char_classes() {
+        let text = "a_1 .\t\n-é";
+        let buf = Buffer::with_text(text);
+        let classes: Vec<_> = text.char_indices().map(|(i, _)| buf.char_class_at(i).unwrap()).collect();
+        use CharClass::*;
+        assert_eq!(classes, vec![Regular, Regular, Regular, Whitespace, Punctuation, Whitespace, Whitespace, Punctuation, Regular]);
+        assert_eq!(buf.char_class_at(text.len()), None);
+    }
+
     #[test]
     fn search_forwards() {
         let src = "abc def abc abl abc pqr abc vwx yz\n";
@@ -288,5 +2052,75 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn reflow_prose() {
+        let mut buf = Buffer::with_text("before\nthe quick brown fox jumps over\nthe lazy dog and keeps on running far away\n\nnext paragraph\n");
+        let last = buf.reflow(1..5, 20);
+        assert_eq!(buf.text.text(), "before\nthe quick brown fox\njumps over the lazy\ndog and keeps on\nrunning far away\n\nnext paragraph\n");
+        assert_eq!(last, buf.index_for_line(6));
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "before\nthe quick brown fox jumps over\nthe lazy dog and keeps on running far away\n\nnext paragraph\n");
+    }
+
+    #[test]
+    fn reflow_comment_block() {
+        let mut buf = Buffer::with_text("fn f() {\n    // a comment that is much too long to fit\n    // in twenty-six columns\n    //\n    // more\n    let x = 1;\n}");
+        buf.reflow(1..5, 26);
+        assert_eq!(buf.text.text(), "fn f() {\n    // a comment that is\n    // much too long to\n    // fit in twenty-six\n    // columns\n    //\n    // more\n    let x = 1;\n}");
+        // long words still get a line and the last line of the buffer doesn't gain a newline
+        let mut buf = Buffer::with_text("# short\n# averyveryverylongword end");
+        buf.reflow(0..2, 10);
+        assert_eq!(buf.text.text(), "# short\n# averyveryverylongword\n# end");
+    }
+
+    #[test]
+    fn reindent() {
+        let mut config = crate::config::Config::default().text_options();
+        config.tabstop = 2;
+        let messy = "fn f() {\n      let x = [\n1,\n        2];\n\n   if x {\n  \"{\".len();\n } else { // {\ny(); }\n  }\n";
+        let tidy = "fn f() {\n  let x = [\n    1,\n    2];\n\n  if x {\n    \"{\".len();\n  } else { // {\n    y(); }\n}\n";
+        let mut buf = Buffer::with_text(messy);
+        assert_eq!(buf.reindent(0..10, &config), 0);
+        assert_eq!(buf.text.text(), tidy);
+        buf.reindent(0..10, &config);
+        assert_eq!(buf.text.text(), tidy, "reindenting again changes nothing");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), messy);
+
+        // part of the buffer carries on from the line before it, with tabs
+        config.softtab = false;
+        let mut buf = Buffer::with_text("a {\n\tb {\nc\n  }\n}");
+        assert_eq!(buf.reindent(2..4, &config), 11);
+        assert_eq!(buf.text.text(), "a {\n\tb {\n\t\tc\n\t}\n}");
+        buf.reindent(2..4, &config);
+        assert_eq!(buf.text.text(), "a {\n\tb {\n\t\tc\n\t}\n}");
+
+        // a blank last line is only deleted, which is still its own undo step
+        let mut buf = Buffer::with_text("a\n");
+        buf.text.insert_range("   ", 2);
+        buf.reindent(1..2, &config);
+        assert_eq!(buf.text.text(), "a\n");
+        assert!(buf.is_modified());
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "a\n   ");
+    }
+
+    #[test]
+    fn block_edit() {
+        let mut buf = Buffer::with_text("first line\nab\n\nthird line\nlast");
+        buf.apply_block_edit(1..4, 3, "// ");
+        assert_eq!(buf.text.text(), "first line\nab // \n   // \nthi// rd line\nlast");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "first line\nab\n\nthird line\nlast");
+        // the last line doesn't end in a newline and lines past the end of the buffer are skipped
+        buf.apply_block_edit(3..6, 0, "> ");
+        assert_eq!(buf.text.text(), "first line\nab\n\n> third line\n> last");
+        // inserting nothing leaves the text and the undo history alone
+        buf.apply_block_edit(0..5, 8, "");
+        assert_eq!(buf.text.text(), "first line\nab\n\n> third line\n> last");
+        buf.text.undo();
+        assert_eq!(buf.text.text(), "first line\nab\n\nthird line\nlast");
+    }
 }
 
@@ -1,6 +1,7 @@
 
 use super::*;
 use super::motion::*;
+use super::leader::LeaderAction;
 use std::ops::Range;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -11,7 +12,11 @@ pub enum Operator {
     Indent(Direction),
     MoveAndEnterMode(ModeTag),
     NewLineAndEnterMode(Direction, ModeTag),
-    ReplaceChar(char)
+    ReplaceChar(char),
+    /// rewrap lines to the configured text width, leaving the cursor where it was if `keep_cursor`
+    Reflow { keep_cursor: bool },
+    /// indent lines by how deeply they are nested in brackets
+    Reindent
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -23,17 +28,32 @@ pub enum ViewportMotion {
     ToggleScrollLock
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FoldCommand {
+    /// fold the lines covered by a motion
+    Create(Motion),
+    Open,
+    Close,
+    OpenAll,
+    CloseAll,
+    /// replace all folds with folds following the indentation of the buffer
+    ByIndent
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum Command {
     Move(Motion),
-    Repeat { count: usize },
+    /// repeat the last command, replacing its count if one is given
+    Repeat { count: Option<usize> },
     Undo { count: usize },
     Redo { count: usize },
     JoinLine { count: usize },
     Put {
         count: usize,
         source_register: char,
-        clear_register: bool
+        clear_register: bool,
+        /// match the indentation of whole lines to the current line
+        adjust_indent: bool
     },
     Edit {
         op: Operator,
@@ -41,19 +61,91 @@ pub enum Command {
         mo: Motion,
         target_register: char,
     },
-    Leader(char),
+    Leader { action: LeaderAction, count: usize },
     Viewport(ViewportMotion),
-    ChangeMode(ModeTag),
-    VisualSwitchSides
+    Fold(FoldCommand),
+    ChangeMode { mode: ModeTag, count: usize },
+    VisualSwitchSides,
+    /// swap the character under the cursor with the next one, like `xp`
+    TransposeChars { count: usize },
+    /// enter Visual mode with the last visual selection
+    ReselectVisual,
+    /// show the next or previous buffer in the current pane, like `gt` and `gT`
+    CycleBuffer { dir: Direction, count: usize }
+}
+
+/// store `text` in `register`, or append it to the lowercase register if `register` is an
+/// uppercase letter
+fn write_register(registers: &mut std::collections::BTreeMap<char, String>, register: char, text: String) {
+    if register.is_ascii_uppercase() {
+        registers.entry(register.to_ascii_lowercase()).or_default().push_str(&text);
+    } else {
+        registers.insert(register, text);
+    }
+}
+
+/// make the unnamed register `""` mirror the contents of `register`
+fn mirror_unnamed(registers: &mut std::collections::BTreeMap<char, String>, register: char) {
+    if let Some(text) = registers.get(&register.to_ascii_lowercase()).cloned() {
+        registers.insert('"', text);
+    }
+}
+
+/// record yanked text: `"0` holds the last yank unless a register was named. Nothing is recorded
+/// for the blackhole register `"_`
+pub fn record_yank(registers: &mut std::collections::BTreeMap<char, String>, register: char, text: String) {
+    if register == '_' { return; }
+    let register = if register == '"' { '0' } else { register };
+    write_register(registers, register, text);
+    mirror_unnamed(registers, register);
+}
+
+/// record yanked text in `register`, where `*` is the system clipboard
+pub fn yank(registers: &mut std::collections::BTreeMap<char, String>, register: char, text: String, client: PClientState) {
+    if register == '*' {
+        use copypasta::ClipboardProvider;
+        match copypasta::ClipboardContext::new().and_then(|mut cb| cb.set_contents(text)) {
+            Ok(()) => {},
+            Err(e) => ClientState::process_usr_msgp(client,
+                UserMessage::error(format!("error getting clipboard contents: {}", e), None)),
+        }
+    } else {
+        record_yank(registers, register, text);
+    }
+}
+
+/// record deleted text: deletes spanning lines shift through `"1`-`"9`, smaller ones go in `"-`,
+/// unless a register was named. Text deleted into the blackhole register `"_` is discarded
+pub fn record_delete(registers: &mut std::collections::BTreeMap<char, String>, register: char, text: String) {
+    let register = if register == '_' {
+        return;
+    } else if register != '"' {
+        register
+    } else if text.contains('\n') {
+        for r in (b'1'..b'9').rev() {
+            if let Some(t) = registers.remove(&(r as char)) {
+                registers.insert((r + 1) as char, t);
+            }
+        }
+        '1'
+    } else {
+        '-'
+    };
+    write_register(registers, register, text);
+    mirror_unnamed(registers, register);
 }
 
 impl Command {
+    /// parse a command typed in Normal mode
     pub fn parse(s: &str) -> Result<Command, Error> { Self::parse_2(s, None) }
+
+    /// parse a command, using `visual_mode` as the motion for operators (and `x` and `zf`) instead
+    /// of reading one after them, so that in Visual mode they act on `Motion::passthrough` of the
+    /// selection as soon as they are typed. Commands without an operator parse the same either way
     pub fn parse_2(s: &str, visual_mode: Option<Motion>) -> Result<Command, Error> {
         let mut target_reg: Option<char> = None;
         let mut schars = s.chars().peekable();
         match schars.peek() {
-            Some('i') => return Ok(Command::ChangeMode(ModeTag::Insert)),
             Some('I') => return Ok(Command::Edit {
                     op: Operator::MoveAndEnterMode(ModeTag::Insert),
                     mo: Motion { count: 1, mo: MotionType::StartOfLine },
@@ -81,18 +173,12 @@ impl Command {
                 op_count: 1, target_register: '"'
             }),
 
-            Some('v') => return Ok(Command::ChangeMode(ModeTag::Visual)),
-            Some(':') => return Ok(Command::ChangeMode(ModeTag::Command)),
-            Some('/') => return Ok(Command::ChangeMode(ModeTag::Search(Direction::Forward))),
-            Some('?') => return Ok(Command::ChangeMode(ModeTag::Search(Direction::Backward))),
-            Some('r') => {
-                schars.next();
-                return Ok(Command::Edit {
-                    op: Operator::ReplaceChar(schars.next().ok_or(Error::IncompleteCommand)?),
-                    mo: Motion { count: 0, mo: MotionType::Char(Direction::Forward) },
-                    op_count: 1, target_register: '"'
-                })
-            },
+            Some('v') => return Ok(Command::ChangeMode { mode: ModeTag::Visual, count: 1 }),
+            Some('V') => return Ok(Command::ChangeMode { mode: ModeTag::VisualLine, count: 1 }),
+            Some('g') if s.starts_with("gv") => return Ok(Command::ReselectVisual),
+            Some(':') => return Ok(Command::ChangeMode { mode: ModeTag::Command, count: 1 }),
+            Some('/') => return Ok(Command::ChangeMode { mode: ModeTag::Search(Direction::Forward), count: 1 }),
+            Some('?') => return Ok(Command::ChangeMode { mode: ModeTag::Search(Direction::Backward), count: 1 }),
             Some('"') => {
                 schars.next();
                 target_reg = schars.next();
@@ -101,16 +187,38 @@ impl Command {
             None => return Err(Error::InvalidCommand(String::from(s)))
         }
         let opcount = take_number(&mut schars);
+        let at_transpose = schars.clone().take(2).eq("gs".chars());
+        let at_cycle = schars.clone().take(2).eq("gt".chars()) || schars.clone().take(2).eq("gT".chars());
+        let at_indent_put = schars.clone().take(2).eq("]p".chars());
+        let at_reflow = schars.clone().take(2).eq("gq".chars()) || schars.clone().take(2).eq("gw".chars());
         let op = match schars.peek() {
-            Some('.') => return Ok(Command::Repeat { count: opcount.unwrap_or(1) }),
+            Some('.') => return Ok(Command::Repeat { count: opcount }),
+            Some('i') => return Ok(Command::ChangeMode { mode: ModeTag::Insert, count: opcount.unwrap_or(1) }),
             Some('u') => return Ok(Command::Undo { count: opcount.unwrap_or(1) }),
             Some('U') => return Ok(Command::Redo { count: opcount.unwrap_or(1) }),
             Some('J') => return Ok(Command::JoinLine { count: opcount.unwrap_or(1) }),
+            Some('g') if at_transpose => return Ok(Command::TransposeChars { count: opcount.unwrap_or(1) }),
+            Some('g') if at_cycle => {
+                schars.next();
+                let dir = if schars.peek() == Some(&'t') { Direction::Forward } else { Direction::Backward };
+                return Ok(Command::CycleBuffer { dir, count: opcount.unwrap_or(1) })
+            },
+            Some('r') => {
+                schars.next();
+                return Ok(Command::Edit {
+                    op: Operator::ReplaceChar(schars.next().ok_or(Error::IncompleteCommand)?),
+                    mo: Motion { count: 0, mo: MotionType::Char(Direction::Forward) },
+                    op_count: opcount.unwrap_or(1), target_register: '"'
+                })
+            },
             Some('d') => Some(Operator::Delete),
             Some('c') => Some(Operator::Change),
             Some('y') => Some(Operator::Yank),
             Some('<') => Some(Operator::Indent(Direction::Backward)),
             Some('>') => Some(Operator::Indent(Direction::Forward)),
+            Some('=') => Some(Operator::Reindent),
+            // skip the g so that the q or w is the operator character, which makes gqq and gww whole lines
+            Some('g') if at_reflow => { schars.next(); Some(Operator::Reflow { keep_cursor: schars.peek() == Some(&'w') }) },
             Some('x') => return Ok(Command::Edit {
                 op: Operator::Delete, op_count: opcount.unwrap_or(1), 
                 mo: visual_mode.unwrap_or(Motion { count: 1, mo: MotionType::Char(Direction::Forward) }),
@@ -119,17 +227,20 @@ impl Command {
             Some('p') => return Ok(Command::Put {
                 count: opcount.unwrap_or(1), 
                 source_register: target_reg.unwrap_or('"'),
-                clear_register: false
+                clear_register: false, adjust_indent: false
             }),
             Some('P') => return Ok(Command::Put {
                 count: opcount.unwrap_or(1), 
                 source_register: target_reg.unwrap_or('"'),
-                clear_register: true
+                clear_register: true, adjust_indent: false
             }),
-            Some(' ') => { schars.next(); return match schars.next() {
-                Some(c) => Ok(Command::Leader(c)),
-                None => Err(Error::IncompleteCommand)
-            } },
+            Some(']') if at_indent_put => return Ok(Command::Put {
+                count: opcount.unwrap_or(1),
+                source_register: target_reg.unwrap_or('"'),
+                clear_register: false, adjust_indent: true
+            }),
+            // leader sequences are resolved by the mode against the configured leader map
+            Some(' ') => return Err(Error::IncompleteCommand),
             Some('z') => { schars.next(); return match schars.next() {
                 Some('z') => Ok(Command::Viewport(ViewportMotion::CursorToMiddle)),
                 Some('t') => Ok(Command::Viewport(ViewportMotion::CursorToTop)),
@@ -137,6 +248,15 @@ impl Command {
                 Some('s') => Ok(Command::Viewport(ViewportMotion::ToggleScrollLock)),
                 Some('j') => Ok(Command::Viewport(ViewportMotion::Line(Direction::Forward, opcount.unwrap_or(1)))),
                 Some('k') => Ok(Command::Viewport(ViewportMotion::Line(Direction::Backward, opcount.unwrap_or(1)))),
+                Some('f') => Ok(Command::Fold(FoldCommand::Create(match visual_mode {
+                    Some(mo) => mo,
+                    None => Motion::parse(&mut schars, None, s)?
+                }))),
+                Some('o') => Ok(Command::Fold(FoldCommand::Open)),
+                Some('c') => Ok(Command::Fold(FoldCommand::Close)),
+                Some('R') => Ok(Command::Fold(FoldCommand::OpenAll)),
+                Some('M') => Ok(Command::Fold(FoldCommand::CloseAll)),
+                Some('i') => Ok(Command::Fold(FoldCommand::ByIndent)),
                 Some(_) => Err(Error::UnknownCommand(String::from(s))),
                 None => Err(Error::IncompleteCommand)
             } },
@@ -167,39 +287,47 @@ impl Command {
     fn count_mut(&mut self) -> Option<&mut usize> {
         match self {
             Command::JoinLine { count } => Some(count),
+            Command::TransposeChars { count } => Some(count),
             Command::Put { count, .. } => Some(count),
             Command::Edit { op_count, .. } => Some(op_count),
+            Command::ChangeMode { count, .. } => Some(count),
             _ => None
         }
     }
      
+    /// true if running the command may edit the text of the current buffer
+    pub fn changes_text(&self) -> bool {
+        match self {
+            Command::Repeat { .. } | Command::Undo { .. } | Command::Redo { .. } | Command::JoinLine { .. }
+                | Command::Put { .. } | Command::TransposeChars { .. } => true,
+            Command::Edit { op, .. } => *op != Operator::Yank,
+            Command::ChangeMode { mode, .. } => *mode == ModeTag::Insert,
+            Command::Leader { action, .. } => matches!(action, LeaderAction::Duplicate(_)),
+            _ => false
+        }
+    }
+
     pub fn execute(&self, state: &mut editor_state::EditorState, client: PClientState) -> Result<Option<ModeTag>, Error> {
+        if self.changes_text() {
+            if let Some(buf) = state.current_buffer() {
+                buf.check_writable()?;
+            }
+        }
         if let Command::Repeat { count } = self {
             let mut cmd = state.last_command.ok_or_else(|| Error::InvalidCommand("no previous command".into()))?;
-            dbg!(cmd);
-            if let Command::ChangeMode(ModeTag::Insert) = cmd {
-                if let Some(buf) = state.current_buffer_index() {
-                    let buf = &mut state.buffers[buf];
-                    let inserted_piece = match buf.text.history.last() {
-                        Some(act) => {
-                            if let piece_table::Change::Insert { new, .. } = act.changes[if act.changes.len() == 1 { 0 } else { 1 }] {
-                                new
-                            } else {
-                                panic!("couldn't find text to reinsert {:?}", buf.text.history.last());
-                            }
-                        },
-                        None => panic!()
-                    };
-                    dbg!(inserted_piece);
-                    for _ in 0..*count {
-                        buf.text.insert_raw_piece(buf.cursor_index, inserted_piece.clone());
-                        buf.cursor_index += inserted_piece.length;
-                    }
+            if let Command::ChangeMode { mode: ModeTag::Insert, count: insert_count } = cmd {
+                let text = state.registers.get(&'.').ok_or(Error::EmptyRegister('.'))?.clone();
+                if let Some(buf) = state.current_buffer_mut() {
+                    let count = count.unwrap_or(insert_count);
+                    let end = buf.insert_at(buf.cursor_index, &text.repeat(count));
+                    buf.set_cursor(end);
                 }
                 return Ok(None);
             }
             else {
-                cmd.count_mut().map(|c| *c = *count);
+                if let (Some(count), Some(c)) = (count, cmd.count_mut()) {
+                    *c = *count;
+                }
                 return cmd.execute(state, client);
             }
         }
@@ -207,11 +335,11 @@ impl Command {
             Command::Move(mo) => {
                 if let Some(buf) = state.current_buffer_mut() {
                     let Range { start: _, end } = mo.range(buf, buf.cursor_index, 1);
-                    buf.cursor_index = end;
+                    buf.set_cursor(end);
                 }
                 Ok(None)
             },
-            Command::Put { count: _, source_register, clear_register } => {
+            Command::Put { count: _, source_register, clear_register, adjust_indent } => {
                 state.last_command = Some(*self);
                 if let Some(buf) = state.current_buffer_index() {
                     let buf = &mut state.buffers[buf];
@@ -227,7 +355,12 @@ impl Command {
                                 }
                             }
                         },
-                        srg => state.registers.get(srg).ok_or(Error::EmptyRegister(*source_register))?.clone()
+                        srg => state.registers.get(&srg.to_ascii_lowercase()).ok_or(Error::EmptyRegister(*source_register))?.clone()
+                    };
+                    let src = if *adjust_indent && src.ends_with('\n') {
+                        buf.auto_indent(&src, buf.cursor_index, &buf.options.resolve(&client.read().unwrap().config))
+                    } else {
+                        src
                     };
                     // we need to check here to see if src contains a full line so that we can put it _after_ the current line
                     let insertion_point = if let Some('\n') = src.chars().last() {
@@ -236,10 +369,10 @@ impl Command {
                     } else {
                         buf.cursor_index
                     };
-                    buf.text.insert_range(&src, insertion_point);
-                    buf.cursor_index = insertion_point + src.len().saturating_sub(1);
+                    buf.insert_at(insertion_point, &src);
+                    buf.set_cursor(insertion_point + src.len().saturating_sub(1));
                     if *clear_register {
-                        state.registers.remove(source_register);
+                        state.registers.remove(&source_register.to_ascii_lowercase());
                     }
                 }
                 Ok(None)
@@ -247,7 +380,19 @@ impl Command {
             Command::Undo { count } => {
                 if let Some(buf) = state.current_buffer_mut() {
                     for _ in 0..*count {
-                        buf.text.undo();
+                        if let Some(ix) = buf.text.undo() {
+                            buf.set_cursor(ix);
+                        }
+                    }
+                }
+                Ok(None)
+            },
+            Command::Redo { count } => {
+                if let Some(buf) = state.current_buffer_mut() {
+                    for _ in 0..*count {
+                        if let Some(ix) = buf.text.redo() {
+                            buf.set_cursor(ix);
+                        }
                     }
                 }
                 Ok(None)
@@ -255,9 +400,31 @@ impl Command {
             Command::JoinLine { count } => {
                 state.last_command = Some(*self);
                 if let Some(buf) = state.current_buffer_mut() {
-                    for _ in 0..*count {
-                        let ln = buf.next_line_index(buf.cursor_index);
-                        buf.text.delete_range(ln-1, ln);
+                    // a count is the number of lines to join, so J and 2J both join two lines
+                    if let Some(ix) = buf.join_lines(buf.cursor_index, count.max(&2) - 1) {
+                        buf.set_cursor(ix);
+                    }
+                }
+                Ok(None)
+            },
+            Command::TransposeChars { count } => {
+                state.last_command = Some(*self);
+                if let Some(buf) = state.current_buffer_mut() {
+                    let mut swaps = 0;
+                    for i in 0..*count {
+                        // only the first swap may look back from the end of the line, otherwise the
+                        // character would just bounce back and forth
+                        if i > 0 && matches!(buf.text.char_at(buf.next_char_index(buf.cursor_index)), Some('\n') | None) {
+                            break;
+                        }
+                        match buf.transpose_chars(buf.cursor_index) {
+                            Some(ix) => buf.set_cursor(ix),
+                            None => break
+                        }
+                        swaps += 1;
+                    }
+                    if swaps > 1 {
+                        buf.text.merge_last_actions(swaps);
                     }
                 }
                 Ok(None)
@@ -268,26 +435,30 @@ impl Command {
                     &mut state.buffers[b]
                 } else { return Err(Error::InvalidCommand("".into())); };
                 match op {
-                    Operator::Delete | Operator::Change => {
-                        let mut r = mo.range(buf, buf.cursor_index, *op_count);
-                        if mo.mo.inclusive() {
-                            r.end += 1;
+                    Operator::Delete if mo.mo == MotionType::WholeLine => {
+                        let line = buf.line_for_index(buf.cursor_index);
+                        if let Some(deleted) = buf.delete_line(line, mo.count * *op_count) {
+                            record_delete(&mut state.registers, *target_register, deleted);
                         }
+                        Ok(None)
+                    },
+                    Operator::Delete | Operator::Change => {
+                        let mut r = mo.operator_range(buf, buf.cursor_index, *op_count);
                         if r.start != r.end {
                             // adjust range for changing so that it doesn't grab trailing
                             // whitespace, especially newlines
-                            if *op == Operator::Change && buf.text.char_at(r.start).map(motion::CharClassify::class)
+                            // a selection is changed exactly as it is
+                            if *op == Operator::Change && !matches!(mo.mo, MotionType::Passthrough(..)) && buf.char_class_at(r.start)
                                 .map_or(false, |c| c != CharClass::Whitespace) {
-                                    while buf.text.char_at(r.end.saturating_sub(1)).map(motion::CharClassify::class)
-                                        .map_or(false, |c| c == CharClass::Whitespace) {
+                                    while buf.char_class_at(r.end.saturating_sub(1)) == Some(CharClass::Whitespace) {
                                             println!("{}", r.end);
                                             r.end = r.end.saturating_sub(1);
                                         }
                             }
-                            state.registers.insert(*target_register, buf.text.copy_range(r.start, r.end));
+                            record_delete(&mut state.registers, *target_register, buf.text.copy_range(r.start, r.end));
                             buf.text.delete_range(r.start, r.end);
+                            buf.set_cursor(r.start);
                         }
-                        buf.cursor_index = r.start;
                         Ok(if *op == Operator::Change {
                             Some(ModeTag::Insert)
                         } else {
@@ -295,34 +466,22 @@ impl Command {
                         })
                     },
                     Operator::Yank => {
-                        let mut r = mo.range(buf, buf.cursor_index, *op_count);
-                        if mo.mo.inclusive() {
-                            r.end += 1;
-                        }
+                        let r = mo.operator_range(buf, buf.cursor_index, *op_count);
                         let txt = buf.text.copy_range(r.start, r.end);
-                        if *target_register == '*' {
-                            use copypasta::ClipboardProvider;
-                            match copypasta::ClipboardContext::new().and_then(|mut cb| cb.set_contents(txt)) {
-                                Ok(()) => {},
-                                Err(e) => ClientState::process_usr_msgp(client,
-                                    UserMessage::error(format!("error getting clipboard contents: {}", e), None)),
-                            }
-                        } else {
-                            state.registers.insert(*target_register, txt);
-                        }
+                        yank(&mut state.registers, *target_register, txt, client);
                         Ok(None)
                     },
                     Operator::ReplaceChar(c) => {
-                        let cursor_index = buf.cursor_index;
-                        buf.text.delete_range(cursor_index, cursor_index+1);
-                        let mut m = buf.text.insert_mutator(cursor_index);
-                        m.push_char(&mut buf.text, *c);
+                        if let Some(last) = buf.replace_char_count(buf.cursor_index, *c, *op_count) {
+                            buf.set_cursor(last);
+                        }
                         Ok(None)
                     },
                     Operator::MoveAndEnterMode(mode) => {
                         let Range { start: _, end } = mo.range(buf, buf.cursor_index, 1);
-                        buf.cursor_index = end;
-                        if mo.mo.inclusive() { buf.cursor_index += 1; }
+                        // inclusive motions like `e` put the cursor after the character they end on
+                        let after = if mo.mo.inclusive() { buf.next_char_index(end) } else { end };
+                        buf.set_cursor(after);
                         Ok(Some(*mode))
                     },
                     Operator::NewLineAndEnterMode(dir, mode) => {
@@ -330,13 +489,12 @@ impl Command {
                             Direction::Forward => buf.next_line_index(buf.cursor_index),
                             Direction::Backward => buf.current_start_of_line(buf.cursor_index)
                         };
-                        buf.text.insert_range("\n", idx);
-                        let cfg = &client.read().unwrap().config;
-                        let indent_level = buf.sense_indent_level(buf.cursor_index, cfg);
-                        buf.cursor_index = idx + buf.indent(idx, indent_level, cfg);
-                        if idx == buf.text.len()-1 {
-                            buf.cursor_index = 1;
-                        }
+                        buf.insert_at(idx, "\n");
+                        let cs = client.read().unwrap();
+                        let cfg = buf.options.resolve(&cs.config);
+                        let indent_level = buf.sense_indent_level(buf.cursor_index, &cfg);
+                        let indent = buf.indent(idx, indent_level, &cfg);
+                        buf.set_cursor(if idx == buf.text.len()-1 { 1 } else { idx + indent });
                         Ok(Some(*mode))
                     }
                     Operator::Indent(direction) => {
@@ -346,12 +504,14 @@ impl Command {
                             Direction::Forward => r.end,
                             Direction::Backward => r.end - 1
                         } + if mo.mo.inclusive() { 1 } else { 0 });
+                        let cs = client.read().unwrap();
+                        let cfg = buf.options.resolve(&cs.config);
                         while ln <= end {
                             //println!("ln = {}, r = {:?}", ln, r);
                             if *direction == Direction::Forward {
-                                buf.indent(ln, 1, &client.read().unwrap().config);
+                                buf.indent(ln, 1, &cfg);
                             } else {
-                                buf.undent(ln, 1, &client.read().unwrap().config);
+                                buf.undent(ln, 1, &cfg);
                             }
                             let nln = buf.next_line_index(ln);
                             if ln == nln { break; }
@@ -359,41 +519,88 @@ impl Command {
                         }
                         Ok(None)
                     }, 
+                    Operator::Reflow { keep_cursor } => {
+                        // like Vim, this always acts on every line the motion touches
+                        let r = mo.range(buf, buf.cursor_index, *op_count);
+                        let first = buf.line_for_index(r.start);
+                        let last = buf.line_for_index(if mo.mo == MotionType::WholeLine {
+                            r.end.saturating_sub(1).max(r.start)
+                        } else { r.end });
+                        let (line, col) = (buf.line_for_index(buf.cursor_index), buf.column_for_index(buf.cursor_index));
+                        let textwidth = buf.options.resolve(&client.read().unwrap().config).textwidth;
+                        let last_start = buf.reflow(first..last+1, textwidth);
+                        buf.set_cursor(if *keep_cursor {
+                            buf.index_for_column(buf.index_for_line(line), col)
+                        } else {
+                            last_start
+                        });
+                        Ok(None)
+                    },
+                    Operator::Reindent => {
+                        let r = mo.range(buf, buf.cursor_index, *op_count);
+                        let first = buf.line_for_index(r.start);
+                        let last = buf.line_for_index(if mo.mo == MotionType::WholeLine {
+                            r.end.saturating_sub(1).max(r.start)
+                        } else { r.end });
+                        let cs = client.read().unwrap();
+                        let cfg = buf.options.resolve(&cs.config);
+                        let first_nonblank = buf.reindent(first..last+1, &cfg);
+                        buf.set_cursor(first_nonblank);
+                        Ok(None)
+                    },
                 }
             },
 
-            &Command::ChangeMode(mode) => {
+            &Command::ChangeMode { mode, .. } => {
                 if mode == ModeTag::Insert {
                     state.last_command = Some(*self);
                 }
                 Ok(Some(mode))
             },
-            Command::Leader(c) => match c {
-                'h' | 'j' | 'k' | 'l' => {
-                    if let Some(ng) = state.current_pane().neighbors[match c {
-                        'h' => 0,
-                        'j' => 3,
-                        'k' => 2,
-                        'l' => 1,
-                        _ => unreachable!()
-                    }] {
-                        state.current_pane = ng;
-                    }
-                    Ok(None)
-                },
-                's' | 'v' => {
-                    let nc = state.current_pane().content.clone();
-                    Pane::split(&mut state.panes, state.current_pane, *c == 'v', 0.5, nc);
-                    Ok(None)
-                },
-                'x' => {
-                    if state.panes.len() == 1 {
-                        return Err(Error::InvalidCommand("can't delete all panes".into()));
+            Command::ReselectVisual => {
+                let sel = state.last_visual_selection.clone()
+                    .filter(|sel| state.current_buffer_index() == Some(sel.buffer_index))
+                    .ok_or_else(|| Error::InvalidCommand("no previous visual selection".into()))?;
+                let buf = state.current_buffer_mut().unwrap();
+                buf.set_cursor(if sel.side { sel.range.end } else { sel.range.start });
+                Ok(Some(if sel.linewise { ModeTag::VisualLine } else { ModeTag::Visual }))
+            },
+            Command::CycleBuffer { dir, count } => {
+                state.cycle_buffer(*dir, *count).ok_or_else(|| Error::InvalidCommand("no buffers are open".into()))?;
+                Ok(None)
+            },
+            Command::Leader { action, count } => {
+                for _ in 0..*count {
+                    match action {
+                        LeaderAction::FocusPane(dir) => {
+                            state.unzoom();
+                            if let Some(ng) = state.current_pane().neighbors[*dir] {
+                                state.current_pane = ng;
+                            }
+                        },
+                        LeaderAction::SplitPane { vertical } => {
+                            state.unzoom();
+                            let nc = state.current_pane().content.clone();
+                            Pane::split(&mut state.panes, state.current_pane, *vertical, 0.5, nc);
+                        },
+                        LeaderAction::ClosePane => {
+                            if state.panes.len() == 1 {
+                                return Err(Error::InvalidCommand("can't delete all panes".into()));
+                            }
+                            state.unzoom();
+                            state.current_pane = Pane::remove(&mut state.panes, state.current_pane);
+                        },
+                        LeaderAction::ZoomPane => state.toggle_zoom(),
+                        LeaderAction::Duplicate(dir) => {
+                            if let Some(buf) = state.current_buffer_mut() {
+                                let start = buf.current_start_of_line(buf.cursor_index);
+                                let end = buf.next_line_index(buf.cursor_index);
+                                buf.duplicate(start..end, *dir, true);
+                            }
+                        }
                     }
-                    state.current_pane = Pane::remove(&mut state.panes, state.current_pane);
-                    Ok(None)
-                },
-                _ => Err(Error::UnknownCommand(format!("unknown leader command {}", c)))
+                }
+                Ok(None)
             },
             
             Command::Viewport(mo) => match mo {
@@ -438,6 +645,32 @@ impl Command {
                 }
             },
 
+            Command::Fold(fc) => {
+                let buf = state.current_buffer_mut()
+                    .ok_or_else(|| Error::InvalidCommand("can't fold on non-buffer pane".into()))?;
+                let curln = buf.line_for_index(buf.cursor_index);
+                match fc {
+                    FoldCommand::Create(mo) => {
+                        let r = mo.range(buf, buf.cursor_index, 1);
+                        let start = buf.line_for_index(r.start.min(r.end));
+                        let end = buf.line_for_index(r.start.max(r.end));
+                        if !buf.create_fold(start .. end+1) {
+                            return Err(Error::InvalidCommand("folds can only nest, not overlap".into()));
+                        }
+                    },
+                    FoldCommand::Open => { buf.open_fold(curln); },
+                    FoldCommand::Close => { buf.close_fold(curln); },
+                    FoldCommand::OpenAll => buf.set_all_folds(false),
+                    FoldCommand::CloseAll => buf.set_all_folds(true),
+                    FoldCommand::ByIndent => buf.fold_by_indent()
+                }
+                // keep the cursor out of hidden lines
+                if let Some(f) = buf.closed_fold_containing(curln) {
+                    buf.set_cursor(buf.index_for_line(f.start));
+                }
+                Ok(None)
+            },
+
             _ => Err(Error::UnknownCommand(format!("unimplemented {:?}", self)))
         }
     }
@@ -449,7 +682,13 @@ mod tests {
     #[test]
     fn cmd_parse_correct() -> Result<(), Error> {
         assert_eq!(Command::parse("i")?,
-            Command::ChangeMode(ModeTag::Insert));
+            Command::ChangeMode { mode: ModeTag::Insert, count: 1 });
+        assert_eq!(Command::parse("3i")?,
+            Command::ChangeMode { mode: ModeTag::Insert, count: 3 });
+        assert_eq!(Command::parse(".")?,
+            Command::Repeat { count: None });
+        assert_eq!(Command::parse("2.")?,
+            Command::Repeat { count: Some(2) });
         assert_eq!(Command::parse("x")?,
             Command::Edit{
                 op: Operator::Delete, op_count: 1,
@@ -489,6 +728,464 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parse_with_selection() -> Result<(), Error> {
+        let sel = Motion::passthrough(&(2..6));
+        for (cmd, op, op_count, target_register) in [("d", Operator::Delete, 1, '"'), ("y", Operator::Yank, 1, '"'),
+                ("c", Operator::Change, 1, '"'), ("x", Operator::Delete, 1, '"'), ("2d", Operator::Delete, 2, '"'),
+                ("\"ay", Operator::Yank, 1, 'a'), (">", Operator::Indent(Direction::Forward), 1, '"')].iter() {
+            assert_eq!(Command::parse_2(cmd, Some(sel))?, Command::Edit { op: *op, op_count: *op_count, mo: sel, target_register: *target_register },
+                "{} acts on the selection", cmd);
+        }
+        // motions still move
+        assert_eq!(Command::parse_2("w", Some(sel))?,
+            Command::Move(Motion { count: 1, mo: MotionType::Word(Direction::Forward) }));
+        assert_eq!(Command::parse_2("zf", Some(sel))?, Command::Fold(FoldCommand::Create(sel)));
+        Ok(())
+    }
+
+    #[test]
+    fn edit_selection() -> Result<(), Error> {
+        let sel = Motion::passthrough(&Range { start: 6, end: 2 });
+        let (mut state, client) = test_state("abc def ghi\n");
+        state.buffers[0].cursor_index = 6;
+        Command::parse_2("y", Some(sel))?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("c def"));
+        Command::parse_2("d", Some(sel))?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "ab ghi\n");
+        assert_eq!(state.buffers[0].cursor_index, 2);
+        // trailing whitespace in the selection is changed too, unlike with cw
+        let (mut state, client) = test_state("abc def ghi\n");
+        let res = Command::parse_2("c", Some(Motion::passthrough(&(4..7))))?.execute(&mut state, client.clone())?;
+        assert_eq!(res, Some(ModeTag::Insert));
+        assert_eq!(state.buffers[0].text.text(), "abc ghi\n");
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("def "));
+        Ok(())
+    }
+
+    fn test_state(text: &str) -> (editor_state::EditorState, PClientState) {
+        let mut state = editor_state::EditorState::new();
+        state.buffers.push(buffer::Buffer::with_text(text));
+        state.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        (state, Arc::new(RwLock::new(ClientState::default())))
+    }
+
+    #[test]
+    fn zoom_then_close() -> Result<(), Error> {
+        let (mut state, client) = test_state("");
+        let leader = |action| Command::Leader { action, count: 1 };
+        state.current_pane = Pane::split(&mut state.panes, 0, true, 0.5, PaneContent::buffer(0));
+        leader(LeaderAction::ZoomPane).execute(&mut state, client.clone())?;
+        assert!(state.zoomed_layout.is_some());
+        // closing a pane puts the layout back first, so its neighbor fills the screen
+        leader(LeaderAction::ClosePane).execute(&mut state, client.clone())?;
+        assert!(state.zoomed_layout.is_none());
+        assert_eq!(state.panes.len(), 1);
+        let b = state.current_pane().bounds;
+        assert_eq!((b.x, b.y, b.w, b.h), (0.0, 0.0, 1.0, 1.0));
+        leader(LeaderAction::ZoomPane).execute(&mut state, client.clone())?;
+        leader(LeaderAction::ZoomPane).execute(&mut state, client.clone())?;
+        let b = state.current_pane().bounds;
+        assert_eq!((b.x, b.y, b.w, b.h), (0.0, 0.0, 1.0, 1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn counted_insert() -> Result<(), Error> {
+        let (mut state, client) = test_state("");
+        // 3ix<Esc>
+        assert_eq!(Command::parse("3i")?.execute(&mut state, client.clone())?, Some(ModeTag::Insert));
+        let buf = &mut state.buffers[0];
+        let start = buf.cursor_index;
+        let mut tmut = buf.text.insert_mutator(start);
+        tmut.push_char(&mut buf.text, 'x');
+        buf.cursor_index += 1;
+        let inserted = buf.repeat_insertion(&mut tmut, start, 0, 3);
+        tmut.finish(&mut buf.text);
+        state.registers.insert('.', inserted);
+        assert_eq!(state.buffers[0].text.text(), "xxx");
+        assert_eq!(state.buffers[0].cursor_index, 3);
+        // the repeats are part of the insert
+        Command::parse("u")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "");
+        Command::parse("U")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "xxx");
+
+        // repeating uses the count from the original insert unless given a new one
+        Command::parse(".")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "xxxxxx");
+        Command::parse("2.")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "xxxxxxxx");
+        Ok(())
+    }
+
+    #[test]
+    fn join_lines() -> Result<(), Error> {
+        let (mut state, client) = test_state("a\n  b\nc\nd\ne\nf\ng");
+        Command::parse("J")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "a b\nc\nd\ne\nf\ng");
+        assert_eq!(state.buffers[0].cursor_index, 1, "the cursor goes to the inserted space");
+        Command::parse("3J")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "a b c d\ne\nf\ng", "3J joins three lines");
+        assert_eq!(state.buffers[0].cursor_index, 5);
+        Command::parse(".")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "a b c d e f\ng");
+        state.buffers[0].text.undo();
+        assert_eq!(state.buffers[0].text.text(), "a b c d\ne\nf\ng", "each join is one undo step");
+        Ok(())
+    }
+
+    #[test]
+    fn replace_chars() -> Result<(), Error> {
+        let (mut state, client) = test_state("abcdef\nghi\n");
+        state.buffers[0].cursor_index = 1;
+        Command::parse("3rx")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "axxxef\nghi\n");
+        assert_eq!(state.buffers[0].cursor_index, 3, "the cursor ends on the last replaced char");
+        state.buffers[0].cursor_index = 4;
+        Command::parse("5ry")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "axxxyy\nghi\n", "the newline is never replaced");
+        assert_eq!(state.buffers[0].cursor_index, 5);
+        state.buffers[0].text.undo();
+        assert_eq!(state.buffers[0].text.text(), "axxxef\nghi\n", "one undo step");
+        state.buffers[0].cursor_index = 4;
+        Command::parse("rz")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "axxxzf\nghi\n");
+        Ok(())
+    }
+
+    #[test]
+    fn search_word_under_cursor() -> Result<(), Error> {
+        let (mut state, client) = test_state("let x = xs;\nx += 1;\n");
+        state.buffers[0].cursor_index = 4;
+        Command::parse("*")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].cursor_index, 12);
+        Command::parse("n")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].cursor_index, 4, "n wraps around");
+        Command::parse("d#")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "let x += 1;\n");
+        Ok(())
+    }
+
+    #[test]
+    fn undo_moves_cursor_to_change() -> Result<(), Error> {
+        let (mut state, client) = test_state("abc def ghi\njkl\n");
+        state.buffers[0].cursor_index = 4;
+        Command::parse("dw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "abc ghi\njkl\n");
+        state.buffers[0].cursor_index = 9;
+        Command::parse("u")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "abc def ghi\njkl\n");
+        assert_eq!(state.buffers[0].cursor_index, 4);
+        state.buffers[0].cursor_index = 0;
+        Command::parse("U")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "abc ghi\njkl\n");
+        assert_eq!(state.buffers[0].cursor_index, 4);
+        Command::parse("p")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "abc def ghi\njkl\n");
+        state.buffers[0].cursor_index = 12;
+        Command::parse("u")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "abc ghi\njkl\n");
+        assert_eq!(state.buffers[0].cursor_index, 4, "start of the put text");
+        Ok(())
+    }
+
+    #[test]
+    fn counted_repeat() -> Result<(), Error> {
+        let (mut state, client) = test_state("a b c d e f\n");
+        Command::parse("2dw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "c d e f\n");
+        Command::parse(".")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "e f\n");
+        Command::parse("1.")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "f\n");
+        Ok(())
+    }
+
+    #[test]
+    fn transpose_chars() -> Result<(), Error> {
+        let (mut state, client) = test_state("abcd\n");
+        Command::parse("gs")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "bacd\n");
+        assert_eq!(state.buffers[0].cursor_index, 1);
+        // with a count the character keeps moving forward
+        state.buffers[0].cursor_index = 0;
+        Command::parse("2gs")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "acbd\n");
+        Command::parse(".")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "acdb\n");
+        assert_eq!(state.buffers[0].cursor_index, 3);
+        Command::parse("u")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "acbd\n");
+        // the swaps of a count are one undo step
+        state.buffers[0].cursor_index = 0;
+        Command::parse("3gs")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "cbda\n");
+        Command::parse("u")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "acbd\n");
+        Ok(())
+    }
+
+    #[test]
+    fn cycle_buffers() -> Result<(), Error> {
+        let (mut state, client) = test_state("a");
+        state.buffers.push(buffer::Buffer::with_text("b"));
+        state.buffers.push(buffer::Buffer::with_text("c"));
+        assert_eq!(Command::parse("gt")?, Command::CycleBuffer { dir: Direction::Forward, count: 1 });
+        assert_eq!(Command::parse("2gT")?, Command::CycleBuffer { dir: Direction::Backward, count: 2 });
+        Command::parse("gt")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.current_buffer_index(), Some(1));
+        Command::parse("2gt")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.current_buffer_index(), Some(0));
+        Command::parse("gT")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.current_buffer_index(), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn put_with_indent() -> Result<(), Error> {
+        let (mut state, client) = test_state("fn f() {\n        deep();\n}\n");
+        state.registers.insert('a', String::from("if x {\n    y();\n}\n"));
+        // into a more indented context
+        state.buffers[0].cursor_index = 12;
+        Command::parse("\"a]p")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(),
+            "fn f() {\n        deep();\n        if x {\n            y();\n        }\n}\n");
+        // and a less indented one
+        state.registers.insert('b', String::from("\t\tz();\n\n\t\t\tw();\n"));
+        state.buffers[0].cursor_index = 0;
+        Command::parse("\"b]p")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(),
+            "fn f() {\nz();\n\n    w();\n        deep();\n        if x {\n            y();\n        }\n}\n");
+        // text that isn't whole lines goes in as it is
+        state.registers.insert('c', String::from("  c"));
+        state.buffers[0].cursor_index = 0;
+        Command::parse("\"c]p")?.execute(&mut state, client.clone())?;
+        assert!(state.buffers[0].text.text().starts_with("  cfn f() {\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn reindent() -> Result<(), Error> {
+        assert_eq!(Command::parse("==")?, Command::Edit {
+            op: Operator::Reindent, op_count: 1,
+            mo: Motion { count: 1, mo: MotionType::WholeLine }, target_register: '"'
+        });
+        let (mut state, client) = test_state("{\n  {\na\n      }\nb\n}\n");
+        state.buffers[0].cursor_index = 2;
+        Command::parse("=2j")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "{\n    {\n        a\n    }\nb\n}\n");
+        assert_eq!(state.buffers[0].cursor_index, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn reflow() -> Result<(), Error> {
+        assert_eq!(Command::parse("gqq")?, Command::Edit {
+            op: Operator::Reflow { keep_cursor: false }, op_count: 1,
+            mo: Motion { count: 1, mo: MotionType::WholeLine }, target_register: '"'
+        });
+        let (mut state, client) = test_state("aaa bbb ccc ddd\neee\n\n// fff ggg hhh\n");
+        client.write().unwrap().config.textwidth = 8;
+        Command::parse("gqj")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "aaa bbb\nccc ddd\neee\n\n// fff ggg hhh\n");
+        assert_eq!(state.buffers[0].cursor_index, 16);
+        // gw leaves the cursor where it was
+        state.buffers[0].cursor_index = 25;
+        Command::parse("gww")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "aaa bbb\nccc ddd\neee\n\n// fff\n// ggg\n// hhh\n");
+        assert_eq!(state.buffers[0].cursor_index, 25);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_to_end_of_word() -> Result<(), Error> {
+        let delete = |text: &str, cmd: &str, cursor: usize| -> Result<(String, usize), Error> {
+            let (mut state, client) = test_state(text);
+            state.buffers[0].cursor_index = cursor;
+            Command::parse(cmd)?.execute(&mut state, client)?;
+            Ok((state.buffers[0].text.text(), state.buffers[0].cursor_index))
+        };
+        assert_eq!(delete("foo bar baz\n", "de", 0)?, (" bar baz\n".into(), 0));
+        assert_eq!(delete("foo bar baz\n", "de", 1)?, ("f bar baz\n".into(), 1));
+        assert_eq!(delete("foo bar baz\n", "de", 2)?, ("fo baz\n".into(), 2), "from the end of a word");
+        assert_eq!(delete("foo bar baz\n", "d2e", 0)?, (" baz\n".into(), 0));
+        // punctuation is its own word, unless it's a WORD
+        assert_eq!(delete("foo.bar baz\n", "de", 0)?, (".bar baz\n".into(), 0));
+        assert_eq!(delete("foo.bar baz\n", "de", 3)?, ("foo baz\n".into(), 3));
+        assert_eq!(delete("foo.bar baz\n", "dE", 0)?, (" baz\n".into(), 0));
+        // at the end of the buffer
+        assert_eq!(delete("foo bar", "de", 4)?.0, "foo ");
+        assert_eq!(delete("foo bar", "de", 6)?.0, "foo ba");
+        assert_eq!(delete("foo bar\n", "de", 6)?.0, "foo ba\n", "doesn't take the newline");
+        Ok(())
+    }
+
+    #[test]
+    fn delete_to_end_of_previous_word() -> Result<(), Error> {
+        let delete = |text: &str, cmd: &str, cursor: usize| -> Result<(String, usize), Error> {
+            let (mut state, client) = test_state(text);
+            state.buffers[0].cursor_index = cursor;
+            Command::parse(cmd)?.execute(&mut state, client)?;
+            Ok((state.buffers[0].text.text(), state.buffers[0].cursor_index))
+        };
+        assert_eq!(delete("foo bar baz\n", "dge", 5)?, ("for baz\n".into(), 2));
+        assert_eq!(delete("foo bar baz\n", "dge", 8)?, ("foo baaz\n".into(), 6), "from the start of a word");
+        assert_eq!(delete("foo.bar\n", "dge", 5)?, ("foor\n".into(), 3));
+        // at the start of the buffer
+        assert_eq!(delete("foo bar\n", "dge", 2)?, (" bar\n".into(), 0));
+        assert_eq!(delete("foo.bar\n", "dgE", 5)?, ("r\n".into(), 0));
+        Ok(())
+    }
+
+    #[test]
+    fn quote_objects() -> Result<(), Error> {
+        let edit = |cmd: &str, cursor: usize| -> Result<(String, usize, Option<ModeTag>), Error> {
+            let (mut state, client) = test_state("say \"one\" and \"two\" end\n");
+            state.buffers[0].cursor_index = cursor;
+            let mode = Command::parse(cmd)?.execute(&mut state, client)?;
+            Ok((state.buffers[0].text.text(), state.buffers[0].cursor_index, mode))
+        };
+        // on the opening quote, inside and on the closing quote
+        assert_eq!(edit("di\"", 4)?.0, "say \"\" and \"two\" end\n");
+        assert_eq!(edit("di\"", 6)?.0, "say \"\" and \"two\" end\n");
+        assert_eq!(edit("da\"", 8)?.0, "say  and \"two\" end\n");
+        // between two strings goes to the next one
+        assert_eq!(edit("di\"", 10)?.0, "say \"one\" and \"\" end\n");
+        assert_eq!(edit("ci\"", 16)?, ("say \"one\" and \"\" end\n".into(), 15, Some(ModeTag::Insert)));
+        // after the last string there's nothing to change
+        assert_eq!(edit("di\"", 21)?, ("say \"one\" and \"two\" end\n".into(), 21, None));
+
+        let (mut state, client) = test_state("\"a\\\"b\" x\n");
+        state.buffers[0].cursor_index = 1;
+        Command::parse("di\"")?.execute(&mut state, client)?;
+        assert_eq!(state.buffers[0].text.text(), "\"\" x\n");
+        Ok(())
+    }
+
+    #[test]
+    fn append_to_register() -> Result<(), Error> {
+        let (mut state, client) = test_state("one two three\n");
+        Command::parse("\"ayw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'a').map(String::as_str), Some("one "));
+        Command::parse("w")?.execute(&mut state, client.clone())?;
+        Command::parse("\"Ayw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'a').map(String::as_str), Some("one two "));
+        assert_eq!(state.registers.get(&'A'), None);
+        Command::parse("\"ap")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "one one two two three\n");
+        // deleting appends too
+        state.buffers[0].cursor_index = 0;
+        Command::parse("\"Adw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'a').map(String::as_str), Some("one two one "));
+        // appending to an empty register just fills it
+        Command::parse("\"Byw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'b').map(String::as_str), Some("one "));
+        Ok(())
+    }
+
+    #[test]
+    fn numbered_registers() -> Result<(), Error> {
+        let (mut state, client) = test_state("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl m\n");
+        Command::parse("yy")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'0').map(String::as_str), Some("a\n"));
+        for _ in 0..10 {
+            Command::parse("dd")?.execute(&mut state, client.clone())?;
+        }
+        assert_eq!(state.registers.get(&'1').map(String::as_str), Some("j\n"));
+        assert_eq!(state.registers.get(&'2').map(String::as_str), Some("i\n"));
+        assert_eq!(state.registers.get(&'9').map(String::as_str), Some("b\n"));
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("j\n"));
+        // small deletes go to "- and leave the numbered registers alone
+        Command::parse("dl")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'-').map(String::as_str), Some("k"));
+        assert_eq!(state.registers.get(&'1').map(String::as_str), Some("j\n"));
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("k"));
+        // deleting into a named register doesn't touch them either
+        Command::parse("\"adw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'a').map(String::as_str), Some("\n"));
+        assert_eq!(state.registers.get(&'1').map(String::as_str), Some("j\n"));
+        assert_eq!(state.registers.get(&'-').map(String::as_str), Some("k"));
+        Ok(())
+    }
+
+    #[test]
+    fn delete_line_lands_on_first_nonblank() -> Result<(), Error> {
+        let (mut state, client) = test_state("one\n    two\n\t\tthree\n  four");
+        Command::parse("dd")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].cursor_index, 4);
+        Command::parse("dd")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].cursor_index, 2);
+        Command::parse("j")?.execute(&mut state, client.clone())?;
+        Command::parse("dd")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "\t\tthree");
+        assert_eq!(state.buffers[0].cursor_index, 2, "moves up to the line before");
+        Ok(())
+    }
+
+    #[test]
+    fn append_after_multibyte_char() -> Result<(), Error> {
+        let (mut state, client) = test_state("\u{e9}t\u{e9}\n");
+        assert_eq!(Command::parse("a")?.execute(&mut state, client.clone())?, Some(ModeTag::Insert));
+        assert_eq!(state.buffers[0].cursor_index, 2);
+        state.buffers[0].set_cursor(3);
+        assert_eq!(Command::parse("a")?.execute(&mut state, client.clone())?, Some(ModeTag::Insert));
+        assert_eq!(state.buffers[0].cursor_index, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn blackhole_register() -> Result<(), Error> {
+        let (mut state, client) = test_state("one\ntwo\nthree\n");
+        Command::parse("yy")?.execute(&mut state, client.clone())?;
+        Command::parse("j")?.execute(&mut state, client.clone())?;
+        Command::parse("\"_dd")?.execute(&mut state, client.clone())?;
+        Command::parse("\"_x")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "one\nhree\n");
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("one\n"), "the yank is still there");
+        assert_eq!(state.registers.get(&'1'), None);
+        assert_eq!(state.registers.get(&'-'), None);
+        assert_eq!(state.registers.get(&'_'), None);
+        Command::parse("p")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "one\nhree\none\n");
+        Ok(())
+    }
+
+    #[test]
+    fn put_last_yank_after_delete() -> Result<(), Error> {
+        let (mut state, client) = test_state("one two three\n");
+        Command::parse("yw")?.execute(&mut state, client.clone())?;
+        Command::parse("w")?.execute(&mut state, client.clone())?;
+        Command::parse("dw")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.registers.get(&'"').map(String::as_str), Some("two "));
+        Command::parse("\"0p")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].text.text(), "one one three\n");
+        Ok(())
+    }
+
+    #[test]
+    fn fold_commands() -> Result<(), Error> {
+        let (mut state, client) = test_state("a {\n  b\n  c\n}\nd\n");
+        Command::parse("j")?.execute(&mut state, client.clone())?;
+        Command::parse("zfj")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![1..3]);
+        Command::parse("zo")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![]);
+        Command::parse("zc")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![1..3]);
+        Command::parse("zR")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![]);
+        Command::parse("zM")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![1..3]);
+        // the closed fold counts as one line, so this folds around it
+        Command::parse("zfj")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].closed_folds(), vec![1..4]);
+        assert!(Command::parse("zfk")?.execute(&mut state, client.clone()).is_err(), "crosses the start of 1..4");
+        Command::parse("zi")?.execute(&mut state, client.clone())?;
+        assert_eq!(state.buffers[0].folds.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn cmd_parse_incorrect() {
         if let Error::UnknownCommand(c) = Command::parse("Z").unwrap_err() {
@@ -1,6 +1,9 @@
 
 use super::Error;
+use super::leader::{LeaderMap, default_leader_map};
 use runic::Color;
+use std::time::Duration;
+use std::collections::HashMap;
 
 fn color_from_hex(h: &str) -> Result<Color, std::num::ParseIntError> {
     let start = if h.chars().next() == Some('#') { 1 } else { 0 };
@@ -53,7 +56,8 @@ pub struct Colorscheme {
     pub three_quarter_gray: Color,
     pub foreground: Color,
 
-    pub accent: [Color; 8]
+    /// at least one accent color, which `ColorschemeSel::Accent` picks from by wrapping around
+    pub accents: Vec<Color>
 }
 
 impl Default for Colorscheme {
@@ -65,7 +69,7 @@ impl Default for Colorscheme {
             three_quarter_gray: Color::rgb(0.75, 0.75, 0.75),
             foreground: color_from_hex("fefefe").unwrap(),
 
-            accent: [
+            accents: vec![
                 color_from_hex("ff2800").unwrap(), //red 0
                 color_from_hex("ff9a21").unwrap(), //orange 1
                 color_from_hex("ffdc00").unwrap(), //yellow 2
@@ -79,7 +83,32 @@ impl Default for Colorscheme {
     }
 }
 
+/// `color` mixed toward `background` by `amount`, from 0 leaving it as it is to 1 making it the
+/// background color
+pub fn dim_color(color: Color, background: Color, amount: f32) -> Color {
+    color.mix(background, amount.clamp(0.0, 1.0))
+}
+
 impl Colorscheme {
+    /// this scheme with every color but the background dimmed toward the background by `amount`
+    pub fn dimmed(&self, amount: f32) -> Colorscheme {
+        let dim = |c| dim_color(c, self.background, amount);
+        Colorscheme {
+            background: self.background,
+            quarter_gray: dim(self.quarter_gray),
+            half_gray: dim(self.half_gray),
+            three_quarter_gray: dim(self.three_quarter_gray),
+            foreground: dim(self.foreground),
+            accents: self.accents.iter().cloned().map(dim).collect()
+        }
+    }
+
+    /// accent color `i`, wrapping around so that schemes with fewer accents still have one for
+    /// every selector
+    pub fn accent(&self, i: usize) -> Color {
+        *self.get(ColorschemeSel::Accent(i))
+    }
+
     pub fn get(&self, sel: ColorschemeSel) -> &Color {
         match sel {
             ColorschemeSel::Background => &self.background,
@@ -87,7 +116,8 @@ impl Colorscheme {
             ColorschemeSel::HalfGray => &self.half_gray,
             ColorschemeSel::ThreeQuarterGray => &self.three_quarter_gray,
             ColorschemeSel::Foreground => &self.foreground,
-            ColorschemeSel::Accent(i) => &self.accent[i],
+            // there are only so many accents, but the config can ask for any of them
+            ColorschemeSel::Accent(i) => self.accents.get(i % self.accents.len().max(1)).unwrap_or(&self.foreground),
         }
     }
 
@@ -107,22 +137,18 @@ impl Colorscheme {
         let three_quarter_gray = val.get("three-quarter-gray").and_then(Value::as_str)
                         .map_or_else(|| Ok(background.mix(foreground, 0.75)), |s| color_from_hex(s).map_err(Error::from_other))?;
         
-        let mut cs = Colorscheme { 
-            background, foreground, quarter_gray, half_gray, three_quarter_gray,
-            accent: [Color::black(); 8]
-        };
-
-        for (i, v) in val.get("accents").and_then(Value::as_array)
-            .ok_or_else(|| Error::ConfigParseError("Expected color scheme to have accent colors".into(), Some(val.clone())))?
+        let accents = val.get("accents").and_then(Value::as_array)
+            .filter(|a| !a.is_empty())
+            .ok_or_else(|| Error::ConfigParseError("Expected color scheme to have at least one accent color".into(), Some(val.clone())))?
             .iter().enumerate()
-        {
-            let col = v.as_str()
+            .map(|(i, v)| v.as_str()
                 .ok_or_else(|| Error::ConfigParseError(format!("Expected color scheme to have valid accent at #{}", i), Some(v.clone())))
-                .and_then(|s| color_from_hex(s).map_err(Error::from_other))?;
-            cs.accent[i] = col;
-        }
-        
-        Ok(cs)
+                .and_then(|s| color_from_hex(s).map_err(Error::from_other)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Colorscheme {
+            background, foreground, quarter_gray, half_gray, three_quarter_gray, accents
+        })
     }
 }
 
@@ -133,7 +159,24 @@ pub struct Config {
     pub tabstop: usize,
     pub softtab: bool,
     pub colors: Colorscheme,
-    pub syntax_coloring: Option<toml::Value>
+    pub syntax_coloring: Option<toml::Value>,
+    pub leader_keys: LeaderMap,
+    /// how long to wait for the next key of an ambiguous leader sequence
+    pub leader_timeout: Duration,
+    /// how long to wait for the next key of an incomplete command before giving up on it
+    pub timeoutlen: Duration,
+    /// how many columns `gq` wraps lines to
+    pub textwidth: usize,
+    /// how often to ping servers to check that they are still there, or None to never check
+    pub heartbeat_interval: Option<Duration>,
+    /// how many columns of a line are drawn before the rest is cut off with an ellipsis, except on
+    /// the line with the cursor, or None to always draw whole lines
+    pub truncate_lines: Option<usize>,
+    /// how far the text of panes other than the current one is dimmed toward the background, or
+    /// None to draw every pane the same
+    pub dim_inactive_panes: Option<f32>,
+    /// words that are replaced in Insert mode by their expansion when a non-word character is typed after them
+    pub abbreviations: HashMap<String, String>
 }
 
 impl Config {
@@ -159,6 +202,9 @@ impl Config {
             { scope = "support.type", style = 1 },
             { scope = "support, support.class", style = 6 },
             { scope = "meta.path", style = 5 },
+            { scope = "markup.inserted", style = 3 },
+            { scope = "markup.deleted", style = 0 },
+            { scope = "markup.changed", style = 2 },
             ]
         }
     }
@@ -167,8 +213,15 @@ impl Config {
         use toml::Value;
 
         let mut cfg = Config::default();
+        if let Some(u) = val.get("local-server-url") {
+            let url = u.as_str()
+                .ok_or_else(|| Error::ConfigParseError("Expected local server URL to be a string".into(), Some(u.clone())))?;
+            if let Some(local) = cfg.autoconnect_servers.iter_mut().find(|(name, _)| name == "local") {
+                local.1 = url.into();
+            }
+        }
         if val.get("no-local-server").and_then(Value::as_bool).unwrap_or(false) {
-            cfg.autoconnect_servers.pop();
+            cfg.autoconnect_servers.retain(|(name, _)| name != "local");
         }
 
         if let Some(s) = val.get("autoconnect").and_then(Value::as_array) {
@@ -205,19 +258,258 @@ impl Config {
             };
         }
 
+        if let Some(lk) = val.get("leader") {
+            cfg.leader_keys = LeaderMap::from_toml(lk)?;
+        }
+
+        if let Some(lt) = val.get("leader-timeout") {
+            cfg.leader_timeout = Duration::from_millis(lt.as_integer().filter(|t| *t >= 0)
+                .ok_or_else(|| Error::ConfigParseError("Expected leader timeout in milliseconds".into(), Some(lt.clone())))? as u64);
+        }
+
+        if let Some(hi) = val.get("heartbeat-interval") {
+            let ms = hi.as_integer().filter(|t| *t >= 0)
+                .ok_or_else(|| Error::ConfigParseError("Expected heartbeat interval in milliseconds".into(), Some(hi.clone())))? as u64;
+            cfg.heartbeat_interval = if ms == 0 { None } else { Some(Duration::from_millis(ms)) };
+        }
+
+        if let Some(t) = val.get("timeoutlen") {
+            cfg.timeoutlen = Duration::from_millis(t.as_integer().filter(|t| *t >= 0)
+                .ok_or_else(|| Error::ConfigParseError("Expected timeoutlen in milliseconds".into(), Some(t.clone())))? as u64);
+        }
+
+        if let Some(tw) = val.get("textwidth") {
+            cfg.textwidth = tw.as_integer().filter(|w| *w > 0)
+                .ok_or_else(|| Error::ConfigParseError("Expected positive textwidth".into(), Some(tw.clone())))? as usize;
+        }
+
+        if let Some(tl) = val.get("truncate-lines") {
+            let columns = tl.as_integer().filter(|c| *c >= 0)
+                .ok_or_else(|| Error::ConfigParseError("Expected number of columns to truncate lines at".into(), Some(tl.clone())))? as usize;
+            cfg.truncate_lines = if columns == 0 { None } else { Some(columns) };
+        }
+
+        if let Some(di) = val.get("dim-inactive-panes") {
+            let amount = di.as_float().or_else(|| di.as_integer().map(|i| i as f64)).filter(|a| (0.0..=1.0).contains(a))
+                .ok_or_else(|| Error::ConfigParseError("Expected inactive pane dimming between 0 and 1".into(), Some(di.clone())))? as f32;
+            cfg.dim_inactive_panes = if amount == 0.0 { None } else { Some(amount) };
+        }
+
+        if let Some(ab) = val.get("abbreviations").and_then(Value::as_table) {
+            for (word, expansion) in ab.iter() {
+                cfg.abbreviations.insert(word.clone(), expansion.as_str()
+                    .ok_or_else(|| Error::ConfigParseError("Expected abbreviation to expand to a string".into(), Some(expansion.clone())))?.into());
+            }
+        }
+
         cfg.syntax_coloring = val.get("syntax-coloring").cloned().or_else(|| Config::default_toml_blob().get("syntax-coloring").cloned());
         Ok(cfg)
     }
 }
 
+/// how many columns apart tab stops are unless the configuration says otherwise
+pub const DEFAULT_TABSTOP: usize = 4;
+
+/// Options set with `setlocal` that take the place of the configuration's for one buffer
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferOptions {
+    pub tabstop: Option<usize>,
+    pub softtab: Option<bool>,
+    pub textwidth: Option<usize>
+}
+
+impl BufferOptions {
+    /// set the option called `name`, which for switches like `softtab` has no value and can be
+    /// turned off with `nosofttab`
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), Error> {
+        let number = || value.and_then(|v| v.parse::<usize>().ok()).filter(|n| *n > 0)
+            .ok_or_else(|| Error::InvalidCommand(format!("expected a positive number for {}", name)));
+        match (name, value) {
+            ("tabstop", _) | ("ts", _) => self.tabstop = Some(number()?),
+            ("textwidth", _) | ("tw", _) => self.textwidth = Some(number()?),
+            ("softtab", None) | ("expandtab", None) | ("et", None) => self.softtab = Some(true),
+            ("nosofttab", None) | ("noexpandtab", None) | ("noet", None) => self.softtab = Some(false),
+            _ => return Err(Error::InvalidCommand(format!("unknown buffer option {}", name)))
+        }
+        Ok(())
+    }
+
+    /// the options from `config` with these in place of any that are set
+    pub fn resolve(&self, config: &Config) -> TextOptions {
+        TextOptions {
+            tabstop: self.tabstop.unwrap_or(config.tabstop),
+            softtab: self.softtab.unwrap_or(config.softtab),
+            textwidth: self.textwidth.unwrap_or(config.textwidth)
+        }
+    }
+}
+
+/// The options that can be set for a buffer, as they are in effect for it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TextOptions {
+    pub tabstop: usize,
+    pub softtab: bool,
+    pub textwidth: usize
+}
+
+impl Config {
+    /// the configuration's options for buffers that don't set their own
+    pub fn text_options(&self) -> TextOptions {
+        BufferOptions::default().resolve(self)
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             autoconnect_servers: vec![("local".into(), "ipc://pk".into())],
             font: ("Consolas".into(), 14.0),
-            tabstop: 4, softtab: true,
+            tabstop: DEFAULT_TABSTOP, softtab: true,
             colors: Colorscheme::default(),
-            syntax_coloring: Config::default_toml_blob().get("syntax-coloring").cloned()
+            syntax_coloring: Config::default_toml_blob().get("syntax-coloring").cloned(),
+            leader_keys: default_leader_map(),
+            leader_timeout: Duration::from_millis(1000),
+            timeoutlen: Duration::from_millis(1000),
+            textwidth: 80,
+            heartbeat_interval: Some(Duration::from_millis(5000)),
+            truncate_lines: None,
+            dim_inactive_panes: None,
+            abbreviations: HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(val: toml::Value) -> Vec<(String, String)> {
+        Config::from_toml(val).unwrap().autoconnect_servers
+    }
+
+    #[test]
+    fn buffer_options() -> Result<(), Error> {
+        let config = Config::default();
+        let mut opts = BufferOptions::default();
+        assert_eq!(opts.resolve(&config), config.text_options());
+        opts.set("ts", Some("2"))?;
+        opts.set("nosofttab", None)?;
+        let local = opts.resolve(&config);
+        assert_eq!((local.tabstop, local.softtab, local.textwidth), (2, false, config.textwidth));
+        assert!(opts.set("tabstop", Some("0")).is_err());
+        assert!(opts.set("tabstop", None).is_err());
+        assert!(opts.set("softtab", Some("1")).is_err());
+        assert!(opts.set("font", Some("x")).is_err());
+        assert_eq!(opts.tabstop, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn accents_wrap_around() {
+        let cs = Colorscheme::default();
+        assert_eq!(cs.get(ColorschemeSel::Accent(7)), &cs.accents[7]);
+        assert_eq!(cs.get(ColorschemeSel::Accent(8)), &cs.accents[0]);
+        assert_eq!(cs.accent(13), cs.accents[5]);
+    }
+
+    #[test]
+    fn accent_counts() {
+        let scheme = |n: usize| {
+            let accents = (0..n).map(|i| toml::Value::String(format!("{:02x}0000", i))).collect::<Vec<_>>();
+            let mut val = toml::toml!{
+                background = "000000"
+                foreground = "ffffff"
+            };
+            val.as_table_mut().unwrap().insert("accents".into(), toml::Value::Array(accents));
+            Colorscheme::from_toml(&val)
+        };
+        for &n in &[4, 8, 12] {
+            let cs = scheme(n).unwrap();
+            assert_eq!(cs.accents.len(), n);
+            assert_eq!(cs.accent(n - 1), color_from_hex(&format!("{:02x}0000", n - 1)).unwrap());
+            assert_eq!(cs.accent(n + 1), cs.accents[1], "{} accents wrap around", n);
         }
+        assert!(scheme(0).is_err());
+        let mut bad = toml::toml!{
+            background = "000000"
+            foreground = "ffffff"
+        };
+        bad.as_table_mut().unwrap().insert("accents".into(), toml::Value::Array(vec![toml::Value::Integer(3)]));
+        assert!(Colorscheme::from_toml(&bad).is_err());
+    }
+
+    #[test]
+    fn local_server() {
+        let local = |url: &str| ("local".to_string(), url.to_string());
+        assert_eq!(servers(toml::toml!{ textwidth = 80 }), vec![local("ipc://pk")]);
+        assert_eq!(servers(toml::toml!{ no-local-server = false }), vec![local("ipc://pk")]);
+        assert_eq!(servers(toml::toml!{ no-local-server = true }), vec![]);
+        assert_eq!(servers(toml::toml!{ local-server-url = "tcp://127.0.0.1:9000" }), vec![local("tcp://127.0.0.1:9000")]);
+        assert_eq!(servers(toml::toml!{
+            no-local-server = true
+            local-server-url = "tcp://127.0.0.1:9000"
+        }), vec![]);
+        assert!(Config::from_toml(toml::toml!{ local-server-url = 9000 }).is_err());
+    }
+
+    #[test]
+    fn heartbeat_interval() {
+        let interval = |val| Config::from_toml(val).unwrap().heartbeat_interval;
+        assert_eq!(interval(toml::toml!{ textwidth = 80 }), Some(Duration::from_millis(5000)));
+        assert_eq!(interval(toml::toml!{ heartbeat-interval = 250 }), Some(Duration::from_millis(250)));
+        assert_eq!(interval(toml::toml!{ heartbeat-interval = 0 }), None);
+        assert!(Config::from_toml(toml::toml!{ heartbeat-interval = -1 }).is_err());
+    }
+
+    #[test]
+    fn dimming_colors() {
+        let bg = Color::rgb(0.0, 0.25, 0.5);
+        let c = Color::rgb(1.0, 0.75, 0.5);
+        assert_eq!(dim_color(c, bg, 0.0), c);
+        assert_eq!(dim_color(c, bg, 1.0), bg);
+        assert_eq!(dim_color(c, bg, 0.5), Color::rgb(0.5, 0.5, 0.5));
+        assert_eq!(dim_color(c, bg, 2.0), bg, "dimming past the background");
+
+        let cs = Colorscheme::default().dimmed(0.5);
+        assert_eq!(cs.background, Colorscheme::default().background);
+        assert_eq!(cs.accent(3), dim_color(Colorscheme::default().accent(3), cs.background, 0.5));
+    }
+
+    #[test]
+    fn dim_inactive_panes() {
+        let dim = |val| Config::from_toml(val).unwrap().dim_inactive_panes;
+        assert_eq!(dim(toml::toml!{ textwidth = 80 }), None);
+        assert_eq!(dim(toml::toml!{ dim-inactive-panes = 0.25 }), Some(0.25));
+        assert_eq!(dim(toml::toml!{ dim-inactive-panes = 0 }), None);
+        assert!(Config::from_toml(toml::toml!{ dim-inactive-panes = 1.5 }).is_err());
+    }
+
+    #[test]
+    fn truncate_lines() {
+        let truncate = |val| Config::from_toml(val).unwrap().truncate_lines;
+        assert_eq!(truncate(toml::toml!{ textwidth = 80 }), None);
+        assert_eq!(truncate(toml::toml!{ truncate-lines = 500 }), Some(500));
+        assert_eq!(truncate(toml::toml!{ truncate-lines = 0 }), None);
+        assert!(Config::from_toml(toml::toml!{ truncate-lines = "long" }).is_err());
+    }
+
+    #[test]
+    fn local_server_with_autoconnect() {
+        let remote = ("remote".to_string(), "tcp://example.com:9000".to_string());
+        assert_eq!(servers(toml::toml!{
+            [[autoconnect]]
+            name = "remote"
+            url = "tcp://example.com:9000"
+        }), vec![("local".into(), "ipc://pk".into()), remote.clone()]);
+        // only the default entry is removed, so it can be added back in with different settings
+        assert_eq!(servers(toml::toml!{
+            no-local-server = true
+            [[autoconnect]]
+            name = "remote"
+            url = "tcp://example.com:9000"
+            [[autoconnect]]
+            name = "local"
+            url = "ipc://other"
+        }), vec![remote, ("local".into(), "ipc://other".into())]);
     }
 }
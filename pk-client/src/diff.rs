@@ -0,0 +1,106 @@
+use pk_common::diff::LineChange;
+
+/// One line of a line-level diff between an old and a new text
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DiffLine {
+    Unchanged(String),
+    Added(String),
+    Removed(String),
+    /// a removed line that was replaced by an added one
+    Changed { old: String, new: String }
+}
+
+impl DiffLine {
+    /// the character shown at the start of the line in a diff view, which matches what diff
+    /// syntax highlighting expects
+    pub fn sign(&self) -> char {
+        match self {
+            DiffLine::Unchanged(_) => ' ',
+            DiffLine::Added(_) => '+',
+            DiffLine::Removed(_) => '-',
+            DiffLine::Changed { .. } => '!'
+        }
+    }
+}
+
+/// the lines of `old` and `new` lined up by their longest common subsequence. Runs of removed
+/// lines directly followed by added lines are paired up as changed lines
+pub fn lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let mut out = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for change in pk_common::diff::lines(&old, &new) {
+        match change {
+            LineChange::Same(l) => {
+                flush_changes(&mut out, &mut removed, &mut added);
+                out.push(DiffLine::Unchanged(l.to_string()));
+            },
+            LineChange::Removed(l) => removed.push(l),
+            LineChange::Added(l) => added.push(l)
+        }
+    }
+    flush_changes(&mut out, &mut removed, &mut added);
+    out
+}
+
+fn flush_changes(out: &mut Vec<DiffLine>, removed: &mut Vec<&str>, added: &mut Vec<&str>) {
+    let paired = removed.len().min(added.len());
+    out.extend(removed.iter().zip(added.iter())
+        .map(|(o, n)| DiffLine::Changed { old: o.to_string(), new: n.to_string() }));
+    out.extend(removed[paired..].iter().map(|l| DiffLine::Removed(l.to_string())));
+    out.extend(added[paired..].iter().map(|l| DiffLine::Added(l.to_string())));
+    removed.clear();
+    added.clear();
+}
+
+/// the text of a diff view, with the sign of each line in front of it. Changed lines show the old
+/// text on one line and the new text on the next
+pub fn display(diff: &[DiffLine]) -> String {
+    let mut s = String::new();
+    for ln in diff {
+        match ln {
+            DiffLine::Unchanged(t) | DiffLine::Added(t) | DiffLine::Removed(t) =>
+                s.push_str(&format!("{} {}\n", ln.sign(), t)),
+            DiffLine::Changed { old, new } =>
+                s.push_str(&format!("! {}\n! {}\n", old, new))
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical() {
+        assert_eq!(lines("a\nb\n", "a\nb"), vec![DiffLine::Unchanged("a".into()), DiffLine::Unchanged("b".into())]);
+        assert_eq!(lines("", ""), vec![]);
+    }
+
+    #[test]
+    fn added_removed_changed() {
+        let diff = lines("a\nb\nc\nd\ne\n", "a\nx\nc\ne\nf\n");
+        assert_eq!(diff, vec![
+            DiffLine::Unchanged("a".into()),
+            DiffLine::Changed { old: "b".into(), new: "x".into() },
+            DiffLine::Unchanged("c".into()),
+            DiffLine::Removed("d".into()),
+            DiffLine::Unchanged("e".into()),
+            DiffLine::Added("f".into())
+        ]);
+        assert_eq!(display(&diff), "  a\n! b\n! x\n  c\n- d\n  e\n+ f\n");
+    }
+
+    #[test]
+    fn uneven_change() {
+        assert_eq!(lines("a\nb\nz\n", "a\nc\nd\ne\nz\n"), vec![
+            DiffLine::Unchanged("a".into()),
+            DiffLine::Changed { old: "b".into(), new: "c".into() },
+            DiffLine::Added("d".into()),
+            DiffLine::Added("e".into()),
+            DiffLine::Unchanged("z".into())
+        ]);
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::{Arc,RwLock};
 use std::collections::{HashMap, BTreeMap};
 use futures::prelude::*;
 use pk_common::*;
-use crate::server::Server;
+use crate::server::{Server, MAX_MISSED_PINGS};
 use pk_common::piece_table::PieceTable;
 use crate::buffer::Buffer;
 use crate::config::Config;
@@ -56,14 +56,18 @@ pub enum PaneContent {
         buffer_index: usize,
         viewport_start: usize,
         viewport_end: usize,
+        /// the first screen column shown, so that the cursor stays visible on long lines
+        horizontal_offset: usize,
         scroll_lock: bool
-    }
+    },
+    /// the quickfix list, with the current entry highlighted
+    QuickFix
 }
 
 impl PaneContent {
     pub fn buffer(buffer_index: usize) -> PaneContent {
         PaneContent::Buffer {
-            buffer_index, viewport_start: 0, viewport_end: 0, scroll_lock: true
+            buffer_index, viewport_start: 0, viewport_end: 0, horizontal_offset: 0, scroll_lock: true
         }
     }
 }
@@ -176,10 +180,266 @@ impl Pane {
     }
 }
 
+#[cfg(test)]
+mod exit_test {
+    use std::sync::{Arc, RwLock};
+    use super::*;
+
+    fn state(texts: &[&str]) -> (PClientState, PEditorState) {
+        let mut es = EditorState::new();
+        for t in texts {
+            let mut b = Buffer::with_text(t);
+            b.path = t.into();
+            es.buffers.push(b);
+        }
+        (Arc::new(RwLock::new(ClientState::default())), Arc::new(RwLock::new(es)))
+    }
+
+    #[test]
+    fn exit_when_clean() {
+        let (cs, es) = state(&["a", "b"]);
+        assert!(es.read().unwrap().modified_buffers().is_empty());
+        assert!(ClientState::request_exit(cs.clone(), es));
+        assert!(cs.read().unwrap().usrmsgs.is_empty());
+    }
+
+    #[test]
+    fn exit_once_synced() {
+        let (cs, _) = state(&[]);
+        let mut cs = cs.write().unwrap();
+        cs.sync_finished(0, true);
+        assert!(!cs.should_exit, "not exiting");
+        cs.exit_after_sync = Some(vec![1, 2]);
+        cs.sync_finished(0, true);
+        cs.sync_finished(1, true);
+        assert!(!cs.should_exit);
+        cs.sync_finished(2, true);
+        assert!(cs.should_exit);
+        // a buffer that fails to sync keeps the editor open
+        cs.should_exit = false;
+        cs.exit_after_sync = Some(vec![1, 2]);
+        cs.sync_finished(1, false);
+        cs.sync_finished(2, true);
+        assert!(!cs.should_exit);
+        assert_eq!(cs.exit_after_sync, None);
+    }
+
+    #[test]
+    fn write_all_selection() {
+        let (cs, es) = state(&["a", "b", "c", "d"]);
+        {
+            let mut es = es.write().unwrap();
+            for i in 1..4 { es.buffers[i].text.insert_range("x", 0); }
+            es.buffers[2].currently_in_conflict = true;
+            es.buffers[3].scratch = true;
+            let mut clean_conflict = Buffer::with_text("e");
+            clean_conflict.currently_in_conflict = true;
+            es.buffers.push(clean_conflict);
+        }
+        assert_eq!(es.read().unwrap().buffers_to_write(), (vec![1], vec![2]));
+        // there is no server to sync to here, so nothing is synced and the user hears why
+        ClientState::write_all(cs.clone(), es.clone());
+        let um = cs.write().unwrap().usrmsgs.pop().unwrap();
+        assert!(matches!(um.mtype, UserMessageType::Warning));
+        assert!(um.message.contains("not connected to the server for :b"), "{}", um.message);
+        assert!(um.message.contains("conflict with the server first for :c"), "{}", um.message);
+        assert!(!um.message.contains(":a") && !um.message.contains(":d"));
+        for b in es.write().unwrap().buffers.iter_mut() { b.mark_saved(); }
+        ClientState::write_all(cs.clone(), es);
+        assert_eq!(cs.write().unwrap().usrmsgs.pop().unwrap().message, "no buffers have unsynced changes");
+    }
+
+    #[test]
+    fn write_all_summary() {
+        let (cs, _) = state(&[]);
+        let mut cs = cs.write().unwrap();
+        cs.write_all = Some(WriteAllProgress {
+            buffers: vec![(1, ":b".into(), None), (2, ":c".into(), None)],
+            skipped: Vec::new()
+        });
+        cs.sync_finished(1, true);
+        cs.sync_finished(1, false);
+        assert!(cs.usrmsgs.is_empty(), "waiting on :c");
+        cs.sync_finished(2, false);
+        assert!(cs.write_all.is_none());
+        let um = cs.usrmsgs.pop().unwrap();
+        assert!(matches!(um.mtype, UserMessageType::Warning));
+        assert_eq!(um.message, "synced :b; couldn't sync :c");
+        assert!(cs.usrmsgs.is_empty(), "only one message");
+    }
+
+    #[test]
+    fn ask_when_modified() {
+        let (cs, es) = state(&["a", "b", "c"]);
+        es.write().unwrap().buffers[1].text.insert_range("x", 0);
+        es.write().unwrap().buffers[2].text.insert_range("x", 0);
+        assert_eq!(es.read().unwrap().modified_buffers(), vec![1, 2]);
+        assert!(!ClientState::request_exit(cs.clone(), es.clone()));
+        let um = cs.write().unwrap().usrmsgs.pop().unwrap();
+        assert!(um.message.contains(":b") && um.message.contains(":c") && !um.message.contains(":a"));
+        let (opts, f) = um.actions.unwrap();
+        assert_eq!(opts.len(), 3);
+        // cancel
+        f(3, cs.clone());
+        assert!(!cs.read().unwrap().should_exit);
+        // there is no server to sync to here, so exiting would lose the changes
+        f(1, cs.clone());
+        assert!(!cs.read().unwrap().should_exit);
+        assert_eq!(cs.read().unwrap().exit_after_sync, None);
+        assert!(cs.write().unwrap().usrmsgs.pop().unwrap().message.contains("can't sync :b, :c"));
+        // discard
+        f(2, cs.clone());
+        assert!(cs.read().unwrap().should_exit);
+        // once everything is synced there's nothing to ask about
+        for b in es.write().unwrap().buffers.iter_mut() { b.mark_saved(); }
+        assert!(ClientState::request_exit(cs, es));
+    }
+}
+
+#[cfg(test)]
+mod autosync_test {
+    use std::sync::{Arc, RwLock};
+    use super::*;
+
+    #[test]
+    fn skip_scratch_buffers() {
+        let mut es = EditorState::new();
+        es.buffers.push(Buffer::with_text("abc"));
+        es.buffers.push(Buffer::scratch());
+        let es = Arc::new(RwLock::new(es));
+        let mut worker = AutosyncWorker::new(Arc::new(RwLock::new(ClientState::default())), es.clone());
+        assert!(worker.buffers_to_sync(&es.read().unwrap()).is_empty());
+        for b in es.write().unwrap().buffers.iter_mut() {
+            b.text.insert_range("x", 0);
+        }
+        assert_eq!(worker.buffers_to_sync(&es.read().unwrap()), vec![0]);
+        assert!(worker.buffers_to_sync(&es.read().unwrap()).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod conflict_test {
+    use std::sync::{Arc, RwLock};
+    use super::*;
+
+    /// a buffer whose local text "one\nb\n" conflicts with "zero\none\n" on the server, and the
+    /// dialog asking what to do about it
+    fn conflict() -> (PClientState, PEditorState, UserMessage) {
+        let mut es = EditorState::new();
+        es.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        let mut b = Buffer::from_server("local".into(), "a.txt".into(), protocol::FileId(1),
+            "one\n".into(), 1, protocol::TextFormat::default());
+        b.text.insert_range("b\n", 4);
+        es.buffers.push(b);
+        let es = Arc::new(RwLock::new(es));
+        let um = ClientState::conflict_message(es.clone(), 0, protocol::FileId(1), 2, "zero\none\n".into());
+        assert!(es.read().unwrap().buffers[0].currently_in_conflict);
+        (Arc::new(RwLock::new(ClientState::default())), es, um)
+    }
+
+    #[test]
+    fn choose_by_shown_number() {
+        let (cs, es, um) = conflict();
+        let (opts, f) = um.actions.unwrap();
+        assert!(opts[0].starts_with("Merge"));
+        f(1, cs.clone());
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), "zero\none\nb\n");
+        assert!(!es.read().unwrap().buffers[0].currently_in_conflict);
+
+        let (cs, es, um) = conflict();
+        um.actions.unwrap().1(2, cs);
+        let es = es.read().unwrap();
+        assert_eq!(es.buffers[0].text.text(), "one\nb\n", "the local version is kept");
+        assert_eq!(es.buffers[0].version, 2);
+        assert!(!es.buffers[0].currently_in_conflict);
+
+        let (cs, es, um) = conflict();
+        um.actions.unwrap().1(3, cs);
+        assert_eq!(es.read().unwrap().buffers[0].text.text(), "zero\none\n");
+        assert!(!es.read().unwrap().buffers[0].is_modified());
+
+        let (cs, es, um) = conflict();
+        um.actions.unwrap().1(4, cs);
+        let es = es.read().unwrap();
+        assert_eq!(es.buffers.len(), 2);
+        assert_eq!(es.buffers[1].text.text(), "zero\none\n");
+        assert_eq!(es.buffers[0].text.text(), "one\nb\n");
+    }
+}
+
+#[cfg(test)]
+mod quickfix_test {
+    use std::sync::{Arc, RwLock};
+    use super::*;
+
+    fn entry(path: &str, line: usize, column: usize) -> QfEntry {
+        QfEntry { server_name: "local".into(), path: path.into(), line, column, text: String::new() }
+    }
+
+    #[test]
+    fn step_wraps_around() {
+        let mut es = EditorState::new();
+        assert_eq!(es.step_quickfix(Direction::Forward, 1), None);
+        es.quickfix = vec![entry("a", 0, 0), entry("b", 1, 0), entry("c", 2, 0)];
+        assert_eq!(es.step_quickfix(Direction::Forward, 1).map(|e| e.line), Some(1));
+        assert_eq!(es.step_quickfix(Direction::Forward, 2).map(|e| e.line), Some(0));
+        assert_eq!(es.step_quickfix(Direction::Backward, 1).map(|e| e.line), Some(2));
+        assert_eq!(es.step_quickfix(Direction::Backward, 7).map(|e| e.line), Some(1));
+    }
+
+    #[test]
+    fn cycle_buffers() {
+        let mut es = EditorState::new();
+        es.panes.insert(0, Pane::whole_screen(PaneContent::Empty));
+        assert_eq!(es.cycle_buffer(Direction::Forward, 1), None);
+        for t in ["a", "b", "c"].iter() { es.buffers.push(Buffer::with_text(t)); }
+        assert_eq!(es.cycle_buffer(Direction::Forward, 1), Some(0));
+        assert_eq!(es.current_buffer_index(), Some(0));
+        assert_eq!(es.cycle_buffer(Direction::Forward, 2), Some(2));
+        assert_eq!(es.cycle_buffer(Direction::Forward, 1), Some(0));
+        assert_eq!(es.cycle_buffer(Direction::Backward, 1), Some(2));
+        assert_eq!(es.cycle_buffer(Direction::Backward, 7), Some(1));
+        assert_eq!(es.current_buffer_index(), Some(1));
+        es.current_pane_mut().content = PaneContent::QuickFix;
+        assert_eq!(es.cycle_buffer(Direction::Backward, 1), Some(2));
+    }
+
+    #[test]
+    fn entry_position() {
+        let buf = Buffer::with_text("one\ntwo three\nfour");
+        assert_eq!(entry("a", 1, 4).index_in(&buf), 8);
+        // columns past the end of the line stay on it
+        assert_eq!(entry("a", 1, 40).index_in(&buf), 13);
+        assert_eq!(entry("a", 2, 40).index_in(&buf), 18);
+    }
+
+    #[test]
+    fn jump_to_open_buffer() {
+        let mut es = EditorState::new();
+        for p in &["a", "b"] {
+            let mut b = Buffer::with_text("x\nyz\n");
+            b.server_name = "local".into();
+            b.path = p.into();
+            es.buffers.push(b);
+        }
+        es.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        let qp = Pane::split(&mut es.panes, 0, false, 0.5, PaneContent::QuickFix);
+        es.current_pane = qp;
+        es.quickfix = vec![entry("b", 1, 1)];
+        let es = Arc::new(RwLock::new(es));
+        ClientState::jump_to_quickfix(Arc::new(RwLock::new(ClientState::default())), es.clone()).unwrap();
+        let es = es.read().unwrap();
+        // the quickfix pane is left alone
+        assert_eq!(es.current_pane, 0);
+        assert_eq!(es.current_buffer_index(), Some(1));
+        assert_eq!(es.buffers[1].cursor_index, 3);
+    }
+}
+
 #[cfg(test)]
 mod winman_test {
     use std::collections::BTreeMap;
-    use super::{Pane,PaneContent};
+    use super::{Pane,PaneContent,EditorState,Rect};
     #[test]
     fn split_horiz() {
         let mut panes = BTreeMap::new();
@@ -243,6 +503,46 @@ mod winman_test {
         assert_eq!(panes[&ai].neighbors, [None, Some(bi), None, None]);
         assert_eq!(panes[&bi].neighbors, [Some(ai), None, None, None]);
     }
+
+    fn xywh(r: Rect) -> (f32, f32, f32, f32) { (r.x, r.y, r.w, r.h) }
+
+    #[test]
+    fn zoom_toggle() {
+        let mut es = EditorState::new();
+        es.panes.insert(0, Pane::whole_screen(PaneContent::Empty));
+        let bi = Pane::split(&mut es.panes, 0, true, 0.5, PaneContent::Empty);
+        let ci = Pane::split(&mut es.panes, bi, false, 0.5, PaneContent::Empty);
+        // [a] | [b]
+        //     | [c]
+        let before: Vec<_> = es.panes.values().map(|p| xywh(p.bounds)).collect();
+        es.current_pane = ci;
+        es.toggle_zoom();
+        assert_eq!(xywh(es.panes[&ci].bounds), (0.0, 0.0, 1.0, 1.0));
+        assert_eq!(xywh(es.panes[&bi].bounds), (0.5, 0.0, 0.5, 0.5), "other panes keep their bounds");
+        es.toggle_zoom();
+        assert!(es.zoomed_layout.is_none());
+        assert_eq!(es.panes.values().map(|p| xywh(p.bounds)).collect::<Vec<_>>(), before);
+    }
+
+    #[test]
+    fn only_pane() {
+        let mut es = EditorState::new();
+        es.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        let bi = Pane::split(&mut es.panes, 0, true, 0.5, PaneContent::buffer(1));
+        let ci = Pane::split(&mut es.panes, bi, false, 0.5, PaneContent::QuickFix);
+        es.current_pane = bi;
+        es.toggle_zoom();
+        es.only_pane();
+        assert_eq!(es.panes.len(), 1);
+        assert_eq!(es.current_pane, bi);
+        assert_eq!(xywh(es.current_pane().bounds), (0.0, 0.0, 1.0, 1.0));
+        assert_eq!(es.current_pane().neighbors, [None; 4]);
+        assert!(es.zoomed_layout.is_none());
+        assert!(!es.panes.contains_key(&ci));
+        // splitting again works from the single pane
+        let di = Pane::split(&mut es.panes, bi, false, 0.5, PaneContent::Empty);
+        assert_eq!(xywh(es.panes[&di].bounds), (0.0, 0.5, 1.0, 0.5));
+    }
 }
 
 // an alternative way to deal with managing Panes. The tradeoff: Splits are simpler for resizing/spliting but way more complex to navigate
@@ -319,10 +619,47 @@ pub struct EditorState {
 
     pub panes: BTreeMap<usize, Pane>,
     pub current_pane: usize,
+    /// the bounds of every pane from before the current pane was zoomed to fill the screen
+    pub zoomed_layout: Option<BTreeMap<usize, Rect>>,
 
     pub last_command: Option<crate::command::Command>,
+    pub last_visual_selection: Option<VisualSelection>,
     
-    pub line_command_history: Vec<String>
+    pub line_command_history: Vec<String>,
+
+    pub quickfix: Vec<QfEntry>,
+    pub quickfix_index: usize
+}
+
+/// A selection made in Visual mode, kept around so that it can be selected again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VisualSelection {
+    pub buffer_index: usize,
+    pub range: std::ops::Range<usize>,
+    pub linewise: bool,
+    /// which end of the selection the cursor is on, true for the end
+    pub side: bool
+}
+
+/// A location in a file in the quickfix list. The line and column count from zero, and the column
+/// is a byte offset into the line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QfEntry {
+    pub server_name: String,
+    pub path: std::path::PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// the text of the line, to show in the quickfix pane
+    pub text: String
+}
+
+impl QfEntry {
+    /// the index in `buf` that the entry points to, kept inside the line in case the text has changed
+    pub fn index_in(&self, buf: &Buffer) -> usize {
+        let start = buf.index_for_line(self.line);
+        let end = buf.text.index_of('\n', start).unwrap_or_else(|| buf.text.len());
+        (start + self.column).min(end)
+    }
 }
 
 pub struct ClientState {
@@ -332,6 +669,10 @@ pub struct ClientState {
 
     pub force_redraw: bool,
     pub should_exit: bool,
+    /// the buffers that still need to be synced before exiting, if exiting once they are
+    pub exit_after_sync: Option<Vec<usize>>,
+    /// how syncing the buffers for the last `:wa` is going, until every reply is in
+    pub write_all: Option<WriteAllProgress>,
 
     pub usrmsgs: Vec<UserMessage>,
     pub selected_usrmsg: usize,
@@ -339,6 +680,42 @@ pub struct ClientState {
     pub config: Config
 }
 
+/// The buffers a `:wa` is syncing and what their servers said, so that the user hears about all
+/// of them at once
+#[derive(Debug, Default)]
+pub struct WriteAllProgress {
+    /// each buffer being synced with its name, and whether it was synced once its server replies
+    buffers: Vec<(usize, String, Option<bool>)>,
+    /// the parts of the summary about buffers that couldn't be synced at all
+    skipped: Vec<String>
+}
+
+impl WriteAllProgress {
+    /// the message to show once every buffer has been heard back about
+    fn summary(&self) -> Option<UserMessage> {
+        let mut synced = Vec::new();
+        let mut failed = Vec::new();
+        for (_, name, outcome) in self.buffers.iter() {
+            match outcome {
+                Some(true) => synced.push(name.as_str()),
+                Some(false) => failed.push(name.as_str()),
+                None => return None
+            }
+        }
+        let mut parts = Vec::new();
+        if !synced.is_empty() { parts.push(format!("synced {}", synced.join(", "))); }
+        if !failed.is_empty() { parts.push(format!("couldn't sync {}", failed.join(", "))); }
+        parts.extend(self.skipped.iter().cloned());
+        Some(if parts.is_empty() {
+            UserMessage::info("no buffers have unsynced changes".into(), None)
+        } else if failed.is_empty() && self.skipped.is_empty() {
+            UserMessage::info(parts.join("; "), None)
+        } else {
+            UserMessage::warning(parts.join("; "), None)
+        })
+    }
+}
+
 impl Default for ClientState {
     fn default() -> ClientState {
         ClientState::with_config(Config::default())
@@ -351,9 +728,13 @@ impl EditorState {
             buffers: Vec::new(),
             panes: BTreeMap::new(),
             current_pane: 0,
+            zoomed_layout: None,
             registers: BTreeMap::new(),
             last_command: None,
-            line_command_history: Vec::new()
+            last_visual_selection: None,
+            line_command_history: Vec::new(),
+            quickfix: Vec::new(),
+            quickfix_index: 0
         }
     }
 
@@ -392,6 +773,105 @@ impl EditorState {
         }
     }
 
+    /// expand the current pane to fill the screen, or put every pane back where it was if a pane
+    /// is already zoomed
+    pub fn toggle_zoom(&mut self) {
+        if self.zoomed_layout.is_some() {
+            self.unzoom();
+        } else {
+            self.zoomed_layout = Some(self.panes.iter().map(|(i, p)| (*i, p.bounds)).collect());
+            self.current_pane_mut().bounds = Rect::xywh(0.0, 0.0, 1.0, 1.0);
+        }
+    }
+
+    /// restore the layout saved by `toggle_zoom`, if there is one
+    pub fn unzoom(&mut self) {
+        if let Some(layout) = self.zoomed_layout.take() {
+            for (i, bounds) in layout {
+                if let Some(p) = self.panes.get_mut(&i) {
+                    p.bounds = bounds;
+                }
+            }
+        }
+    }
+
+    /// close every pane except the current one, which then fills the screen. Buffers stay open
+    pub fn only_pane(&mut self) {
+        self.zoomed_layout = None;
+        let mut pane = self.panes.remove(&self.current_pane).expect("current pane exists");
+        pane.bounds = Rect::xywh(0.0, 0.0, 1.0, 1.0);
+        pane.neighbors = [None; 4];
+        self.panes.clear();
+        self.panes.insert(self.current_pane, pane);
+    }
+
+    /// indices of the buffers with changes that haven't been synced yet
+    pub fn modified_buffers(&self) -> Vec<usize> {
+        self.buffers.iter().enumerate().filter(|(_, b)| b.is_modified()).map(|(i, _)| i).collect()
+    }
+
+    /// the modified buffers to sync when writing everything, and the modified buffers left out
+    /// because they are in conflict with their server until that gets resolved. Scratch buffers
+    /// are never modified, so they are never written
+    pub fn buffers_to_write(&self) -> (Vec<usize>, Vec<usize>) {
+        self.modified_buffers().into_iter().partition(|&i| !self.buffers[i].currently_in_conflict)
+    }
+
+    /// the server and path of each of `buffers`, to tell the user about them
+    pub fn buffer_names<'a>(&self, buffers: impl Iterator<Item = &'a usize>) -> String {
+        buffers.map(|&i| format!("{}:{}", self.buffers[i].server_name,
+                self.buffers[i].path.to_str().unwrap_or(""))).collect::<Vec<_>>().join(", ")
+    }
+
+    /// move `count` entries through the quickfix list in `dir`, wrapping around at either end, and
+    /// return the new current entry
+    pub fn step_quickfix(&mut self, dir: Direction, count: usize) -> Option<&QfEntry> {
+        let n = self.quickfix.len();
+        if n == 0 { return None; }
+        self.quickfix_index = match dir {
+            Direction::Forward => (self.quickfix_index + count) % n,
+            Direction::Backward => (self.quickfix_index + n - count % n) % n
+        };
+        self.quickfix.get(self.quickfix_index)
+    }
+
+    /// show the buffer `count` buffers away from the current one in `dir` in the current pane,
+    /// wrapping around at either end like `gt` and `gT`, and return its index. A pane without a
+    /// buffer counts as being just before the first one
+    pub fn cycle_buffer(&mut self, dir: Direction, count: usize) -> Option<usize> {
+        let n = self.buffers.len();
+        if n == 0 { return None; }
+        let next = match (self.current_buffer_index(), dir) {
+            (Some(i), Direction::Forward) => (i + count) % n,
+            (Some(i), Direction::Backward) => (i + n - count % n) % n,
+            (None, Direction::Forward) => (count + n - 1) % n,
+            (None, Direction::Backward) => (n - count % n) % n
+        };
+        if self.current_buffer_index() != Some(next) {
+            self.current_pane_mut().content = PaneContent::buffer(next);
+        }
+        Some(next)
+    }
+
+    /// show `buffer_index` with the cursor on the current quickfix entry. This uses the current
+    /// pane unless it is showing the quickfix list and there is another pane to use instead
+    pub fn show_quickfix_entry(&mut self, buffer_index: usize) {
+        let index = match self.quickfix.get(self.quickfix_index) {
+            Some(entry) => entry.index_in(&self.buffers[buffer_index]),
+            None => return
+        };
+        self.buffers[buffer_index].set_cursor(index);
+        if let PaneContent::QuickFix = self.current_pane().content {
+            if let Some(&p) = self.panes.iter().find(|(_, p)| !matches!(p.content, PaneContent::QuickFix)).map(|(i, _)| i) {
+                self.current_pane = p;
+            }
+        }
+        match self.current_pane().content {
+            PaneContent::Buffer { buffer_index: bi, .. } if bi == buffer_index => {},
+            _ => self.current_pane_mut().content = PaneContent::buffer(buffer_index)
+        }
+    }
+
 }
 
 pub type PEditorState = Arc<RwLock<EditorState>>;
@@ -405,6 +885,8 @@ impl ClientState {
             servers: HashMap::new(),
             force_redraw: false,
             should_exit: false,
+            exit_after_sync: None,
+            write_all: None,
             usrmsgs: Vec::new(),
             selected_usrmsg: 0,
             config
@@ -439,29 +921,41 @@ impl ClientState {
 
     pub fn make_request_async<F>(state: PClientState, server_name: impl AsRef<str>, request: protocol::Request, f: F)
         where F: FnOnce(PClientState, protocol::Response) + Send + Sync + 'static
+    {
+        ClientState::make_request_async_with_errors(state, server_name, request, move |cs, resp| {
+            match resp {
+                protocol::Response::Error { message } => {
+                    let mut cs = cs.write().unwrap();
+                    // anything waiting on the response, like exiting after a sync, won't happen
+                    cs.exit_after_sync = None;
+                    cs.process_error_str(message);
+                },
+                _ => f(cs, resp)
+            }
+        });
+    }
+
+    /// like `make_request_async`, except that `f` gets errors too, including the request not
+    /// being sent at all, as `Response::Error`
+    pub fn make_request_async_with_errors<F>(state: PClientState, server_name: impl AsRef<str>, request: protocol::Request, f: F)
+        where F: FnOnce(PClientState, protocol::Response) + Send + Sync + 'static
     {
         let mut cs = state.write().unwrap();
         let req_fut = match {
             //println!("a {:?}", std::time::Instant::now());
             cs.servers.get_mut(server_name.as_ref())
-                .ok_or(Error::InvalidCommand(String::from("server name ") + server_name.as_ref() + " is unknown"))
+                .ok_or_else(|| Error::NotConnected(server_name.as_ref().into()))
+                .and_then(|r| r.request(request))
         } {
-            Ok(r) => r.request(request),
+            Ok(r) => r,
             Err(e) => {
-                cs.process_error(e);
+                drop(cs);
+                f(state, protocol::Response::Error { message: e.to_string() });
                 return;
             }
         };
         let ess = state.clone();
-        cs.thread_pool.spawn_ok(req_fut.then(move |resp: protocol::Response| async move
-        {
-            match resp {
-                protocol::Response::Error { message } => {
-                    ess.write().unwrap().process_error_str(message);
-                },
-                _ => f(ess, resp)
-            }
-        }));
+        cs.thread_pool.spawn_ok(req_fut.then(move |resp: protocol::Response| async move { f(ess, resp) }));
     }
 
     pub fn open_buffer(state: PClientState, ess: PEditorState, server_name: String, path: std::path::PathBuf,
@@ -471,6 +965,11 @@ impl ClientState {
         ClientState::make_request_async(state, server_name.clone(), protocol::Request::OpenFile { path: path.clone() }, move |_, resp| {
             match resp {
                 protocol::Response::FileInfo { id, contents, version, format } => {
+                    if format.mixed_line_endings {
+                        ClientState::process_usr_msgp(sstate.clone(), UserMessage::warning(
+                                format!("{}:{} has mixed line endings, they will all be saved as {:?}",
+                                    server_name, path.to_str().unwrap_or(""), format.line_ending), None));
+                    }
                     let mut estate = ess.write().unwrap();
                     let buffer_index = estate.buffers.len();
                     estate.buffers.push(Buffer::from_server(String::from(server_name),
@@ -482,20 +981,155 @@ impl ClientState {
         });
     }
 
+    /// attach the buffer to a file on a server, which replaces the file's contents with the buffer's
+    pub fn save_buffer_as(state: PClientState, ess: PEditorState, buffer_index: usize, server_name: String, path: std::path::PathBuf) {
+        let sstate = state.clone();
+        ClientState::make_request_async(state, server_name.clone(), protocol::Request::OpenFile { path: path.clone() }, move |_, resp| {
+            match resp {
+                protocol::Response::FileInfo { id, contents, version, format } => {
+                    {
+                        let mut estate = ess.write().unwrap();
+                        let b = &mut estate.buffers[buffer_index];
+                        b.server_name = server_name;
+                        b.path = path;
+                        b.file_id = id;
+                        b.version = version;
+                        b.synced_text = contents;
+                        b.format = format;
+                        b.scratch = false;
+                        b.highlights = None;
+                        // the file's contents are still on the server, so they need to be replaced
+                        b.last_saved_action_id = usize::MAX;
+                    }
+                    ClientState::sync_buffer(sstate.clone(), ess, buffer_index);
+                    sstate.write().unwrap().force_redraw = true;
+                },
+                _ => panic!() 
+            }
+        });
+    }
+
+    /// true if it is safe to exit because every buffer has been synced, otherwise asks the user
+    /// whether to sync them all and then exit, exit anyway, or cancel
+    pub fn request_exit(state: PClientState, ed_state: PEditorState) -> bool {
+        let modified = ed_state.read().unwrap().modified_buffers();
+        if modified.is_empty() { return true; }
+        let paths = ed_state.read().unwrap().buffer_names(modified.iter());
+        state.write().unwrap().process_usr_msg(UserMessage::warning(
+                format!("Unsynced changes in {}!", paths),
+                Some((vec![
+                        "Sync all and quit".into(),
+                        "Discard changes and quit".into(),
+                        "Cancel".into()
+                ], Box::new(move |index, cs| {
+                    match index {
+                        1 => {
+                            let (writing, disconnected, conflicted) = cs.read().unwrap().buffers_to_sync(&ed_state.read().unwrap());
+                            if !disconnected.is_empty() || !conflicted.is_empty() {
+                                // exiting would lose the changes that can't be synced
+                                let names = ed_state.read().unwrap().buffer_names(disconnected.iter().chain(conflicted.iter()));
+                                cs.write().unwrap().process_usr_msg(UserMessage::warning(
+                                        format!("can't sync {}, so not exiting", names), None));
+                                return;
+                            }
+                            if writing.is_empty() {
+                                cs.write().unwrap().should_exit = true;
+                                return;
+                            }
+                            cs.write().unwrap().exit_after_sync = Some(writing.clone());
+                            for i in writing {
+                                ClientState::sync_buffer(cs.clone(), ed_state.clone(), i);
+                            }
+                        },
+                        2 => cs.write().unwrap().should_exit = true,
+                        _ => cs.write().unwrap().exit_after_sync = None
+                    }
+                })))
+        ));
+        false
+    }
+
+    /// sync every modified buffer like `:wa`. Once every server has replied, the user hears which
+    /// buffers were synced, which weren't, and which couldn't be because they are in conflict or
+    /// their server isn't connected
+    pub fn write_all(state: PClientState, ed_state: PEditorState) {
+        let (writing, disconnected, conflicted) = state.read().unwrap().buffers_to_sync(&ed_state.read().unwrap());
+        let progress = {
+            let es = ed_state.read().unwrap();
+            let names = |buffers: &[usize]| es.buffer_names(buffers.iter());
+            let mut skipped = Vec::new();
+            if !disconnected.is_empty() { skipped.push(format!("not connected to the server for {}", names(&disconnected))); }
+            if !conflicted.is_empty() { skipped.push(format!("resolve the conflict with the server first for {}", names(&conflicted))); }
+            WriteAllProgress {
+                buffers: writing.iter().map(|&i| (i, es.buffer_names(std::iter::once(&i)), None)).collect(),
+                skipped
+            }
+        };
+        {
+            let mut cs = state.write().unwrap();
+            match progress.summary() {
+                Some(summary) => { cs.process_usr_msg(summary); return; },
+                None => cs.write_all = Some(progress)
+            }
+        }
+        for &i in writing.iter() {
+            ClientState::sync_buffer(state.clone(), ed_state.clone(), i);
+        }
+    }
+
+    /// the modified buffers split into those that can be synced, those whose server isn't
+    /// connected, and those that are in conflict with their server
+    fn buffers_to_sync(&self, es: &EditorState) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
+        let (to_write, conflicted) = es.buffers_to_write();
+        let (writing, disconnected) = to_write.into_iter().partition(|&i| self.servers.contains_key(&es.buffers[i].server_name));
+        (writing, disconnected, conflicted)
+    }
+
+    /// note that the server replied to syncing a buffer, exiting if it was the last one left to
+    /// sync before exiting, and summing up a `:wa` if it was the last reply that was waiting on
+    fn sync_finished(&mut self, buffer_index: usize, synced: bool) {
+        if !synced {
+            // the user has to sort out what went wrong before exiting
+            self.exit_after_sync = None;
+        }
+        if let Some(waiting) = self.exit_after_sync.as_mut() {
+            waiting.retain(|&i| i != buffer_index);
+            if waiting.is_empty() {
+                self.should_exit = true;
+                self.force_redraw = true;
+            }
+        }
+        if let Some(progress) = self.write_all.as_mut() {
+            for (i, _, outcome) in progress.buffers.iter_mut() {
+                if *i == buffer_index && outcome.is_none() { *outcome = Some(synced); }
+            }
+            if let Some(summary) = progress.summary() {
+                self.write_all = None;
+                self.process_usr_msg(summary);
+            }
+        }
+    }
+
     pub fn sync_buffer(state: PClientState, ed_state: PEditorState, buffer_index: usize) {
-        let (server_name, id, new_text, version) = {
+        let (server_name, id, new_text, version, format, action_id) = {
             let state = ed_state.read().unwrap();
             let b = &state.buffers[buffer_index];
-            if b.currently_in_conflict { return; }
-            (b.server_name.clone(), b.file_id, b.text.text(), b.version+1)
+            if b.currently_in_conflict || b.scratch { return; }
+            (b.server_name.clone(), b.file_id, b.text.text(), b.version+1, b.format.clone(), b.text.most_recent_action_id())
         };
-        ClientState::make_request_async(state, server_name,
-            protocol::Request::SyncFile { id, new_text, version },
+        let synced_text = new_text.clone();
+        ClientState::make_request_async_with_errors(state, server_name,
+            protocol::Request::SyncFile { id, new_text, version, format },
             move |css, resp| {
                 match resp {
                     protocol::Response::Ack => {
-                        let mut state = ed_state.write().unwrap();
-                        state.buffers[buffer_index].version = version;
+                        {
+                            let mut state = ed_state.write().unwrap();
+                            state.buffers[buffer_index].version = version;
+                            state.buffers[buffer_index].synced_text = synced_text;
+                            state.buffers[buffer_index].last_saved_action_id = action_id;
+                        }
+                        css.write().unwrap().sync_finished(buffer_index, true);
                     },
                     protocol::Response::VersionConflict { id, client_version_recieved: _,
                         server_version, server_text } =>
@@ -504,59 +1138,103 @@ impl ClientState {
                         // want to do about the conflict. this becomes a tricky situation since
                         // there's no reason to become Git, but it is nice to able to handle this
                         // situation in a nice way
-                        let m = {
-                            let mut ed_state = ed_state.write().unwrap();
-                            let b = &mut ed_state.buffers[buffer_index];
-                            b.currently_in_conflict = true;
-                            format!("Server version of {}:{} conflicts with local version!",
-                                b.server_name, b.path.to_str().unwrap_or(""))
-                        };
-                        css.write().unwrap().usrmsgs.push(UserMessage::warning(m,
-                                Some((vec![
-                                        "Keep local version".into(),
-                                        "Open server version/Discard local".into(),
-                                        "Open server version in new buffer".into()
-                                ], Box::new(move |index, _| {
-                                    let mut state = ed_state.write().unwrap();
-                                    match index {
-                                        0 => {
-                                            // next time we sync, overwrite server version
-                                            state.buffers[buffer_index].version = 
-                                                server_version;
-                                            state.buffers[buffer_index].currently_in_conflict = false;
-                                        },
-                                        1 => {
-                                            state.buffers[buffer_index].version =
-                                                server_version;
-                                            state.buffers[buffer_index].text =
-                                                PieceTable::with_text(&server_text);
-                                            state.buffers[buffer_index].currently_in_conflict = false;
-                                        },
-                                        2 => {
-                                            let cp = state.current_pane;
-                                            let nbi = state.buffers.len();
-                                            Pane::split(&mut state.panes, cp, true, 0.5,
-                                                PaneContent::buffer(nbi));
-                                            let p = state.buffers[buffer_index].path.clone();
-                                            let f = state.buffers[buffer_index].format.clone();
-                                            let server_name = state.buffers[buffer_index].server_name.clone();
-                                            state.buffers.push(Buffer::from_server(server_name, p,
-                                                    id, server_text.clone(), server_version, f));
-                                            // don't clear conflict flag on buffer so we don't try
-                                            // to sync the conflicting version again. TODO: some
-                                            // way to manually clear the flag?
-                                        },
-                                        _ => {} 
-                                    }
-                                })))
-                        ));
+                        let m = ClientState::conflict_message(ed_state.clone(), buffer_index, id, server_version, server_text);
+                        let mut cs = css.write().unwrap();
+                        cs.usrmsgs.push(m);
+                        cs.sync_finished(buffer_index, false);
+                    },
+                    protocol::Response::Error { message } => {
+                        let mut cs = css.write().unwrap();
+                        cs.process_error_str(message);
+                        cs.sync_finished(buffer_index, false);
+                    },
+                    _ => {
+                        let mut cs = css.write().unwrap();
+                        cs.process_error_str(format!("unexpected server response to sync {:?}", resp));
+                        cs.sync_finished(buffer_index, false);
                     }
-                    _ => panic!() 
-            }
+                }
             }
         );
     }
 
+    /// the message asking the user what to do about the version of a buffer on the server
+    /// conflicting with the local one, which marks the buffer as in conflict until they choose
+    fn conflict_message(ed_state: PEditorState, buffer_index: usize, id: protocol::FileId,
+        server_version: usize, server_text: String) -> UserMessage
+    {
+        let m = {
+            let mut ed_state = ed_state.write().unwrap();
+            let b = &mut ed_state.buffers[buffer_index];
+            b.currently_in_conflict = true;
+            format!("Server version of {}:{} conflicts with local version!",
+                b.server_name, b.path.to_str().unwrap_or(""))
+        };
+        // the options are numbered from 1 when they are shown and chosen
+        UserMessage::warning(m, Some((vec![
+                "Merge server changes into local version".into(),
+                "Keep local version".into(),
+                "Open server version/Discard local".into(),
+                "Open server version in new buffer".into()
+            ], Box::new(move |index, _| {
+                let mut state = ed_state.write().unwrap();
+                match index {
+                    1 => {
+                        // the merged text gets synced next time
+                        let b = &mut state.buffers[buffer_index];
+                        b.merge_server_text(server_text.clone(), server_version);
+                        b.currently_in_conflict = false;
+                    },
+                    2 => {
+                        // next time we sync, overwrite server version
+                        state.buffers[buffer_index].version = server_version;
+                        state.buffers[buffer_index].currently_in_conflict = false;
+                    },
+                    3 => {
+                        state.buffers[buffer_index].version = server_version;
+                        state.buffers[buffer_index].text = PieceTable::with_text(&server_text);
+                        state.buffers[buffer_index].synced_text = server_text.clone();
+                        state.buffers[buffer_index].mark_saved();
+                        state.buffers[buffer_index].currently_in_conflict = false;
+                    },
+                    4 => {
+                        state.unzoom();
+                        let cp = state.current_pane;
+                        let nbi = state.buffers.len();
+                        Pane::split(&mut state.panes, cp, true, 0.5, PaneContent::buffer(nbi));
+                        let p = state.buffers[buffer_index].path.clone();
+                        let f = state.buffers[buffer_index].format.clone();
+                        let server_name = state.buffers[buffer_index].server_name.clone();
+                        state.buffers.push(Buffer::from_server(server_name, p,
+                                id, server_text.clone(), server_version, f));
+                        // don't clear conflict flag on buffer so we don't try
+                        // to sync the conflicting version again. TODO: some
+                        // way to manually clear the flag?
+                    },
+                    _ => {}
+                }
+            }))))
+    }
+
+    /// show the current quickfix entry, opening its file first if there isn't a buffer for it
+    pub fn jump_to_quickfix(state: PClientState, ess: PEditorState) -> Result<(), Error> {
+        let (entry, open) = {
+            let es = ess.read().unwrap();
+            let entry = es.quickfix.get(es.quickfix_index).cloned()
+                .ok_or_else(|| Error::InvalidCommand("quickfix list is empty".into()))?;
+            let open = es.buffers.iter().position(|b| b.server_name == entry.server_name && b.path == entry.path);
+            (entry, open)
+        };
+        match open {
+            Some(buffer_index) => ess.write().unwrap().show_quickfix_entry(buffer_index),
+            None => ClientState::open_buffer(state, ess, entry.server_name, entry.path, |es, cs, buffer_index| {
+                es.show_quickfix_entry(buffer_index);
+                cs.write().unwrap().force_redraw = true;
+            })
+        }
+        Ok(())
+    }
+
     pub fn process_usr_msg(&mut self, um: UserMessage) {
         self.usrmsgs.push(um);
         self.force_redraw = true;
@@ -585,27 +1263,36 @@ impl AutosyncWorker {
         AutosyncWorker { cstate, state, last_synced_action_ids: HashMap::new() }
     }
 
+    /// indices of the buffers that have changed since the last time this was called, skipping
+    /// scratch buffers since there is nowhere to sync them to
+    fn buffers_to_sync(&mut self, state: &EditorState) -> Vec<usize> {
+        let mut need_sync = Vec::new();
+        for (i,b) in state.buffers.iter().enumerate() {
+            if b.scratch { continue; }
+            if let Some(last_synced_action_id) = self.last_synced_action_ids
+                .entry(b.server_name.clone())
+                    .or_insert_with(HashMap::new)
+                .insert(b.file_id, b.text.most_recent_action_id())
+            {
+                if last_synced_action_id < b.text.most_recent_action_id() {
+                    need_sync.push(i);
+                }
+            }
+        }
+        need_sync
+    }
+
     pub fn run(&mut self) {
         loop {
             std::thread::sleep(std::time::Duration::from_millis(1000));
             // should this function directly manipulate the futures? 
             // it would be possible to join all the request futures together and then poll them
             // with only one task, which would be more efficent.
-            let mut need_sync = Vec::new();
-            {
-            let state = self.state.read().unwrap();
-            for (i,b) in state.buffers.iter().enumerate() {
-                if let Some(last_synced_action_id) = self.last_synced_action_ids
-                    .entry(b.server_name.clone())
-                        .or_insert_with(HashMap::new)
-                    .insert(b.file_id, b.text.most_recent_action_id())
-                {
-                    if last_synced_action_id < b.text.most_recent_action_id() {
-                        need_sync.push(i);
-                    }
-                }
-            }
-            }
+            let need_sync = {
+                let state = self.state.clone();
+                let state = state.read().unwrap();
+                self.buffers_to_sync(&state)
+            };
             // println!("autosync {:?}", need_sync);
             for i in need_sync {
                 ClientState::sync_buffer(self.cstate.clone(), self.state.clone(), i);
@@ -614,5 +1301,39 @@ impl AutosyncWorker {
     }
 }
 
+/// Pings each server every `heartbeat_interval`, and reconnects to any that stop answering
+pub struct HeartbeatWorker {
+    cstate: PClientState
+}
 
+impl HeartbeatWorker {
+    pub fn new(cstate: PClientState) -> HeartbeatWorker {
+        HeartbeatWorker { cstate }
+    }
 
+    pub fn run(&mut self) {
+        loop {
+            let interval = match self.cstate.read().unwrap().config.heartbeat_interval {
+                Some(i) => i,
+                None => return
+            };
+            std::thread::sleep(interval);
+            let lost: Vec<(String, String)> = {
+                let mut cs = self.cstate.write().unwrap();
+                let dead: Vec<String> = cs.servers.iter_mut()
+                    .filter_map(|(name, s)| if s.beat(MAX_MISSED_PINGS) { None } else { Some(name.clone()) })
+                    .collect();
+                let lost = dead.into_iter()
+                    .filter_map(|name| cs.servers.remove(&name).map(|s| (name, s.url))).collect::<Vec<_>>();
+                for (name, url) in lost.iter() {
+                    cs.process_usr_msg(UserMessage::warning(
+                        format!("Lost connection to {} ({}), reconnecting", name, url), None));
+                }
+                lost
+            };
+            for (name, url) in lost {
+                ClientState::connect_to_server(self.cstate.clone(), name, &url);
+            }
+        }
+    }
+}
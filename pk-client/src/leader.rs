@@ -0,0 +1,194 @@
+use super::Error;
+use pk_common::Direction;
+use std::collections::BTreeMap;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum LeaderAction {
+    /// move to the neighboring pane in the direction given by its index into `Pane::neighbors`
+    FocusPane(usize),
+    SplitPane { vertical: bool },
+    ClosePane,
+    /// make the current pane fill the screen, or restore the other panes if it already does
+    ZoomPane,
+    /// copy the current line or selection, putting the copy after it when going forward
+    Duplicate(Direction)
+}
+
+impl LeaderAction {
+    pub fn from_name(name: &str) -> Option<LeaderAction> {
+        Some(match name {
+            "focus-left" => LeaderAction::FocusPane(0),
+            "focus-right" => LeaderAction::FocusPane(1),
+            "focus-up" => LeaderAction::FocusPane(2),
+            "focus-down" => LeaderAction::FocusPane(3),
+            "split-horizontal" => LeaderAction::SplitPane { vertical: false },
+            "split-vertical" => LeaderAction::SplitPane { vertical: true },
+            "close-pane" => LeaderAction::ClosePane,
+            "zoom-pane" => LeaderAction::ZoomPane,
+            "duplicate-down" => LeaderAction::Duplicate(Direction::Forward),
+            "duplicate-up" => LeaderAction::Duplicate(Direction::Backward),
+            _ => return None
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LeaderAction::FocusPane(0) => "focus-left",
+            LeaderAction::FocusPane(1) => "focus-right",
+            LeaderAction::FocusPane(2) => "focus-up",
+            LeaderAction::FocusPane(_) => "focus-down",
+            LeaderAction::SplitPane { vertical: false } => "split-horizontal",
+            LeaderAction::SplitPane { vertical: true } => "split-vertical",
+            LeaderAction::ClosePane => "close-pane",
+            LeaderAction::ZoomPane => "zoom-pane",
+            LeaderAction::Duplicate(Direction::Forward) => "duplicate-down",
+            LeaderAction::Duplicate(Direction::Backward) => "duplicate-up"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct LeaderNode {
+    action: Option<LeaderAction>,
+    children: BTreeMap<char, LeaderNode>
+}
+
+/// The result of looking up a (possibly partial) key sequence typed after the leader key
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LeaderMatch {
+    /// the sequence is bound and no longer sequence starts with it
+    Exact(LeaderAction),
+    /// more keys are needed to decide. `action` is what the sequence itself is bound to, which
+    /// gets run if the timeout expires before another key arrives
+    Prefix { action: Option<LeaderAction>, options: Vec<(String, LeaderAction)> },
+    NoMatch
+}
+
+/// A trie of key sequences bound to leader actions
+#[derive(Debug, Clone, Default)]
+pub struct LeaderMap {
+    root: LeaderNode
+}
+
+impl LeaderMap {
+    pub fn bind(&mut self, keys: &str, action: LeaderAction) {
+        let mut node = &mut self.root;
+        for c in keys.chars() {
+            node = node.children.entry(c).or_default();
+        }
+        node.action = Some(action);
+    }
+
+    pub fn lookup(&self, keys: &str) -> LeaderMatch {
+        let mut node = &self.root;
+        for c in keys.chars() {
+            match node.children.get(&c) {
+                Some(n) => node = n,
+                None => return LeaderMatch::NoMatch
+            }
+        }
+        if node.children.is_empty() {
+            node.action.map_or(LeaderMatch::NoMatch, LeaderMatch::Exact)
+        } else {
+            let mut options = Vec::new();
+            let mut prefix = String::from(keys);
+            Self::collect_options(node, &mut prefix, &mut options);
+            LeaderMatch::Prefix { action: node.action, options }
+        }
+    }
+
+    fn collect_options(node: &LeaderNode, prefix: &mut String, options: &mut Vec<(String, LeaderAction)>) {
+        for (c, child) in node.children.iter() {
+            prefix.push(*c);
+            if let Some(a) = child.action {
+                options.push((prefix.clone(), a));
+            }
+            Self::collect_options(child, prefix, options);
+            prefix.pop();
+        }
+    }
+
+    pub fn from_toml(val: &toml::Value) -> Result<LeaderMap, Error> {
+        let mut map = LeaderMap::default();
+        for (keys, action) in val.as_table()
+            .ok_or_else(|| Error::ConfigParseError("expected table of leader key bindings".into(), Some(val.clone())))?.iter()
+        {
+            map.bind(keys, action.as_str().and_then(LeaderAction::from_name)
+                .ok_or_else(|| Error::ConfigParseError(format!("unknown leader action for '{}'", keys), Some(action.clone())))?);
+        }
+        Ok(map)
+    }
+}
+
+impl std::fmt::Display for LeaderMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let LeaderMatch::Prefix { options, .. } = self {
+            for (keys, action) in options.iter() {
+                write!(f, " {}:{}", keys, action.name())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn default_leader_map() -> LeaderMap {
+    let mut map = LeaderMap::default();
+    map.bind("h", LeaderAction::FocusPane(0));
+    map.bind("l", LeaderAction::FocusPane(1));
+    map.bind("k", LeaderAction::FocusPane(2));
+    map.bind("j", LeaderAction::FocusPane(3));
+    map.bind("s", LeaderAction::SplitPane { vertical: false });
+    map.bind("v", LeaderAction::SplitPane { vertical: true });
+    map.bind("x", LeaderAction::ClosePane);
+    map.bind("z", LeaderAction::ZoomPane);
+    map.bind("d", LeaderAction::Duplicate(Direction::Forward));
+    map.bind("D", LeaderAction::Duplicate(Direction::Backward));
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_map() -> LeaderMap {
+        let mut map = LeaderMap::default();
+        map.bind("x", LeaderAction::ClosePane);
+        map.bind("f", LeaderAction::FocusPane(0));
+        map.bind("ff", LeaderAction::FocusPane(1));
+        map.bind("fg", LeaderAction::FocusPane(2));
+        map.bind("ws", LeaderAction::SplitPane { vertical: false });
+        map.bind("wv", LeaderAction::SplitPane { vertical: true });
+        map
+    }
+
+    #[test]
+    fn unambiguous_prefix() {
+        let map = test_map();
+        assert_eq!(map.lookup("x"), LeaderMatch::Exact(LeaderAction::ClosePane));
+        assert_eq!(map.lookup("ff"), LeaderMatch::Exact(LeaderAction::FocusPane(1)));
+        assert_eq!(map.lookup("wv"), LeaderMatch::Exact(LeaderAction::SplitPane { vertical: true }));
+    }
+
+    #[test]
+    fn ambiguous_prefix() {
+        let map = test_map();
+        assert_eq!(map.lookup("w"), LeaderMatch::Prefix {
+            action: None,
+            options: vec![("ws".into(), LeaderAction::SplitPane { vertical: false }),
+                          ("wv".into(), LeaderAction::SplitPane { vertical: true })]
+        });
+        assert_eq!(map.lookup("f"), LeaderMatch::Prefix {
+            action: Some(LeaderAction::FocusPane(0)),
+            options: vec![("ff".into(), LeaderAction::FocusPane(1)),
+                          ("fg".into(), LeaderAction::FocusPane(2))]
+        });
+    }
+
+    #[test]
+    fn no_match() {
+        let map = test_map();
+        assert_eq!(map.lookup("q"), LeaderMatch::NoMatch);
+        assert_eq!(map.lookup("wq"), LeaderMatch::NoMatch);
+        assert_eq!(map.lookup("xx"), LeaderMatch::NoMatch);
+    }
+}
@@ -22,7 +22,14 @@ impl CommandFn for TestCommand {
 pub struct QuitCommand;
 
 impl CommandFn for QuitCommand {
-    fn process(&self, cs: PClientState, _: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        if a.name("force").is_none() {
+            let es = es.read().unwrap();
+            let modified = es.modified_buffers();
+            if !modified.is_empty() {
+                return Err(Error::InvalidCommand(format!("unsynced changes in {}, use q! to quit anyway", es.buffer_names(modified.iter()))));
+            }
+        }
         cs.write().unwrap().should_exit = true;
         Ok(Some(Box::new(NormalMode::new())))
     }
@@ -46,6 +53,352 @@ impl CommandFn for DebugRegistersCommand {
     }
 }
 
+/// one line per register, with the contents escaped so that they stay on one line and cut off
+/// if they are too long
+pub fn format_registers(registers: &std::collections::BTreeMap<char, String>) -> String {
+    const MAX_LEN: usize = 60;
+    registers.iter().map(|(r, contents)| {
+        let mut esc: String = contents.escape_debug().collect();
+        if esc.chars().count() > MAX_LEN {
+            esc = esc.chars().take(MAX_LEN).collect::<String>() + "…";
+        }
+        format!("\"{}  {}", r, esc)
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// one line per file open on `server_name`, with its id and version
+pub fn format_open_files(server_name: &str, files: &[(protocol::FileId, std::path::PathBuf, usize)]) -> String {
+    if files.is_empty() {
+        return format!("no files open on {}", server_name);
+    }
+    files.iter().map(|(id, path, version)| format!("{}#{}  {}  v{}", server_name, id.0, path.to_str().unwrap_or(""), version))
+        .collect::<Vec<_>>().join("\n")
+}
+
+pub struct RegistersCommand;
+
+impl CommandFn for RegistersCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let registers = &es.read().unwrap().registers;
+        ClientState::process_usr_msgp(cs, UserMessage::info(if registers.is_empty() {
+            "all registers are empty".into()
+        } else {
+            format_registers(registers)
+        }, None));
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// undo the escapes in a quoted string from the command line
+fn unescape(s: &str) -> Result<String, Error> {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        res.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some(c) if c == '\\' || c == '"' => c,
+            Some(c) => return Err(Error::InvalidCommand(format!("unknown escape \\{}", c))),
+            None => return Err(Error::InvalidCommand("unfinished escape".into()))
+        });
+    }
+    Ok(res)
+}
+
+pub struct SetRegisterCommand;
+
+impl CommandFn for SetRegisterCommand {
+    fn process(&self, _: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let register = a.name("register").and_then(|r| r.as_str().chars().next())
+            .ok_or_else(|| Error::InvalidCommand("expected register name".into()))?;
+        let text = unescape(a.name("text").map_or("", |t| t.as_str()))?;
+        es.write().unwrap().registers.insert(register, text);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// the lines named by the range before a command, counted from zero with the end excluded. No
+/// range or `%` is the whole buffer, `'<,'>` is the last visual selection and `a,b` or `a` are
+/// line numbers counting from one
+fn line_range(range: Option<&str>, state: &EditorState) -> Result<std::ops::Range<usize>, Error> {
+    let buf = state.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer for range".into()))?;
+    match range {
+        None | Some("%") => Ok(0..buf.line_for_index(buf.text.len())+1),
+        Some("'<,'>") => match &state.last_visual_selection {
+            Some(sel) if Some(sel.buffer_index) == state.current_buffer_index() => {
+                let lo = sel.range.start.min(sel.range.end);
+                let hi = sel.range.start.max(sel.range.end);
+                Ok(buf.line_for_index(lo)..buf.line_for_index(hi)+1)
+            },
+            _ => Err(Error::InvalidCommand("no visual selection in this buffer".into()))
+        },
+        Some(r) => {
+            let mut bounds = r.split(',').map(str::parse::<usize>);
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(a)), Some(Ok(b))) if a > 0 && a <= b => Ok(a-1..b),
+                (Some(Ok(a)), None) if a > 0 => Ok(a-1..a),
+                _ => Err(Error::InvalidCommand(format!("invalid range {}", r)))
+            }
+        }
+    }
+}
+
+/// sort the lines in `text`, keeping whether it ends with a newline. Numeric sorts compare the
+/// first number on each line, putting lines without one first, and `unique` keeps only the first
+/// of lines that compare equal
+pub fn sort_lines(text: &str, reverse: bool, numeric: bool, unique: bool) -> String {
+    use std::cmp::Ordering;
+    let trailing_newline = text.ends_with('\n');
+    let mut lines: Vec<&str> = text.strip_suffix('\n').unwrap_or(text).split('\n').collect();
+    let number = regex::Regex::new(r"-?\d+").unwrap();
+    let first_number = |ln: &str| number.find(ln).and_then(|m| m.as_str().parse::<i64>().ok());
+    let cmp = |a: &&str, b: &&str| -> Ordering {
+        let ord = if numeric { first_number(a).cmp(&first_number(b)) } else { a.cmp(b) };
+        if reverse { ord.reverse() } else { ord }
+    };
+    lines.sort_by(&cmp);
+    if unique {
+        lines.dedup_by(|a, b| cmp(a, b) == Ordering::Equal);
+    }
+    let mut sorted = lines.join("\n");
+    if trailing_newline {
+        sorted.push('\n');
+    }
+    sorted
+}
+
+/// replace `lines` in `buf` with the result of `f` on their text, as a single undo step, and move
+/// the cursor to the first of them
+fn replace_lines(buf: &mut crate::buffer::Buffer, lines: std::ops::Range<usize>, f: impl FnOnce(&str) -> String) {
+    let start = buf.index_for_line(lines.start);
+    let end = buf.index_for_line(lines.end);
+    if start >= end { return; }
+    let old = buf.text.copy_range(start, end);
+    let new = f(&old);
+    if new != old {
+        buf.text.delete_range(start, end);
+        if !new.is_empty() {
+            buf.text.insert_range(&new, start);
+            buf.text.merge_last_actions(2);
+        }
+    }
+    buf.set_cursor(start);
+}
+
+pub struct SortCommand;
+
+impl CommandFn for SortCommand {
+    fn process(&self, _: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let lines = line_range(a.name("range").map(|m| m.as_str()), &state)?;
+        let flags = a.name("flags").map_or("", |f| f.as_str());
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to sort".into()))?;
+        buf.check_writable()?;
+        replace_lines(buf, lines, |text| sort_lines(text,
+            a.name("reverse").is_some(), flags.contains('n'), flags.contains('u')));
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// whitespace that goes from column `start` to column `end`, using tabs unless `softtab` is set
+fn whitespace_between(start: usize, end: usize, tabstop: usize, softtab: bool) -> String {
+    let mut ws = String::new();
+    let mut col = start;
+    if !softtab {
+        while (col / tabstop + 1) * tabstop <= end {
+            ws.push('\t');
+            col = (col / tabstop + 1) * tabstop;
+        }
+    }
+    ws.push_str(&" ".repeat(end - col));
+    ws
+}
+
+/// rewrite the indentation of each line in `text`, which was laid out with tabs `old_tabstop`
+/// columns wide, using tabs `new_tabstop` wide or only spaces if `softtab` is set, so that the
+/// text after it stays in the same column. If `all` is set then runs of whitespace containing
+/// tabs after the indentation are rewritten too
+pub fn retab_lines(text: &str, old_tabstop: usize, new_tabstop: usize, softtab: bool, all: bool) -> String {
+    text.split('\n').map(|ln| {
+        let mut res = String::with_capacity(ln.len());
+        let mut col = 0;
+        // the starting column and text of the current run of whitespace
+        let mut run: Option<(usize, String)> = None;
+        let mut leading = true;
+        let flush = |res: &mut String, run: Option<(usize, String)>, col: usize, leading: bool| {
+            if let Some((start, ws)) = run {
+                if leading || (all && ws.contains('\t')) {
+                    res.push_str(&whitespace_between(start, col, new_tabstop, softtab));
+                } else {
+                    res.push_str(&ws);
+                }
+            }
+        };
+        for c in ln.chars() {
+            if c == ' ' || c == '\t' {
+                run.get_or_insert_with(|| (col, String::new())).1.push(c);
+                col += if c == '\t' { old_tabstop - col % old_tabstop } else { 1 };
+            } else {
+                flush(&mut res, run.take(), col, leading);
+                leading = false;
+                res.push(c);
+                col += 1;
+            }
+        }
+        flush(&mut res, run, col, leading);
+        res
+    }).collect::<Vec<_>>().join("\n")
+}
+
+pub struct RetabCommand;
+
+impl CommandFn for RetabCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let lines = line_range(a.name("range").map(|m| m.as_str()), &state)?;
+        let mut cs = cs.write().unwrap();
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to retab".into()))?;
+        buf.check_writable()?;
+        let (old_tabstop, softtab) = {
+            let cfg = buf.options.resolve(&cs.config);
+            (cfg.tabstop, cfg.softtab)
+        };
+        let new_tabstop = match a.name("tabstop") {
+            Some(ts) => ts.as_str().parse::<usize>().ok().filter(|ts| *ts > 0)
+                .ok_or_else(|| Error::InvalidCommand(format!("invalid tabstop {}", ts.as_str())))?,
+            None => old_tabstop
+        };
+        replace_lines(buf, lines, |text| retab_lines(text, old_tabstop, new_tabstop, softtab, a.name("all").is_some()));
+        // a tabstop set with setlocal only changes for this buffer
+        if buf.options.tabstop.is_some() {
+            buf.options.tabstop = Some(new_tabstop);
+        } else {
+            cs.config.tabstop = new_tabstop;
+        }
+        buf.tabstop = new_tabstop;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct ReindentCommand;
+
+impl CommandFn for ReindentCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let lines = line_range(a.name("range").map(|m| m.as_str()), &state)?;
+        let cs = cs.read().unwrap();
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to reindent".into()))?;
+        buf.check_writable()?;
+        let cfg = buf.options.resolve(&cs.config);
+        let first_nonblank = buf.reindent(lines, &cfg);
+        buf.set_cursor(first_nonblank);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct SetLocalCommand;
+
+impl CommandFn for SetLocalCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to set options for".into()))?;
+        // nothing is changed unless every option is valid
+        let mut options = buf.options.clone();
+        for opt in a.name("options").unwrap().as_str().split_whitespace() {
+            let mut parts = opt.splitn(2, '=');
+            options.set(parts.next().unwrap(), parts.next())?;
+        }
+        buf.options = options;
+        buf.tabstop = buf.options.resolve(&cs.read().unwrap().config).tabstop;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct FileFormatCommand;
+
+impl CommandFn for FileFormatCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let buffer_index = {
+            let mut state = es.write().unwrap();
+            let buffer_index = state.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to set fileformat for".into()))?;
+            let buf = &mut state.buffers[buffer_index];
+            match a.name("format") {
+                Some(f) => {
+                    buf.check_writable()?;
+                    buf.set_line_ending(protocol::LineEnding::from_fileformat(f.as_str())
+                        .ok_or_else(|| Error::InvalidCommand(format!("unknown fileformat {}", f.as_str())))?)
+                },
+                None => {
+                    let msg = format!("fileformat={}", buf.format.line_ending.fileformat());
+                    cs.write().unwrap().process_usr_msg(UserMessage::info(msg, None));
+                    return Ok(Some(Box::new(NormalMode::new())));
+                }
+            }
+            buffer_index
+        };
+        ClientState::sync_buffer(cs, es, buffer_index);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct NoHighlightCommand;
+
+impl CommandFn for NoHighlightCommand {
+    fn process(&self, _: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        if let Some(buf) = es.write().unwrap().current_buffer_mut() {
+            buf.clear_search_highlights();
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// move the cursor to the first non-blank of a line, counting from 1, or the last line for `$`
+pub struct GotoLineCommand;
+
+impl CommandFn for GotoLineCommand {
+    fn process(&self, _: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to move in".into()))?;
+        let last_line = buf.line_for_index(buf.text.len().saturating_sub(1));
+        let line = match &a["line"] {
+            "$" => last_line,
+            n => n.parse::<usize>().map_err(|_| Error::InvalidCommand(format!("invalid line number {}", n)))?
+                    .saturating_sub(1).min(last_line)
+        };
+        buf.set_cursor(buf.first_nonblank(line));
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// delete the lines in the range, or the line with the cursor without one, into a register
+pub struct DeleteLinesCommand;
+
+impl CommandFn for DeleteLinesCommand {
+    fn process(&self, _: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let lines = match a.name("range") {
+            Some(r) => line_range(Some(r.as_str()), &state)?,
+            None => {
+                let buf = state.current_buffer().ok_or_else(|| Error::InvalidCommand("no buffer to delete in".into()))?;
+                let line = buf.line_for_index(buf.cursor_index);
+                line..line+1
+            }
+        };
+        let register = a.name("register").and_then(|r| r.as_str().chars().next()).unwrap_or('"');
+        let buf = state.current_buffer_mut().ok_or_else(|| Error::InvalidCommand("no buffer to delete in".into()))?;
+        buf.check_writable()?;
+        let deleted = buf.delete_line(lines.start, lines.end - lines.start);
+        if let Some(deleted) = deleted {
+            crate::command::record_delete(&mut state.registers, register, deleted);
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct EditFileCommand;
 
 impl CommandFn for EditFileCommand {
@@ -62,6 +415,122 @@ impl CommandFn for EditFileCommand {
     }
 }
 
+pub struct GrepCommand;
+
+impl CommandFn for GrepCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let pattern = regex::Regex::new(&a["pattern"])
+            .map_err(|e| Error::InvalidCommand(format!("invalid pattern: {}", e)))?;
+        {
+            let mut state = es.write().unwrap();
+            // scratch buffers don't have a file that an entry could point to
+            let quickfix: Vec<QfEntry> = state.buffers.iter().filter(|b| !b.scratch)
+                .flat_map(|b| b.find_all(&pattern).into_iter().map(move |m| {
+                    let line = b.line_for_index(m.start);
+                    QfEntry {
+                        server_name: b.server_name.clone(), path: b.path.clone(), line,
+                        column: m.start - b.index_for_line(line),
+                        text: b.line_text(line).map_or_else(String::new, |t| t.trim().into())
+                    }
+                })).collect();
+            if quickfix.is_empty() {
+                return Err(Error::InvalidCommand(format!("no matches for {}", pattern)));
+            }
+            state.quickfix = quickfix;
+            state.quickfix_index = 0;
+        }
+        ClientState::jump_to_quickfix(cs, es)?;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct QuickfixCommand;
+
+impl CommandFn for QuickfixCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let count = match a.name("count") {
+            Some(c) => Some(c.as_str().parse::<usize>()
+                .map_err(|_| Error::InvalidCommand(format!("invalid count {}", c.as_str())))?),
+            None => None
+        };
+        {
+            let mut state = es.write().unwrap();
+            if state.quickfix.is_empty() {
+                return Err(Error::InvalidCommand("quickfix list is empty".into()));
+            }
+            match &a["cmd"] {
+                // entries are numbered from one, like they are shown
+                "c" => match count {
+                    Some(n) if n == 0 || n > state.quickfix.len() =>
+                        return Err(Error::InvalidCommand(format!("no quickfix entry {}", n))),
+                    Some(n) => state.quickfix_index = n - 1,
+                    None => {}
+                },
+                "n" | "next" => { state.step_quickfix(Direction::Forward, count.unwrap_or(1)); },
+                _ => { state.step_quickfix(Direction::Backward, count.unwrap_or(1)); }
+            }
+        }
+        ClientState::jump_to_quickfix(cs, es)?;
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct QuickfixOpenCommand;
+
+impl CommandFn for QuickfixOpenCommand {
+    fn process(&self, _: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        if !state.panes.values().any(|p| matches!(p.content, PaneContent::QuickFix)) {
+            state.unzoom();
+            let cp = state.current_pane;
+            Pane::split(&mut state.panes, cp, false, 0.25, PaneContent::QuickFix);
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+/// close every pane but the current one
+pub struct OnlyCommand;
+
+impl CommandFn for OnlyCommand {
+    fn process(&self, _: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        es.write().unwrap().only_pane();
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct NewBufferCommand;
+
+impl CommandFn for NewBufferCommand {
+    fn process(&self, _: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let mut state = es.write().unwrap();
+        let buffer_index = state.buffers.len();
+        state.buffers.push(buffer::Buffer::scratch());
+        if a.name("split").is_some() {
+            state.unzoom();
+            let cp = state.current_pane;
+            state.current_pane = Pane::split(&mut state.panes, cp, false, 0.5, PaneContent::buffer(buffer_index));
+        } else {
+            state.current_pane_mut().content = PaneContent::buffer(buffer_index);
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct SaveAsCommand;
+
+impl CommandFn for SaveAsCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        use std::path::PathBuf;
+        let server_name: String = a.name("server_name").map(|m| m.as_str()).unwrap_or("local").to_owned();
+        let path = a.name("path").map(|m| PathBuf::from(m.as_str()))
+            .ok_or(Error::InvalidCommand("missing path to save as".into()))?;
+        let cb = { es.read().unwrap().current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to save".into()))? };
+        ClientState::save_buffer_as(cs, es, cb, server_name, path);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct BufferCommand;
 
 impl CommandFn for BufferCommand {
@@ -91,6 +560,10 @@ impl CommandFn for BufferCommand {
             Some("x") => {
                 if let Some((index, _score)) = bufs.get(0) {
                     let mut state = es.write().unwrap();
+                    if a.name("force").is_none() && state.buffers[*index].is_modified() {
+                        return Err(Error::InvalidCommand(format!("unsynced changes in {}, use bx! to close anyway",
+                                    state.buffers[*index].path.to_str().unwrap_or(""))));
+                    }
                     let buf = state.buffers.remove(*index);
                     drop(state);
                     ClientState::make_request_async(cs, buf.server_name, protocol::Request::CloseFile(buf.file_id), 
@@ -132,6 +605,72 @@ impl CommandFn for SyncFileCommand {
     }
 }
 
+/// sync every buffer with unsynced changes
+pub struct WriteAllCommand;
+
+impl CommandFn for WriteAllCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        ClientState::write_all(cs, es);
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct DiffCommand;
+
+impl CommandFn for DiffCommand {
+    fn process(&self, cs: PClientState, es: PEditorState, _: &regex::Captures) -> mode::ModeEventResult {
+        let (buffer_index, server_name, id) = {
+            let state = es.read().unwrap();
+            let cb = state.current_buffer_index().ok_or_else(|| Error::InvalidCommand("no buffer to diff".into()))?;
+            let buf = &state.buffers[cb];
+            if buf.scratch {
+                return Err(Error::InvalidCommand("scratch buffers have no server version to diff against".into()));
+            }
+            (cb, buf.server_name.clone(), buf.file_id)
+        };
+        ClientState::make_request_async(cs, server_name, protocol::Request::GetFile(id), move |cs, resp| {
+            match resp {
+                protocol::Response::FileInfo { contents, .. } => {
+                    let mut state = es.write().unwrap();
+                    let local = &state.buffers[buffer_index];
+                    // a scratch buffer is never synced, and the extension picks diff highlighting for it
+                    let mut view = buffer::Buffer::with_text(&diff::display(&diff::lines(&contents, &local.text.text())));
+                    view.scratch = true;
+                    view.read_only = true;
+                    let mut path = local.path.clone().into_os_string();
+                    path.push(".diff");
+                    view.path = path.into();
+                    let nbi = state.buffers.len();
+                    state.buffers.push(view);
+                    state.unzoom();
+                    let cp = state.current_pane;
+                    Pane::split(&mut state.panes, cp, true, 0.5, PaneContent::buffer(nbi));
+                    cs.write().unwrap().force_redraw = true;
+                },
+                _ => cs.write().unwrap().process_usr_msg(
+                    UserMessage::error(format!("unexpected server response to diff: {:?}", resp), None))
+            }
+        });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
+pub struct FilesCommand;
+
+impl CommandFn for FilesCommand {
+    fn process(&self, cs: PClientState, _: PEditorState, a: &regex::Captures) -> mode::ModeEventResult {
+        let server_name = a.name("server_name").map_or("local", |m| m.as_str()).to_owned();
+        ClientState::make_request_async(cs, server_name.clone(), protocol::Request::ListOpenFiles, move |cs, resp| {
+            match resp {
+                protocol::Response::OpenFiles { files } =>
+                    ClientState::process_usr_msgp(cs, UserMessage::info(format_open_files(&server_name, &files), None)),
+                _ => panic!("unexpected server response {:?}", resp)
+            }
+        });
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+}
+
 pub struct ConnectToServerCommand;
 
 impl CommandFn for ConnectToServerCommand {
@@ -158,10 +697,92 @@ impl CommandFn for SearchCommand {
             "?" => Direction::Backward,
             _ => panic!()
         }, true) {
-            Some(ix) => cb.cursor_index = ix,
+            Some(ix) => cb.set_cursor(ix),
             None => ClientState::process_usr_msgp(cs, UserMessage::error(format!("no matches for \"{}\"", args.get(2).unwrap().as_str()), None))
         }
         Ok(Some(Box::new(NormalMode::new())))
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatted_registers() {
+        let mut registers = std::collections::BTreeMap::new();
+        registers.insert('"', String::from("last yank\n"));
+        registers.insert('a', String::from("abc"));
+        registers.insert('b', "x".repeat(100));
+        assert_eq!(format_registers(&registers),
+            format!("\"\"  last yank\\n\n\"a  abc\n\"b  {}…", "x".repeat(60)));
+    }
+
+    #[test]
+    fn formatted_open_files() {
+        assert_eq!(format_open_files("local", &[]), "no files open on local");
+        assert_eq!(format_open_files("remote", &[(protocol::FileId(1), "src/a.rs".into(), 0), (protocol::FileId(4), "b.txt".into(), 12)]),
+            "remote#1  src/a.rs  v0\nremote#4  b.txt  v12");
+    }
+
+    #[test]
+    fn sort_lexical() {
+        assert_eq!(sort_lines("pear\napple\nfig\napple\n", false, false, false), "apple\napple\nfig\npear\n");
+        assert_eq!(sort_lines("b\nc\na", false, false, false), "a\nb\nc");
+    }
+
+    #[test]
+    fn sort_reverse() {
+        assert_eq!(sort_lines("pear\napple\nfig\n", true, false, false), "pear\nfig\napple\n");
+    }
+
+    #[test]
+    fn sort_numeric() {
+        assert_eq!(sort_lines("item 10\nitem 9\nnone\nx -3 y\nitem 100\n", false, true, false),
+            "none\nx -3 y\nitem 9\nitem 10\nitem 100\n");
+        assert_eq!(sort_lines("a 2\nb 1\nc 2\n", true, true, false), "a 2\nc 2\nb 1\n", "ties keep their order");
+    }
+
+    #[test]
+    fn sort_unique() {
+        assert_eq!(sort_lines("b\na\nb\nc\na\n", false, false, true), "a\nb\nc\n");
+        assert_eq!(sort_lines("x 1\ny 2\nz 1\n", false, true, true), "x 1\ny 2\n");
+    }
+
+    #[test]
+    fn retab_to_spaces() {
+        let text = "fn f() {\n\tif x {\n\t\ty();\n\t}\n}\n";
+        assert_eq!(retab_lines(text, 4, 4, true, false), "fn f() {\n    if x {\n        y();\n    }\n}\n");
+        assert_eq!(retab_lines(text, 2, 2, true, false), "fn f() {\n  if x {\n    y();\n  }\n}\n");
+        assert_eq!(retab_lines(text, 8, 8, true, false), "fn f() {\n        if x {\n                y();\n        }\n}\n");
+        // mixed indentation lines up at the next tab stop
+        assert_eq!(retab_lines("  \tx", 4, 4, true, false), "    x");
+    }
+
+    #[test]
+    fn retab_to_tabs() {
+        let text = "a\n    b\n        c\n      d\n";
+        assert_eq!(retab_lines(text, 4, 4, false, false), "a\n\tb\n\t\tc\n\t  d\n");
+        assert_eq!(retab_lines(text, 2, 2, false, false), "a\n\t\tb\n\t\t\t\tc\n\t\t\td\n");
+        assert_eq!(retab_lines(text, 8, 8, false, false), "a\n    b\n\tc\n      d\n");
+        // changing the tabstop keeps the indentation the same width
+        assert_eq!(retab_lines("\t\tx\n", 4, 8, false, false), "\tx\n");
+        assert_eq!(retab_lines("\tx\n", 8, 4, false, false), "\t\tx\n");
+    }
+
+    #[test]
+    fn retab_leading_only() {
+        let text = "\tlet s = \"a\tb\";\t// c\n";
+        assert_eq!(retab_lines(text, 4, 4, true, false), "    let s = \"a\tb\";\t// c\n");
+        assert_eq!(retab_lines(text, 4, 4, true, true), "    let s = \"a  b\"; // c\n");
+        assert_eq!(retab_lines("x  y\n", 4, 4, false, true), "x  y\n", "spaces after the indentation are left alone");
+    }
+
+    #[test]
+    fn unescape_strings() {
+        assert_eq!(unescape(r#"a\tb\n\"c\"\\"#).unwrap(), "a\tb\n\"c\"\\");
+        assert!(unescape(r#"\x"#).is_err());
+        assert!(unescape(r#"abc\"#).is_err());
+    }
+}
@@ -5,13 +5,16 @@ extern crate lazy_static;
 mod buffer;
 mod motion;
 mod command;
+mod leader;
 mod mode;
 mod piece_table_render;
 mod line_command;
 mod server;
 mod editor_state;
 mod config;
+mod diff;
 mod syntax_highlight;
+mod tabline;
 
 use runic::*;
 use pk_common::*;
@@ -30,6 +33,13 @@ pub enum Error {
     UnknownCommand(String),
     ConfigParseError(String, Option<toml::Value>),
     EmptyRegister(char),
+    /// sending or receiving a message failed
+    Transport(nng::Error),
+    /// a message couldn't be encoded or decoded
+    Serde(serde_cbor::Error),
+    /// there's no connection to a server with this name
+    NotConnected(String),
+    FileNotFound(std::path::PathBuf),
     Other(Box<dyn ErrorTrait + 'static>)
 }
 
@@ -41,6 +51,10 @@ impl std::fmt::Display for Error {
             Error::UnknownCommand(cmd) => write!(f, "unknown command: {}", cmd),
             Error::ConfigParseError(cmd, val) => write!(f, "bad configuration: {} (value = {:?})", cmd, val),
             Error::EmptyRegister(c) => write!(f, "nothing in register \"{}", c),
+            Error::Transport(e) => write!(f, "error in transport: {}", e),
+            Error::Serde(e) => write!(f, "error encoding/decoding message: {}", e),
+            Error::NotConnected(name) => write!(f, "not connected to a server named {}", name),
+            Error::FileNotFound(path) => write!(f, "file not found: {}", path.display()),
             Error::Other(e) => e.fmt(f)
         }
     }
@@ -49,12 +63,26 @@ impl std::fmt::Display for Error {
 impl ErrorTrait for Error {
     fn source(&self)  -> Option<&(dyn ErrorTrait + 'static)> {
         match self {
+            Error::Transport(e) => Some(e),
+            Error::Serde(e) => Some(e),
             Error::Other(e) => Some(e.as_ref()),
             _ => None
         }
     }
 }
 
+impl From<nng::Error> for Error {
+    fn from(e: nng::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Self {
+        Error::Serde(e)
+    }
+}
+
 impl Error {
     pub fn from_other<E: ErrorTrait + 'static>(e: E) -> Self {
         Error::Other(Box::new(e))
@@ -82,11 +110,17 @@ struct PkApp {
             (Config::default(), None)
         } else {
             let projd = directories_next::ProjectDirs::from("", "", "pk").expect("compute application directory");
-            let config_dir = cargs.opt_value_from_str("--config").unwrap()
+            let explicit_config: Option<std::path::PathBuf> = cargs.opt_value_from_str("--config").unwrap();
+            // a missing default configuration file just means the defaults are used
+            let explicit = explicit_config.is_some();
+            let config_dir = explicit_config
                 .unwrap_or_else(|| std::path::Path::join(projd.config_dir(), "client.toml"));
-            std::fs::read_to_string(config_dir).map_or_else(|e| {
-                    (Config::default(), if e.kind() != std::io::ErrorKind::NotFound { Some(UserMessage::error(
-                                format!("error loading configuration file: {}", e), None)) } else { None })
+            std::fs::read_to_string(&config_dir).map_or_else(|e| {
+                    (Config::default(), if e.kind() != std::io::ErrorKind::NotFound {
+                        Some(UserMessage::error(format!("error loading configuration file: {}", e), None))
+                    } else if explicit {
+                        Some(UserMessage::error(format!("error loading configuration file: {}", Error::FileNotFound(config_dir.clone())), None))
+                    } else { None })
                 }, |v|  v.parse::<toml::Value>().map_err(Error::from_other).and_then(Config::from_toml).map_or_else(|e| {
                     (Config::default(), Some(UserMessage::error(
                                 format!("error parsing configuration file: {}", e), None)))
@@ -129,6 +163,11 @@ struct PkApp {
             asw.run();
         });
 
+        let mut hbw = editor_state::HeartbeatWorker::new(client.clone());
+        std::thread::spawn(move || {
+            hbw.run();
+        });
+
         let highlighter = syntax_highlight::Highlighter::from_toml(config.syntax_coloring.as_ref());
 
         let fnt = rx.new_font(&config.font.0, config.font.1,
@@ -157,7 +196,15 @@ struct PkApp {
             *event_loop_flow = ControlFlowOpts::Exit;
         }
         match e {
-            Event::CloseRequested => *event_loop_flow = ControlFlowOpts::Exit,
+            Event::CloseRequested => {
+                if ClientState::request_exit(self.client.clone(), self.state.clone()) {
+                    *event_loop_flow = ControlFlowOpts::Exit;
+                } else {
+                    // the question about unsynced buffers needs an answer before anything else
+                    self.mode = Box::new(UserMessageInteractionMode::new(self.client.clone()));
+                    *should_redraw = true;
+                }
+            },
             _ => {
                 match self.mode.event(e, self.client.clone(), self.state.clone()) {
                     Ok(Some(new_mode)) => { self.mode = new_mode },
@@ -183,7 +230,7 @@ struct PkApp {
 
         // might be nice to expose this as some sort of command for debugging color schemes
         // let mut x = 0f32;
-        // for c in state.config.colors.accent.iter() {
+        // for c in state.config.colors.accents.iter() {
         //     rx.set_color(*c);
         //     rx.fill_rect(Rect::xywh(x, 256.0, 64.0, 64.0));
         //     x += 64.0;
@@ -193,8 +240,8 @@ struct PkApp {
             let x = 8f32; let mut y = rx.bounds().h-8f32; 
             for (i, um) in client.usrmsgs.iter().enumerate().rev() {
                 rx.set_color(match um.mtype {
-                    UserMessageType::Error => config.colors.accent[0],
-                    UserMessageType::Warning => config.colors.accent[2],
+                    UserMessageType::Error => config.colors.accent(0),
+                    UserMessageType::Warning => config.colors.accent(2),
                     UserMessageType::Info => config.colors.half_gray,
                 });
                 let msg_tf = rx.new_text_layout(&um.message, &self.fnt, rx.bounds().w, 1000.0).unwrap();
@@ -205,7 +252,7 @@ struct PkApp {
                     let mut x = x + msgb.w * 0.1;
                     for (j, op) in opts.iter().enumerate() {
                         let f = rx.new_text_layout(&format!("[{}] {}", j+1, op), &self.fnt, 1000.0, 1000.0).unwrap();
-                        f.color_range(rx, 0..3, config.colors.accent[6]);
+                        f.color_range(rx, 0..3, config.colors.accent(6));
                         rx.draw_text_layout(Point::xy(x,y), &f);
                         x += f.bounds().w + self.txr.em_bounds.w*3.0;
                     }
@@ -221,21 +268,46 @@ struct PkApp {
             y
         } else { rx.bounds().h };
 
-        let screen_bounds = Rect::xywh(0.0, 0.0, rx.bounds().w, usrmsg_y);
+        // once there is more than one buffer, they are shown as tabs across the top
+        let tabline_h = if state.buffers.len() > 1 {
+            let h = self.txr.em_bounds.h + 2.0;
+            let current = state.current_buffer_index();
+            let labels: Vec<String> = state.buffers.iter().map(tabline::label).collect();
+            let mut x = 0f32;
+            for (i, (label, w)) in labels.iter().zip(tabline::widths(&labels, self.txr.em_bounds.w, rx.bounds().w)).enumerate() {
+                let active = current == Some(i);
+                rx.set_color(if active { config.colors.quarter_gray } else { config.colors.background });
+                rx.fill_rect(Rect::xywh(x, 0.0, w, h));
+                rx.set_color(if active { config.colors.accent(1) } else { config.colors.three_quarter_gray });
+                rx.draw_text(Rect::xywh(x + self.txr.em_bounds.w, 1.0, w - self.txr.em_bounds.w, h), label, &self.fnt);
+                x += w;
+            }
+            h
+        } else { 0.0 };
+
+        let screen_bounds = Rect::xywh(0.0, tabline_h, rx.bounds().w, usrmsg_y - tabline_h);
+        let inactive_colors = config.dim_inactive_panes.map(|amount| config.colors.dimmed(amount));
 
         for i in state.panes.keys().cloned().collect::<Vec<_>>() {
+            // a zoomed pane covers all the others
+            if state.zoomed_layout.is_some() && i != state.current_pane { continue; }
             let bounds = Rect::xywh(screen_bounds.x + screen_bounds.w * state.panes[&i].bounds.x + 1.0,
                                     screen_bounds.y + screen_bounds.h * state.panes[&i].bounds.y + 1.0,
                                     screen_bounds.w * state.panes[&i].bounds.w - 1.0, screen_bounds.h * state.panes[&i].bounds.h - 1.0);
 
             let active = i == state.current_pane;
+            let colors = match (active, &inactive_colors) {
+                (false, Some(dimmed)) => dimmed,
+                _ => &config.colors
+            };
 
             rx.set_color(if active { config.colors.half_gray } else { config.colors.quarter_gray });
             rx.stroke_rect(bounds, 1.0);
 
             match state.panes[&i].content {
-                PaneContent::Buffer { buffer_index, viewport_start, scroll_lock, .. } => {
+                PaneContent::Buffer { buffer_index, viewport_start, mut horizontal_offset, scroll_lock, .. } => {
                     let buf = &mut state.buffers[buffer_index];
+                    buf.tabstop = buf.options.resolve(config).tabstop;
                     let editor_bounds = Rect::xywh(bounds.x, bounds.y + self.txr.em_bounds.h + 4.0, bounds.w,
                                                        bounds.h);
                     let curln = buf.line_for_index(buf.cursor_index);
@@ -243,29 +315,59 @@ struct PkApp {
                     // draw status line
                     rx.set_color(config.colors.quarter_gray);
                     rx.fill_rect(Rect::xywh(bounds.x, bounds.y, bounds.w, self.txr.em_bounds.h+2.0));
-                    rx.set_color(if active { config.colors.accent[1] } else { config.colors.three_quarter_gray });
+                    rx.set_color(if active { config.colors.accent(1) } else { config.colors.three_quarter_gray });
                     rx.draw_text(Rect::xywh(bounds.x + 8.0, bounds.y + 1.0, bounds.w, 1000.0),
-                        &format!("{} | ln {} col {} {}| {}:{} v{}{} [{}]", self.mode, curln + 1,
+                        &format!("{} | ln {} col {} {}{}| {}:{}{} v{}{} [{} {}]", self.mode, curln + 1,
                             buf.column_for_index(buf.cursor_index),
+                            buf.search_match_position().map_or_else(String::new, |(i, n)| format!("match {} of {} ", i, n)),
                             if !scroll_lock { "!L " } else { "" },
-                            buf.server_name, buf.path.to_str().unwrap_or("!"), buf.version,
-                            if buf.currently_in_conflict { "⮾" } else { "" }, buf.format.stype
+                            buf.server_name, buf.path.to_str().unwrap_or("!"),
+                            if buf.is_modified() { " [+]" } else { "" }, buf.version,
+                            if buf.currently_in_conflict { "⮾" } else { "" }, buf.format.stype,
+                            buf.format.line_ending.fileformat()
                     ), &self.fnt);
 
                     self.txr.cursor_style = if active { self.mode.cursor_style() } else { CursorStyle::Box };
-                    let mut vp = viewport_start;
-                    if scroll_lock { self.txr.ensure_line_visible(&mut vp, curln, editor_bounds); }
+                    self.txr.dimmed = !active && config.dim_inactive_panes.is_some();
+                    self.txr.tabstop = buf.tabstop;
+                    // the viewport is tracked in lines but scrolls by rows on screen, which differ when lines are folded
+                    let mut vp = buf.visible_line(viewport_start);
+                    if scroll_lock {
+                        self.txr.ensure_line_visible(&mut vp, buf.visible_line(curln), editor_bounds);
+                        self.txr.ensure_column_visible(&mut horizontal_offset, buf.column_for_index(buf.cursor_index), editor_bounds, true);
+                    }
+                    let viewport_end = buf.line_for_visible(self.txr.viewport_end(vp, &editor_bounds));
+                    let vp = buf.line_for_visible(vp);
+                    if active { buf.viewport = vp..viewport_end; }
                     if buf.highlights.is_none() || buf.last_highlighted_action_id < buf.text.most_recent_action_id() 
                         || self.mode.mode_tag() == ModeTag::Insert
                     {
                         //let hstart = std::time::Instant::now();
-                        buf.highlights = Some(self.highlighter.compute_highlighting(buf));
+                        let (mut hi, changed) = self.highlighter.compute_highlighting(buf);
+                        let matches_changed = buf.update_search_matches();
+                        if !buf.search_matches.is_empty() {
+                            hi.extend(buf.search_matches.iter().map(|m|
+                                piece_table_render::Highlight::new(m.clone(), config::ColorschemeSel::Accent(2),
+                                    piece_table_render::HighlightKind::Search)));
+                            hi = piece_table_render::Highlight::resolve_overlaps(hi);
+                        }
+                        buf.highlights = Some(hi);
                         buf.last_highlighted_action_id = buf.text.most_recent_action_id();
                         self.txr.invalidate_layout_cashe(buf.current_start_of_line(buf.cursor_index) .. buf.next_line_index(buf.cursor_index));
+                        // lines after an edit may have moved, so anything cached for where they used to start is stale
+                        if !changed.is_empty() {
+                            self.txr.invalidate_layout_cashe_from(changed.start);
+                        }
+                        // search matches can be anywhere, even if the text hasn't changed
+                        if matches_changed {
+                            self.txr.invalidate_layout_cashe_from(0);
+                        }
                         // println!("highlight took {}ms", (std::time::Instant::now()-hstart).as_nanos() as f32 / 1000000.0);
                     }
-                    self.txr.paint(rx, &buf.text, vp, buf.cursor_index,
-                        &config, editor_bounds, buf.highlights.as_ref(), true, self.mode.selection());
+                    debug_assert!(buf.text.is_char_boundary(buf.cursor_index), "cursor at {} is out of place", buf.cursor_index);
+                    self.txr.paint(rx, &buf.text, vp, horizontal_offset, buf.cursor_index,
+                        config, colors, editor_bounds, buf.highlights.as_ref(), true,
+                        self.mode.selection().map(|sel| (sel, self.mode.mode_tag() == ModeTag::VisualLine)), &buf.closed_folds());
 
                      /*let mut y = 30.0;
                      let mut global_index = 0;
@@ -279,11 +381,28 @@ struct PkApp {
                     state.panes.get_mut(&i).unwrap().content = PaneContent::Buffer {
                         buffer_index,
                         viewport_start: vp, scroll_lock,
-                        viewport_end: self.txr.viewport_end(vp, &editor_bounds)
+                        viewport_end, horizontal_offset
                     };
                 },
+                PaneContent::QuickFix => {
+                    rx.set_color(config.colors.quarter_gray);
+                    rx.fill_rect(Rect::xywh(bounds.x, bounds.y, bounds.w, self.txr.em_bounds.h+2.0));
+                    rx.set_color(if active { config.colors.accent(1) } else { config.colors.three_quarter_gray });
+                    rx.draw_text(Rect::xywh(bounds.x + 8.0, bounds.y + 1.0, bounds.w, 1000.0),
+                        &format!("quickfix | {}/{}", (state.quickfix_index + 1).min(state.quickfix.len()), state.quickfix.len()), &self.fnt);
+                    // keep the current entry in view by scrolling just far enough to show it at the bottom
+                    let rows = ((bounds.h / self.txr.em_bounds.h) as usize).saturating_sub(2).max(1);
+                    let first = (state.quickfix_index + 1).saturating_sub(rows);
+                    let mut y = bounds.y + self.txr.em_bounds.h + 4.0;
+                    for (i, e) in state.quickfix.iter().enumerate().skip(first).take(rows) {
+                        rx.set_color(if i == state.quickfix_index { colors.accent(5) } else { colors.foreground });
+                        rx.draw_text(Rect::xywh(bounds.x + 8.0, y, bounds.w - 8.0, self.txr.em_bounds.h),
+                            &format!("{}:{}:{}:{}: {}", e.server_name, e.path.to_str().unwrap_or("!"), e.line + 1, e.column + 1, e.text), &self.fnt);
+                        y += self.txr.em_bounds.h;
+                    }
+                },
                 PaneContent::Empty => {
-                    rx.set_color(config.colors.accent[5]);
+                    rx.set_color(config.colors.accent(5));
                     rx.draw_text(bounds.offset(Point::xy(self.txr.em_bounds.w, self.txr.em_bounds.h*3.0)), 
                         "enter a command to begin", &self.fnt);
                 }
@@ -295,9 +414,9 @@ struct PkApp {
             rx.set_color(config.colors.quarter_gray);
             rx.fill_rect(Rect::xywh(0.0, self.txr.em_bounds.h+2.0, rx.bounds().w, self.txr.em_bounds.h+2.0));
             rx.set_color(config.colors.three_quarter_gray);
-            self.cmd_txr.paint(rx, pending_cmd, 0, cmd_cur_index, &config,
+            self.cmd_txr.paint(rx, pending_cmd, 0, 0, cmd_cur_index, &config, &config.colors,
                                Rect::xywh(8.0, self.txr.em_bounds.h+2.0, rx.bounds().w-8.0, rx.bounds().h-20.0),
-                               None, false, None);
+                               None, false, None, &[]);
         }
 
         let end = std::time::Instant::now();
@@ -310,3 +429,29 @@ struct PkApp {
 fn main() {
     runic::start::<PkApp>(WindowOptions::new().with_title("pk"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_messages() {
+        let e = Error::from(nng::Error::ConnectionRefused);
+        assert_eq!(e.to_string(), format!("error in transport: {}", nng::Error::ConnectionRefused));
+        assert_eq!(e.source().unwrap().to_string(), nng::Error::ConnectionRefused.to_string());
+
+        let cbor_err = serde_cbor::from_slice::<String>(&[]).unwrap_err();
+        let cbor_msg = cbor_err.to_string();
+        let e = Error::from(cbor_err);
+        assert_eq!(e.to_string(), format!("error encoding/decoding message: {}", cbor_msg));
+        assert_eq!(e.source().unwrap().to_string(), cbor_msg);
+
+        let e = Error::NotConnected("remote".into());
+        assert_eq!(e.to_string(), "not connected to a server named remote");
+        assert!(e.source().is_none());
+
+        let e = Error::FileNotFound(std::path::PathBuf::from("some/file.txt"));
+        assert_eq!(e.to_string(), format!("file not found: {}", std::path::Path::new("some/file.txt").display()));
+        assert!(e.source().is_none());
+    }
+}
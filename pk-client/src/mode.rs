@@ -3,6 +3,7 @@ use std::fmt;
 use runic::*;
 use super::*;
 use std::ops::Range;
+use super::leader::{LeaderAction, LeaderMatch};
 
 pub enum CursorStyle {
     Line, Block, Box, Underline
@@ -19,18 +20,131 @@ pub trait Mode : fmt::Display {
 }
 
 pub struct NormalMode {
-    pending_buf: String, ctrl_pressed: bool
+    pending_buf: String, ctrl_pressed: bool, alt_pressed: bool,
+    pending_leader: Option<(LeaderMatch, std::time::Instant)>,
+    /// when the last key of an incomplete command in `pending_buf` was typed
+    pending_since: std::time::Instant
 }
 
 impl NormalMode {
     pub fn new() -> NormalMode {
-        NormalMode { pending_buf: String::new(), ctrl_pressed: false }
+        NormalMode { pending_buf: String::new(), ctrl_pressed: false, alt_pressed: false, pending_leader: None,
+            pending_since: std::time::Instant::now() }
+    }
+
+    fn cancel_pending(&mut self) {
+        self.pending_buf.clear();
+        self.pending_leader = None;
+    }
+
+    /// drop an incomplete command if the user has waited longer than `timeoutlen` to finish it
+    fn expire_pending(&mut self, client: &PClientState) {
+        if self.pending_leader.is_none() && !self.pending_buf.is_empty()
+            && self.pending_since.elapsed() >= client.read().unwrap().config.timeoutlen
+        {
+            self.pending_buf.clear();
+        }
+    }
+
+    fn run_command(&mut self, cmd: command::Command, client: PClientState, state: PEditorState) -> ModeEventResult {
+        let insert_count = match cmd {
+            command::Command::ChangeMode { count, .. } => count,
+            _ => 1
+        };
+        let res = {
+            match cmd.execute(&mut state.write().unwrap(), client) {
+                Ok(r) => r,
+                Err(e) => {
+                    self.pending_buf.clear();
+                    return Err(e);
+                }
+            } 
+        };
+        self.pending_buf.clear();
+        match res {
+            None | Some(ModeTag::Normal) => Ok(None),
+            Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::new()))),
+            Some(ModeTag::Search(dir)) => Ok(Some(Box::new(CommandMode::search(dir)))),
+            Some(ModeTag::Visual) | Some(ModeTag::VisualLine) => {
+                let mut state = state.write().unwrap();
+                let vm = match (cmd, state.last_visual_selection.as_ref()) {
+                    (command::Command::ReselectVisual, Some(sel)) => VisualMode::restore(sel, state.current_buffer().unwrap()),
+                    _ if res == Some(ModeTag::VisualLine) => VisualMode::linewise(state.current_buffer().unwrap()),
+                    _ => VisualMode::new(state.current_buffer().unwrap().cursor_index)
+                };
+                vm.save(&mut state);
+                Ok(Some(Box::new(vm)))
+            },
+            Some(ModeTag::Insert) => {
+                let mut state = state.write().unwrap();
+                if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
+                    let buf = &mut state.buffers[buffer_index];
+                    Ok(Some(Box::new(InsertMode::new(buf, insert_count)))) 
+                } else {
+                    Err(Error::InvalidCommand("".into()))
+                }
+            },
+            _ => panic!("unknown mode: {:?}", res)
+        }
+    }
+
+    /// splits a pending leader sequence like `3 ws` into its count and the keys after the leader
+    fn leader_keys(&self) -> Option<(usize, &str)> {
+        let keys = self.pending_buf.trim_start_matches(|c: char| c.is_digit(10));
+        if !keys.starts_with(' ') { return None; }
+        let count = self.pending_buf[0..self.pending_buf.len()-keys.len()].parse().unwrap_or(1);
+        Some((count, &keys[1..]))
+    }
+
+    /// if the user has waited too long to finish an ambiguous leader sequence, run whatever the
+    /// sequence typed so far is bound to and clear it
+    fn expire_leader(&mut self, client: PClientState, state: PEditorState) -> ModeEventResult {
+        let timeout = client.read().unwrap().config.leader_timeout;
+        match self.pending_leader.take() {
+            Some((m, t)) if t.elapsed() >= timeout => {
+                let count = self.leader_keys().map_or(1, |(count, _)| count);
+                self.pending_buf.clear();
+                match m {
+                    LeaderMatch::Prefix { action: Some(action), .. } =>
+                        self.run_command(command::Command::Leader { action, count }, client, state),
+                    _ => Ok(None)
+                }
+            },
+            p => {
+                self.pending_leader = p;
+                Ok(None)
+            }
+        }
+    }
+
+    fn process_leader(&mut self, count: usize, keys: &str, client: PClientState, state: PEditorState) -> ModeEventResult {
+        let m = client.read().unwrap().config.leader_keys.lookup(keys);
+        match m {
+            LeaderMatch::Exact(action) => {
+                self.pending_leader = None;
+                self.run_command(command::Command::Leader { action, count }, client, state)
+            },
+            LeaderMatch::Prefix { .. } => {
+                self.pending_leader = Some((m, std::time::Instant::now()));
+                Ok(None)
+            },
+            LeaderMatch::NoMatch => {
+                self.pending_leader = None;
+                let err = Error::UnknownCommand(format!("unknown leader command {}", keys));
+                self.pending_buf.clear();
+                Err(err)
+            }
+        }
     }
 }
 
 impl fmt::Display for NormalMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "normal [{}]", self.pending_buf)
+        write!(f, "normal [{}]", self.pending_buf)?;
+        if let Some((m, _)) = self.pending_leader.as_ref() {
+            write!(f, "{}", m)?;
+        }
+        Ok(())
     }
 }
 
@@ -39,17 +153,27 @@ impl Mode for NormalMode {
         ModeTag::Normal
     }
 
+    fn cursor_style(&self) -> CursorStyle {
+        // show that the next keys are still part of a command
+        if self.pending_buf.is_empty() { CursorStyle::Block } else { CursorStyle::Underline }
+    }
+
     fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult {
+        if let Some(new_mode) = self.expire_leader(client.clone(), state.clone())? {
+            return Ok(Some(new_mode));
+        }
+        self.expire_pending(&client);
         match e {
             Event::ModifiersChanged(ms) => {
                 self.ctrl_pressed = ms.ctrl();
+                self.alt_pressed = ms.alt();
                 Ok(None)
             }
             
             Event::KeyboardInput { input: KeyboardInput { virtual_keycode: Some(vk), state: ElementState::Pressed, .. }, .. } => {
                 match vk {
                     VirtualKeyCode::Escape => {
-                        self.pending_buf.clear();
+                        self.cancel_pending();
                         Ok(None)
                     },
                     VirtualKeyCode::Left => {
@@ -74,43 +198,34 @@ impl Mode for NormalMode {
                     VirtualKeyCode::E if self.ctrl_pressed => {
                         Ok(Some(Box::new(UserMessageInteractionMode::new(client))))
                     }
+                    VirtualKeyCode::J | VirtualKeyCode::K if self.alt_pressed => {
+                        let dir = if vk == VirtualKeyCode::J { Direction::Forward } else { Direction::Backward };
+                        let mut state = state.write().unwrap();
+                        if let Some(buf) = state.current_buffer_mut() {
+                            buf.check_writable()?;
+                            let line = buf.line_for_index(buf.cursor_index);
+                            buf.move_lines(line..line+1, dir);
+                        }
+                        Ok(None)
+                    }
                     _ => Ok(None) 
                 }
             },
             
-            Event::ReceivedCharacter(c) if !c.is_control() => {
+            // the character typed along with Alt is a key binding, not a command
+            Event::ReceivedCharacter(c) if !c.is_control() && !self.alt_pressed => {
                 use super::command::*;
                 self.pending_buf.push(c);
+                if let Some((count, keys)) = self.leader_keys() {
+                    let keys = keys.to_owned();
+                    return self.process_leader(count, &keys, client, state);
+                }
                 match Command::parse(&self.pending_buf) {
-                    Ok(cmd) => {
-                        let res = {
-                            match cmd.execute(&mut state.write().unwrap(), client) {
-                                Ok(r) => r,
-                                Err(e) => {
-                                    self.pending_buf.clear();
-                                    return Err(e);
-                                }
-                            } 
-                        };
-                        self.pending_buf.clear();
-                        match res {
-                            None | Some(ModeTag::Normal) => Ok(None),
-                            Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::new()))),
-                            Some(ModeTag::Search(dir)) => Ok(Some(Box::new(CommandMode::search(dir)))),
-                            Some(ModeTag::Visual) => Ok(Some(Box::new(VisualMode::new(state.read().unwrap().current_buffer().unwrap().cursor_index)))),
-                            Some(ModeTag::Insert) => {
-                                let mut state = state.write().unwrap();
-                                if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
-                                    let buf = &mut state.buffers[buffer_index];
-                                    Ok(Some(Box::new(InsertMode::new(buf.text.insert_mutator(buf.cursor_index))))) 
-                                } else {
-                                    Err(Error::InvalidCommand("".into()))
-                                }
-                            },
-                            _ => panic!("unknown mode: {:?}", res)
-                        }
+                    Ok(cmd) => self.run_command(cmd, client, state),
+                    Err(Error::IncompleteCommand) => {
+                        self.pending_since = std::time::Instant::now();
+                        Ok(None)
                     },
-                    Err(Error::IncompleteCommand) => Ok(None),
                     Err(e) => { 
                         self.pending_buf.clear();
                         Err(e)
@@ -128,15 +243,27 @@ impl Mode for NormalMode {
 
 pub struct InsertMode {
     tmut: Option<piece_table::TableMutator>,
+    start_index: usize,
+    /// how much of the text after the cursor was inserted along with it, by an abbreviation that
+    /// put the cursor inside of its expansion
+    inserted_after: usize,
+    /// how many mutators were finished earlier in this insertion, whose actions are merged with
+    /// the last one's so that it is undone in one step
+    finished_mutators: usize,
+    count: usize,
     ctrl_pressed: bool,
     shift_pressed: bool,
     clipboard: Option<copypasta::ClipboardContext>
 }
 
 impl InsertMode {
-    fn new(tmut: piece_table::TableMutator) -> InsertMode {
+    fn new(buf: &mut buffer::Buffer, count: usize) -> InsertMode {
         InsertMode {
-            tmut: Some(tmut),
+            tmut: Some(buf.text.insert_mutator(buf.cursor_index)),
+            start_index: buf.cursor_index,
+            inserted_after: 0,
+            finished_mutators: 0,
+            count,
             shift_pressed: false,
             ctrl_pressed: false,
             clipboard: copypasta::ClipboardContext::new()
@@ -146,6 +273,53 @@ impl InsertMode {
     }
 }
 
+/// where the cursor goes in the expansion of an abbreviation
+const ABBREVIATION_CURSOR: &str = "$0";
+
+impl InsertMode {
+    /// if the word before the cursor was typed during this insertion and is an abbreviation, replace
+    /// it with its expansion. Lines in the expansion after the first get the indentation of the
+    /// current line. Returns true if the expansion had a cursor marker and the cursor was moved to it
+    fn expand_abbreviation(&mut self, buf: &mut buffer::Buffer, abbreviations: &std::collections::HashMap<String, String>) -> bool {
+        let line_start = buf.current_start_of_line(buf.cursor_index);
+        let line = buf.text.copy_range(line_start, buf.cursor_index);
+        let word_start = line.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_').len();
+        let word = &line[word_start..];
+        if word.is_empty() || line_start + word_start < self.start_index {
+            return false;
+        }
+        let expansion = match abbreviations.get(word) {
+            Some(e) => e,
+            None => return false
+        };
+        let indent: String = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+        let expansion = expansion.replace('\n', &format!("\n{}", indent));
+        let tmut = self.tmut.as_mut().unwrap();
+        for _ in word.chars() {
+            tmut.pop_char(&mut buf.text);
+        }
+        buf.cursor_index -= word.len();
+        match expansion.find(ABBREVIATION_CURSOR) {
+            Some(at) => {
+                let expansion = expansion.replacen(ABBREVIATION_CURSOR, "", 1);
+                tmut.push_str(&mut buf.text, &expansion);
+                // the mutator can only add text at its end, so start a new one at the cursor
+                self.tmut.take().unwrap().finish(&mut buf.text);
+                self.finished_mutators += 1;
+                buf.cursor_index += at;
+                self.inserted_after += expansion.len() - at;
+                self.tmut = Some(buf.text.insert_mutator(buf.cursor_index));
+                true
+            },
+            None => {
+                tmut.push_str(&mut buf.text, &expansion);
+                buf.cursor_index += expansion.len();
+                false
+            }
+        }
+    }
+}
+
 impl fmt::Display for InsertMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "insert")
@@ -166,8 +340,13 @@ impl Mode for InsertMode {
             let buf = &mut state.buffers[buffer_index];
             match e {
                 Event::ReceivedCharacter(c) if !c.is_control() => {
+                    // the character that ends an abbreviation is dropped if the expansion placed the cursor itself
+                    if !c.is_alphanumeric() && c != '_'
+                        && self.expand_abbreviation(buf, &client.read().unwrap().config.abbreviations) {
+                        return Ok(None);
+                    }
                     self.tmut.as_mut().unwrap().push_char(&mut buf.text, c);
-                    buf.cursor_index += 1;
+                    buf.cursor_index += c.len_utf8();
                     Ok(None)
                 },
                 Event::ModifiersChanged(ms) => {
@@ -181,7 +360,10 @@ impl Mode for InsertMode {
                     match vk {
                         VirtualKeyCode::V if self.ctrl_pressed => {
                             match self.clipboard.as_mut().map(|cb| cb.get_contents()) {
-                                Some(Ok(snip)) => self.tmut.as_mut().unwrap().push_str(&mut buf.text, &snip),
+                                Some(Ok(snip)) => {
+                                    self.tmut.as_mut().unwrap().push_str(&mut buf.text, &snip);
+                                    buf.cursor_index += snip.len();
+                                },
                                 Some(Err(e)) => ClientState::process_usr_msgp(client,
                                     UserMessage::error(format!("error getting clipboard contents: {}", e), None)),
                                 None => ClientState::process_usr_msgp(client,
@@ -191,7 +373,8 @@ impl Mode for InsertMode {
                         },
                         VirtualKeyCode::Tab => {
                             let (softtab, tabstop) = {
-                                let cfg = &client.read().unwrap().config;
+                                let cs = client.read().unwrap();
+                                let cfg = buf.options.resolve(&cs.config);
                                 (cfg.softtab, cfg.tabstop)
                             };
 
@@ -207,19 +390,28 @@ impl Mode for InsertMode {
                             Ok(None)
                         },
                         VirtualKeyCode::Back => {
+                            let prev = buf.prev_char_index(buf.cursor_index);
                             if !self.tmut.as_mut().unwrap().pop_char(&mut buf.text) {
-                                buf.cursor_index -= 1;
+                                buf.cursor_index = prev;
                             }
                             Ok(None)
                         },
                         VirtualKeyCode::Return => {
+                            if self.expand_abbreviation(buf, &client.read().unwrap().config.abbreviations) {
+                                return Ok(None);
+                            }
                             self.tmut.as_mut().unwrap().push_char(&mut buf.text, '\n');
-                            let cfg = &client.read().unwrap().config;
-                            buf.cursor_index += 1 + buf.indent_with_mutator(self.tmut.as_mut().unwrap(), buf.sense_indent_level(buf.cursor_index, cfg), cfg);
+                            let cs = client.read().unwrap();
+                            let cfg = buf.options.resolve(&cs.config);
+                            buf.cursor_index += 1 + buf.indent_with_mutator(self.tmut.as_mut().unwrap(), buf.sense_indent_level(buf.cursor_index, &cfg), &cfg);
                             Ok(None)
                         }
                         VirtualKeyCode::Escape => {
-                            self.tmut.take().unwrap().finish(&mut buf.text);
+                            let mut tmut = self.tmut.take().unwrap();
+                            let inserted = buf.repeat_insertion(&mut tmut, self.start_index, self.inserted_after, self.count);
+                            tmut.finish(&mut buf.text);
+                            buf.text.merge_last_actions(self.finished_mutators + 1);
+                            state.registers.insert('.', inserted);
                             Ok(Some(Box::new(NormalMode::new())))
                         },
                         _ => Ok(None)
@@ -233,23 +425,119 @@ impl Mode for InsertMode {
 
 pub struct VisualMode {
     pending_buf: String,
+    alt_pressed: bool,
     selection: Range<usize>,
-    side: bool
+    side: bool,
+    linewise: bool,
+    /// `selection` extended to cover whole lines, which is what gets used when `linewise` is set
+    line_selection: Range<usize>
 }
 
 impl VisualMode {
     fn new(start_index: usize) -> VisualMode {
         VisualMode {
             pending_buf: String::new(),
+            alt_pressed: false,
             selection: start_index .. start_index,
-            side: true
+            side: true,
+            linewise: false,
+            line_selection: start_index .. start_index
+        }
+    }
+
+    fn linewise(buf: &buffer::Buffer) -> VisualMode {
+        let mut vm = VisualMode::new(buf.cursor_index);
+        vm.set_linewise(true, buf);
+        vm
+    }
+
+    /// select `sel` again, clamped to the text since it may have changed since `sel` was saved
+    fn restore(sel: &editor_state::VisualSelection, buf: &buffer::Buffer) -> VisualMode {
+        let range = buf.clamp_index(sel.range.start)..buf.clamp_index(sel.range.end);
+        let mut vm = VisualMode::new(range.start);
+        vm.selection = range;
+        vm.side = sel.side;
+        vm.set_linewise(sel.linewise, buf);
+        vm
+    }
+
+    /// remember the selection in the editor state so that `gv` can select it again
+    fn save(&self, state: &mut editor_state::EditorState) {
+        if let Some(buffer_index) = state.current_buffer_index() {
+            state.last_visual_selection = Some(editor_state::VisualSelection {
+                buffer_index, range: self.selection.clone(), linewise: self.linewise, side: self.side
+            });
+        }
+    }
+
+    fn set_linewise(&mut self, linewise: bool, buf: &buffer::Buffer) {
+        self.linewise = linewise;
+        self.snap_to_lines(buf);
+    }
+
+    /// update `line_selection` from the start of the first selected line through the newline at
+    /// the end of the last one
+    fn snap_to_lines(&mut self, buf: &buffer::Buffer) {
+        let lo = self.selection.start.min(self.selection.end);
+        let hi = self.selection.start.max(self.selection.end);
+        self.line_selection = buf.current_start_of_line(lo) .. buf.next_line_index(hi).saturating_sub(1).max(lo);
+    }
+
+    fn current_selection(&self) -> &Range<usize> {
+        if self.linewise { &self.line_selection } else { &self.selection }
+    }
+
+    /// run the leader action bound to the keys typed after the leader. Duplicating copies the
+    /// selection, and anything else runs as it would in Normal mode
+    fn process_leader(&mut self, client: PClientState, state: PEditorState) -> ModeEventResult {
+        let m = client.read().unwrap().config.leader_keys.lookup(&self.pending_buf[1..]);
+        let action = match m {
+            LeaderMatch::Exact(action) => action,
+            LeaderMatch::Prefix { .. } => return Ok(None),
+            LeaderMatch::NoMatch => {
+                let err = Error::UnknownCommand(format!("unknown leader command {}", &self.pending_buf[1..]));
+                self.pending_buf.clear();
+                return Err(err);
+            }
+        };
+        self.pending_buf.clear();
+        if let LeaderAction::Duplicate(dir) = action {
+            let mut state = state.write().unwrap();
+            self.save(&mut state);
+            if let Some(buf) = state.current_buffer_mut() {
+                buf.check_writable()?;
+                let sel = self.current_selection();
+                let (lo, hi) = (sel.start.min(sel.end), sel.start.max(sel.end));
+                let range = if self.linewise {
+                    buf.current_start_of_line(lo) .. buf.next_line_index(hi)
+                } else {
+                    lo .. (hi+1).min(buf.text.len())
+                };
+                buf.duplicate(range, dir, self.linewise);
+            }
+        } else {
+            command::Command::Leader { action, count: 1 }.execute(&mut state.write().unwrap(), client)?;
+        }
+        Ok(Some(Box::new(NormalMode::new())))
+    }
+
+    /// move the selected lines past the line before or after them, keeping them selected
+    fn move_selected_lines(&mut self, dir: Direction, buf: &mut buffer::Buffer) {
+        let lo = self.selection.start.min(self.selection.end);
+        let hi = self.selection.start.max(self.selection.end);
+        let first = buf.line_for_index(lo);
+        let old_start = buf.index_for_line(first);
+        if let Some(new_start) = buf.move_lines(first..buf.line_for_index(hi)+1, dir) {
+            let shift = |i: usize| i + new_start - old_start;
+            self.selection = shift(self.selection.start)..shift(self.selection.end);
+            self.snap_to_lines(buf);
         }
-   }
+    }
 }
 
 impl fmt::Display for VisualMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "visual [{}]", self.pending_buf)
+        write!(f, "visual{} [{}]", if self.linewise { " line" } else { "" }, self.pending_buf)
     }
 }
     
@@ -257,11 +545,11 @@ impl Mode for VisualMode {
     fn cursor_style(&self) -> CursorStyle { CursorStyle::Block }
     
     fn mode_tag(&self) -> ModeTag {
-        ModeTag::Visual
+        if self.linewise { ModeTag::VisualLine } else { ModeTag::Visual }
     }
     
     fn selection(&self) -> Option<&Range<usize>> {
-        Some(&self.selection)
+        Some(self.current_selection())
     }
     
     fn event(&mut self, e: Event, client: PClientState, state: PEditorState) -> ModeEventResult {
@@ -272,39 +560,93 @@ impl Mode for VisualMode {
                         self.pending_buf.clear();
                         Ok(Some(Box::new(NormalMode::new())))
                     },
+                    VirtualKeyCode::J | VirtualKeyCode::K if self.alt_pressed => {
+                        let dir = if vk == VirtualKeyCode::J { Direction::Forward } else { Direction::Backward };
+                        let mut state = state.write().unwrap();
+                        if let Some(buf) = state.current_buffer_mut() {
+                            buf.check_writable()?;
+                            self.move_selected_lines(dir, buf);
+                        }
+                        self.save(&mut state);
+                        Ok(None)
+                    },
                     _ => Ok(None) 
                 }
             },
+
+            Event::ModifiersChanged(ms) => {
+                self.alt_pressed = ms.alt();
+                Ok(None)
+            },
             
-            Event::ReceivedCharacter(c) if !c.is_control() => {
+            Event::ReceivedCharacter(c) if !c.is_control() && !self.alt_pressed => {
                 use super::command::*;
                 self.pending_buf.push(c);
-                match Command::parse_2(&self.pending_buf, Some(crate::motion::Motion::passthrough(&self.selection))) {
+                if self.pending_buf.starts_with(' ') {
+                    return self.process_leader(client, state);
+                }
+                match Command::parse_2(&self.pending_buf, Some(crate::motion::Motion::passthrough(self.current_selection()))) {
                     Ok(Command::VisualSwitchSides) => {
                         self.side = !self.side;
                         self.pending_buf.clear();
-                        if let Some(buf) = state.write().unwrap().current_buffer_mut() {
-                            buf.cursor_index = if self.side {
+                        let mut state = state.write().unwrap();
+                        if let Some(buf) = state.current_buffer_mut() {
+                            buf.set_cursor(if self.side {
                                 self.selection.end
                             } else {
                                 self.selection.start
-                            };
+                            });
                         }
+                        self.save(&mut state);
                         Ok(None)
                     },
                     Ok(Command::Move(mo)) => {
-                        if let Some(buf) = state.write().unwrap().current_buffer_mut() {
+                        let mut state = state.write().unwrap();
+                        if let Some(buf) = state.current_buffer_mut() {
                             let Range { start: _, end } = mo.range(buf, buf.cursor_index, 1);
                             if self.side {
                                 self.selection.end = end;
                             } else {
                                 self.selection.start = end;
                             }
-                            buf.cursor_index = end;
+                            buf.set_cursor(end);
+                            self.snap_to_lines(buf);
                         }
+                        self.save(&mut state);
                         self.pending_buf.clear();
                         Ok(None)
                     },
+                    Ok(Command::Edit { op: op @ (Operator::Yank | Operator::Delete), target_register, .. }) => {
+                        self.pending_buf.clear();
+                        let mut state = state.write().unwrap();
+                        self.save(&mut state);
+                        let state = &mut *state;
+                        if let Some(buffer_index) = state.current_buffer_index() {
+                            let buf = &mut state.buffers[buffer_index];
+                            if op == Operator::Delete {
+                                buf.check_writable()?;
+                            }
+                            let (range, text) = buf.visual_selection_text(&self.selection, self.linewise);
+                            if op == Operator::Yank {
+                                command::yank(&mut state.registers, target_register, text, client);
+                                buf.set_cursor(range.start);
+                            } else if self.linewise {
+                                // whole lines go like `dd`, which also takes the newline before the last line
+                                let first = buf.line_for_index(range.start);
+                                let last = buf.line_for_index(self.selection.start.max(self.selection.end));
+                                if let Some(text) = buf.delete_line(first, last + 1 - first) {
+                                    command::record_delete(&mut state.registers, target_register, text);
+                                }
+                            } else {
+                                command::record_delete(&mut state.registers, target_register, text);
+                                if range.start < range.end {
+                                    buf.text.delete_range(range.start, range.end);
+                                }
+                                buf.set_cursor(range.start);
+                            }
+                        }
+                        Ok(Some(Box::new(NormalMode::new())))
+                    },
                     Ok(cmd) => {
                         let res = {
                             match cmd.execute(&mut state.write().unwrap(), client) {
@@ -317,14 +659,24 @@ impl Mode for VisualMode {
                         };
                         self.pending_buf.clear();
                         match res {
-                            Some(ModeTag::Visual) => Ok(None),
-                            Some(ModeTag::Command) => Ok(Some(Box::new(CommandMode::new()))),
+                            Some(ModeTag::Visual) | Some(ModeTag::VisualLine) => {
+                                let mut state = state.write().unwrap();
+                                if let Some(buf) = state.current_buffer() {
+                                    self.set_linewise(res == Some(ModeTag::VisualLine), buf);
+                                }
+                                self.save(&mut state);
+                                Ok(None)
+                            },
+                            Some(ModeTag::Command) => {
+                                self.save(&mut state.write().unwrap());
+                                Ok(Some(Box::new(CommandMode::visual_range())))
+                            },
                             None | Some(ModeTag::Normal) => Ok(Some(Box::new(NormalMode::new()))),
                             Some(ModeTag::Insert) => {
                                 let mut state = state.write().unwrap();
                                 if let PaneContent::Buffer { buffer_index, .. } = state.current_pane().content {
                                     let buf = &mut state.buffers[buffer_index];
-                                    Ok(Some(Box::new(InsertMode::new(buf.text.insert_mutator(buf.cursor_index))))) 
+                                    Ok(Some(Box::new(InsertMode::new(buf, 1)))) 
                                 } else {
                                     Err(Error::InvalidCommand("".into()))
                                 }
@@ -370,12 +722,31 @@ impl CommandMode {
             history_index: 0,
             commands: vec![
                 (Regex::new("^test (.*)").unwrap(), Rc::new(TestCommand)),
-                (Regex::new("^q").unwrap(), Rc::new(QuitCommand)),
+                (Regex::new(r#"^q(?:uit)?(?P<force>!)?\s*$"#).unwrap(), Rc::new(QuitCommand)),
                 (Regex::new("^dbg pt").unwrap(), Rc::new(DebugPieceTableCommand)),
                 (Regex::new("^dbg rg").unwrap(), Rc::new(DebugRegistersCommand)),
+                (Regex::new(r#"^reg(?:isters)?$"#).unwrap(), Rc::new(RegistersCommand)),
+                (Regex::new(r#"^let\s+@(?P<register>.)\s*=\s*"(?P<text>(?:[^"\\]|\\.)*)"\s*$"#).unwrap(), Rc::new(SetRegisterCommand)),
                 (Regex::new(r#"^e\s+(?:(?P<server_name>\w+):)?(?P<path>.*)"#).unwrap(), Rc::new(EditFileCommand)),
-                (Regex::new(r#"^b(?P<subcmd>\w+)?\s+(?P<name_query>.*)"#).unwrap(), Rc::new(BufferCommand)),
+                (Regex::new(r#"^(?:e|(?P<split>))new\s*$"#).unwrap(), Rc::new(NewBufferCommand)),
+                (Regex::new(r#"^saveas\s+(?:(?P<server_name>\w+):)?(?P<path>.+)"#).unwrap(), Rc::new(SaveAsCommand)),
+                (Regex::new(r#"^b(?P<subcmd>\w+)?(?P<force>!)?\s+(?P<name_query>.*)"#).unwrap(), Rc::new(BufferCommand)),
+                (Regex::new(r#"^(?P<range>%|'<,'>|\d+,\d+)?sort(?P<reverse>!)?(?:\s+(?P<flags>[nu]+))?\s*$"#).unwrap(), Rc::new(SortCommand)),
+                (Regex::new(r#"^(?P<range>%|'<,'>|\d+,\d+)?retab(?P<all>!)?(?:\s+(?P<tabstop>\d+))?\s*$"#).unwrap(), Rc::new(RetabCommand)),
+                (Regex::new(r#"^(?P<range>%|'<,'>|\d+,\d+|\d+)?reindent\s*$"#).unwrap(), Rc::new(ReindentCommand)),
+                (Regex::new("^noh(?:lsearch)?$").unwrap(), Rc::new(NoHighlightCommand)),
+                (Regex::new(r#"^(?P<line>\d+|\$)\s*$"#).unwrap(), Rc::new(GotoLineCommand)),
+                (Regex::new(r#"^(?P<range>%|'<,'>|\d+,\d+|\d+)?d(?:elete)?(?:\s+(?P<register>[^\s\d]))?\s*$"#).unwrap(), Rc::new(DeleteLinesCommand)),
+                (Regex::new(r#"^set\s+(?:ff|fileformat)(?:=(?P<format>\w+))?\s*$"#).unwrap(), Rc::new(FileFormatCommand)),
+                (Regex::new(r#"^setl(?:ocal)?\s+(?P<options>.+)$"#).unwrap(), Rc::new(SetLocalCommand)),
+                (Regex::new(r#"^grep\s+(?P<pattern>.+)$"#).unwrap(), Rc::new(GrepCommand)),
+                (Regex::new(r#"^c(?P<cmd>c|n(?:ext)?|p(?:rev(?:ious)?)?|N(?:ext)?)(?:\s+(?P<count>\d+))?\s*$"#).unwrap(), Rc::new(QuickfixCommand)),
+                (Regex::new(r#"^copen\s*$"#).unwrap(), Rc::new(QuickfixOpenCommand)),
+                (Regex::new(r#"^on(?:ly)?\s*$"#).unwrap(), Rc::new(OnlyCommand)),
                 (Regex::new("^sync").unwrap(), Rc::new(SyncFileCommand)),
+                (Regex::new(r#"^wa(?:ll)?\s*$"#).unwrap(), Rc::new(WriteAllCommand)),
+                (Regex::new(r#"^diff(?:this)?\s*$"#).unwrap(), Rc::new(DiffCommand)),
+                (Regex::new(r#"^files(?:\s+(?P<server_name>\w+))?\s*$"#).unwrap(), Rc::new(FilesCommand)),
                 (Regex::new(r#"^con\s+(?P<server_name>\w+)\s(?P<server_url>.*)"#).unwrap(), Rc::new(ConnectToServerCommand)),
                 (Regex::new(r#"(\?|/)(.*)"#).unwrap(), Rc::new(SearchCommand))
             ],
@@ -386,6 +757,11 @@ impl CommandMode {
         CommandMode::with_table(PieceTable::default())
     }
     
+    /// command mode with the range for the last visual selection already typed
+    pub fn visual_range() -> CommandMode {
+        CommandMode::with_table(PieceTable::with_text("'<,'>"))
+    }
+
     pub fn search(dir: Direction) -> CommandMode {
         CommandMode::with_table(PieceTable::with_text(match dir {
             Direction::Forward => "/",
@@ -393,6 +769,18 @@ impl CommandMode {
         }))
     }
     
+    /// run the first command in the table that matches `cmdstr`
+    fn run_line(&self, cmdstr: &str, cs: PClientState, es: PEditorState) -> ModeEventResult {
+        if let Some((cmdix, args)) = self.commands.iter().enumerate()
+            .filter_map(|(i,cmd)| cmd.0.captures(cmdstr).map(|c| (i, c))).nth(0)
+        {
+            let cmd = self.commands[cmdix].1.clone();
+            cmd.process(cs, es, &args)
+        } else {
+            Err(Error::InvalidCommand(cmdstr.into()))
+        }
+    }
+
     fn load_history_cmd(&mut self, es: PEditorState) {
         let hist = &es.read().unwrap().line_command_history;
         let tx = if self.history_index == 0 {
@@ -460,14 +848,7 @@ impl Mode for CommandMode {
                     VirtualKeyCode::Return => {
                         let cmdstr = self.command_line.text();
                         { es.write().unwrap().line_command_history.push(cmdstr.clone()); }
-                        if let Some((cmdix, args)) = self.commands.iter().enumerate()
-                            .filter_map(|(i,cmd)| cmd.0.captures(&cmdstr).map(|c| (i, c))).nth(0)
-                        {
-                            let cmd = self.commands[cmdix].1.clone();
-                            cmd.process(cs, es, &args)
-                        } else {
-                            Err(Error::InvalidCommand(cmdstr))
-                        }
+                        self.run_line(&cmdstr, cs, es)
                     }
                     VirtualKeyCode::Escape => {
                         Ok(Some(Box::new(NormalMode::new())))
@@ -489,7 +870,7 @@ impl fmt::Display for CommandMode {
 pub struct UserMessageInteractionMode;
 
 impl UserMessageInteractionMode {
-    fn new(state: PClientState) -> UserMessageInteractionMode {
+    pub fn new(state: PClientState) -> UserMessageInteractionMode {
         let mut s = state.write().unwrap();
         s.selected_usrmsg = s.usrmsgs.len()-1;
         UserMessageInteractionMode
@@ -567,3 +948,581 @@ impl fmt::Display for UserMessageInteractionMode {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(text: &str, cursor_index: usize) -> (PClientState, PEditorState) {
+        let mut state = editor_state::EditorState::new();
+        let mut buf = buffer::Buffer::with_text(text);
+        buf.cursor_index = cursor_index;
+        state.buffers.push(buf);
+        state.panes.insert(0, Pane::whole_screen(PaneContent::buffer(0)));
+        (Arc::new(RwLock::new(ClientState::default())), Arc::new(RwLock::new(state)))
+    }
+
+    fn type_keys(mode: &mut impl Mode, keys: &str, client: &PClientState, state: &PEditorState) -> ModeEventResult {
+        let mut res = Ok(None);
+        for c in keys.chars() {
+            res = mode.event(Event::ReceivedCharacter(c), client.clone(), state.clone());
+        }
+        res
+    }
+
+    fn press(mode: &mut dyn Mode, key: VirtualKeyCode, client: &PClientState, state: &PEditorState) -> ModeEventResult {
+        #[allow(deprecated)]
+        let input = KeyboardInput { scancode: 0, state: ElementState::Pressed, virtual_keycode: Some(key), modifiers: ModifiersState::empty() };
+        mode.event(Event::KeyboardInput { device_id: unsafe { DeviceId::dummy() }, input, is_synthetic: false }, client.clone(), state.clone())
+    }
+
+    #[test]
+    fn backspace_past_start_of_insert() -> Result<(), Error> {
+        let (client, state) = test_state("ab", 2);
+        let mut mode = InsertMode::new(state.write().unwrap().current_buffer_mut().unwrap(), 1);
+        type_keys(&mut mode, "xé", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 5);
+        for _ in 0..4 {
+            press(&mut mode, VirtualKeyCode::Back, &client, &state)?;
+        }
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "ab", "text from before the insert stays");
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 2);
+        type_keys(&mut mode, "z", &client, &state)?;
+        assert!(press(&mut mode, VirtualKeyCode::Escape, &client, &state)?.is_some());
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abz");
+        type_keys(&mut NormalMode::new(), "u", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "ab");
+        Ok(())
+    }
+
+    #[test]
+    fn linewise_selection_snaps_to_lines() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\nghi\njkl\n", 5);
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        assert_eq!(mode.selection(), Some(&(4..7)));
+        type_keys(&mut mode, "j", &client, &state)?;
+        assert_eq!(mode.selection(), Some(&(4..11)));
+        type_keys(&mut mode, "kk", &client, &state)?;
+        assert_eq!(mode.selection(), Some(&(0..7)));
+        // switching to characterwise keeps the underlying selection
+        type_keys(&mut mode, "v", &client, &state)?;
+        assert_eq!(mode.mode_tag(), ModeTag::Visual);
+        assert_eq!(mode.selection(), Some(&(5..1)));
+        type_keys(&mut mode, "V", &client, &state)?;
+        assert_eq!(mode.mode_tag(), ModeTag::VisualLine);
+        assert_eq!(mode.selection(), Some(&(0..7)));
+        Ok(())
+    }
+
+    #[test]
+    fn linewise_delete() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\nghi\njkl", 5);
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        assert!(type_keys(&mut mode, "jd", &client, &state)?.is_some());
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\njkl");
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("def\nghi\n"));
+        // the last line has no newline after it
+        state.write().unwrap().buffers[0].cursor_index = 5;
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        type_keys(&mut mode, "d", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc");
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("jkl\n"));
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\n", 0);
+        state.write().unwrap().buffers[0].read_only = true;
+        for keys in &["x", "dd", "p", "i", "J", "u"] {
+            assert!(type_keys(&mut NormalMode::new(), keys, &client, &state).is_err(), "{} edited", keys);
+        }
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        assert!(type_keys(&mut mode, "d", &client, &state).is_err());
+        // looking around and yanking still work
+        type_keys(&mut NormalMode::new(), "jyy", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 4);
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndef\n");
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_selection_up() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\nghi\njkl", 5);
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        assert!(type_keys(&mut mode, "j D", &client, &state)?.is_some());
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndef\nghi\ndef\nghi\njkl");
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 9, "cursor stays on the copy");
+        Ok(())
+    }
+
+    #[test]
+    fn linewise_yank() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\nghi\n", 1);
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        assert!(type_keys(&mut mode, "y", &client, &state)?.is_some());
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndef\nghi\n");
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("abc\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn visual_yank_includes_both_ends() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef", 5);
+        let mut mode = VisualMode::new(5);
+        type_keys(&mut mode, "hhhy", &client, &state)?;
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("c\nde"));
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 2, "the cursor goes to the start of the selection");
+        let mut normal = NormalMode::new();
+        type_keys(&mut normal, "p", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndec\ndef", "charwise text is put inside the line");
+        // the last line has no newline, but is still yanked as a whole line
+        state.write().unwrap().buffers[0].cursor_index = 9;
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        type_keys(&mut mode, "\"ay", &client, &state)?;
+        assert_eq!(state.read().unwrap().registers.get(&'a').map(String::as_str), Some("def\n"));
+        state.write().unwrap().buffers[0].cursor_index = 0;
+        type_keys(&mut normal, "\"ap", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndef\ndec\ndef");
+        // deleting a selection at the end of the buffer takes its last character too
+        state.write().unwrap().buffers[0].cursor_index = 14;
+        let mut mode = VisualMode::new(14);
+        type_keys(&mut mode, "hx", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\ndef\ndec\nd");
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("ef"));
+        Ok(())
+    }
+
+    #[test]
+    fn visual_change_replaces_selection() -> Result<(), Error> {
+        let (client, state) = test_state("abc def\n", 1);
+        let mut mode = VisualMode::new(1);
+        let insert = type_keys(&mut mode, "llc", &client, &state)?.expect("enter insert mode");
+        assert_eq!(insert.mode_tag(), ModeTag::Insert);
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "adef\n");
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some("bc "));
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reselect_visual() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\n", 0);
+        let mut normal = NormalMode::new();
+        let mut visual = normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone())?.expect("enter visual mode");
+        for c in "llo".chars() {
+            visual.event(Event::ReceivedCharacter(c), client.clone(), state.clone())?;
+        }
+        assert_eq!(visual.selection(), Some(&(0..2)));
+        // leave visual mode and move somewhere else
+        drop(visual);
+        let mut normal = NormalMode::new();
+        state.write().unwrap().buffers[0].cursor_index = 6;
+        assert!(normal.event(Event::ReceivedCharacter('g'), client.clone(), state.clone())?.is_none());
+        let mut visual = normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone())?.expect("reselect");
+        assert_eq!(visual.mode_tag(), ModeTag::Visual);
+        assert_eq!(visual.selection(), Some(&(0..2)));
+        // the cursor is back on the start, since the ends were swapped
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 0);
+        visual.event(Event::ReceivedCharacter('l'), client.clone(), state.clone())?;
+        assert_eq!(visual.selection(), Some(&(1..2)));
+        Ok(())
+    }
+
+    #[test]
+    fn reselect_linewise() -> Result<(), Error> {
+        let (client, state) = test_state("abc\ndef\nghi\n", 5);
+        let mut normal = NormalMode::new();
+        let mut visual = normal.event(Event::ReceivedCharacter('V'), client.clone(), state.clone())?.expect("enter visual mode");
+        visual.event(Event::ReceivedCharacter('j'), client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().last_visual_selection, Some(editor_state::VisualSelection {
+            buffer_index: 0, range: 5..9, linewise: true, side: true
+        }));
+        drop(visual);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('g'), client.clone(), state.clone())?;
+        let visual = normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone())?.expect("reselect");
+        assert_eq!(visual.mode_tag(), ModeTag::VisualLine);
+        assert_eq!(visual.selection(), Some(&(4..11)));
+        Ok(())
+    }
+
+    #[test]
+    fn reselect_after_text_shrinks() -> Result<(), Error> {
+        let (client, state) = test_state("aé\ndef\n", 0);
+        state.write().unwrap().last_visual_selection = Some(editor_state::VisualSelection {
+            buffer_index: 0, range: 2..7, linewise: false, side: true
+        });
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('g'), client.clone(), state.clone())?;
+        let visual = normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone())?.expect("reselect");
+        assert_eq!(visual.selection(), Some(&(1..7)));
+
+        state.write().unwrap().buffers[0].text.delete_range(3, 8);
+        state.write().unwrap().buffers[0].set_cursor(0);
+        drop(visual);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('g'), client.clone(), state.clone())?;
+        let visual = normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone())?.expect("reselect");
+        assert_eq!(visual.selection(), Some(&(1..3)));
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn reselect_without_selection() {
+        let (client, state) = test_state("abc\n", 0);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('g'), client.clone(), state.clone()).unwrap();
+        assert!(normal.event(Event::ReceivedCharacter('v'), client.clone(), state.clone()).is_err());
+    }
+
+    #[test]
+    fn pending_command_times_out() -> Result<(), Error> {
+        let (client, state) = test_state("abc def\n", 0);
+        client.write().unwrap().config.timeoutlen = std::time::Duration::from_millis(0);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('d'), client.clone(), state.clone())?;
+        assert_eq!(normal.pending_buf, "d");
+        // the `d` has expired by the time `w` arrives, so this is just a motion
+        normal.event(Event::ReceivedCharacter('w'), client.clone(), state.clone())?;
+        assert_eq!(normal.pending_buf, "");
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc def\n");
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 4);
+
+        client.write().unwrap().config.timeoutlen = std::time::Duration::from_secs(60);
+        normal.event(Event::ReceivedCharacter('d'), client.clone(), state.clone())?;
+        normal.event(Event::ReceivedCharacter('w'), client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc \n");
+        Ok(())
+    }
+
+    #[test]
+    fn pending_command_escape() {
+        let (client, state) = test_state("abc\n", 0);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('2'), client.clone(), state.clone()).unwrap();
+        normal.event(Event::ReceivedCharacter('d'), client.clone(), state.clone()).unwrap();
+        assert_eq!(normal.pending_buf, "2d");
+        press(&mut normal, VirtualKeyCode::Escape, &client, &state).unwrap();
+        assert_eq!(normal.pending_buf, "");
+        // the next command starts from scratch
+        normal.event(Event::ReceivedCharacter('x'), client.clone(), state.clone()).unwrap();
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "bc\n");
+    }
+
+    #[test]
+    fn pending_command_unknown() {
+        let (client, state) = test_state("abc\n", 0);
+        let mut normal = NormalMode::new();
+        normal.event(Event::ReceivedCharacter('d'), client.clone(), state.clone()).unwrap();
+        assert!(normal.event(Event::ReceivedCharacter('Q'), client.clone(), state.clone()).is_err());
+        assert_eq!(normal.pending_buf, "");
+        assert!(normal.event(Event::ReceivedCharacter('Z'), client.clone(), state.clone()).is_err());
+        assert_eq!(normal.pending_buf, "");
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "abc\n");
+    }
+
+    #[test]
+    fn sort_ranges() -> Result<(), Error> {
+        let (client, state) = test_state("d\nc\nb\na", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("2,3sort", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "d\nb\nc\na");
+        cmd.run_line("sort!", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "d\nc\nb\na");
+        // sorting is a single undo step
+        state.write().unwrap().buffers[0].text.undo();
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "d\nb\nc\na");
+        assert!(cmd.run_line("'<,'>sort", client.clone(), state.clone()).is_err());
+        assert!(cmd.run_line("3,2sort", client.clone(), state.clone()).is_err());
+
+        let mut mode = VisualMode::linewise(state.read().unwrap().current_buffer().unwrap());
+        type_keys(&mut mode, "jj", &client, &state)?;
+        let next = type_keys(&mut mode, ":", &client, &state)?.expect("enter command mode");
+        assert_eq!(next.mode_tag(), ModeTag::Command);
+        cmd.run_line("'<,'>sort", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "b\nc\nd\na");
+        Ok(())
+    }
+
+    #[test]
+    fn setlocal_only_changes_current_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("a\n", 0);
+        state.write().unwrap().buffers.push(buffer::Buffer::with_text("b\n"));
+        let cmd = CommandMode::new();
+        cmd.run_line("setlocal tabstop=2", client.clone(), state.clone())?;
+        assert!(cmd.run_line("setlocal tw=0", client.clone(), state.clone()).is_err());
+        assert_eq!(state.read().unwrap().buffers[0].options.textwidth, None, "invalid options don't change anything");
+        {
+            let state = state.read().unwrap();
+            let cs = client.read().unwrap();
+            assert_eq!(state.buffers[0].options.resolve(&cs.config).tabstop, 2);
+            assert_eq!(state.buffers[1].options.resolve(&cs.config).tabstop, 4);
+            assert_eq!(cs.config.tabstop, 4);
+        }
+        // editing commands use the buffer's options
+        type_keys(&mut NormalMode::new(), ">>", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "  a\n");
+        // and so do columns, which are what gets drawn
+        state.write().unwrap().buffers[0].insert_at(0, "\t");
+        assert_eq!(state.read().unwrap().buffers[0].column_for_index(1), 2);
+        assert_eq!(state.read().unwrap().buffers[1].column_for_index(1), 1);
+        // retab keeps a local tabstop local
+        cmd.run_line("retab 8", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].options.tabstop, Some(8));
+        assert_eq!(client.read().unwrap().config.tabstop, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn goto_line() -> Result<(), Error> {
+        let (client, state) = test_state("one\ntwo\nthree\n", 5);
+        let cmd = CommandMode::new();
+        cmd.run_line("1", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 0);
+        cmd.run_line("$", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 8);
+        cmd.run_line("2", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 4);
+        cmd.run_line("42", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 8, "clamped to the last line");
+        cmd.run_line("0", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_lines() -> Result<(), Error> {
+        let (client, state) = test_state("one\ntwo\nthree\nfour", 5);
+        let cmd = CommandMode::new();
+        cmd.run_line("d", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "one\nthree\nfour");
+        assert_eq!(state.read().unwrap().registers.get(&'1').map(String::as_str), Some("two\n"));
+        cmd.run_line("3d a", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "one\nthree");
+        assert_eq!(state.read().unwrap().registers.get(&'a').map(String::as_str), Some("four\n"));
+        cmd.run_line("1,2delete", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "");
+        Ok(())
+    }
+
+    #[test]
+    fn line_commands_land_on_first_nonblank() -> Result<(), Error> {
+        let (client, state) = test_state("fn main() {\n    let x = 1;\n\tx\n}\n", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("2", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 16);
+        cmd.run_line("3", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 28);
+        cmd.run_line("1d", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 4);
+        cmd.run_line("$", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 18);
+        Ok(())
+    }
+
+    #[test]
+    fn only_command() -> Result<(), Error> {
+        let (client, state) = test_state("one\n", 0);
+        {
+            let mut es = state.write().unwrap();
+            es.buffers.push(buffer::Buffer::with_text("two\n"));
+            let right = Pane::split(&mut es.panes, 0, true, 0.5, PaneContent::buffer(1));
+            Pane::split(&mut es.panes, right, false, 0.5, PaneContent::QuickFix);
+            Pane::split(&mut es.panes, 0, false, 0.5, PaneContent::buffer(0));
+            es.current_pane = right;
+        }
+        CommandMode::new().run_line("only", client.clone(), state.clone())?;
+        let es = state.read().unwrap();
+        assert_eq!(es.panes.len(), 1);
+        let bounds = es.current_pane().bounds;
+        assert_eq!((bounds.x, bounds.y, bounds.w, bounds.h), (0.0, 0.0, 1.0, 1.0));
+        assert_eq!(es.current_buffer_index(), Some(1));
+        assert_eq!(es.buffers.len(), 2, "buffers stay open");
+        Ok(())
+    }
+
+    #[test]
+    fn retab_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("a\n\tb\n\t\tc\n", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("retab 2", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "a\n    b\n        c\n");
+        assert_eq!(client.read().unwrap().config.tabstop, 2);
+        client.write().unwrap().config.softtab = false;
+        cmd.run_line("2,2retab", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "a\n\t\tb\n        c\n");
+        state.write().unwrap().buffers[0].text.undo();
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "a\n    b\n        c\n");
+        assert!(cmd.run_line("retab 0", client.clone(), state.clone()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reindent_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("if a {\nb(\nc);\n    }\nd\n", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("2reindent", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "if a {\n    b(\nc);\n    }\nd\n");
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 11);
+        cmd.run_line("reindent", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "if a {\n    b(\n        c);\n}\nd\n");
+        Ok(())
+    }
+
+    #[test]
+    fn quit_with_modified_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("abc", 0);
+        let cmd = CommandMode::new();
+        state.write().unwrap().buffers[0].text.insert_range("x", 0);
+        assert!(cmd.run_line("q", client.clone(), state.clone()).is_err());
+        assert!(!client.read().unwrap().should_exit);
+        state.write().unwrap().buffers[0].mark_saved();
+        cmd.run_line("q", client.clone(), state.clone())?;
+        assert!(client.read().unwrap().should_exit);
+        client.write().unwrap().should_exit = false;
+        state.write().unwrap().buffers[0].text.insert_range("y", 0);
+        cmd.run_line("q!", client.clone(), state.clone())?;
+        assert!(client.read().unwrap().should_exit);
+        Ok(())
+    }
+
+    #[test]
+    fn new_scratch_buffer() -> Result<(), Error> {
+        let (client, state) = test_state("abc", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("enew", client.clone(), state.clone())?;
+        {
+            let state = state.read().unwrap();
+            assert_eq!(state.buffers.len(), 2);
+            assert_eq!(state.panes.len(), 1);
+            assert_eq!(state.current_buffer_index(), Some(1));
+            assert!(state.buffers[1].scratch);
+        }
+        cmd.run_line("new", client.clone(), state.clone())?;
+        let state = state.read().unwrap();
+        assert_eq!(state.panes.len(), 2);
+        assert_eq!(state.current_buffer_index(), Some(2));
+        assert!(state.buffers[2].scratch && state.buffers[2].path.as_os_str().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn abbreviations() -> Result<(), Error> {
+        let insert = |text: &str, cursor: usize, keys: &str| -> Result<String, Error> {
+            let (client, state) = test_state(text, cursor);
+            {
+                let abbreviations = &mut client.write().unwrap().config.abbreviations;
+                abbreviations.insert("teh".into(), "the".into());
+                abbreviations.insert("fn".into(), "fn $0() {\n}".into());
+            }
+            let mut mode = InsertMode::new(state.write().unwrap().current_buffer_mut().unwrap(), 1);
+            type_keys(&mut mode, keys, &client, &state)?;
+            let text = state.read().unwrap().buffers[0].text.text();
+            Ok(text)
+        };
+        assert_eq!(insert("", 0, "teh cat")?, "the cat");
+        assert_eq!(insert("", 0, "(teh)")?, "(the)");
+        assert_eq!(insert("", 0, "tehx x_teh teh")?, "tehx x_teh teh");
+        // only words typed in this insertion expand
+        assert_eq!(insert("teh", 3, " ")?, "teh ");
+        // the cursor goes to the marker and later lines are indented like the current one
+        assert_eq!(insert("    \n", 4, "fn main")?, "    fn main() {\n    }\n");
+        Ok(())
+    }
+
+    #[test]
+    fn abbreviation_cursor_is_one_insert() -> Result<(), Error> {
+        let (client, state) = test_state("", 0);
+        client.write().unwrap().config.abbreviations.insert("fn".into(), "fn $0() {}".into());
+        let mut mode = InsertMode::new(state.write().unwrap().current_buffer_mut().unwrap(), 2);
+        type_keys(&mut mode, "a fn f", &client, &state)?;
+        assert!(press(&mut mode, VirtualKeyCode::Escape, &client, &state)?.is_some());
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "a fn f() {}a fn f() {}");
+        assert_eq!(state.read().unwrap().registers.get(&'.').map(String::as_str), Some("a fn f() {}"));
+        type_keys(&mut NormalMode::new(), "u", &client, &state)?;
+        assert_eq!(state.read().unwrap().buffers[0].text.text(), "");
+        Ok(())
+    }
+
+    #[test]
+    fn set_fileformat() -> Result<(), Error> {
+        let (client, state) = test_state("a\nb\n", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("set ff", client.clone(), state.clone())?;
+        assert_eq!(client.read().unwrap().usrmsgs.last().unwrap().message, "fileformat=unix");
+        cmd.run_line("set fileformat=dos", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].format.line_ending, protocol::LineEnding::CRLF);
+        assert!(state.read().unwrap().buffers[0].is_modified());
+        assert!(cmd.run_line("set ff=mac", client.clone(), state.clone()).is_err());
+        assert_eq!(state.read().unwrap().buffers[0].format.line_ending, protocol::LineEnding::CRLF);
+        Ok(())
+    }
+
+    #[test]
+    fn grep_quickfix() -> Result<(), Error> {
+        let (client, state) = test_state("one two\none\n", 0);
+        {
+            let mut st = state.write().unwrap();
+            st.buffers[0].path = "a".into();
+            let mut b = buffer::Buffer::with_text("none\n");
+            b.path = "b".into();
+            st.buffers.push(b);
+            st.buffers.push(buffer::Buffer::scratch());
+            st.buffers[2].text.insert_range("one", 0);
+        }
+        let cmd = CommandMode::new();
+        cmd.run_line("grep on.", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().quickfix.iter().map(|e| (e.path.to_str().unwrap(), e.line, e.column)).collect::<Vec<_>>(),
+            vec![("a", 0, 0), ("a", 1, 0), ("b", 0, 1)]);
+        assert_eq!(state.read().unwrap().quickfix[2].text, "none");
+        cmd.run_line("cn 2", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().current_buffer_index(), Some(1));
+        assert_eq!(state.read().unwrap().buffers[1].cursor_index, 1);
+        cmd.run_line("cn", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().current_buffer_index(), Some(0));
+        cmd.run_line("cp", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().quickfix_index, 2);
+        cmd.run_line("cc 2", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().buffers[0].cursor_index, 8);
+        assert!(cmd.run_line("cc 4", client.clone(), state.clone()).is_err());
+        assert!(cmd.run_line("grep nothing", client.clone(), state.clone()).is_err());
+        assert_eq!(state.read().unwrap().quickfix.len(), 3);
+        cmd.run_line("copen", client.clone(), state.clone())?;
+        cmd.run_line("copen", client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().panes.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn no_highlight() -> Result<(), Error> {
+        let (client, state) = test_state("one two one", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line("/one", client.clone(), state.clone())?;
+        state.write().unwrap().buffers[0].update_search_matches();
+        assert_eq!(state.read().unwrap().buffers[0].search_matches, vec![0..3, 8..11]);
+        cmd.run_line("noh", client.clone(), state.clone())?;
+        state.write().unwrap().buffers[0].update_search_matches();
+        assert!(state.read().unwrap().buffers[0].search_matches.is_empty());
+        assert_eq!(state.read().unwrap().buffers[0].current_query.as_deref(), Some("one"));
+        Ok(())
+    }
+
+    #[test]
+    fn let_register() -> Result<(), Error> {
+        let (client, state) = test_state("", 0);
+        let cmd = CommandMode::new();
+        cmd.run_line(r#"let @a = "text""#, client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().registers.get(&'a').map(String::as_str), Some("text"));
+        cmd.run_line(r#"let  @b="two\nlines \"quoted\" \\ "  "#, client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().registers.get(&'b').map(String::as_str), Some("two\nlines \"quoted\" \\ "));
+        cmd.run_line(r#"let @" = """#, client.clone(), state.clone())?;
+        assert_eq!(state.read().unwrap().registers.get(&'"').map(String::as_str), Some(""));
+        assert!(cmd.run_line(r#"let @a = "bad \q escape""#, client.clone(), state.clone()).is_err());
+        assert!(cmd.run_line(r#"let @a = unquoted"#, client.clone(), state.clone()).is_err());
+        assert_eq!(state.read().unwrap().registers.get(&'a').map(String::as_str), Some("text"));
+        Ok(())
+    }
+}
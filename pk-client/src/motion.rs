@@ -1,33 +1,10 @@
 use super::*;
 use std::ops::Range;
-use crate::buffer::Buffer;
-
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub enum CharClass {
-    Whitespace,
-    Punctuation,
-    Regular
-}
-
-pub trait CharClassify {
-    fn class(self) -> CharClass;
-}
-
-impl CharClassify for char {
-    fn class(self) -> CharClass {
-        if self.is_whitespace() || self.is_ascii_whitespace() {
-            CharClass::Whitespace
-        } else if !self.is_alphanumeric() && self != '_' {
-            CharClass::Punctuation
-        } else {
-            CharClass::Regular
-        }
-    }
-}
+use crate::buffer::{Buffer, IndentRelation};
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum TextObject {
-    Word, BigWord, Paragraph, Block(char)
+    Word, BigWord, Paragraph, Block(char), Tag
 }
 
 fn matching_block_char(c: char) -> char {
@@ -57,14 +34,14 @@ impl TextObject {
                     .map(|cc| if bigword && cc == CharClass::Punctuation { CharClass::Regular } else { cc })
                     .peekable();
                 let starting_class = chars.next().unwrap();
-                while chars.peek().map(|cc| *cc == starting_class).unwrap_or(false) {
+                while range.start > 0 && chars.peek().map(|cc| *cc == starting_class).unwrap_or(false) {
                     range.start -= 1;
                     chars.next();
                 }
                 // find end of range
                 if !include && starting_class == CharClass::Whitespace { return range; }
                 range.end = range.start+1;
-                let mut chars = buf.text.chars(range.end)
+                let mut chars = buf.text.chars_in_range(range.end, buf.text.len())
                     // .inspect(|i| println!("f-{}", i))
                     .map(CharClassify::class)
                     .map(|cc| if bigword && cc == CharClass::Punctuation { CharClass::Regular } else { cc })
@@ -76,7 +53,10 @@ impl TextObject {
                     }
                     if i > 0 || include {
                         let class = if starting_class == CharClass::Whitespace {
-                            *chars.peek().unwrap()
+                            match chars.peek() {
+                                Some(cc) => *cc,
+                                None => break
+                            }
                         } else { CharClass::Whitespace };
                         while chars.peek().map(|cc| *cc == class).unwrap_or(false) {
                             range.end += 1;
@@ -87,51 +67,135 @@ impl TextObject {
                 range.end -= 1;
                 range
             },
+            // when there's no block around the cursor the range is empty once its end is made
+            // exclusive, so that operators do nothing
+            TextObject::Block(q) if *q == '"' || *q == '\'' => {
+                quote_range(buf, cursor_index, include, *q)
+                    .unwrap_or(cursor_index+1 .. cursor_index)
+            },
             TextObject::Block(open_char) => {
-                println!("---");
-                let mut range = cursor_index..cursor_index;
+                block_range(buf, cursor_index, count, include, *open_char)
+                    .unwrap_or(cursor_index+1 .. cursor_index)
+            },
+            TextObject::Tag => {
+                tag_range(buf, cursor_index, count, include)
+                    .unwrap_or(cursor_index+1 .. cursor_index)
+            },
+            TextObject::Paragraph => buf.paragraph_object(cursor_index, count, include)
+        }
+    }
+}
 
-                let mut chars = buf.text.chars(range.start).rev().peekable();
-                while chars.peek().map(|cc| cc != open_char).unwrap_or(false) {
-                    chars.next();
-                    range.start -= 1;
-                }
-                if !include { range.start += 1; }
-
-                let close_char = matching_block_char(*open_char);
-                let mut chars = buf.text.chars(range.end).peekable();
-                let mut count = 0;
-                loop {
-                    match chars.peek() {
-                        Some(ch) if *ch == *open_char => {
-                            println!("+");
-                            count += 1;
-                        }
-                        Some(ch) if *ch == close_char => {
-                            if count == 0 {
-                                break;
-                            } else {
-                                println!("-");
-                                count -= 1;
-                            }
-                        },
-                        Some(_) => { }
-                        None => break
-                    }
-                    range.end += 1;
-                    println!("{:?}",chars.next());
+/// the block delimited by `open` and its matching character that is `count` levels out from
+/// `cursor_index`, as an inclusive range. The cursor counts as inside a block when it is on either
+/// of its delimiters
+fn block_range(buf: &Buffer, cursor_index: usize, count: usize, include: bool, open: char) -> Option<Range<usize>> {
+    let close = matching_block_char(open);
+    let mut open_ix = cursor_index;
+    for i in 0..count.max(1) {
+        if i == 0 && buf.text.char_at(cursor_index) == Some(open) { continue; }
+        if open_ix == 0 { return None; }
+        let mut ix = open_ix;
+        let mut depth = 0;
+        let mut found = false;
+        for c in buf.text.chars_in_range(0, open_ix).rev() {
+            ix -= c.len_utf8();
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 { found = true; break; }
+                depth -= 1;
+            }
+        }
+        if !found { return None; }
+        open_ix = ix;
+    }
+    let mut ix = open_ix + open.len_utf8();
+    let mut depth = 0;
+    for c in buf.text.chars_in_range(ix, buf.text.len()) {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                return Some(if include { open_ix..ix } else { open_ix+1 .. ix-1 });
+            }
+            depth -= 1;
+        }
+        ix += c.len_utf8();
+    }
+    None
+}
+
+/// a markup tag starting at a '<', as (name, is a closing tag, index of its '>'). Self-closing
+/// tags, comments and declarations aren't tags that can enclose anything, so they are None
+fn parse_tag(text: &str, start: usize) -> Option<(&str, bool, usize)> {
+    let rest = &text[start+1..];
+    let closing = rest.starts_with('/');
+    let name_start = if closing { 1 } else { 0 };
+    let name_len = rest[name_start..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == ':' || c == '.'))
+        .unwrap_or(rest.len() - name_start);
+    let name = &rest[name_start..name_start+name_len];
+    if !name.starts_with(char::is_alphabetic) { return None; }
+    let end = rest.find('>')?;
+    if rest[..end].ends_with('/') { return None; }
+    Some((name, closing, start + 1 + end))
+}
+
+/// the `<tag>...</tag>` pair that is `count` levels out from `cursor_index`, as an inclusive range.
+/// Tags are paired by name, so opening tags that are never closed (like `<br>`) are skipped over,
+/// and the cursor counts as inside a pair when it is on either of its tags
+fn tag_range(buf: &Buffer, cursor_index: usize, count: usize, include: bool) -> Option<Range<usize>> {
+    let text = buf.text.text();
+    let mut open_tags: Vec<(&str, usize, usize)> = Vec::new();
+    let mut enclosing = 0;
+    for (start, _) in text.match_indices('<') {
+        let (name, closing, end) = match parse_tag(&text, start) {
+            Some(t) => t,
+            None => continue
+        };
+        if !closing {
+            open_tags.push((name, start, end));
+        } else if let Some(i) = open_tags.iter().rposition(|(n, _, _)| *n == name) {
+            let (_, open_start, open_end) = open_tags[i];
+            open_tags.truncate(i);
+            // pairs close from the innermost outwards, so the enclosing ones are found in order
+            if open_start <= cursor_index && cursor_index <= end {
+                enclosing += 1;
+                if enclosing == count.max(1) {
+                    return Some(if include { open_start..end } else { open_end+1 .. start-1 });
                 }
-                /*while chars.peek().map(|cc| *cc != mc).unwrap_or(false) {
-                  chars.next();
-                  range.end += 1;
-                  }*/
-                if !include { range.end -= 1; }
+            }
+        }
+    }
+    None
+}
 
-                range
-            },
-            _ => unimplemented!()
+/// the quoted string on the cursor's line that the cursor is in or on a quote of, or else the next
+/// one after the cursor, as an inclusive range. Quotes are paired up from the start of the line,
+/// skipping escaped ones, since the same character opens and closes the string
+fn quote_range(buf: &Buffer, cursor_index: usize, include: bool, quote: char) -> Option<Range<usize>> {
+    let mut ix = buf.current_start_of_line(cursor_index);
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for c in buf.text.chars_in_range(ix, buf.next_line_index(ix)) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            quotes.push(ix);
         }
+        ix += c.len_utf8();
     }
+    let (open, close) = quotes.chunks_exact(2).map(|p| (p[0], p[1])).find(|&(_, close)| cursor_index <= close)?;
+    Some(if include { open..close } else { open+1 .. close-1 })
+}
+
+/// A line in the viewport to move to
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ViewportPosition {
+    Top, Middle, Bottom
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -157,6 +221,16 @@ pub enum MotionType {
     An(TextObject),
     Inner(TextObject),
     NextSearchMatch(Direction),
+    /// search for the whole word under the cursor
+    SearchWord(Direction),
+    /// the first non-blank of the next line with indentation related to the current line's
+    Indent(Direction, IndentRelation),
+    /// the screen column given by the count on the current line
+    Column,
+    /// the first non-blank of a line in the viewport, offset by the count for the top and bottom
+    ViewportLine(ViewportPosition),
+    /// exactly the range from the first index through the second whatever the cursor or count,
+    /// which can be in either order, like a selection in Visual mode
     Passthrough(usize, usize)
 }
 
@@ -167,6 +241,7 @@ impl MotionType {
             MotionType::An(_) | MotionType::Inner(_) => true,
             MotionType::Passthrough(_,_) => true,
             MotionType::EndOfLine => true,
+            MotionType::EndOfWord(_) | MotionType::EndOfBigWord(_) => true,
             _ => false
         }
     }
@@ -191,6 +266,8 @@ pub fn take_number(schars: &mut std::iter::Peekable<std::str::Chars>) -> Option<
 }
 
 impl Motion {
+    /// a motion over the selection `r`, so that an operator applied to it acts on the selection
+    /// from start to end, including the character at the end
     pub fn passthrough(r: &Range<usize>) -> Motion {
         Motion { count: 1, mo: MotionType::Passthrough(r.start, r.end) }
     }
@@ -209,6 +286,8 @@ impl Motion {
             Some('E') => MotionType::EndOfBigWord(Direction::Forward),
             Some('n') => MotionType::NextSearchMatch(Direction::Forward),
             Some('N') => MotionType::NextSearchMatch(Direction::Backward),
+            Some('*') => MotionType::SearchWord(Direction::Forward),
+            Some('#') => MotionType::SearchWord(Direction::Backward),
             Some('g') => {
                 c.next();
                 match c.peek() {
@@ -222,6 +301,10 @@ impl Motion {
             Some('^') => MotionType::StartOfLine,
             Some('$') => MotionType::EndOfLine,
             Some('_') => MotionType::WholeLine,
+            Some('|') => MotionType::Column,
+            Some('H') => MotionType::ViewportLine(ViewportPosition::Top),
+            Some('M') => MotionType::ViewportLine(ViewportPosition::Middle),
+            Some('L') => MotionType::ViewportLine(ViewportPosition::Bottom),
             Some(&tc) if tc == 'f' || tc == 'F' || tc == 't' || tc == 'T' => {
                 c.next();
                 MotionType::NextChar {
@@ -254,6 +337,7 @@ impl Motion {
                     Some('<') | Some('>') => TextObject::Block('<'),
                     Some('"')  => TextObject::Block('"'),
                     Some('\'') => TextObject::Block('\''),
+                    Some('t') => TextObject::Tag,
                     Some(_) => return Err(Error::UnknownCommand(String::from(wholecmd))),
                     None => return Err(Error::IncompleteCommand)
                 };
@@ -263,6 +347,18 @@ impl Motion {
                     _ => unreachable!()
                 }
             },
+            Some(&bc) if bc == '[' || bc == ']' => {
+                c.next();
+                let direction = if bc == ']' { Direction::Forward } else { Direction::Backward };
+                match c.peek() {
+                    Some('-') => MotionType::Indent(direction, IndentRelation::Less),
+                    Some('=') => MotionType::Indent(direction, IndentRelation::Equal),
+                    Some('+') => MotionType::Indent(direction, IndentRelation::Greater),
+                    Some(&nc) if nc == bc => MotionType::Indent(direction, IndentRelation::LessOrEqual),
+                    Some(_) => return Err(Error::UnknownCommand(String::from(wholecmd))),
+                    None => return Err(Error::IncompleteCommand)
+                }
+            },
             Some(';') => MotionType::RepeatNextChar { opposite: false },
             Some(',') => MotionType::RepeatNextChar { opposite: true },
             Some(c) if opchar.map(|opc| opc == *c).unwrap_or(false)
@@ -277,37 +373,76 @@ impl Motion {
         })
     }
 
+    /// the text an operator using this motion acts on, in order and with an exclusive end
+    pub fn operator_range(&self, buf: &mut Buffer, cursor_index: usize, multiplier: usize) -> Range<usize> {
+        let mut r = self.range(buf, cursor_index, multiplier);
+        // text objects are already in order, and use a reversed range to mean nothing
+        if r.end < r.start && !matches!(self.mo, MotionType::An(_) | MotionType::Inner(_)) {
+            r = r.end..r.start;
+        }
+        if self.mo.inclusive() {
+            r.end = buf.next_char_index(r.end);
+        }
+        r
+    }
+
+    /// the text this motion moves over from `cursor_index`, which stops at the ends of the buffer
+    /// instead of going past them
     pub fn range(&self, buf: &mut Buffer, cursor_index: usize, multiplier: usize) -> Range<usize> {
+        let len = buf.text.len();
+        if len == 0 { return 0..0; }
+        let r = self.unclamped_range(buf, cursor_index.min(len), multiplier);
+        r.start.min(len) .. r.end.min(len)
+    }
+
+    /// the range of the motion in a buffer that isn't empty
+    fn unclamped_range(&self, buf: &mut Buffer, cursor_index: usize, multiplier: usize) -> Range<usize> {
+        // the last character, for motions that need one under the cursor
+        let last = buf.text.len() - 1;
         match &self.mo {
             MotionType::Passthrough(s, e) => return *s .. *e,
             MotionType::An(obj) => {
-                return obj.range(buf, cursor_index, self.count * multiplier, true);
+                return obj.range(buf, cursor_index.min(last), self.count * multiplier, true);
             },
             MotionType::Inner(obj) => {
-                return obj.range(buf, cursor_index, self.count * multiplier, false);
+                return obj.range(buf, cursor_index.min(last), self.count * multiplier, false);
+            },
+            MotionType::Column => {
+                return cursor_index..buf.goto_column(cursor_index, self.count * multiplier);
+            },
+            MotionType::ViewportLine(pos) => {
+                let (first, last) = buf.viewport_lines();
+                let offset = (self.count * multiplier).saturating_sub(1);
+                let line = match pos {
+                    ViewportPosition::Top => (first + offset).min(last),
+                    ViewportPosition::Middle => buf.line_for_visible((buf.visible_line(first) + buf.visible_line(last)) / 2),
+                    ViewportPosition::Bottom => last.saturating_sub(offset).max(first)
+                };
+                return cursor_index..buf.first_nonblank(line);
             },
             _ => {}
         };
         let mut range = cursor_index..cursor_index;
         for _ in 0..(self.count * multiplier) {
             match &self.mo {
-                MotionType::Char(Direction::Forward) => { range.end = (range.end+1).min(buf.text.len()); }
-                MotionType::Char(Direction::Backward) => { range.end = range.end.saturating_sub(1); }
+                MotionType::Char(Direction::Forward) => { range.end = buf.next_char_index(range.end); }
+                MotionType::Char(Direction::Backward) => { range.end = buf.prev_char_index(range.end); }
                 MotionType::Line(direction) => {
-                    let new_line_index = match direction {
-                        Direction::Forward => buf.next_line_index(range.end),
-                        Direction::Backward => buf.last_line_index(range.end)
+                    // closed folds count as a single line
+                    let line = buf.line_for_index(range.end);
+                    let new_line = match direction {
+                        Direction::Forward => buf.closed_fold_containing(line).map_or(line + 1, |f| f.end),
+                        Direction::Backward => {
+                            let prev = line.saturating_sub(1);
+                            buf.closed_fold_containing(prev).map_or(prev, |f| f.start)
+                        }
                     };
-                    // probably should unwrap to the end of the buffer
-                    let line_len = buf.text.index_of('\n', new_line_index).unwrap_or(buf.text.len())-new_line_index;
-                    range.end = buf.column_for_index(cursor_index).min(line_len)+new_line_index;
+                    range.end = buf.index_at_line_column(new_line, buf.column_for_index(cursor_index));
                 },
                 MotionType::StartOfLine => {
                     range.end = buf.current_start_of_line(range.end);
-                    let mut chars = buf.text.chars(range.end).map(CharClassify::class).peekable();
-                    while chars.peek().map_or(false, |cc| *cc == CharClass::Whitespace) {
+                    while buf.text.char_at(range.end).map_or(false, |c| c == ' ' || c == '\t') {
                         range.end += 1;
-                        chars.next();
                     }
                 },
                 MotionType::EndOfLine => {
@@ -315,9 +450,9 @@ impl Motion {
                 }
                 MotionType::Word(Direction::Forward) => {
                     // is the character under the cursor alphanumeric+ or a 'other non-blank'?
-                    if buf.text.char_at(range.end).map(|c| c.is_alphanumeric()||c=='_').unwrap_or(false) {
+                    if buf.char_class_at(range.end) == Some(CharClass::Regular) {
                         // find the next whitespace or non-blank char
-                        let f = buf.text.index_of_pred(|sc| !(sc.is_alphanumeric() || sc == '_'), range.end)
+                        let f = buf.text.index_of_pred(|sc| sc.class() != CharClass::Regular, range.end)
                             .unwrap_or(range.end);
                         // println!("F{}",f);
                         // the next word starts at either `f` or if `f` is whitespace, the next
@@ -340,18 +475,21 @@ impl Motion {
                 },
 
                 MotionType::Word(Direction::Backward) => {
+                    range.end = range.end.min(last);
+                    if range.end == 0 { break; }
                     let mut chars = buf.text.chars(range.end).rev()
                         .map(CharClassify::class)
                         .peekable();
                     if let Some(_) = chars.next() {
                         range.end -= 1;
-                        while let Some(CharClass::Whitespace) = chars.peek() {
+                        while range.end > 0 && chars.peek() == Some(&CharClass::Whitespace) {
                             chars.next();
                             range.end -= 1;
                         }
-                        let scls = chars.peek().cloned().unwrap();
-                        while range.end > 0 && chars.next().map_or(false, |x| x == scls) {
-                            range.end -= 1;
+                        if let Some(scls) = chars.peek().cloned() {
+                            while range.end > 0 && chars.next().map_or(false, |x| x == scls) {
+                                range.end -= 1;
+                            }
                         }
                         if range.end > 0 { range.end += 1; }
                     } else {
@@ -371,6 +509,7 @@ impl Motion {
                 },
 
                 MotionType::EndOfWord(Direction::Forward) | MotionType::EndOfBigWord(Direction::Forward) => {
+                    if range.end >= last { break; }
                     let mut chars: Box<dyn Iterator<Item=CharClass>> = Box::new(buf.text.chars(range.end)
                                                                                 .map(CharClassify::class));
                     if let MotionType::EndOfBigWord(_) = self.mo {
@@ -380,6 +519,7 @@ impl Motion {
                         }));
                     }
                     let mut chars = chars.peekable();
+                    let start = range.end;
                     if let Some(starting_class) = chars.next() {
                         range.end += 1;
                         if starting_class != CharClass::Whitespace &&
@@ -393,12 +533,15 @@ impl Motion {
                                     chars.next();
                                     range.end += 1;
                                 }
-                                let scls = chars.peek().cloned().unwrap();
-                                while range.end < buf.text.len() &&
-                                    chars.next().map_or(false, |x| x == scls)
-                                    {
-                                        range.end += 1;
-                                    }
+                                match chars.peek().cloned() {
+                                    Some(scls) => while range.end < buf.text.len() &&
+                                        chars.next().map_or(false, |x| x == scls)
+                                        {
+                                            range.end += 1;
+                                        },
+                                    // stay put if there are no more words
+                                    None => range.end = start+1
+                                }
                             }
                         range.end -= 1;
                     } else {
@@ -408,6 +551,7 @@ impl Motion {
 
                 // of course, the most arcane is the simplest
                 MotionType::EndOfWord(Direction::Backward) | MotionType::EndOfBigWord(Direction::Backward) => {
+                    range.end = range.end.min(last);
                     let mut chars: Box<dyn Iterator<Item=CharClass>> = Box::new(buf.text.chars(range.end).rev()
                                                                                 .map(CharClassify::class));
                     if let MotionType::EndOfBigWord(_) = self.mo {
@@ -417,18 +561,26 @@ impl Motion {
                         }));
                     }
                     let mut chars = chars.peekable();
-                    if let Some(starting_class) = chars.next() {
+                    if range.end == 0 {
+                        // already at the start of the buffer
+                    } else if let Some(starting_class) = chars.next() {
+                        // running out of characters leaves the range at the start of the buffer
                         range.end -= 1;
                         if starting_class != CharClass::Whitespace {
                             while chars.peek().map_or(false, |cc| *cc == starting_class) {
                                 chars.next();
-                                range.end -= 1;
+                                range.end = range.end.saturating_sub(1);
                             }
                         }
 
                         while chars.peek().map_or(false, |cc| *cc == CharClass::Whitespace) {
+                            // like Vim, an empty line counts as a word
+                            if buf.text.char_at(range.end) == Some('\n') &&
+                                (range.end == 0 || buf.text.char_at(range.end - 1) == Some('\n')) {
+                                break;
+                            }
                             chars.next();
-                            range.end -= 1;
+                            range.end = range.end.saturating_sub(1);
                         }
                     } else {
                         range.end = 0;
@@ -437,15 +589,13 @@ impl Motion {
 
                 MotionType::NextChar { c, place_before, direction } => {
                     buf.last_char_query = Some((*c, *place_before, *direction));
-                    range.end = buf.text.dir_index_of(|cc| cc == *c, match direction {
-                        Direction::Forward => range.end+1,
-                        Direction::Backward => range.end-1
-                    }, *direction).unwrap_or(range.end);
-                    if *place_before {
-                        match direction {
-                            Direction::Forward => range.end -= 1,
-                            Direction::Backward => range.end += 1,
-                        }
+                    // stay put if the character isn't on this line
+                    if let Some(ix) = buf.find_char_on_line(*c, range.end, *direction) {
+                        range.end = match (place_before, direction) {
+                            (true, Direction::Forward) => buf.prev_char_index(ix),
+                            (true, Direction::Backward) => buf.next_char_index(ix),
+                            (false, _) => ix
+                        };
                     }
                 },
                 
@@ -474,10 +624,34 @@ impl Motion {
                 },
                 
                 MotionType::NextSearchMatch(direction) => {
-                    range.end = buf.next_query_index(range.start + 1, *direction, true).unwrap_or(range.start);
+                    let from = if *direction == Direction::Forward { range.end + 1 } else { range.end };
+                    range.end = buf.next_query_index(from, *direction, true).unwrap_or(range.end);
+                },
+
+                MotionType::SearchWord(direction) => {
+                    // later repeats of the count go on to the next match of the same word
+                    if range.end == range.start {
+                        let cursor_index = buf.cursor_index;
+                        buf.cursor_index = range.start;
+                        let word = buf.set_word_query();
+                        buf.cursor_index = cursor_index;
+                        if word.is_none() { break; }
+                    }
+                    let from = if *direction == Direction::Forward { range.end + 1 } else { range.end };
+                    range.end = buf.next_query_index(from, *direction, true).unwrap_or(range.end);
                 },
 
-                _ => unimplemented!()
+                MotionType::Indent(direction, relation) => {
+                    let line = buf.line_for_index(range.end);
+                    if let Some(target) = buf.next_line_at_indent(line, *direction, *relation) {
+                        range.end = buf.first_nonblank(target);
+                    }
+                },
+
+                // paragraphs are only text objects so far
+                MotionType::Paragraph => {},
+
+                _ => unreachable!()
             }
         }
         range
@@ -528,6 +702,36 @@ mod tests {
         assert_eq!(mo.range(&b, 4, 1), 4..4);
     }      
 
+    #[test]
+    fn txo_column() {
+        let mut b = Buffer::with_text("abcdef\n\tx\n");
+        let mo = Motion { mo: MotionType::Column, count: 3 };
+        assert_eq!(mo.range(&mut b, 5, 1), 5..2);
+        assert_eq!(mo.range(&mut b, 0, 3), 0..5, "clamped to the line");
+        assert_eq!(mo.range(&mut b, 8, 1), 8..7, "inside a tab");
+        assert_eq!(mo.range(&mut b, 7, 5), 7..8);
+    }
+
+    #[test]
+    fn txo_viewport_line() {
+        let mut b = Buffer::with_text(&"  ln\n".repeat(30));
+        b.viewport = 10..20;
+        let top = Motion { mo: MotionType::ViewportLine(ViewportPosition::Top), count: 1 };
+        let middle = Motion { mo: MotionType::ViewportLine(ViewportPosition::Middle), count: 1 };
+        let bottom = Motion { mo: MotionType::ViewportLine(ViewportPosition::Bottom), count: 1 };
+        let line = |b: &mut Buffer, mo: &Motion, n| { let r = mo.range(b, 0, n); b.line_for_index(r.end) };
+        assert_eq!(line(&mut b, &top, 1), 11, "keeps a line above the cursor");
+        assert_eq!(top.range(&mut b, 0, 1).end, b.index_for_line(11) + 2, "first non-blank");
+        assert_eq!(line(&mut b, &top, 3), 13);
+        assert_eq!(line(&mut b, &middle, 1), 15);
+        assert_eq!(line(&mut b, &bottom, 1), 19);
+        assert_eq!(line(&mut b, &bottom, 4), 16);
+        assert_eq!(line(&mut b, &bottom, 40), 11, "counts stay inside the viewport");
+        b.viewport = 0..10;
+        assert_eq!(line(&mut b, &top, 1), 0);
+        assert_eq!(line(&mut b, &middle, 1), 4);
+    }
+
     #[test]
     fn txo_end_of_line() {
         let b = create_line_test_buffer();
@@ -548,6 +752,30 @@ mod tests {
         assert_eq!(mo.range(&b, 4, 1), 4..0);
     }
 
+    #[test]
+    fn txo_line_keeps_screen_column() {
+        let mut b = Buffer::with_text("日本語\nabcdefg\n\tx\nab\n");
+        let down = Motion { mo: MotionType::Line(Direction::Forward), count: 1 };
+        let up = Motion { mo: MotionType::Line(Direction::Backward), count: 1 };
+        assert_eq!(down.range(&mut b, 3, 1), 3..12);
+        assert_eq!(up.range(&mut b, 13, 1), 13..3);
+        assert_eq!(down.range(&mut b, 14, 1), 14..19, "past the tab");
+        assert_eq!(down.range(&mut b, 19, 1), 19..23, "shorter line");
+    }
+
+    #[test]
+    fn txo_line_over_fold() {
+        let mut b = Buffer::with_text("abc\ndef\nghi\njkl\n");
+        b.create_fold(1..3);
+        let down = Motion { mo: MotionType::Line(Direction::Forward), count: 1 };
+        let up = Motion { mo: MotionType::Line(Direction::Backward), count: 1 };
+        assert_eq!(down.range(&mut b, 1, 1), 1..5);
+        assert_eq!(down.range(&mut b, 5, 1), 5..13);
+        assert_eq!(up.range(&mut b, 13, 1), 13..5);
+        b.open_fold(1);
+        assert_eq!(down.range(&mut b, 5, 1), 5..9);
+    }
+
     fn run_repeated_test<'a>(b: &mut Buffer, cursor_index: &mut usize, mo: &Motion, 
                              correct_ends: impl Iterator<Item=&'a usize>, assert_msg: &str) {
         for (i, cwb) in correct_ends.enumerate() {
@@ -651,6 +879,122 @@ mod tests {
         run_repeated_test(&mut b, &mut ci, &mo, [18,13,8,3].iter(), "backward");
     }
 
+    #[test]
+    fn txo_end_word_backward_edges() {
+        let ge = |text: &str, big: bool, cursor_index: usize| {
+            let mut b = Buffer::with_text(text);
+            let mo = Motion {
+                mo: if big { MotionType::EndOfBigWord(Direction::Backward) } else { MotionType::EndOfWord(Direction::Backward) },
+                count: 1
+            };
+            mo.range(&mut b, cursor_index, 1).end
+        };
+        // at or inside the first word there is nowhere to go but the start
+        assert_eq!(ge("foo bar", false, 0), 0);
+        assert_eq!(ge("foo bar", false, 2), 0);
+        assert_eq!(ge("a", false, 0), 0);
+        assert_eq!(ge("  foo", false, 2), 0);
+        assert_eq!(ge("  foo", false, 1), 0);
+        assert_eq!(ge("foo bar", true, 1), 0);
+        // on whitespace
+        assert_eq!(ge("foo bar", false, 3), 2);
+        assert_eq!(ge("foo   bar", false, 4), 2);
+        assert_eq!(ge("foo\n\nbar", false, 4), 2);
+        assert_eq!(ge("foo\n\nbar", false, 5), 4, "empty lines are words");
+        assert_eq!(ge("\nfoo", true, 1), 0);
+        assert_eq!(ge("foo\n  bar", false, 6), 2);
+        // after punctuation
+        assert_eq!(ge("foo.bar", false, 4), 3);
+        assert_eq!(ge("foo.bar", false, 3), 2);
+        assert_eq!(ge("foo...bar", false, 6), 5);
+        assert_eq!(ge("foo...bar", false, 4), 2);
+        assert_eq!(ge("foo  !!bar", false, 6), 2);
+        assert_eq!(ge("foo  !!bar", false, 7), 6);
+        assert_eq!(ge("..foo", false, 2), 1);
+        assert_eq!(ge("foo.bar baz", true, 8), 6);
+        assert_eq!(ge("foo.bar baz", true, 4), 0);
+        assert_eq!(ge("a, b", true, 3), 1);
+        // counts stop at the start of the buffer
+        let mut b = Buffer::with_text("a b c");
+        let mo = Motion { mo: MotionType::EndOfWord(Direction::Backward), count: 5 };
+        assert_eq!(mo.range(&mut b, 4, 1).end, 0);
+        assert_eq!(mo.range(&mut b, 4, 2).end, 0);
+    }
+
+    fn every_motion_type() -> Vec<MotionType> {
+        let mut types = vec![
+            MotionType::WholeLine, MotionType::StartOfLine, MotionType::EndOfLine, MotionType::Paragraph,
+            MotionType::Column, MotionType::RepeatNextChar { opposite: false }, MotionType::RepeatNextChar { opposite: true },
+            MotionType::ViewportLine(ViewportPosition::Top), MotionType::ViewportLine(ViewportPosition::Middle),
+            MotionType::ViewportLine(ViewportPosition::Bottom), MotionType::Passthrough(0, 0)
+        ];
+        for obj in [TextObject::Word, TextObject::BigWord, TextObject::Paragraph, TextObject::Block('('), TextObject::Block('"'), TextObject::Tag].iter() {
+            types.push(MotionType::An(*obj));
+            types.push(MotionType::Inner(*obj));
+        }
+        for d in [Direction::Forward, Direction::Backward].iter().cloned() {
+            types.extend_from_slice(&[
+                MotionType::Char(d), MotionType::Word(d), MotionType::BigWord(d), MotionType::EndOfWord(d),
+                MotionType::EndOfBigWord(d), MotionType::Line(d), MotionType::NextSearchMatch(d), MotionType::SearchWord(d),
+                MotionType::NextChar { c: 'a', place_before: false, direction: d },
+                MotionType::NextChar { c: 'a', place_before: true, direction: d },
+                MotionType::NextChar { c: 'q', place_before: true, direction: d }
+            ]);
+            for rel in [IndentRelation::Less, IndentRelation::LessOrEqual, IndentRelation::Equal, IndentRelation::Greater].iter() {
+                types.push(MotionType::Indent(d, *rel));
+            }
+        }
+        types
+    }
+
+    #[test]
+    fn every_motion_at_the_ends() {
+        for text in ["abc def\n  (ghi)\n", "a", "", "\n\n"].iter() {
+            let len = Buffer::with_text(text).text.len();
+            for mo in every_motion_type() {
+                for cursor_index in [0, len].iter().cloned() {
+                    let mut b = Buffer::with_text(text);
+                    b.last_char_query = Some(('c', true, Direction::Backward));
+                    let mo = Motion { mo, count: 2 };
+                    let r = mo.range(&mut b, cursor_index, 1);
+                    let or = mo.operator_range(&mut b, cursor_index, 1);
+                    assert!(r.start <= len && r.end <= len, "{:?} in {:?} at {} went to {:?}", mo, text, cursor_index, r);
+                    assert!(or.start <= len && or.end <= len, "{:?} in {:?} at {} acts on {:?}", mo, text, cursor_index, or);
+                }
+            }
+        }
+        let mut b = Buffer::with_text("ab\n  c");
+        let at = |b: &mut Buffer, mo: MotionType, cursor_index: usize| Motion { mo, count: 1 }.range(b, cursor_index, 1).end;
+        assert_eq!(at(&mut b, MotionType::Char(Direction::Backward), 0), 0);
+        assert_eq!(at(&mut b, MotionType::Char(Direction::Forward), 6), 6);
+        assert_eq!(at(&mut b, MotionType::Word(Direction::Backward), 0), 0);
+        assert_eq!(at(&mut b, MotionType::EndOfWord(Direction::Forward), 6), 6);
+        assert_eq!(at(&mut b, MotionType::NextChar { c: 'a', place_before: false, direction: Direction::Backward }, 0), 0);
+        assert_eq!(at(&mut b, MotionType::NextChar { c: 'q', place_before: true, direction: Direction::Forward }, 0), 0,
+            "missing characters don't move the cursor");
+        assert_eq!(at(&mut b, MotionType::NextChar { c: 'a', place_before: true, direction: Direction::Backward }, 6), 6,
+            "the character is on another line");
+        assert_eq!(at(&mut b, MotionType::NextChar { c: 'a', place_before: true, direction: Direction::Backward }, 2), 1);
+        assert_eq!(at(&mut b, MotionType::StartOfLine, 7), 5);
+        assert_eq!(at(&mut b, MotionType::StartOfLine, 1), 0);
+    }
+
+    #[test]
+    fn txo_search_word() {
+        let mut b = Buffer::with_text("foo bar foobar foo. foo");
+        let mo = Motion { mo: MotionType::SearchWord(Direction::Forward), count: 1 };
+        let mut ci = 1;
+        run_repeated_test(&mut b, &mut ci, &mo, [15,20,0].iter(), "forward");
+        assert_eq!(b.current_query.as_deref(), Some("foo"));
+        let mo = Motion { mo: MotionType::SearchWord(Direction::Backward), count: 1 };
+        run_repeated_test(&mut b, &mut ci, &mo, [20,15,0].iter(), "backward");
+        let mo = Motion { mo: MotionType::NextSearchMatch(Direction::Forward), count: 2 };
+        assert_eq!(mo.range(&mut b, 0, 1).end, 20, "n repeats with the count");
+        // off a word, nothing happens
+        let mo = Motion { mo: MotionType::SearchWord(Direction::Forward), count: 1 };
+        assert_eq!(mo.range(&mut b, 3, 1), 3..3);
+    }
+
     #[test]
     fn txo_find_next_on() {
         let mut b = Buffer::with_text("so!me s!ample tex!t");
@@ -673,6 +1017,18 @@ mod tests {
         run_repeated_test(&mut b, &mut ci, &mo, correct.iter().rev().skip(1), "backward, place on");
     }
 
+    #[test]
+    fn find_char_stays_on_line() {
+        let mut b = Buffer::with_text("let x = 1;\nfoo(x);\n");
+        let f = |c| Motion { mo: MotionType::NextChar { c, place_before: false, direction: Direction::Forward }, count: 1 };
+        let t = |c| Motion { mo: MotionType::NextChar { c, place_before: true, direction: Direction::Forward }, count: 1 };
+        assert_eq!(f('x').range(&mut b, 0, 1).end, 4, "on the same line");
+        assert_eq!(f('(').range(&mut b, 0, 1).end, 0, "only on a later line, so the cursor stays");
+        assert_eq!(f('x').range(&mut b, 4, 1).end, 4, "the next x is on the next line");
+        assert_eq!(t(';').range(&mut b, 0, 1).end, 8, "t lands before the target");
+        assert_eq!(t(')').range(&mut b, 11, 1).end, 15);
+    }
+
     #[test]
     fn txo_find_next_before() {
         let mut b = Buffer::with_text("so!me s!ample tex!t");
@@ -758,6 +1114,29 @@ mod tests {
         assert_eq!(mo.range(&b, 6, 1), 5..6);
     }
 
+    #[test]
+    fn txo_object_paragraph() {
+        let mut b = Buffer::with_text("a\nb\n\n\nc\nd\n");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Paragraph), count: 1 };
+        assert_eq!(mo.range(&b, 0, 1), 0..3);
+        assert_eq!(mo.range(&b, 4, 1), 4..5, "on blank lines");
+        assert_eq!(mo.range(&b, 0, 2), 0..5);
+        mo.mo = MotionType::An(TextObject::Paragraph);
+        assert_eq!(mo.range(&b, 2, 1), 0..5);
+        assert_eq!(mo.range(&b, 6, 1), 4..9, "last paragraph takes the blank lines before it");
+    }
+
+    #[test]
+    fn txo_object_unmatched_block() {
+        let mut b = Buffer::with_text("a (b c");
+        let mo = Motion { mo: MotionType::Inner(TextObject::Block('(')), count: 1 };
+        assert_eq!(mo.range(&b, 4, 1), 5..4);
+        assert_eq!(mo.range(&b, 0, 1), 1..0);
+        // an opening bracket at the very end has nothing after it to search
+        let mut b = Buffer::with_text("a (");
+        assert_eq!(mo.range(&b, 2, 1), 3..2);
+    }
+
     #[test]
     fn txo_object_a_block() {
         let mut b = Buffer::with_text("<(bl(o)ck) {\nblock\n}>");
@@ -810,6 +1189,47 @@ mod tests {
         assert_eq!(mo.range(&b, cursor_index, 1), 12..18, "in {{");
     }
 
+    #[test]
+    fn txo_object_tag() {
+        let mut b = Buffer::with_text("<div><div a=\"1\">x<br>y</div><p>z</p></div>");
+        let an = |count| Motion { mo: MotionType::An(TextObject::Tag), count };
+        let inner = |count| Motion { mo: MotionType::Inner(TextObject::Tag), count };
+
+        assert_eq!(inner(1).range(&b, 16, 1), 16..21, "in nested div");
+        assert_eq!(an(1).range(&b, 16, 1), 5..27, "in nested div");
+        assert_eq!(inner(2).range(&b, 16, 1), 5..35, "in outer div");
+        assert_eq!(an(2).range(&b, 16, 1), 0..41, "in outer div");
+        assert_eq!(an(1).range(&b, 31, 1), 28..35, "in p");
+        assert_eq!(inner(1).range(&b, 31, 1), 31..31, "in p");
+        assert_eq!(an(2).range(&b, 31, 1), 0..41, "around p");
+        assert_eq!(an(1).range(&b, 3, 1), 0..41, "on opening tag");
+        assert_eq!(an(1).range(&b, 39, 1), 0..41, "on closing tag");
+        assert_eq!(an(3).range(&b, 16, 1), 17..16, "past outermost tag");
+    }
 
-}
+    #[test]
+    fn txo_object_tag_self_closing() {
+        let mut b = Buffer::with_text("<a><b/>c</a>");
+        let mut mo = Motion { mo: MotionType::Inner(TextObject::Tag), count: 1 };
+        assert_eq!(mo.range(&b, 5, 1), 3..7);
+        mo.mo = MotionType::An(TextObject::Tag);
+        assert_eq!(mo.range(&b, 5, 1), 0..11);
+
+        let mut b = Buffer::with_text("<a></a> plain");
+        mo.mo = MotionType::Inner(TextObject::Tag);
+        assert_eq!(mo.range(&b, 0, 1), 3..2);
+        assert_eq!(mo.range(&b, 9, 1), 10..9);
+    }
 
+    #[test]
+    fn txo_passthrough() {
+        let mut b = Buffer::with_text("abc def ghi\n");
+        let mo = Motion::passthrough(&(2..6));
+        // the cursor and count don't matter
+        assert_eq!(mo.range(&b, 9, 3), 2..6);
+        assert_eq!(mo.operator_range(&mut b, 0, 2), 2..7);
+        // a selection made backwards covers the same text
+        assert_eq!(Motion::passthrough(&Range { start: 6, end: 2 }).operator_range(&mut b, 0, 1), 2..7);
+        assert_eq!(Motion::passthrough(&(3..3)).operator_range(&mut b, 0, 1), 3..4);
+    }
+}
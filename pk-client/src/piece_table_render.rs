@@ -29,25 +29,104 @@ impl CursorStyleDraw for CursorStyle {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 enum HighlightType {
     Foreground(ColorschemeSel),
 }
 
+/// Where a highlight came from, which determines its default priority
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HighlightKind {
+    Syntax,
+    Search,
+    Selection
+}
+
+impl HighlightKind {
+    pub fn default_priority(&self) -> u8 {
+        match self {
+            HighlightKind::Syntax => 0,
+            HighlightKind::Search => 128,
+            HighlightKind::Selection => 192
+        }
+    }
+}
+
 use std::ops::Range;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Highlight {
     pub range: Range<usize>,
+    pub kind: HighlightKind,
+    /// when highlights overlap, the one with the highest priority wins
+    pub priority: u8,
     sort: HighlightType
 }
 
 impl Highlight {
     pub fn foreground(range: Range<usize>, sel: ColorschemeSel) -> Highlight {
+        Highlight::new(range, sel, HighlightKind::Syntax)
+    }
+
+    pub fn new(range: Range<usize>, sel: ColorschemeSel, kind: HighlightKind) -> Highlight {
         Highlight {
-            range, sort: HighlightType::Foreground(sel)
+            range, kind, priority: kind.default_priority(), sort: HighlightType::Foreground(sel)
         }
     }
+
+    pub fn with_priority(mut self, priority: u8) -> Highlight {
+        self.priority = priority;
+        self
+    }
+
+    /// Resolve overlapping highlights so that every index is covered by at most one highlight.
+    /// Where highlights overlap the one with the higher priority wins, and ties go to whichever
+    /// came first. The lower priority highlight is split around the winner rather than dropped
+    /// entirely. The result is sorted by the start of each range.
+    pub fn resolve_overlaps(hi: Vec<Highlight>) -> Vec<Highlight> {
+        use std::collections::BinaryHeap;
+        use std::cmp::Reverse;
+        // every place a highlight starts or ends, with ends first so that a highlight ending
+        // where another starts doesn't cover it
+        let mut bounds: Vec<(usize, bool, usize)> = hi.iter().enumerate()
+            .filter(|(_, h)| h.range.start < h.range.end)
+            .flat_map(|(i, h)| vec![(h.range.start, true, i), (h.range.end, false, i)])
+            .collect();
+        bounds.sort_unstable();
+        // the highlights covering the current position, best first. Ones that have ended are
+        // only removed once they get to the top
+        let mut covering = BinaryHeap::new();
+        let mut ended = vec![false; hi.len()];
+        let mut res: Vec<Highlight> = Vec::with_capacity(hi.len());
+        let mut last_winner = None;
+        let mut bounds = bounds.into_iter().peekable();
+        while let Some((at, starts, i)) = bounds.next() {
+            if starts {
+                covering.push((hi[i].priority, Reverse(i)));
+            } else {
+                ended[i] = true;
+            }
+            let next = match bounds.peek() {
+                Some(&(next, ..)) if next == at => continue,
+                Some(&(next, ..)) => next,
+                None => break
+            };
+            while let Some(&(_, Reverse(w))) = covering.peek() {
+                if !ended[w] { break; }
+                covering.pop();
+            }
+            if let Some(&(_, Reverse(w))) = covering.peek() {
+                match res.last_mut() {
+                    Some(r) if last_winner == Some(w) && r.range.end == at => r.range.end = next,
+                    _ => {
+                        res.push(Highlight { range: at..next, ..hi[w].clone() });
+                        last_winner = Some(w);
+                    }
+                }
+            }
+        }
+        res
+    }
 }
 
 impl HighlightType {
@@ -60,16 +139,102 @@ impl HighlightType {
     }
 }
 
+/// the bounds of the character at byte `index` in `ln` relative to the start of the line, laid
+/// out in screen columns `em_bounds` wide so that wide characters cover two columns. The end of
+/// the line is one column wide
+fn column_bounds(ln: &str, index: usize, em_bounds: &Rect, tabstop: usize) -> Rect {
+    let col = line_columns(&ln[..index.min(ln.len())], 0, tabstop);
+    let width = ln[index.min(ln.len())..].chars().next()
+        .map_or(1, |c| crate::buffer::display_width(c, col, tabstop).max(1));
+    Rect::xywh(col as f32 * em_bounds.w, 0.0, width as f32 * em_bounds.w, em_bounds.h)
+}
+
+/// the screen column `ln` ends at if it starts at column `start`
+fn line_columns(ln: &str, start: usize, tabstop: usize) -> usize {
+    ln.chars().fold(start, |col, c| col + crate::buffer::display_width(c, col, tabstop))
+}
+
+/// whether a line `columns` wide gets cut off after `threshold` columns, which never happens to the
+/// line with the cursor so that it can always be seen whole while editing it
+pub fn truncate_line(columns: usize, threshold: Option<usize>, cursor_line: bool) -> bool {
+    !cursor_line && threshold.map_or(false, |t| columns > t)
+}
+
+/// the byte index in `ln` of the first character that starts at or after screen column `col`, if
+/// `ln` starts at column `start`
+fn index_for_column(ln: &str, start: usize, col: usize, tabstop: usize) -> usize {
+    let mut c = start;
+    for (i, ch) in ln.char_indices() {
+        if c >= col { return i; }
+        c += crate::buffer::display_width(ch, c, tabstop);
+    }
+    ln.len()
+}
+
+/// the first screen column to show in a pane `width` wide so that `column` is visible, moving
+/// `offset` as little as possible
+pub fn horizontal_offset(offset: usize, column: usize, width: f32, em_width: f32) -> usize {
+    let columns = ((width / em_width).floor() as usize).max(1);
+    if column < offset {
+        column
+    } else if column >= offset + columns {
+        column + 1 - columns
+    } else {
+        offset
+    }
+}
+
 use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
+fn line_hash(ln: &str) -> u64 {
+    let mut hh = DefaultHasher::new();
+    ln.hash(&mut hh);
+    hh.finish()
+}
+
+/// layouts of lines keyed by the index of the start of the line, along with a hash of the text
+/// they were made from. Highlights are baked into each layout, so an entry is only good while
+/// the text before it hasn't changed length
+struct LayoutCache<L> {
+    lines: HashMap<usize, (u64, L)>
+}
+
+impl<L: Clone> LayoutCache<L> {
+    fn new() -> Self {
+        LayoutCache { lines: HashMap::new() }
+    }
+
+    fn get(&self, global_index: usize, ln_hash: u64) -> Option<L> {
+        self.lines.get(&global_index).filter(|(h, _)| *h == ln_hash).map(|(_, ly)| ly.clone())
+    }
+
+    fn insert(&mut self, global_index: usize, ln_hash: u64, layout: L) {
+        self.lines.insert(global_index, (ln_hash, layout));
+    }
+
+    fn invalidate(&mut self, rn: Range<usize>) {
+        self.lines.retain(|i, _| !rn.contains(i));
+    }
+
+    fn invalidate_from(&mut self, start: usize) {
+        self.lines.retain(|i, _| *i < start);
+    }
+}
+
 pub struct PieceTableRenderer {
     fnt: Font,
     pub em_bounds: Rect,
     pub cursor_style: CursorStyle,
     pub highlight_line: bool,
-    layout_cashe: HashMap<usize, (u64, TextLayout)>
+    /// whether the pane being painted is dimmed, which keeps its layouts apart from the others'
+    /// since their colors are baked in
+    pub dimmed: bool,
+    /// how many columns apart tab stops are in the text being painted
+    pub tabstop: usize,
+    layout_cashe: LayoutCache<TextLayout>,
+    dimmed_layout_cashe: LayoutCache<TextLayout>
 }
 
 impl PieceTableRenderer {
@@ -79,14 +244,23 @@ impl PieceTableRenderer {
             em_bounds,
             cursor_style: CursorStyle::Underline,
             highlight_line: true,
-            layout_cashe: HashMap::new()
+            dimmed: false,
+            tabstop: crate::config::DEFAULT_TABSTOP,
+            layout_cashe: LayoutCache::new(),
+            dimmed_layout_cashe: LayoutCache::new()
         }
     }
     
     pub fn invalidate_layout_cashe(&mut self, rn: Range<usize>) {
-        for i in rn {
-            self.layout_cashe.remove(&i);
-        }
+        self.dimmed_layout_cashe.invalidate(rn.clone());
+        self.layout_cashe.invalidate(rn);
+    }
+
+    /// forget the layouts of every line starting at or after `start`, since an edit there moves
+    /// where all of the following lines start
+    pub fn invalidate_layout_cashe_from(&mut self, start: usize) {
+        self.layout_cashe.invalidate_from(start);
+        self.dimmed_layout_cashe.invalidate_from(start);
     }
 
     pub fn viewport_end(&self, viewport_start: usize, bounds: &Rect) -> usize {
@@ -94,26 +268,30 @@ impl PieceTableRenderer {
     }
 
     fn generate_line_layout(&mut self, ln: &str, global_index: usize, rx: &mut RenderContext, colors: &Colorscheme, highlights: Option<&Vec<Highlight>>) -> TextLayout {
-        let mut hh = DefaultHasher::new();
-        ln.hash(&mut hh);
-        let ln_hash = hh.finish();
-        if let Some((cashe_line_hash, ly)) = self.layout_cashe.get(&global_index) {
-            if ln_hash == *cashe_line_hash {
-                return ly.clone();
-            }
+        let ln_hash = line_hash(ln);
+        let cache = if self.dimmed { &self.dimmed_layout_cashe } else { &self.layout_cashe };
+        if let Some(ly) = cache.get(global_index, ln_hash) {
+            return ly;
         }
         let layout = rx.new_text_layout(ln, &self.fnt, 10000.0, 10000.0).expect("create text layout");
         if let Some(hl) = highlights.as_ref() {
+            let mut line_hl = Vec::new();
             for h in hl.iter() {
                 if h.range.start > global_index + ln.len() { break; }
                 if h.range.start < global_index && h.range.end < global_index { continue; }
+                line_hl.push(h);
+            }
+            // apply lower priorities first so that higher priority highlights end up on top
+            line_hl.sort_by_key(|h| h.priority);
+            for h in line_hl {
                 // that subtraction of h.range.end and global_index looks real sketchy
                 let range = h.range.start.saturating_sub(global_index) .. h.range.end.saturating_sub(global_index).min(ln.len());
                 //if range.len() == 0 { continue; }
                 h.sort.apply_to_layout(range, rx, &layout, colors);
             }
         }
-        self.layout_cashe.insert(global_index, (ln_hash, layout.clone()));
+        let cache = if self.dimmed { &mut self.dimmed_layout_cashe } else { &mut self.layout_cashe };
+        cache.insert(global_index, ln_hash, layout.clone());
         layout
     }
 
@@ -122,40 +300,56 @@ impl PieceTableRenderer {
         if *viewport_start >= line { *viewport_start = line.saturating_sub(1); }
         if viewport_end <= line { *viewport_start += line - viewport_end; }
     }
+
+    pub fn ensure_column_visible(&self, offset: &mut usize, column: usize, bounds: Rect, line_numbers: bool) {
+        let width = if line_numbers { bounds.w - self.em_bounds.w * 7.0 } else { bounds.w };
+        *offset = horizontal_offset(*offset, column, width, self.em_bounds.w);
+    }
     
-    fn paint_line_numbers(&mut self, rx: &mut RenderContext, config: &Config, cur_pos: &mut Point, line_num: usize) {
-        rx.set_color(config.colors.quarter_gray);
+    fn paint_line_numbers(&mut self, rx: &mut RenderContext, colors: &Colorscheme, cur_pos: &mut Point, line_num: usize) {
+        rx.set_color(colors.quarter_gray);
         rx.draw_text(Rect::xywh(cur_pos.x, cur_pos.y, self.em_bounds.w*5.0 + 10.0, self.em_bounds.h),
             &format!("{:5}", line_num), &self.fnt);
-        rx.set_color(config.colors.foreground);
+        rx.set_color(colors.foreground);
         cur_pos.x += self.em_bounds.w * 7.0;
     }
     
-    fn paint_visual_selection(&mut self, rx: &mut RenderContext, config: &Config, cur_pos: &Point, layout: &TextLayout, cur_range: Range<usize>, sel_range: &Range<usize>) {
+    fn paint_visual_selection(&mut self, rx: &mut RenderContext, colors: &Colorscheme, cur_pos: &Point, ln: &str, cur_range: Range<usize>, sel_range: &Range<usize>) {
         if sel_range.start < cur_range.start && sel_range.end < cur_range.start { return; } // skip if the selection is totally before the current range
         if sel_range.start > cur_range.end   && sel_range.end > cur_range.end   { return; } // skip if the selection is totally after the current range
         let start = cur_range.start.max(sel_range.start);
         let end   = cur_range.end  .min(sel_range.end);
         if start >= end { return; }
-        let start_rect = layout.char_bounds(start - cur_range.start);
-        let end_rect = layout.char_bounds(end - cur_range.start);
+        let start_rect = column_bounds(ln, start - cur_range.start, &self.em_bounds, self.tabstop);
+        let end_rect = column_bounds(ln, end - cur_range.start, &self.em_bounds, self.tabstop);
         let r = Rect::pnwh(*cur_pos + Point::xy(start_rect.x, 0.0), end_rect.x-start_rect.x + end_rect.w, start_rect.h.max(end_rect.h));
-        rx.set_color(config.colors.three_quarter_gray.with_alpha(0.4));
+        rx.set_color(colors.three_quarter_gray.with_alpha(0.4));
         rx.fill_rect(r);
-        rx.set_color(config.colors.foreground);
+        rx.set_color(colors.foreground);
     }
 
     pub fn paint(&mut self, rx: &mut RenderContext, table: &PieceTable,
-                 viewport_start: usize, cursor_index: usize, config: &Config, bounds: Rect,
-                 highlights: Option<&Vec<Highlight>>, line_numbers: bool, selection: Option<&Range<usize>>)
+                 viewport_start: usize, horizontal_offset: usize, cursor_index: usize, config: &Config, colors: &Colorscheme, bounds: Rect,
+                 highlights: Option<&Vec<Highlight>>, line_numbers: bool, selection: Option<(&Range<usize>, bool)>,
+                 folds: &[Range<usize>])
     {
-        rx.set_color(config.colors.foreground);
+        rx.set_color(colors.foreground);
         let mut global_index = 0usize;
         let mut cur_pos = Point::xy(bounds.x, bounds.y); 
         if line_numbers { cur_pos.x += self.em_bounds.w * 7.0; }
         let mut line_num = 0usize;
-        let viewport_end = self.viewport_end(viewport_start, &bounds);
+        let rows = self.viewport_end(viewport_start, &bounds) - viewport_start;
+        let mut row = 0usize;
+        let mut folds = folds.iter().skip_while(|f| f.end <= viewport_start).peekable();
         let table_len = table.len();
+        let cursor_line = table.last_index_of('\n', cursor_index).map_or(0, |i| i+1)
+            ..= table.index_of('\n', cursor_index).unwrap_or(table_len);
+        // the current line can be split across pieces, so this is the column of the line the
+        // next piece of it starts at, the columns of it drawn so far, and whether the rest of it
+        // has been cut off. Tabs in later pieces are measured from where the piece starts
+        let mut line_col = 0usize;
+        let mut line_cols = 0usize;
+        let mut truncated = false;
         //self.paint_start_of_line(rx, &mut cur_pos, line_num);
         'top: for p in table.pieces.iter() {
             if p.length == 0 { continue; }
@@ -166,7 +360,11 @@ impl PieceTableRenderer {
                 if ln.is_none() { break; }
                 let ln = ln.unwrap();
                 
-                if line_num < viewport_start {
+                // lines hidden inside of a closed fold
+                while folds.peek().map_or(false, |f| f.end <= line_num) { folds.next(); }
+                let hidden = folds.peek().map_or(false, |f| f.start < line_num);
+
+                if line_num < viewport_start || hidden {
                     if lni.peek().is_some() {
                         line_num+=1; 
                         global_index += 1;
@@ -174,37 +372,82 @@ impl PieceTableRenderer {
                     global_index += ln.len();
                     continue;
                 }
+
+                // scrolling horizontally skips the start of the line, even if that is in an earlier piece
+                let skip = index_for_column(ln, line_col, horizontal_offset, self.tabstop);
+                let start_col = line_columns(&ln[..skip], line_col, self.tabstop);
+                line_col = line_columns(ln, line_col, self.tabstop);
+                let ln = &ln[skip..];
+                global_index += skip;
+
+                // very long lines are cut off, so the rest of them is skipped over
+                let full_len = ln.len();
+                let cols = line_col - start_col;
+                let cut = !truncated && truncate_line(line_cols + cols, config.truncate_lines, cursor_line.contains(&global_index));
+                let ln = if truncated {
+                    &ln[..0]
+                } else if cut {
+                    &ln[..index_for_column(ln, start_col, start_col + config.truncate_lines.unwrap_or(0).saturating_sub(line_cols), self.tabstop)]
+                } else {
+                    ln
+                };
+                line_cols += cols;
                 
-                let layout = self.generate_line_layout(ln, global_index, rx, &config.colors, highlights);
+                let layout = self.generate_line_layout(ln, global_index, rx, colors, highlights);
                 rx.draw_text_layout(cur_pos, &layout);
                 
-                if selection.is_some() {
-                    self.paint_visual_selection(rx, config, &mut cur_pos, &layout, global_index .. global_index+ln.len(), selection.as_ref().unwrap());
+                if let Some((sel_range, linewise)) = selection {
+                    self.paint_visual_selection(rx, colors, &mut cur_pos, ln, global_index .. global_index+ln.len(), sel_range);
+                    // linewise selections cover the rest of the line too
+                    let eol = global_index+full_len;
+                    if linewise && lni.peek().is_some() && sel_range.start <= eol && eol <= sel_range.end {
+                        let x = cur_pos.x + layout.bounds().w;
+                        rx.set_color(colors.three_quarter_gray.with_alpha(0.4));
+                        rx.fill_rect(Rect::xywh(x, cur_pos.y, (bounds.x + bounds.w - x).max(0.0), self.em_bounds.h));
+                        rx.set_color(colors.foreground);
+                    }
                 }
                 
                 if cursor_index >= global_index && cursor_index < global_index+ln.len() ||
                     ((lni.peek().is_some() || cursor_index == table_len) && cursor_index == global_index+ln.len()) {
-                    let curbounds = layout.char_bounds(cursor_index - global_index).offset(cur_pos);
-                    self.cursor_style.paint(rx, &curbounds, &self.em_bounds, config.colors.foreground);
+                    let curbounds = column_bounds(ln, cursor_index - global_index, &self.em_bounds, self.tabstop).offset(cur_pos);
+                    self.cursor_style.paint(rx, &curbounds, &self.em_bounds, colors.foreground);
                     if self.highlight_line {
-                        rx.set_color(config.colors.half_gray.with_alpha(0.1));
+                        rx.set_color(colors.half_gray.with_alpha(0.1));
                         rx.fill_rect(Rect::xywh(bounds.x, cur_pos.y, bounds.w, self.em_bounds.h));
-                        rx.set_color(config.colors.foreground);
+                        rx.set_color(colors.foreground);
                     }
                 }
                 
                 let text_size = layout.bounds();
                 cur_pos.x += text_size.w; 
-                global_index += ln.len();
+                if cut {
+                    rx.set_color(colors.half_gray);
+                    rx.draw_text(Rect::xywh(cur_pos.x, cur_pos.y, bounds.w, self.em_bounds.h), "…", &self.fnt);
+                    rx.set_color(colors.foreground);
+                    cur_pos.x += self.em_bounds.w;
+                    truncated = true;
+                }
+                global_index += full_len;
                 if lni.peek().is_some() {
+                    line_col = 0;
+                    line_cols = 0;
+                    truncated = false;
+                    if let Some(f) = folds.peek().filter(|f| f.start == line_num) {
+                        rx.set_color(colors.half_gray);
+                        rx.draw_text(Rect::xywh(cur_pos.x, cur_pos.y, bounds.w, self.em_bounds.h),
+                            &format!(" +-- {} lines", f.len()), &self.fnt);
+                        rx.set_color(colors.foreground);
+                    }
                     // new line
                     line_num+=1;
+                    row+=1;
                     cur_pos.x = bounds.x;
                     // paint the line numbers for the line that we just drawed
-                    if line_numbers { self.paint_line_numbers(rx, config, &mut cur_pos, line_num); }
+                    if line_numbers { self.paint_line_numbers(rx, colors, &mut cur_pos, line_num); }
                     cur_pos.y += text_size.h.min(self.em_bounds.h);
                     global_index += 1;
-                    if line_num > viewport_end { break 'top; }
+                    if row > rows { break 'top; }
                     //if cur_pos.y + text_size.h > bounds.h { break; }
                 } else {
                     break;
@@ -213,3 +456,131 @@ impl PieceTableRenderer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_character_columns() {
+        let em = Rect::xywh(0.0, 0.0, 8.0, 16.0);
+        let ln = "a日本b\u{301}c";
+        let x_w = |i| { let r = column_bounds(ln, i, &em, 4); (r.x, r.w) };
+        assert_eq!(x_w(0), (0.0, 8.0));
+        assert_eq!(x_w(1), (8.0, 16.0), "wide characters are two columns");
+        assert_eq!(x_w(4), (24.0, 16.0));
+        assert_eq!(x_w(7), (40.0, 8.0));
+        assert_eq!(x_w(10), (48.0, 8.0), "combining marks take no space");
+        assert_eq!(x_w(ln.len()), (56.0, 8.0), "end of the line");
+        let ln = "🚀\u{483}x";
+        assert_eq!(column_bounds(ln, 0, &em, 4).w, 16.0, "every wide character, not just common ones");
+        assert_eq!(column_bounds(ln, ln.len() - 1, &em, 4).x, 16.0, "every combining mark too");
+        assert_eq!(column_bounds("a\tb", 2, &em, 2).x, 16.0, "tabs go to the next tab stop");
+        assert_eq!(column_bounds("a\tb", 2, &em, 8).x, 64.0);
+    }
+
+    #[test]
+    fn truncating_long_lines() {
+        assert!(!truncate_line(80, None, false), "no threshold");
+        assert!(!truncate_line(80, Some(100), false));
+        assert!(!truncate_line(100, Some(100), false), "exactly at the threshold");
+        assert!(truncate_line(101, Some(100), false));
+        assert!(truncate_line(50000, Some(100), false));
+        assert!(!truncate_line(50000, Some(100), true), "the cursor line is drawn whole");
+        assert_eq!(line_columns("a\u{65e5}\u{672c}b", 0, 4), 6);
+        // a piece of a line that starts partway along it lines its tabs up with the whole line
+        assert_eq!(line_columns("\tb", 0, 4), 5);
+        assert_eq!(line_columns("\tb", 3, 4), 5);
+        assert_eq!(line_columns("\tb", 4, 4), 9);
+    }
+
+    #[test]
+    fn horizontal_scrolling() {
+        // 10 columns of 8px each
+        assert_eq!(horizontal_offset(0, 5, 80.0, 8.0), 0);
+        assert_eq!(horizontal_offset(0, 9, 80.0, 8.0), 0);
+        assert_eq!(horizontal_offset(0, 10, 84.0, 8.0), 1, "partial columns don't count");
+        assert_eq!(horizontal_offset(0, 25, 80.0, 8.0), 16);
+        assert_eq!(horizontal_offset(16, 20, 80.0, 8.0), 16, "stays put while the cursor is visible");
+        assert_eq!(horizontal_offset(16, 3, 80.0, 8.0), 3);
+        assert_eq!(horizontal_offset(4, 7, 4.0, 8.0), 7, "always shows at least a column");
+
+        assert_eq!(index_for_column("abc", 0, 2, 4), 2);
+        assert_eq!(index_for_column("日本語", 0, 2, 4), 3);
+        assert_eq!(index_for_column("日本語", 0, 3, 4), 6, "starts after a wide character that is cut off");
+        assert_eq!(index_for_column("ab", 0, 5, 4), 2);
+        assert_eq!(index_for_column("\tab", 2, 4, 4), 1, "the tab only reaches the next tab stop");
+        assert_eq!(index_for_column("ab", 3, 2, 4), 0, "before the start");
+    }
+
+    #[test]
+    fn layout_cache_line_insertion() {
+        let mut cache = LayoutCache::new();
+        let cache_lines = |cache: &mut LayoutCache<String>, text: &str| {
+            let mut i = 0;
+            for ln in text.split('\n') {
+                if cache.get(i, line_hash(ln)).is_none() {
+                    cache.insert(i, line_hash(ln), format!("{}@{}", ln, i));
+                }
+                i += ln.len() + 1;
+            }
+        };
+        cache_lines(&mut cache, "ab\ncd\nab\ncd");
+        // inserting "cd\n" after the first line makes the third line start where the second did
+        cache.invalidate_from(3);
+        assert_eq!(cache.get(0, line_hash("ab")), Some("ab@0".into()));
+        assert_eq!(cache.get(3, line_hash("cd")), None);
+        assert_eq!(cache.get(6, line_hash("ab")), None, "lines after the edit are stale too");
+        cache_lines(&mut cache, "ab\ncd\ncd\nab\ncd");
+        assert_eq!(cache.get(9, line_hash("ab")), Some("ab@9".into()));
+        cache.invalidate(3..9);
+        assert_eq!(cache.lines.keys().copied().collect::<std::collections::BTreeSet<_>>(),
+            [0, 9, 12].iter().copied().collect());
+    }
+
+    #[test]
+    fn resolve_by_priority() {
+        // a comment containing a keyword, where the keyword was found first
+        let hi = Highlight::resolve_overlaps(vec![
+            Highlight::foreground(3..5, ColorschemeSel::Accent(0)),
+            Highlight::foreground(0..9, ColorschemeSel::HalfGray).with_priority(2),
+        ]);
+        assert_eq!(hi, vec![Highlight::foreground(0..9, ColorschemeSel::HalfGray).with_priority(2)]);
+    }
+
+    #[test]
+    fn resolve_ties_first_wins() {
+        let hi = Highlight::resolve_overlaps(vec![
+            Highlight::foreground(0..4, ColorschemeSel::Accent(0)),
+            Highlight::foreground(2..6, ColorschemeSel::Accent(1)),
+        ]);
+        assert_eq!(hi, vec![
+            Highlight::foreground(0..4, ColorschemeSel::Accent(0)),
+            Highlight::foreground(4..6, ColorschemeSel::Accent(1)),
+        ]);
+    }
+
+    #[test]
+    fn selection_overrides_syntax() {
+        let hi = Highlight::resolve_overlaps(vec![
+            Highlight::foreground(0..10, ColorschemeSel::Accent(3)),
+            Highlight::new(4..6, ColorschemeSel::Foreground, HighlightKind::Selection),
+        ]);
+        assert_eq!(hi, vec![
+            Highlight::foreground(0..4, ColorschemeSel::Accent(3)),
+            Highlight::new(4..6, ColorschemeSel::Foreground, HighlightKind::Selection),
+            Highlight::foreground(6..10, ColorschemeSel::Accent(3)),
+        ]);
+    }
+
+    #[test]
+    fn resolve_many_overlaps() {
+        // a search match in every other place of a file highlighted as one big comment
+        let mut hi = vec![Highlight::foreground(0..200_000, ColorschemeSel::HalfGray)];
+        hi.extend((0..100_000).map(|i| Highlight::new(i*2..i*2+1, ColorschemeSel::Accent(2), HighlightKind::Search)));
+        let hi = Highlight::resolve_overlaps(hi);
+        assert_eq!(hi.len(), 200_000);
+        assert!(hi.windows(2).all(|w| w[0].range.end == w[1].range.start && w[0].kind != w[1].kind));
+        assert_eq!(hi[1], Highlight::foreground(1..2, ColorschemeSel::HalfGray));
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use futures::prelude::*;
 use pk_common::protocol;
 use super::Error;
@@ -31,7 +32,50 @@ impl Future for FutureResponse {
     }
 }
 
+/// how many pings in a row a server can leave unanswered before it is considered gone
+pub const MAX_MISSED_PINGS: usize = 3;
+
+/// The connection that a `Heartbeat` pings a server over
+pub trait PingTransport {
+    /// send a ping, which gets answered some time later
+    fn send_ping(&mut self) -> Result<(), Error>;
+    /// true if a pong has arrived since the last time this was called
+    fn take_pong(&mut self) -> bool;
+}
+
+/// Keeps track of whether a server is still answering pings
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    missed: usize,
+    waiting: bool
+}
+
+impl Heartbeat {
+    /// check whether the last ping was answered and send the next one. Returns false once
+    /// `max_missed` pings in a row have gone unanswered
+    pub fn beat(&mut self, transport: &mut impl PingTransport, max_missed: usize) -> bool {
+        if self.waiting && !transport.take_pong() {
+            self.missed += 1;
+        } else {
+            self.missed = 0;
+        }
+        if self.missed >= max_missed {
+            return false;
+        }
+        // a ping that fails to send counts as missed the next time around
+        self.waiting = true;
+        if let Err(e) = transport.send_ping() {
+            println!("error sending ping: {}", e);
+        }
+        true
+    }
+}
+
 pub struct Server {
+    /// the URL the server was connected to, for reconnecting to it
+    pub url: String,
+    heartbeat: Heartbeat,
+    pong: Arc<AtomicBool>,
     socket: nng::Socket,
     responses: Arc<Mutex<HashMap<protocol::MessageId, protocol::Response>>>,
     wakers: Arc<Mutex<HashMap<protocol::MessageId, futures::task::Waker>>>,
@@ -90,31 +134,104 @@ impl Server {
     }
 
     pub fn init(url: &str, thread_pool: futures::executor::ThreadPool) -> Result<Server, Error> {
-        let socket = nng::Socket::new(nng::Protocol::Req0).map_err(Error::from_other)?;
+        let socket = nng::Socket::new(nng::Protocol::Req0)?;
 
         let responses = Arc::new(Mutex::new(HashMap::new()));
         let wakers = Arc::new(Mutex::new(HashMap::new()));
 
-        socket.dial(url).map_err(Error::from_other)?;
+        socket.dial(url)?;
 
         Ok(Server {
+            url: url.to_owned(), heartbeat: Heartbeat::default(), pong: Arc::new(AtomicBool::new(false)),
             responses, wakers, socket, next_msg_id: protocol::MessageId(1),
             thread_pool
         })
     }
 
-    pub fn request(&mut self, msg: protocol::Request) -> impl Future<Output=protocol::Response> {
+    /// ping the server, returning false if it has stopped answering
+    pub fn beat(&mut self, max_missed: usize) -> bool {
+        let mut heartbeat = std::mem::take(&mut self.heartbeat);
+        let alive = heartbeat.beat(self, max_missed);
+        self.heartbeat = heartbeat;
+        alive
+    }
+
+    pub fn request(&mut self, msg: protocol::Request) -> Result<impl Future<Output=protocol::Response>, Error> {
         let mut wmsg = nng::Message::new();
         let msg_id = self.next_msg_id;
-        serde_cbor::to_writer(&mut wmsg, &protocol::MsgRequest { msg_id, msg }).unwrap();
-        let cx = nng::Context::new(&self.socket).map_err(Error::from_other).unwrap();
+        serde_cbor::to_writer(&mut wmsg, &protocol::MsgRequest { msg_id, msg })?;
+        let cx = nng::Context::new(&self.socket)?;
         let context = cx.clone();
         let resp = self.responses.clone();
         let waks = self.wakers.clone();
         let tp = self.thread_pool.clone();
-        let aio = nng::Aio::new(move |aio, res| Server::process(&aio, &cx, &resp, &waks, &tp, res)).unwrap();
-        context.send(&aio, wmsg).unwrap();
+        let aio = nng::Aio::new(move |aio, res| Server::process(&aio, &cx, &resp, &waks, &tp, res))?;
+        context.send(&aio, wmsg).map_err(|(_, e)| e)?;
         self.next_msg_id = protocol::MessageId(self.next_msg_id.0 + 1);
-        FutureResponse { msg_id, responses: self.responses.clone(), wakers: self.wakers.clone(), aio }
+        Ok(FutureResponse { msg_id, responses: self.responses.clone(), wakers: self.wakers.clone(), aio })
+    }
+}
+
+impl PingTransport for Server {
+    fn send_ping(&mut self) -> Result<(), Error> {
+        let resp = self.request(protocol::Request::Ping)?;
+        let pong = self.pong.clone();
+        self.thread_pool.spawn_ok(async move {
+            if let protocol::Response::Pong = resp.await {
+                pong.store(true, Ordering::SeqCst);
+            }
+        });
+        Ok(())
+    }
+
+    fn take_pong(&mut self) -> bool {
+        self.pong.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// answers the pings sent to it in order with `answers`, then stops answering
+    struct MockTransport {
+        answers: std::collections::VecDeque<bool>,
+        pending: Option<bool>,
+        pings: usize
+    }
+
+    impl MockTransport {
+        fn new(answers: &[bool]) -> MockTransport {
+            MockTransport { answers: answers.iter().copied().collect(), pending: None, pings: 0 }
+        }
+    }
+
+    impl PingTransport for MockTransport {
+        fn send_ping(&mut self) -> Result<(), Error> {
+            self.pings += 1;
+            self.pending = Some(self.answers.pop_front().unwrap_or(false));
+            Ok(())
+        }
+
+        fn take_pong(&mut self) -> bool {
+            self.pending.take().unwrap_or(false)
+        }
+    }
+
+    #[test]
+    fn heartbeat_misses_in_a_row() {
+        let mut transport = MockTransport::new(&[true, false, false, true, false, false, false]);
+        let mut hb = Heartbeat::default();
+        let alive: Vec<bool> = (0..8).map(|_| hb.beat(&mut transport, 3)).collect();
+        // the answer to each ping is checked on the beat after it is sent
+        assert_eq!(alive, vec![true, true, true, true, true, true, true, false]);
+        assert_eq!(transport.pings, 7, "no ping after the server is given up on");
+    }
+
+    #[test]
+    fn heartbeat_answered() {
+        let mut transport = MockTransport::new(&[true; 10]);
+        let mut hb = Heartbeat::default();
+        assert!((0..10).all(|_| hb.beat(&mut transport, 1)));
     }
 }
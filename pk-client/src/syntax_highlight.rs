@@ -1,8 +1,9 @@
 use regex::Regex;
 use crate::piece_table_render::Highlight;
 use crate::config::ColorschemeSel;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ops::Range;
+use std::sync::Mutex;
 
 struct KeywordMatchIter<'t> {
     text: &'t str,
@@ -43,7 +44,7 @@ impl HighlightRule {
     }
 }
 
-#[derive(Hash, Eq, PartialEq)]
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
 pub enum LexicalItemType {
     Comment, Keyword,
     Strings, Character, Number, Identifier,
@@ -52,26 +53,45 @@ pub enum LexicalItemType {
     Macro, Label, Special
 }
 
-pub struct SyntaxRules {
-    pub highlight_rules: Vec<(LexicalItemType, HighlightRule)>
+impl LexicalItemType {
+    /// comments and strings contain other lexical items, so they need to win any overlap
+    fn priority(&self) -> u8 {
+        match self {
+            LexicalItemType::Comment => 2,
+            LexicalItemType::Strings | LexicalItemType::Character => 1,
+            _ => 0
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // the lexical item types that have already been reported as missing from a color map
+    static ref UNMAPPED_TYPES: Mutex<HashSet<LexicalItemType>> = Mutex::new(HashSet::new());
 }
 
-fn overlaps<T: Ord>(a: &Range<T>, b: &Range<T>) -> bool {
-    (b.start >= a.start && b.start < a.end) ||
-    (b.end >= a.start && b.end < a.end) 
+pub struct SyntaxRules {
+    pub highlight_rules: Vec<(LexicalItemType, HighlightRule)>
 }
 
 impl SyntaxRules {
+    /// lexical items that aren't in `color_map` use the foreground color
     pub fn apply(&self, text: &str, color_map: &HashMap<LexicalItemType, ColorschemeSel>) -> Vec<Highlight> {
         let mut hi: Vec<Highlight> = Vec::new();
         for rule in self.highlight_rules.iter() {
+            let color = match color_map.get(&rule.0) {
+                Some(c) => *c,
+                None => {
+                    if UNMAPPED_TYPES.lock().unwrap().insert(rule.0) {
+                        println!("no color for {:?}, using the foreground color", rule.0);
+                    }
+                    ColorschemeSel::Foreground
+                }
+            };
             for m in rule.1.matches(text) {
-                if hi.iter().any(|h| overlaps(&h.range, &m)) { continue; }
-                hi.push(Highlight::foreground(m, *color_map.get(&rule.0).unwrap()));
+                hi.push(Highlight::foreground(m, color).with_priority(rule.0.priority()));
             }
         }
-        hi.sort_unstable_by(|a, b| a.range.start.cmp(&b.range.start));
-        hi
+        Highlight::resolve_overlaps(hi)
     }
 }
 
@@ -493,44 +513,213 @@ pub struct Highlighter {
 }
 
 impl Highlighter {
+    /// rules that can't be parsed are skipped, so their scopes use the foreground color
     pub fn from_toml(val: Option<&toml::Value>) -> Highlighter {
-        use std::str::FromStr;
         Highlighter {
             synset: SyntaxSet::load_defaults_nonewlines(),
-            // error handling here could be a lot better
             color_sel: syntect_highlighter::Theme {
                 scopes: val.and_then(|val| val.as_array()
                             .map(|rules| rules.iter()
-                                .map(|rule| syntect_highlighter::ThemeItem {
-                                    scope: ScopeSelectors::from_str(rule.get("scope").and_then(toml::Value::as_str).unwrap()).unwrap(),
-                                    style: syntect_highlighter::StyleModifier::fg(
-                                        ColorschemeSel::from_toml(rule.get("style").unwrap()).unwrap())
+                                .filter_map(|rule| match Highlighter::theme_item(rule) {
+                                    Ok(item) => Some(item),
+                                    Err(e) => { println!("skipping syntax coloring rule: {}", e); None }
                                 }).collect())).unwrap_or_else(Vec::new)
             }
         }
     }
 
-    pub fn compute_highlighting(&self, buf: &buffer::Buffer) -> Vec<Highlight> {
-        // dbg!(&self.color_sel);
-        let mut parser = ParseState::new(buf.path.extension().and_then(|s| s.to_str())
+    fn theme_item(rule: &toml::Value) -> Result<syntect_highlighter::ThemeItem, crate::Error> {
+        use std::str::FromStr;
+        use crate::Error;
+        let scope = rule.get("scope").and_then(toml::Value::as_str)
+            .ok_or_else(|| Error::ConfigParseError("Expected syntax coloring rule to have a scope".into(), Some(rule.clone())))?;
+        let style = rule.get("style")
+            .ok_or_else(|| Error::ConfigParseError("Expected syntax coloring rule to have a style".into(), Some(rule.clone())))?;
+        Ok(syntect_highlighter::ThemeItem {
+            scope: ScopeSelectors::from_str(scope).map_err(Error::from_other)?,
+            style: syntect_highlighter::StyleModifier::fg(ColorschemeSel::from_toml(style)?)
+        })
+    }
+
+    /// highlight the buffer's text, reusing the highlighting in `buf.syntax_cache` for lines that
+    /// weren't changed since it was computed. Returns the highlights and the range of the text
+    /// whose highlighting had to be computed again
+    pub fn compute_highlighting(&self, buf: &mut buffer::Buffer) -> (Vec<Highlight>, Range<usize>) {
+        let syntax = buf.path.extension().and_then(|s| s.to_str())
             .and_then(|ext| self.synset.find_syntax_by_extension(ext))
-            .unwrap_or_else(|| self.synset.find_syntax_plain_text()));
-        let mut hi = Vec::new();
+            .unwrap_or_else(|| self.synset.find_syntax_plain_text());
+        let text = buf.text.text();
         let hl = syntect_highlighter::Highlighter::new(&self.color_sel);
-        let mut hlstate = syntect_highlighter::HighlightState::new(&hl, ScopeStack::new());
+        let old = buf.syntax_cache.take().filter(|c| c.syntax == syntax.name);
+
+        // everything before the line with the first change is highlighted the same as before
+        let (mut state, mut line_states, mut line_highlights, start_line, suffix) = match old.as_ref() {
+            Some(c) if c.text == text => {
+                let hi = c.highlights();
+                buf.syntax_cache = old;
+                return (hi, 0..0);
+            },
+            Some(c) => {
+                let prefix = c.text.bytes().zip(text.bytes()).take_while(|(a, b)| a == b).count();
+                let suffix = c.text.bytes().rev().zip(text.bytes().rev()).take_while(|(a, b)| a == b).count()
+                    .min(c.text.len().min(text.len()) - prefix);
+                let start_line = text.as_bytes()[..prefix].iter().filter(|b| **b == b'\n').count()
+                    .min(c.line_highlights.len());
+                (c.line_states[start_line].clone(), c.line_states[..start_line].to_vec(),
+                    c.line_highlights[..start_line].to_vec(), start_line, suffix)
+            },
+            None => (LineState {
+                parse: ParseState::new(syntax),
+                highlight: syntect_highlighter::HighlightState::new(&hl, ScopeStack::new())
+            }, Vec::new(), Vec::new(), 0, 0)
+        };
+
+        let line_count = text.lines().count();
+        let mut ix: usize = text.lines().take(start_line).map(|ln| ln.len() + 1).sum();
+        let start = ix;
+        let mut end = text.len();
+        for (j, ln) in text.lines().enumerate().skip(start_line) {
+            // lines in the unchanged end of the text only need to be highlighted again if the
+            // state at their start is different from before
+            if let Some(c) = old.as_ref() {
+                let old_j = (j + c.line_highlights.len()).checked_sub(line_count);
+                if ix > text.len() - suffix && old_j.map_or(false, |oj| c.line_states[oj] == state) {
+                    let oj = old_j.unwrap();
+                    line_states.extend_from_slice(&c.line_states[oj..]);
+                    line_highlights.extend_from_slice(&c.line_highlights[oj..]);
+                    end = ix;
+                    break;
+                }
+            }
+            line_states.push(state.clone());
+            let ops = state.parse.parse_line(ln, &self.synset);
+            line_highlights.push(syntect_highlighter::RangedHighlightIterator::new(&mut state.highlight, &ops[..], ln, &hl)
+                .map(|(style, _, range)| (range, style.foreground)).collect());
+            ix += ln.len() + 1;
+        }
+        if line_states.len() == line_highlights.len() {
+            line_states.push(state);
+        }
+
+        let cache = HighlightCache { syntax: syntax.name.clone(), text, line_states, line_highlights };
+        let hi = cache.highlights();
+        buf.syntax_cache = Some(cache);
+        (hi, start..end)
+    }
+}
+
+/// the parser and highlighter state at the start of a line
+#[derive(Clone, PartialEq)]
+struct LineState {
+    parse: ParseState,
+    highlight: syntect_highlighter::HighlightState
+}
+
+/// the highlighting of a buffer's text the last time it was computed, so that after an edit only
+/// the lines from the first change until parsing gets back to the same state as before need to be
+/// highlighted again
+pub struct HighlightCache {
+    syntax: String,
+    text: String,
+    /// the state at the start of each line, and then at the end of the text
+    line_states: Vec<LineState>,
+    /// the colors on each line, relative to the start of the line
+    line_highlights: Vec<Vec<(Range<usize>, ColorschemeSel)>>
+}
+
+impl HighlightCache {
+    fn highlights(&self) -> Vec<Highlight> {
+        let mut hi = Vec::new();
         let mut gi = 0;
-        //let tx = buf.text.text();
-        //println!("highlighting text:\n{}", tx);
-        for (ops, ln) in buf.text.text().lines()
-            .map(|ln| (parser.parse_line(ln, &self.synset), ln))
-        {
-            hi.extend(syntect_highlighter::RangedHighlightIterator::new(&mut hlstate, &ops[..], ln, &hl)
-                .map(|(style, _, range)| {
-                    Highlight::foreground((range.start + gi) .. (range.end + gi), style.foreground)
-                }));
+        for (ln, lh) in self.text.lines().zip(self.line_highlights.iter()) {
+            hi.extend(lh.iter().map(|(range, sel)| Highlight::foreground((range.start + gi) .. (range.end + gi), *sel)));
             gi += ln.len() + 1;
         }
         hi
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_wins_over_keyword() {
+        let rules = SyntaxRules {
+            highlight_rules: vec![
+                (LexicalItemType::Keyword, HighlightRule::Keyword("fn".into())),
+                (LexicalItemType::Comment, HighlightRule::RegexMatch(Regex::new("//.*").unwrap()))
+            ]
+        };
+        let mut color_map = HashMap::new();
+        color_map.insert(LexicalItemType::Keyword, ColorschemeSel::Accent(0));
+        color_map.insert(LexicalItemType::Comment, ColorschemeSel::HalfGray);
+        let hi = rules.apply("fn a() // fn b", &color_map);
+        assert_eq!(hi, vec![
+            Highlight::foreground(0..2, ColorschemeSel::Accent(0)),
+            Highlight::foreground(7..14, ColorschemeSel::HalfGray).with_priority(2)
+        ]);
+    }
+
+    #[test]
+    fn incomplete_color_map() {
+        let rules = SyntaxRules {
+            highlight_rules: vec![
+                (LexicalItemType::Keyword, HighlightRule::Keyword("fn".into())),
+                (LexicalItemType::Number, HighlightRule::RegexMatch(Regex::new("[0-9]+").unwrap()))
+            ]
+        };
+        let mut color_map = HashMap::new();
+        color_map.insert(LexicalItemType::Keyword, ColorschemeSel::Accent(0));
+        assert_eq!(rules.apply("fn a() 12", &color_map), vec![
+            Highlight::foreground(0..2, ColorschemeSel::Accent(0)),
+            Highlight::foreground(7..9, ColorschemeSel::Foreground)
+        ]);
+        assert_eq!(rules.apply("34", &HashMap::new()), vec![Highlight::foreground(0..2, ColorschemeSel::Foreground)]);
+    }
+
+    #[test]
+    fn skip_bad_coloring_rules() {
+        let cfg = toml::toml!{
+            syntax-coloring = [
+            { scope = "comment", style = "half-gray" },
+            { scope = "string" },
+            { style = 3 },
+            { scope = "keyword", style = "purple" },
+            { scope = "number", style = 1 },
+            ]
+        };
+        let h = Highlighter::from_toml(cfg.get("syntax-coloring"));
+        assert_eq!(h.color_sel.scopes.iter().map(|i| i.style).collect::<Vec<_>>(), vec![
+            syntect_highlighter::StyleModifier::fg(ColorschemeSel::HalfGray),
+            syntect_highlighter::StyleModifier::fg(ColorschemeSel::Accent(1))
+        ]);
+    }
+
+    #[test]
+    fn rehighlight_from_edited_line() {
+        let h = Highlighter::from_toml(crate::config::Config::default().syntax_coloring.as_ref());
+        let mut buf = buffer::Buffer::with_text("fn a() {}\n// b\nlet c = \"d\";\nlet e = 1;\n");
+        buf.path = "a.rs".into();
+        let (before, changed) = h.compute_highlighting(&mut buf);
+        assert_eq!(changed, 0..buf.text.len());
+        assert_eq!(h.compute_highlighting(&mut buf), (before.clone(), 0..0), "nothing changed");
+
+        let line2 = buf.index_for_line(2);
+        buf.text.insert_range("x", line2 + 5);
+        let (after, changed) = h.compute_highlighting(&mut buf);
+        assert_eq!(changed, line2..buf.index_for_line(3));
+        let before_line2 = |hi: &[Highlight]| hi.iter().filter(|h| h.range.start < line2).cloned().collect::<Vec<_>>();
+        assert_eq!(before_line2(&before), before_line2(&after));
+        buf.syntax_cache = None;
+        assert_eq!(h.compute_highlighting(&mut buf).0, after, "same as highlighting everything again");
+
+        // removing a line moves the rest of the highlights up
+        let line1 = buf.index_for_line(1);
+        buf.text.delete_range(line1, line2);
+        let (after, changed) = h.compute_highlighting(&mut buf);
+        assert_eq!(changed.start, line1);
+        buf.syntax_cache = None;
+        assert_eq!(h.compute_highlighting(&mut buf).0, after);
+    }
+}
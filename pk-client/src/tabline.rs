@@ -0,0 +1,47 @@
+use crate::buffer::Buffer;
+
+/// the text shown in a buffer's tab, which is its file name, marked like the status line if the
+/// buffer has changes that haven't been synced yet
+pub fn label(buf: &Buffer) -> String {
+    let name = if buf.scratch {
+        String::from("[scratch]")
+    } else {
+        buf.path.file_name().map_or_else(|| buf.path.to_string_lossy(), |n| n.to_string_lossy()).into_owned()
+    };
+    if buf.is_modified() { name + " [+]" } else { name }
+}
+
+/// how wide each tab is for `labels`, with `em_width` of padding on either side of the text. If
+/// the tabs don't fit in `max_width` they all get an equal share of it, cutting off long labels
+pub fn widths(labels: &[String], em_width: f32, max_width: f32) -> Vec<f32> {
+    let ws: Vec<f32> = labels.iter().map(|l| (l.chars().count() + 2) as f32 * em_width).collect();
+    if ws.iter().sum::<f32>() > max_width {
+        vec![max_width / ws.len() as f32; ws.len()]
+    } else {
+        ws
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let mut b = Buffer::with_text("abc");
+        b.path = "src/some/file.rs".into();
+        assert_eq!(label(&b), "file.rs");
+        b.text.insert_range("x", 0);
+        assert_eq!(label(&b), "file.rs [+]");
+        assert_eq!(label(&Buffer::scratch()), "[scratch]");
+    }
+
+    #[test]
+    fn label_widths() {
+        let labels = vec![String::from("a.rs"), String::from("main.rs [+]")];
+        assert_eq!(widths(&labels, 2.0, 100.0), vec![12.0, 26.0]);
+        // too wide to fit, so they are squeezed evenly
+        assert_eq!(widths(&labels, 2.0, 30.0), vec![15.0, 15.0]);
+        assert!(widths(&[], 2.0, 30.0).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+/// how one line lines up between an old and a new version of some text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineChange<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str)
+}
+
+/// the lines of `old` and `new` lined up by a shortest edit script, in order. Within a run of
+/// changed lines, removed and added lines may come in any order
+pub fn lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<LineChange<'a>> {
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[prefix..old.len()-suffix], &new[prefix..new.len()-suffix]);
+
+    let mut out: Vec<LineChange> = old[..prefix].iter().map(|l| LineChange::Same(l)).collect();
+    out.extend(myers(a, b));
+    out.extend(old[old.len()-suffix..].iter().map(|l| LineChange::Same(l)));
+    out
+}
+
+/// Myers' O((N+M)D) diff. `v[k]` is the furthest index into `a` reached on diagonal `k = x - y`;
+/// the part of `v` each round of `d` looked at is kept so the path can be walked back afterwards
+fn myers<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<LineChange<'a>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let at = |k: isize| (k + max + 1) as usize;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // trace[d][k + d + 1] is v[k] as it was before round d, for k in -d-1..=d+1
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v[at(-d-1)..=at(d+1)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k-1)] < v[at(k+1)]) { v[at(k+1)] } else { v[at(k-1)] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1; y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(a.len().max(b.len()));
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| (k + d + 1) as usize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[at(k-1)] < v[at(k+1)]) { k + 1 } else { k - 1 };
+        let prev_x = v[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1; y -= 1;
+            out.push(LineChange::Same(a[x as usize]));
+        }
+        if d > 0 {
+            if x == prev_x {
+                out.push(LineChange::Added(b[prev_y as usize]));
+            } else {
+                out.push(LineChange::Removed(a[prev_x as usize]));
+            }
+        }
+        x = prev_x; y = prev_y;
+    }
+    out.reverse();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_changes() {
+        use LineChange::*;
+        assert_eq!(lines(&["a", "b", "c", "d"], &["a", "x", "c", "d", "e"]),
+            vec![Same("a"), Removed("b"), Added("x"), Same("c"), Same("d"), Added("e")]);
+        assert_eq!(lines(&[], &["a"]), vec![Added("a")]);
+        assert_eq!(lines(&["a"], &[]), vec![Removed("a")]);
+        assert_eq!(lines(&["a", "b"], &["b", "a"]), vec![Removed("a"), Same("b"), Added("a")]);
+    }
+
+    #[test]
+    fn large_line_changes() {
+        // a table of every pair of lines would be 10^10 entries here
+        let old: Vec<String> = (0..100_000).map(|i| i.to_string()).collect();
+        let mut new = old.clone();
+        new[10] = "x".into();
+        new.remove(50_000);
+        new.push("y".into());
+        let old: Vec<&str> = old.iter().map(|s| s.as_str()).collect();
+        let new: Vec<&str> = new.iter().map(|s| s.as_str()).collect();
+        let changes = lines(&old, &new);
+        let changed: Vec<_> = changes.iter().filter(|c| !matches!(c, LineChange::Same(_))).collect();
+        assert_eq!(changed, vec![&LineChange::Removed("10"), &LineChange::Added("x"),
+            &LineChange::Removed("50000"), &LineChange::Added("y")]);
+        assert_eq!(changes.len() - changed.len(), 99_998);
+    }
+}
@@ -13,11 +13,38 @@ impl Direction {
     }
 }
 
+/// The kinds of characters that words are made of
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CharClass {
+    Whitespace,
+    Punctuation,
+    /// letters, digits and underscores
+    Regular
+}
+
+pub trait CharClassify {
+    fn class(self) -> CharClass;
+}
+
+impl CharClassify for char {
+    fn class(self) -> CharClass {
+        if self.is_whitespace() || self.is_ascii_whitespace() {
+            CharClass::Whitespace
+        } else if !self.is_alphanumeric() && self != '_' {
+            CharClass::Punctuation
+        } else {
+            CharClass::Regular
+        }
+    }
+}
+
+pub mod diff;
 pub mod piece_table;
+pub mod transform;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ModeTag {
-    Normal, Insert, Command, Visual, UserMessage, Search(Direction)
+    Normal, Insert, Command, Visual, VisualLine, UserMessage, Search(Direction)
 }
 
 pub mod protocol {
@@ -29,7 +56,7 @@ pub mod protocol {
     #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
     pub struct FileId(pub u64);
 
-    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+    #[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash, Debug)]
     pub enum LineEnding {
         LF, CRLF
     }
@@ -44,12 +71,45 @@ pub mod protocol {
         }
     }
 
+    /// how many lines in some text end with each kind of line ending
+    #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+    pub struct LineEndingCounts {
+        pub lf: usize,
+        pub crlf: usize,
+        /// the ending of the first line, which breaks ties
+        pub first: Option<LineEnding>
+    }
+
+    impl LineEndingCounts {
+        /// the most common line ending, or `None` if there are no line endings at all
+        pub fn majority(&self) -> Option<LineEnding> {
+            if self.lf > self.crlf { Some(LineEnding::LF) }
+            else if self.crlf > self.lf { Some(LineEnding::CRLF) }
+            else { self.first }
+        }
+
+        pub fn is_mixed(&self) -> bool {
+            self.lf > 0 && self.crlf > 0
+        }
+    }
+
     impl LineEnding {
+        pub fn count(s: &str) -> LineEndingCounts {
+            let mut counts = LineEndingCounts::default();
+            for (i, _) in s.match_indices('\n') {
+                let ending = if s[..i].ends_with('\r') { LineEnding::CRLF } else { LineEnding::LF };
+                match ending {
+                    LineEnding::LF => counts.lf += 1,
+                    LineEnding::CRLF => counts.crlf += 1
+                }
+                counts.first.get_or_insert(ending);
+            }
+            counts
+        }
+
+        /// the most common line ending in `s`, or the platform default if it doesn't have any
         pub fn from_analysis(s: &str) -> LineEnding {
-            s.find('\n')
-                .and_then(|i| s.chars().nth(i.saturating_sub(1)))
-                .map(|c| if c == '\r' { LineEnding::CRLF } else { LineEnding::LF })
-                .unwrap_or(LineEnding::default())
+            LineEnding::count(s).majority().unwrap_or_default()
         }
 
         pub fn as_str(&self) -> &'static str {
@@ -58,8 +118,24 @@ pub mod protocol {
                 &LineEnding::CRLF => "\r\n"
             }
         }
+
+        /// the name of the line ending as a Vim `fileformat`
+        pub fn fileformat(&self) -> &'static str {
+            match self {
+                LineEnding::LF => "unix",
+                LineEnding::CRLF => "dos"
+            }
+        }
+
+        pub fn from_fileformat(s: &str) -> Option<LineEnding> {
+            match s {
+                "unix" => Some(LineEnding::LF),
+                "dos" => Some(LineEnding::CRLF),
+                _ => None
+            }
+        }
     }
-    #[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
     pub struct FileType {
         data: [u8; 4]
     }
@@ -72,11 +148,15 @@ pub mod protocol {
         }
     }
 
+    /// uses the first four characters of the name, padded with spaces. Characters that aren't
+    /// ASCII become `?` so the name is always valid UTF-8
     impl From<&str> for FileType {
         fn from(s: &str) -> Self {
-            assert!(s.len() >= 4);
-            let b = s.as_bytes();
-            FileType { data: [b[0], b[1], b[2], b[3]] }
+            let mut data = [b' '; 4];
+            for (d, b) in data.iter_mut().zip(s.bytes()) {
+                *d = if b.is_ascii() { b } else { b'?' };
+            }
+            FileType { data }
         }
     }
 
@@ -96,19 +176,32 @@ pub mod protocol {
         }
     }
 
-    #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Clone)]
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq, Eq, Hash, Clone)]
     pub struct TextFormat {
         pub line_ending: LineEnding,
-        pub stype: FileType
+        pub stype: FileType,
+        /// true if the file had both kinds of line endings when it was opened, in which case they
+        /// will all be written back as `line_ending`
+        pub mixed_line_endings: bool
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
     pub enum Request {
         /* files */
         OpenFile { path: std::path::PathBuf },
-        SyncFile { id: FileId, new_text: String, version: usize },
+        /// `format` replaces the format the file will be written with
+        SyncFile { id: FileId, new_text: String, version: usize, format: TextFormat },
         ReloadFile(FileId),
+        /// the text the server currently has for an open file, answered with `FileInfo`
+        GetFile(FileId),
         CloseFile(FileId),
+        /// check that the server is still there, answered with `Pong`
+        Ping,
+        /// the files the server has open for any client, answered with `OpenFiles`
+        ListOpenFiles,
+        /// wait for another client to sync a file past `since_version`, answered with `FileInfo`
+        /// as soon as it is, or with the current version if nothing changes for a while
+        WaitForChanges { id: FileId, since_version: usize },
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -117,7 +210,7 @@ pub mod protocol {
         pub msg: Request
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub enum Response {
         Ack,
         Error { message: String },
@@ -133,6 +226,9 @@ pub mod protocol {
             version: usize,
             format: TextFormat
         },
+        Pong,
+        /// the id, path and version of each open file, in order of id
+        OpenFiles { files: Vec<(FileId, std::path::PathBuf, usize)> },
     }
 
     #[derive(Serialize, Deserialize, Debug)]
@@ -141,5 +237,63 @@ pub mod protocol {
         pub msg: Response
     }
 
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn file_type_names() {
+            assert_eq!(FileType::from("rust").to_string(), "rust");
+            assert_eq!(FileType::from("python").to_string(), "pyth");
+            assert_eq!(FileType::from("js").to_string(), "js  ");
+            assert_eq!(FileType::from("ñam").to_string(), "??am");
+        }
+
+        #[test]
+        fn line_endings_lf() {
+            let counts = LineEnding::count("a\nb\nc");
+            assert_eq!((counts.lf, counts.crlf), (2, 0));
+            assert!(!counts.is_mixed());
+            assert_eq!(LineEnding::from_analysis("a\nb\nc"), LineEnding::LF);
+            assert_eq!(LineEnding::from_analysis("\n"), LineEnding::LF);
+        }
+
+        #[test]
+        fn line_endings_crlf() {
+            let counts = LineEnding::count("ä\r\nb\r\n");
+            assert_eq!((counts.lf, counts.crlf), (0, 2));
+            assert!(!counts.is_mixed());
+            assert_eq!(LineEnding::from_analysis("ä\r\nb\r\n"), LineEnding::CRLF);
+        }
+
+        #[test]
+        fn line_endings_mixed() {
+            // the first line doesn't decide when the rest disagree
+            let counts = LineEnding::count("a\r\nb\nc\nd");
+            assert!(counts.is_mixed());
+            assert_eq!(counts.majority(), Some(LineEnding::LF));
+            assert_eq!(LineEnding::from_analysis("a\nb\r\nc\r\n"), LineEnding::CRLF);
+            // but it does break ties
+            assert_eq!(LineEnding::from_analysis("a\r\nb\n"), LineEnding::CRLF);
+            assert_eq!(LineEnding::from_analysis("a\nb\r\n"), LineEnding::LF);
+        }
+
+        #[test]
+        fn line_endings_none() {
+            let counts = LineEnding::count("no newline\r");
+            assert_eq!(counts, LineEndingCounts::default());
+            assert_eq!(counts.majority(), None);
+            assert!(!counts.is_mixed());
+            assert_eq!(LineEnding::from_analysis(""), LineEnding::default());
+        }
+
+        #[test]
+        fn line_ending_fileformats() {
+            for le in [LineEnding::LF, LineEnding::CRLF].iter() {
+                assert_eq!(LineEnding::from_fileformat(le.fileformat()), Some(*le));
+            }
+            assert_eq!(LineEnding::from_fileformat("mac"), None);
+        }
+    }
 }
 
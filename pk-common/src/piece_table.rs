@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
 use super::Direction;
 
 #[derive(Copy,Clone,Debug, Serialize, Deserialize)]
@@ -33,18 +34,150 @@ pub enum Change {
     }
 }
 
+impl Change {
+    fn piece_index_mut(&mut self) -> &mut usize {
+        match self {
+            Change::Insert { piece_index, .. } | Change::Modify { piece_index, .. }
+                | Change::Delete { piece_index, .. } => piece_index
+        }
+    }
+
+    /// what making this change does to the list of pieces
+    fn op(&self) -> PieceOp {
+        match *self {
+            Change::Insert { piece_index, .. } => PieceOp::Insert(piece_index),
+            Change::Modify { piece_index, old, new } => PieceOp::Modify(piece_index, Grows::between(old, new)),
+            Change::Delete { piece_index, .. } => PieceOp::Delete(piece_index)
+        }
+    }
+
+    /// what undoing this change does to the list of pieces
+    fn undo_op(&self) -> PieceOp {
+        match *self {
+            Change::Insert { piece_index, .. } => PieceOp::Delete(piece_index),
+            Change::Modify { piece_index, old, new } => PieceOp::Modify(piece_index, Grows::between(new, old)),
+            Change::Delete { piece_index, .. } => PieceOp::Insert(piece_index)
+        }
+    }
+}
+
+/// which ends of a piece a `PieceOp::Modify` adds text to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Grows {
+    front: bool, back: bool
+}
+
+impl Grows {
+    fn between(old: Piece, new: Piece) -> Grows {
+        Grows {
+            front: old.source != new.source || new.start < old.start,
+            back: old.source != new.source || new.start + new.length > old.start + old.length
+        }
+    }
+}
+
+/// a change to the list of pieces, leaving out what the pieces are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceOp {
+    Insert(usize), Modify(usize, Grows), Delete(usize)
+}
+
+impl PieceOp {
+    fn index(self) -> usize {
+        match self { PieceOp::Insert(i) | PieceOp::Modify(i, _) | PieceOp::Delete(i) => i }
+    }
+
+    /// rewrite `a` and `b`, both made on the same list of pieces, so that `a` can be made after
+    /// `b` and `b` after `a`. Pieces inserted by `b` go first if both insert at the same index.
+    /// Returns `None` if they both change the same piece, or if `b` inserts a piece right next
+    /// to the end of a piece `a` adds text to, because that text may have been split off from it
+    fn transform(a: PieceOp, b: PieceOp) -> Option<(PieceOp, PieceOp)> {
+        use PieceOp::*;
+        let (x, y) = (a.index(), b.index());
+        let (ax, by) = match (a, b) {
+            (Insert(_), Insert(_)) => if y <= x { (x + 1, y) } else { (x, y + 1) },
+            (Insert(_), Delete(_)) => if y < x { (x - 1, y) } else { (x, y + 1) },
+            (Insert(_), Modify(..)) => if y < x { (x, y) } else { (x, y + 1) },
+            (Delete(_), Insert(_)) => if y <= x { (x + 1, y) } else { (x, y - 1) },
+            (Modify(_, g), Insert(_)) if (g.front && y == x) || (g.back && y == x + 1) => return None,
+            (Modify(..), Insert(_)) => if y <= x { (x + 1, y) } else { (x, y) },
+            _ if x == y => return None,
+            (Delete(_), Delete(_)) => if y < x { (x - 1, y) } else { (x, y - 1) },
+            (Delete(_), Modify(..)) => if y < x { (x, y) } else { (x, y - 1) },
+            (Modify(..), Delete(_)) => if y < x { (x - 1, y) } else { (x, y) },
+            (Modify(..), Modify(..)) => (x, y)
+        };
+        let with = |op: PieceOp, i: usize| match op { Insert(_) => Insert(i), Modify(_, g) => Modify(i, g), Delete(_) => Delete(i) };
+        Some((with(a, ax), with(b, by)))
+    }
+
+    /// rewrite `ops` to be made after `other`, and `other` to be made after `ops`
+    fn transform_all(ops: &mut [PieceOp], other: &mut [PieceOp]) -> Option<()> {
+        for op in ops.iter_mut() {
+            for o in other.iter_mut() {
+                let (a, b) = PieceOp::transform(*op, *o)?;
+                *op = a;
+                *o = b;
+            }
+        }
+        Some(())
+    }
+}
+
+/// where `index` ends up after `edits`, which each insert (when positive) or delete that many
+/// bytes at an index. Indices in deleted text move to the start of the deletion, and insertions
+/// right at `index` go after it
+fn move_index(edits: &[(usize, isize)], index: usize) -> usize {
+    edits.iter().fold(index, |i, &(at, len)| {
+        if len >= 0 {
+            if i > at { i + len as usize } else { i }
+        } else {
+            let end = at + (-len) as usize;
+            if i >= end { i - (-len) as usize } else if i > at { at } else { i }
+        }
+    })
+}
+
+/// the source and the index in it of the character at `index` in the text `pieces` make up
+fn source_position(pieces: &[Piece], index: usize) -> Option<(usize, usize)> {
+    let mut at = 0;
+    for p in pieces {
+        if index < at + p.length {
+            return Some((p.source, p.start + index - at));
+        }
+        at += p.length;
+    }
+    None
+}
+
+/// the index in the text `pieces` make up of the character at `pos` in a source, if it is still there
+fn text_position(pieces: &[Piece], (source, i): (usize, usize)) -> Option<usize> {
+    let mut at = 0;
+    for p in pieces {
+        if p.source == source && i >= p.start && i < p.start + p.length {
+            return Some(at + i - p.start);
+        }
+        at += p.length;
+    }
+    None
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Action {
     pub changes: Vec<Change>,
-    pub id: usize
+    pub id: usize,
+    /// where the cursor goes when this action is undone, which is the start of the text it changed
+    pub cursor_before: usize,
+    /// where the cursor goes when this action is redone, which is the end of the text it changed
+    pub cursor_after: usize
 }
 
 impl Action {
-    fn new(pt: &mut PieceTable) -> Action {
+    fn new(pt: &mut PieceTable, cursor_before: usize, cursor_after: usize) -> Action {
         let id = pt.next_action_id;
         pt.next_action_id += 1;
         Action {
-            changes: Vec::new(), id
+            changes: Vec::new(), id, cursor_before, cursor_after
         }
     }
 
@@ -63,6 +196,8 @@ pub struct PieceTable {
     pub sources: Vec<String>,
     pub pieces: Vec<Piece>,
     pub history: Vec<Action>,
+    /// actions that have been undone, most recent last, until a new action is made
+    pub undone: Vec<Action>,
     pub next_action_id: usize
 }
 // is it ok to have empty, zero length pieces in the table? for now these algorithms assume that it
@@ -75,23 +210,26 @@ impl Default for PieceTable {
 }
 
 pub struct TableMutator {
-    piece_ix: usize, action: Action
+    piece_ix: usize, action: Action,
+    /// the length of the piece when the mutator started, so that only text it inserted is popped
+    floor: usize
 }
 
 impl TableMutator {
     pub fn push_char(&mut self, pt: &mut PieceTable, c: char) {
-        pt.pieces[self.piece_ix].length += 1;
+        pt.pieces[self.piece_ix].length += c.len_utf8();
         let si = pt.pieces[self.piece_ix].source;
         pt.sources[si].push(c);
     }
 
+    /// remove the last character inserted, returning true if there weren't any left to remove
     pub fn pop_char(&mut self, pt: &mut PieceTable) -> bool {
-        if pt.pieces[self.piece_ix].length == 0 {
+        if pt.pieces[self.piece_ix].length <= self.floor {
             return true;
         }
-        pt.pieces[self.piece_ix].length -= 1;
         let si = pt.pieces[self.piece_ix].source;
-        pt.sources[si].pop();
+        let c = pt.sources[si].pop().expect("piece is at the end of its source");
+        pt.pieces[self.piece_ix].length -= c.len_utf8();
         false
     }
     
@@ -107,12 +245,13 @@ impl TableMutator {
         if self.action.changes.len() == 0 {
             println!("broken piece table mutator! {}#{:?} {:?} {:#?}", self.piece_ix, pt.pieces[self.piece_ix], self.action, pt);
         }
-        if let Change::Insert { new, .. } | Change::Modify { new, .. } = &mut self.action.changes[ix] {
-            *new = pt.pieces[self.piece_ix];
-        } else {
-            panic!();
-        }
-        pt.history.push(self.action);
+        let inserted = match &mut self.action.changes[ix] {
+            Change::Insert { new, .. } => { *new = pt.pieces[self.piece_ix]; new.length },
+            Change::Modify { old, new, .. } => { *new = pt.pieces[self.piece_ix]; new.length - old.length },
+            _ => panic!()
+        };
+        self.action.cursor_after = self.action.cursor_before + inserted;
+        pt.push_action(self.action);
     }
 }
 
@@ -218,6 +357,52 @@ impl<'t> DoubleEndedIterator for TableChars<'t> {
 }
 
 
+/// iterates over lines like `str::lines`, borrowing each line from its source unless it is split
+/// across pieces
+pub struct TableLines<'table> {
+    table: &'table PieceTable,
+    current_piece: usize,
+    /// offset into the current piece
+    current_index: usize
+}
+
+impl<'t> Iterator for TableLines<'t> {
+    type Item = Cow<'t, str>;
+
+    fn next(&mut self) -> Option<Cow<'t, str>> {
+        let mut line: Option<Cow<'t, str>> = None;
+        let append = |line: &mut Option<Cow<'t, str>>, s: &'t str| {
+            *line = Some(match line.take() {
+                None => Cow::Borrowed(s),
+                Some(l) => Cow::Owned(l.into_owned() + s)
+            });
+        };
+        while self.current_piece < self.table.pieces.len() {
+            let p = &self.table.pieces[self.current_piece];
+            let rest = &self.table.sources[p.source][p.start+self.current_index..p.start+p.length];
+            match rest.find('\n') {
+                Some(nl) => {
+                    self.current_index += nl+1;
+                    append(&mut line, &rest[..nl]);
+                    break;
+                },
+                None => {
+                    if !rest.is_empty() { append(&mut line, rest); }
+                    self.current_piece += 1;
+                    self.current_index = 0;
+                }
+            }
+        }
+        line.map(|l| match l {
+            Cow::Borrowed(l) => Cow::Borrowed(l.strip_suffix('\r').unwrap_or(l)),
+            Cow::Owned(mut l) => {
+                if l.ends_with('\r') { l.pop(); }
+                Cow::Owned(l)
+            }
+        })
+    }
+}
+
 impl<'table> PieceTable {
     pub fn with_text(s: &str) -> PieceTable {
         PieceTable::with_text_and_starting_action_id(s, 1)
@@ -227,7 +412,7 @@ impl<'table> PieceTable {
         PieceTable {
             sources: vec![s.to_string()],
             pieces: vec![ Piece { source: 0, start: 0, length: s.len() } ],
-            history: Vec::new(), next_action_id: start_aid 
+            history: Vec::new(), undone: Vec::new(), next_action_id: start_aid 
         }
     }
 
@@ -253,6 +438,121 @@ impl<'table> PieceTable {
         self.next_action_id = action.id + 1;
     }
 
+    /// the bytes inserted or deleted in the text by making `change`, for `move_index`
+    fn change_edits(&self, change: &Change, edits: &mut Vec<(usize, isize)>) {
+        let at: usize = self.pieces[..change.op().index().min(self.pieces.len())].iter().map(|p| p.length).sum();
+        match *change {
+            Change::Insert { new, .. } => edits.push((at, new.length as isize)),
+            Change::Delete { old, .. } => edits.push((at, -(old.length as isize))),
+            Change::Modify { old, new, .. } if old.source != new.source => {
+                edits.push((at, -(old.length as isize)));
+                edits.push((at, new.length as isize));
+            },
+            Change::Modify { old, new, .. } => {
+                // the piece can grow or shrink at either end
+                let front = new.start as isize - old.start as isize;
+                if front != 0 { edits.push((at, -front)); }
+                let (old_end, new_end) = (old.start + old.length, new.start + new.length);
+                if new_end > old_end {
+                    edits.push((at + (old_end - new.start.min(old_end)), (new_end - old_end) as isize));
+                } else if new_end < old_end {
+                    edits.push((at + (new_end - new.start), -((old_end - new_end) as isize)));
+                }
+            }
+        }
+    }
+
+    /// apply `action`, made on another copy of this text, without adding it to the history, and
+    /// return where `index` ends up. The history, along with any action `tmut` is in the middle of,
+    /// is rewritten to undo and redo around it. An action that changed a piece `action` also
+    /// changed can't be undone anymore, so it is dropped from the history with everything before it.
+    /// If `action` changed the text `tmut` was inserting, `tmut` starts a new insertion where
+    /// `index` ends up instead
+    pub fn apply_remote_action(&mut self, action: &Action, tmut: Option<&mut TableMutator>, index: usize) -> usize {
+        assert!(action.id >= self.next_action_id, "remote action {} is older than local actions", action.id);
+        let remote: Vec<PieceOp> = action.iter().map(Change::op).collect();
+        let old_pieces = self.pieces.clone();
+        let mut edits = Vec::new();
+        for c in action.iter() {
+            self.change_edits(c, &mut edits);
+            self.enact_change(c);
+        }
+        self.next_action_id = action.id + 1;
+        // indices stay on the same character if it is still there, even if its piece was split
+        let pieces = &self.pieces;
+        let moved = |i: usize| source_position(&old_pieces, i)
+            .and_then(|pos| text_position(pieces, pos))
+            .unwrap_or_else(|| move_index(&edits, i));
+
+        // rewrite `a` to undo after the remote action, leaving `ops` as the remote action from
+        // before `a` was made
+        let rebase_undo = |a: &mut Action, ops: &mut Vec<PieceOp>| -> bool {
+            let mut undo: Vec<PieceOp> = a.iter().rev().map(Change::undo_op).collect();
+            if PieceOp::transform_all(&mut undo, ops).is_none() { return false; }
+            for (c, u) in a.changes.iter_mut().rev().zip(undo) {
+                *c.piece_index_mut() = u.index();
+            }
+            a.cursor_before = moved(a.cursor_before);
+            a.cursor_after = moved(a.cursor_after);
+            true
+        };
+
+        let mut ops = remote.clone();
+        let mut restart = None;
+        if let Some(tmut) = tmut {
+            let mut piece = [PieceOp::Modify(tmut.piece_ix, Grows::default())];
+            let mut r = remote.clone();
+            // the text pushed so far is still at the end of its source if the piece was moved or
+            // split, but if it was cut short then there is nothing left to push onto
+            let pending = old_pieces[tmut.piece_ix];
+            let inserted = pending.length - tmut.floor;
+            let followed = match PieceOp::transform_all(&mut piece, &mut r) {
+                Some(()) => Some(piece[0].index()),
+                None => pieces.iter().position(|p| p.source == pending.source
+                    && p.start + p.length == pending.start + pending.length && p.length >= inserted)
+            };
+            let rebased = match followed {
+                Some(i) => {
+                    tmut.piece_ix = i;
+                    tmut.floor = pieces[i].length - inserted;
+                    rebase_undo(&mut tmut.action, &mut ops)
+                },
+                None => false
+            };
+            if !rebased {
+                // nothing before the insertion can be undone now
+                ops.clear();
+                self.history.clear();
+            }
+            if followed.is_none() { restart = Some(tmut); }
+        }
+        for i in (0..self.history.len()).rev() {
+            if !rebase_undo(&mut self.history[i], &mut ops) {
+                self.history.drain(..=i);
+                break;
+            }
+        }
+        // undone actions are redone on the text as it is, before the history
+        let mut ops = remote;
+        for i in (0..self.undone.len()).rev() {
+            let a = &mut self.undone[i];
+            let mut redo: Vec<PieceOp> = a.iter().map(Change::op).collect();
+            if PieceOp::transform_all(&mut redo, &mut ops).is_none() {
+                self.undone.drain(..=i);
+                break;
+            }
+            for (c, r) in a.changes.iter_mut().zip(redo) {
+                *c.piece_index_mut() = r.index();
+            }
+        }
+        // text that only this copy had can be gone along with the piece it was in
+        let index = moved(index).min(self.len());
+        if let Some(tmut) = restart {
+            *tmut = self.insert_mutator(index);
+        }
+        index
+    }
+
     pub fn get_changes_from(&self, id: usize) -> Vec<Action> {
         self.history.iter().filter(|a| a.id >= id).cloned().collect()
     }
@@ -271,13 +571,47 @@ impl<'table> PieceTable {
         }
     }
 
-    pub fn undo(&mut self) {
-        if let Some(action) = self.history.pop() {
-            println!("undoing {:?}", action);
-            for change in action.iter().rev() {
-                self.reverse_change(&change);
-            }
+    /// add a new action to the history, which can't be followed by anything that was undone
+    fn push_action(&mut self, action: Action) {
+        self.history.push(action);
+        self.undone.clear();
+    }
+
+    /// reverse the most recent action, returning where the cursor should go to show the change
+    pub fn undo(&mut self) -> Option<usize> {
+        let action = self.history.pop()?;
+        println!("undoing {:?}", action);
+        for change in action.iter().rev() {
+            self.reverse_change(&change);
         }
+        let cursor = action.cursor_before;
+        self.undone.push(action);
+        Some(cursor)
+    }
+
+    /// make the most recently undone action again, returning where the cursor should go to show the change
+    pub fn redo(&mut self) -> Option<usize> {
+        let action = self.undone.pop()?;
+        for change in action.iter() {
+            self.enact_change(change);
+        }
+        let cursor = action.cursor_after;
+        self.history.push(action);
+        Some(cursor)
+    }
+
+    /// combine the last `count` actions in the history into one, so that they are undone together
+    pub fn merge_last_actions(&mut self, count: usize) {
+        if count < 2 || self.history.len() < count { return; }
+        let at = self.history.len() - count;
+        let mut rest = self.history.split_off(at).into_iter();
+        let mut merged = rest.next().unwrap();
+        for action in rest {
+            merged.changes.extend(action.changes);
+            merged.cursor_before = merged.cursor_before.min(action.cursor_before);
+            merged.cursor_after = action.cursor_after;
+        }
+        self.history.push(merged);
     }
 
     pub fn most_recent_action_id(&self) -> usize {
@@ -289,8 +623,30 @@ impl<'table> PieceTable {
         }
     }
 
+    /// the index of the last piece if it ends at the end of its source, so that text pushed onto
+    /// the source can be added to it by making it longer
+    fn last_piece_at_end_of_source(&self) -> Option<usize> {
+        let i = self.pieces.len().checked_sub(1)?;
+        let p = &self.pieces[i];
+        if self.sources[p.source].len() == p.start + p.length { Some(i) } else { None }
+    }
+
     pub fn insert_range(&mut self, s: &str, index: usize) {
         if s.len() == 0 { return; }
+        if index == self.len() {
+            // appending to the source of the last piece means that adding text at the end over
+            // and over doesn't keep making new pieces
+            if let Some(i) = self.last_piece_at_end_of_source() {
+                let old = self.pieces[i];
+                self.sources[old.source].push_str(s);
+                let new = Piece { length: old.length + s.len(), ..old };
+                self.pieces[i] = new;
+                let mut action = Action::new(self, index, index + s.len());
+                action.push(Change::Modify { piece_index: i, old, new });
+                self.push_action(action);
+                return;
+            }
+        }
         let new_piece = Piece { source: self.sources.len(), start: 0, length: s.len() };
         self.sources.push(String::from(s));
         self.insert_raw_piece(index, new_piece);
@@ -299,7 +655,15 @@ impl<'table> PieceTable {
     pub fn insert_raw_piece(&mut self, index: usize, new_piece: Piece) {
         assert!(new_piece.source < self.sources.len());
         let mut ix = 0usize;
-        let mut action = Action::new(self);
+        let mut action = Action::new(self, index, index + new_piece.length);
+        if index == self.len() {
+            // this also covers a table with no pieces left, which the loop would never find
+            let i = self.pieces.len();
+            self.pieces.push(new_piece);
+            action.push(Change::Insert { piece_index: i, new: new_piece });
+            self.push_action(action);
+            return;
+        }
         for (i,p) in self.pieces.iter().enumerate() {
             //println!("{} {:?}", i, p);
             if index >= ix && index <= ix+p.length {
@@ -322,14 +686,32 @@ impl<'table> PieceTable {
             }
             ix += p.length;
         }
-        self.history.push(action);
+        self.push_action(action);
     }
 
 
     pub fn insert_mutator(&mut self, index: usize) -> TableMutator {
         let mut ix = 0usize;
         let mut insertion_piece_index: Option<usize> = None;
-        let mut action = Action::new(self);
+        let mut action = Action::new(self, index, index);
+        if index == self.len() {
+            let i = match self.last_piece_at_end_of_source() {
+                Some(i) => {
+                    action.push(Change::Modify { piece_index: i, old: self.pieces[i], new: self.pieces[i] });
+                    i
+                },
+                None => {
+                    let i = self.pieces.len();
+                    let new = Piece { source: self.sources.len(), start: 0, length: 0 };
+                    self.sources.push(String::new());
+                    self.pieces.push(new);
+                    action.push(Change::Insert { piece_index: i, new });
+                    i
+                }
+            };
+            let floor = self.pieces[i].length;
+            return TableMutator { piece_ix: i, action, floor };
+        }
         for (i,p) in self.pieces.iter().enumerate() {
             if index >= ix && index <= ix+p.length {
                 if index == ix { // we're inserting at the start of this piece
@@ -366,7 +748,8 @@ impl<'table> PieceTable {
             ix += p.length;
         }
         let insertion_piece_index = insertion_piece_index.unwrap();
-        TableMutator { piece_ix: insertion_piece_index, action }
+        let floor = self.pieces[insertion_piece_index].length;
+        TableMutator { piece_ix: insertion_piece_index, action, floor }
     }
 
     /// deletes the range [start, end)
@@ -376,7 +759,7 @@ impl<'table> PieceTable {
         let mut end_piece:   Option<(usize,usize)> = None;
         let mut mid_pieces:  Vec<usize>            = Vec::new();
         let mut global_index                       = 0usize;
-        let mut action = Action::new(self);
+        let mut action = Action::new(self, start, start);
 
         for (i,p) in self.pieces.iter().enumerate() {
             if start < global_index && end >= global_index+p.length {
@@ -418,7 +801,7 @@ impl<'table> PieceTable {
                             self.pieces.insert(i+1, right_keep);
                         }
                     }
-                    self.history.push(action);
+                    self.push_action(action);
                     return;
                 } else {
                     start_piece = Some((i, start-global_index));
@@ -433,19 +816,22 @@ impl<'table> PieceTable {
         //println!("start: {:?}\nend: {:?}\nmid: {:?}", start_piece, end_piece, mid_pieces);
 
         let (start_piece, start_cut) = start_piece.unwrap();
-        let (end_piece,   end_cut)   = end_piece.unwrap();
-
         let new_start = self.pieces[start_piece].split(start_cut).0;
-        let new_end   = self.pieces[end_piece].split(end_cut).1;
-
         action.push(Change::Modify { piece_index: start_piece, old: self.pieces[start_piece], new: new_start });
-        action.push(Change::Modify { piece_index: end_piece, old: self.pieces[end_piece], new: new_end });
-
         self.pieces[start_piece] = new_start;
-        self.pieces[end_piece]   = new_end;
 
-        for i in &mid_pieces {
-            action.push(Change::Delete { piece_index: *i,  old: self.pieces[*i] });
+        // if the range runs to the end of the table then the last piece is entirely deleted and
+        // there is no piece left to cut the end out of
+        if let Some((end_piece, end_cut)) = end_piece {
+            let new_end = self.pieces[end_piece].split(end_cut).1;
+            action.push(Change::Modify { piece_index: end_piece, old: self.pieces[end_piece], new: new_end });
+            self.pieces[end_piece] = new_end;
+        }
+
+        // record the deletions as if they happen one after another, so each index accounts for the
+        // pieces removed before it and undoing them in reverse puts every piece back in place
+        for (removed, i) in mid_pieces.iter().enumerate() {
+            action.push(Change::Delete { piece_index: *i - removed,  old: self.pieces[*i] });
         }
 
         self.pieces = self.pieces.iter()
@@ -453,11 +839,27 @@ impl<'table> PieceTable {
             .filter(|(i, _)| !mid_pieces.contains(i))
             .map(|(_, p)| p.clone()).collect();
 
-        self.history.push(action);
+        self.push_action(action);
     }
 
     /// copies the range [start, end)
     pub fn copy_range(&self, start: usize, end: usize) -> String {
+        // ranges inside of a single piece, like text that was just typed, are a single slice of its source
+        let mut global_index = 0usize;
+        for p in self.pieces.iter() {
+            if start < global_index+p.length {
+                if end <= global_index+p.length {
+                    return self.sources[p.source][(p.start + start-global_index)..(p.start + end-global_index)].to_string();
+                }
+                break;
+            }
+            global_index += p.length;
+        }
+        self.copy_range_pieces(start, end)
+    }
+
+    /// copy a range that can span any number of pieces
+    fn copy_range_pieces(&self, start: usize, end: usize) -> String {
         let mut buf = String::with_capacity(end-start);
         let mut global_index = 0usize;
         for p in self.pieces.iter() {
@@ -558,7 +960,7 @@ impl<'table> PieceTable {
         let mut global_index = 0;
         for p in self.pieces.iter() {
             if index >= global_index && index < global_index+p.length { 
-                return self.sources[p.source].chars().nth(p.start + index-global_index);
+                return self.sources[p.source][p.start + index-global_index..].chars().next();
             }
             global_index += p.length;
         }
@@ -582,6 +984,48 @@ impl<'table> PieceTable {
         panic!("tried to start char iterator out of bounds");
     }
     
+    /// the characters in [start, end), which unlike `chars` stops at `end`, can start at the end
+    /// of the text and goes backwards from `end` when reversed
+    pub fn chars_in_range(&self, start: usize, end: usize) -> impl DoubleEndedIterator<Item=char> + '_ {
+        let mut slices = Vec::new();
+        let mut global_index = 0usize;
+        for p in self.pieces.iter() {
+            let piece_start = global_index;
+            global_index += p.length;
+            if piece_start >= end { break; }
+            if global_index <= start { continue; }
+            let s = start.max(piece_start) - piece_start;
+            let e = end.min(global_index) - piece_start;
+            slices.push(&self.sources[p.source][p.start+s .. p.start+e]);
+        }
+        slices.into_iter().flat_map(str::chars)
+    }
+
+    /// the lines starting from the one that `index` is at the start of
+    pub fn lines(&self, index: usize) -> TableLines<'_> {
+        let mut global_index = 0;
+        for (pi, p) in self.pieces.iter().enumerate() {
+            if index >= global_index && index < global_index+p.length {
+                return TableLines { table: self, current_piece: pi, current_index: index - global_index };
+            }
+            global_index += p.length;
+        }
+        TableLines { table: self, current_piece: self.pieces.len(), current_index: 0 }
+    }
+
+    /// true if `index` is at the start of a character or the end of the text, rather than past
+    /// the end or in the middle of a multibyte character
+    pub fn is_char_boundary(&self, index: usize) -> bool {
+        let mut global_index = 0;
+        for p in self.pieces.iter() {
+            if index >= global_index && index < global_index+p.length {
+                return self.sources[p.source].is_char_boundary(p.start + index-global_index);
+            }
+            global_index += p.length;
+        }
+        index == global_index
+    }
+
     pub fn round_to_grapheme_boundary(&self, index: usize, dir: Direction) -> usize {
         index
     }
@@ -613,7 +1057,7 @@ mod tests {
         
         for i in 0..1_000 {
             if pt.text().len() == 0 { println!("deleted entire text"); break; }
-            match (rand::random::<usize>()+1) % 8 {
+            match (rand::random::<usize>()+1) % 10 {
                 0 => {
                     let mut tx = pt.text();
                     let x = rand::random::<usize>() % tx.len();
@@ -685,12 +1129,35 @@ mod tests {
                     println!("undo()");
                     println!("history = {:?}", history);
                     pt.undo();
-                    history.pop();
-                    assert_eq!(pt.text(), history.last().cloned().unwrap_or("".into()));
+                    if history.len() > 1 { history.pop(); }
+                    assert_eq!(pt.text(), *history.last().unwrap());
+                },
+                8 | 9 => {
+                    let mut tx = pt.text();
+                    let x = rand::random::<usize>() % tx.len();
+                    let pushes = rand::random::<usize>() % 8;
+                    let pops = rand::random::<usize>() % 10;
+                    println!("insert_mutator({}) push_char x{} pop_char x{}", x, pushes, pops);
+                    let mut m = pt.insert_mutator(x);
+                    let mut inserted = String::new();
+                    for _ in 0..pushes {
+                        let c = ['a', ' ', '\n', 'z'][rand::random::<usize>() % 4];
+                        m.push_char(&mut pt, c);
+                        inserted.push(c);
+                    }
+                    for _ in 0..pops {
+                        assert_eq!(m.pop_char(&mut pt), inserted.pop().is_none());
+                    }
+                    m.finish(&mut pt);
+                    tx.insert_str(x, &inserted);
+                    assert_eq!(pt.text(), tx);
                 },
                 x@_ => { println!("{}", x); }
             }
-            history.push(pt.text());
+            // only the operations that changed the text can be undone
+            if pt.history.len() >= history.len() {
+                history.push(pt.text());
+            }
             println!("sources [");
             for (i, s) in pt.sources.iter().enumerate() {
                 println!("\t{} = \"{}\"", i, s.escape_debug());
@@ -718,6 +1185,44 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn merge_actions() {
+        let mut pt = PieceTable::with_text("hello");
+        pt.delete_range(0, 1);
+        pt.insert_range("j", 0);
+        pt.merge_last_actions(2);
+        pt.insert_range("!", 5);
+        assert_eq!(pt.text(), "jello!");
+        assert_eq!(pt.history.len(), 2);
+        pt.undo();
+        assert_eq!(pt.text(), "jello");
+        pt.undo();
+        assert_eq!(pt.text(), "hello");
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut pt = PieceTable::with_text("hello world");
+        pt.delete_range(0, 6);
+        let mut m = pt.insert_mutator(5);
+        m.push_char(&mut pt, '!');
+        m.push_char(&mut pt, '?');
+        m.finish(&mut pt);
+        assert_eq!(pt.text(), "world!?");
+        assert_eq!(pt.undo(), Some(5));
+        assert_eq!(pt.undo(), Some(0));
+        assert_eq!(pt.text(), "hello world");
+        assert_eq!(pt.redo(), Some(0));
+        assert_eq!(pt.text(), "world");
+        assert_eq!(pt.redo(), Some(7));
+        assert_eq!(pt.text(), "world!?");
+        assert_eq!(pt.redo(), None);
+        pt.undo();
+        pt.insert_range("s", 5);
+        assert_eq!(pt.redo(), None, "a new action drops undone ones");
+        assert_eq!(pt.text(), "worlds");
+    }
+
     #[test]
     fn insert_cont() {
         let mut pt = PieceTable::with_text("hello");
@@ -747,6 +1252,73 @@ mod tests {
         assert_eq!(pt.text(), "xyz");
     }
 
+    #[test]
+    fn insert_range_append_many() {
+        let mut pt = PieceTable::with_text("x");
+        let mut expected = String::from("x");
+        for i in 0..1000 {
+            let s = if i % 10 == 0 { "\n" } else { "ab" };
+            pt.insert_range(s, pt.len());
+            expected.push_str(s);
+        }
+        assert_eq!(pt.text(), expected);
+        assert_eq!(pt.pieces.len(), 1);
+        pt.undo();
+        assert_eq!(pt.text(), expected[..expected.len()-2]);
+        // the piece doesn't end at the end of its source any more, so this needs a new one
+        pt.insert_range("cd", pt.len());
+        assert_eq!(pt.pieces.len(), 2);
+        assert!(pt.text().ends_with("abcd"));
+    }
+
+    #[test]
+    fn insert_mutator_append_many() {
+        let mut pt = PieceTable::with_text("");
+        for c in "hello world".chars() {
+            let mut m = pt.insert_mutator(pt.len());
+            m.push_char(&mut pt, c);
+            m.finish(&mut pt);
+        }
+        assert_eq!(pt.text(), "hello world");
+        assert_eq!(pt.pieces.len(), 1);
+        pt.undo();
+        assert_eq!(pt.text(), "hello worl");
+    }
+
+    #[test]
+    fn pop_char_stops_at_start_of_insert() {
+        let mut pt = PieceTable::with_text("ab");
+        pt.insert_range("cd", 2);
+        let mut m = pt.insert_mutator(pt.len());
+        m.push_char(&mut pt, 'é');
+        assert!(!m.pop_char(&mut pt));
+        assert!(m.pop_char(&mut pt), "text from before the insert stays");
+        assert!(m.pop_char(&mut pt));
+        assert_eq!(pt.text(), "abcd");
+        m.push_char(&mut pt, 'x');
+        m.finish(&mut pt);
+        assert_eq!(pt.text(), "abcdx");
+        pt.undo();
+        assert_eq!(pt.text(), "abcd");
+        pt.undo();
+        assert_eq!(pt.text(), "ab");
+    }
+
+    #[test]
+    fn insert_after_deleting_everything() {
+        let mut pt = PieceTable::with_text("abc");
+        pt.insert_range("def", 1);
+        pt.delete_range(0, 6);
+        assert_eq!(pt.text(), "");
+        pt.insert_range("xyz", 0);
+        assert_eq!(pt.text(), "xyz");
+        pt.delete_range(0, 3);
+        let mut m = pt.insert_mutator(0);
+        m.push_char(&mut pt, 'q');
+        m.finish(&mut pt);
+        assert_eq!(pt.text(), "q");
+    }
+
     #[test]
     fn delete_range_single_piece() {
         let mut pt = PieceTable::with_text("hello");
@@ -755,6 +1327,33 @@ mod tests {
         println!("{:#?}", pt);
     }
  
+    #[test]
+    fn delete_range_to_end() {
+        let mut pt = PieceTable::with_text("hello");
+        pt.insert_range(" wor", 5);
+        pt.insert_range("ld", 9);
+        pt.delete_range(3, 11);
+        assert_eq!(pt.text(), "hel");
+        pt.undo();
+        assert_eq!(pt.text(), "hello world");
+    }
+
+    #[test]
+    fn lines_across_pieces() {
+        let mut pt = PieceTable::with_text("one\ntwo\nthree\n");
+        pt.insert_range("X\nY", 5);
+        pt.insert_range("\n", 0);
+        pt.insert_range("four\r\n\nfive", pt.len());
+        let text = pt.text();
+        assert_eq!(pt.lines(0).collect::<Vec<_>>(), text.lines().collect::<Vec<_>>());
+        let second = text.find('\n').unwrap()+1;
+        assert_eq!(pt.lines(second).collect::<Vec<_>>(), text[second..].lines().collect::<Vec<_>>());
+        assert!(matches!(pt.lines(0).nth(1), Some(Cow::Borrowed("one"))));
+        assert!(matches!(pt.lines(0).nth(2), Some(Cow::Owned(_))));
+        assert_eq!(pt.lines(pt.len()).next(), None);
+        assert_eq!(PieceTable::with_text("").lines(0).next(), None);
+    }
+
     #[test]
     fn delete_range_multiple_pieces() {
         let mut pt = PieceTable::with_text("hello");
@@ -805,6 +1404,73 @@ mod tests {
         println!("{:#?}", pt);
     }
 
+    #[test]
+    fn char_boundaries() {
+        let mut pt = PieceTable::with_text("a\u{e9}b");
+        pt.insert_range("\u{65e5}", 1);
+        let tx = pt.text();
+        for i in 0..=tx.len() + 1 {
+            assert_eq!(pt.is_char_boundary(i), i <= tx.len() && tx.is_char_boundary(i), "{}", i);
+        }
+    }
+
+    #[test]
+    fn chars_in_range() {
+        let mut pt = PieceTable::with_text("hello w\u{f6}rld");
+        pt.insert_range("big \u{65e5} ", 6);
+        pt.delete_range(2, 4);
+        pt.insert_range("", 3);
+        pt.insert_range("!", pt.len());
+        let tx = pt.text();
+        for s in (0..=tx.len()).filter(|i| tx.is_char_boundary(*i)) {
+            for e in (s..=tx.len()).filter(|i| tx.is_char_boundary(*i)) {
+                assert_eq!(pt.chars_in_range(s, e).collect::<String>(), tx[s..e], "{}..{}", s, e);
+                assert_eq!(pt.chars_in_range(s, e).rev().collect::<Vec<_>>(), tx[s..e].chars().rev().collect::<Vec<_>>(), "{}..{} reversed", s, e);
+            }
+        }
+        // both ends at once meet in the middle
+        let (mut chars, mut expected) = (pt.chars_in_range(1, 6), tx[1..6].chars());
+        assert_eq!((chars.next(), chars.next_back(), chars.next()), (expected.next(), expected.next_back(), expected.next()));
+        assert_eq!(pt.chars_in_range(0, tx.len() + 10).count(), tx.chars().count());
+    }
+
+    #[test]
+    fn copy_range_fast_path() {
+        let mut pt = PieceTable::with_text("hello world");
+        pt.insert_range("big ", 6);
+        pt.delete_range(2, 4);
+        pt.insert_range("", 3);
+        let tx = pt.text();
+        for s in 0..=tx.len() {
+            for e in s..=tx.len() {
+                assert_eq!(pt.copy_range(s, e), pt.copy_range_pieces(s, e), "{}..{}", s, e);
+                assert_eq!(pt.copy_range(s, e), tx[s..e]);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore] // timing depends on the machine, run with `cargo test -- --ignored`
+    fn copy_range_fast_path_timing() {
+        // lots of small pieces, like a file that has been edited for a while
+        let mut pt = PieceTable::with_text("");
+        for i in 0..20_000 {
+            pt.insert_range(if i % 2 == 0 { "some text " } else { "more text\n" }, 0);
+        }
+        let time = |copy: &dyn Fn(usize, usize) -> String| {
+            let start = std::time::Instant::now();
+            // ranges that run to the end of the piece they are in, like text that was just typed
+            for i in 0..10_000 {
+                assert_eq!(copy(i % 10, 10).len(), 10 - i % 10);
+            }
+            start.elapsed()
+        };
+        let fast = time(&|s, e| pt.copy_range(s, e));
+        let general = time(&|s, e| pt.copy_range_pieces(s, e));
+        println!("copy_range: {:?}, copy_range_pieces: {:?}", fast, general);
+        assert!(fast < general);
+    }
+
     #[test]
     fn undo_insert_cont() {
         let mut pt = PieceTable::with_text("hello");
@@ -909,6 +1575,13 @@ mod tests {
         for (i, c) in tx.chars().enumerate() {
             assert_eq!(pt.char_at(i).unwrap(), c, "i = {}", i);
         }
+
+        let mut pt = PieceTable::with_text("hé€lo");
+        pt.insert_range("ü", 3);
+        let tx = pt.text();
+        for (i, c) in tx.char_indices() {
+            assert_eq!(pt.char_at(i).unwrap(), c, "i = {}", i);
+        }
     }
 
     #[test]
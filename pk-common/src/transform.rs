@@ -0,0 +1,204 @@
+use serde::{Serialize, Deserialize};
+use crate::piece_table::PieceTable;
+use crate::diff::{self, LineChange};
+use std::ops::Range;
+
+/// A change to some text in terms of where it happens, rather than which pieces of a table it
+/// touches, so that edits made concurrently on different copies of the same text can be
+/// transformed to apply after each other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Edit {
+    Insert { at: usize, text: String },
+    Delete { at: usize, len: usize }
+}
+
+impl Edit {
+    /// the edits that turn `old` into `new`, found by lining up the lines of each by their
+    /// longest common subsequence so that changes to different lines stay separate. The new text
+    /// for each run of changed lines is inserted before the old text is deleted, so that text
+    /// another edit inserts at the end of the run ends up after the new text rather than before
+    pub fn between(old: &str, new: &str) -> Vec<Edit> {
+        let old: Vec<&str> = old.split_inclusive('\n').collect();
+        let new: Vec<&str> = new.split_inclusive('\n').collect();
+
+        let mut edits = Vec::new();
+        // where the next edit goes in the text with the edits so far applied
+        let mut at = 0;
+        let (mut deleted, mut inserted) = (0, String::new());
+        fn flush(edits: &mut Vec<Edit>, at: &mut usize, deleted: &mut usize, inserted: &mut String) {
+            if !inserted.is_empty() {
+                *at += inserted.len();
+                edits.push(Edit::Insert { at: *at - inserted.len(), text: std::mem::take(inserted) });
+            }
+            if *deleted > 0 {
+                edits.push(Edit::Delete { at: *at, len: *deleted });
+                *deleted = 0;
+            }
+        }
+        for change in diff::lines(&old, &new) {
+            match change {
+                LineChange::Same(l) => {
+                    flush(&mut edits, &mut at, &mut deleted, &mut inserted);
+                    at += l.len();
+                },
+                LineChange::Removed(l) => deleted += l.len(),
+                LineChange::Added(l) => inserted.push_str(l)
+            }
+        }
+        flush(&mut edits, &mut at, &mut deleted, &mut inserted);
+        edits
+    }
+
+    pub fn apply(&self, pt: &mut PieceTable) {
+        match self {
+            Edit::Insert { at, text } => pt.insert_range(text, *at),
+            Edit::Delete { at, len } => pt.delete_range(*at, at + len)
+        }
+    }
+
+    /// this edit rewritten to apply after `other`, where both were made on the same text. When
+    /// both insert at the same place, the text of the edit that goes `first` ends up first. A
+    /// deletion that another edit inserted into the middle of is split around the inserted text
+    pub fn transform(&self, other: &Edit, first: bool) -> Vec<Edit> {
+        match (self, other) {
+            (Edit::Insert { at, text }, Edit::Insert { at: oat, text: otext }) => {
+                let at = if *at < *oat || (*at == *oat && first) { *at } else { at + otext.len() };
+                vec![Edit::Insert { at, text: text.clone() }]
+            },
+            (Edit::Insert { at, text }, Edit::Delete { .. }) =>
+                vec![Edit::Insert { at: other.map_index(*at), text: text.clone() }],
+            (Edit::Delete { at, len }, Edit::Insert { at: oat, text }) => {
+                if *oat <= *at {
+                    vec![Edit::Delete { at: at + text.len(), len: *len }]
+                } else if *oat >= at + len {
+                    vec![self.clone()]
+                } else {
+                    vec![Edit::Delete { at: *at, len: oat - at },
+                         Edit::Delete { at: at + text.len(), len: at + len - oat }]
+                }
+            },
+            (Edit::Delete { at, len }, Edit::Delete { .. }) => {
+                let (start, end) = (other.map_index(*at), other.map_index(at + len));
+                if end > start { vec![Edit::Delete { at: start, len: end - start }] } else { vec![] }
+            }
+        }
+    }
+
+    /// where `index` ends up after this edit. Text inserted at `index` goes before it, and indices
+    /// in deleted text move to the start of the deletion
+    pub fn map_index(&self, index: usize) -> usize {
+        match self {
+            Edit::Insert { at, text } => if index >= *at { index + text.len() } else { index },
+            Edit::Delete { at, len } => if index >= at + len { index - len } else { index.min(*at) }
+        }
+    }
+}
+
+/// transform two sequences of edits made concurrently on the same text so that each can be
+/// applied after the other, returning `a` rewritten to follow `b` and `b` rewritten to follow
+/// `a`. Either way around, the result is the same text. Ties between insertions at the same place
+/// go to `a` if `a_first`
+pub fn transform(a: &[Edit], b: &[Edit], a_first: bool) -> (Vec<Edit>, Vec<Edit>) {
+    match (a.len(), b.len()) {
+        (0, _) | (_, 0) => (a.to_vec(), b.to_vec()),
+        (1, 1) => (a[0].transform(&b[0], a_first), b[0].transform(&a[0], !a_first)),
+        (1, _) => {
+            let (a, b0) = transform(a, &b[..1], a_first);
+            let (a, rest) = transform(&a, &b[1..], a_first);
+            (a, b0.into_iter().chain(rest).collect())
+        },
+        _ => {
+            let (a0, b) = transform(&a[..1], b, a_first);
+            let (rest, b) = transform(&a[1..], &b, a_first);
+            (a0.into_iter().chain(rest).collect(), b)
+        }
+    }
+}
+
+/// where `index` ends up after all of `edits`. An index in text that is replaced, by inserting
+/// new text right before deleting it, ends up at the start of the new text
+pub fn map_index(index: usize, edits: &[Edit]) -> usize {
+    let mut index = index;
+    let mut inserted: Option<Range<usize>> = None;
+    for e in edits {
+        index = match e {
+            Edit::Delete { at, len } if index >= *at && index < at + len =>
+                inserted.filter(|r| r.end == *at).map_or(*at, |r| r.start),
+            _ => e.map_index(index)
+        };
+        inserted = match e {
+            Edit::Insert { at, text } => Some(*at .. at + text.len()),
+            Edit::Delete { .. } => None
+        };
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_all(text: &str, edits: &[Edit]) -> String {
+        let mut pt = PieceTable::with_text(text);
+        for e in edits {
+            e.apply(&mut pt);
+        }
+        pt.text()
+    }
+
+    /// both orders of applying `a` and `b` to `base` give the same text, which is returned
+    fn converge(base: &str, a: &[Edit], b: &[Edit]) -> String {
+        let (a2, b2) = transform(a, b, true);
+        let ab = apply_all(&apply_all(base, a), &b2);
+        let ba = apply_all(&apply_all(base, b), &a2);
+        assert_eq!(ab, ba, "a then b' = b then a'");
+        ab
+    }
+
+    fn ins(at: usize, text: &str) -> Edit { Edit::Insert { at, text: text.into() } }
+    fn del(at: usize, len: usize) -> Edit { Edit::Delete { at, len } }
+
+    #[test]
+    fn between() {
+        assert_eq!(Edit::between("a\nb\nc\n", "a\nx\nc\n"), vec![ins(2, "x\n"), del(4, 2)]);
+        assert_eq!(Edit::between("a\nb\n", "a\nb\nc"), vec![ins(4, "c")]);
+        assert_eq!(Edit::between("a\nb\nc\nd\n", "b\nc\nx\n"), vec![del(0, 2), ins(4, "x\n"), del(6, 2)]);
+        assert_eq!(Edit::between("a\nb", "a\nb"), vec![]);
+        for (old, new) in [("x\ny\nz", "y\nq\nz\n"), ("", "a\nb"), ("a\n\nb\n", "\n")].iter() {
+            assert_eq!(apply_all(old, &Edit::between(old, new)), *new);
+        }
+    }
+
+    #[test]
+    fn concurrent_insertions() {
+        assert_eq!(converge("hello world", &[ins(0, "oh, ")], &[ins(11, "!")]), "oh, hello world!");
+        assert_eq!(converge("ab", &[ins(1, "x")], &[ins(1, "y")]), "axyb", "ties go to the first");
+    }
+
+    #[test]
+    fn insertion_and_deletion() {
+        assert_eq!(converge("hello world", &[ins(6, "big ")], &[del(0, 6)]), "big world");
+        assert_eq!(converge("hello world", &[del(2, 6)], &[ins(5, ",")]), "he,rld", "inserted text survives");
+        assert_eq!(converge("hello world", &[ins(3, "X")], &[del(2, 6)]), "heXrld");
+    }
+
+    #[test]
+    fn overlapping_deletions() {
+        assert_eq!(converge("abcdefgh", &[del(1, 4)], &[del(3, 4)]), "ah");
+        assert_eq!(converge("abcdefgh", &[del(2, 2)], &[del(1, 5)]), "agh");
+        assert_eq!(converge("abcdefgh", &[del(2, 2)], &[del(2, 2)]), "abefgh");
+    }
+
+    #[test]
+    fn divergent_streams() {
+        let base = "fn main() {\n    let x = 1;\n}\n";
+        // one client renames the variable while the other adds a line after it
+        let a = Edit::between(base, "fn main() {\n    let y = 1;\n}\n").into_iter()
+            .chain(vec![ins(0, "// main\n")]).collect::<Vec<_>>();
+        let b = vec![ins(27, "    println!(\"{}\", x);\n"), del(3, 4), ins(3, "start")];
+        assert_eq!(converge(base, &a, &b),
+            "// main\nfn start() {\n    let y = 1;\n    println!(\"{}\", x);\n}\n");
+        assert_eq!(map_index(27, &a), 35);
+        assert_eq!(map_index(22, &a), 20, "replaced text goes to the start of the new line");
+    }
+}
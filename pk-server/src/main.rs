@@ -1,9 +1,13 @@
 
 use pk_common::protocol;
 
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::path::{PathBuf, Path};
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{PathBuf, Path, Component};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 enum ServerError { 
@@ -12,6 +16,9 @@ enum ServerError {
     IoError(std::io::Error),
     InternalError,
     BadFileId(protocol::FileId),
+    FileTooLarge { size: u64, max_size: u64 },
+    PathNotAllowed(PathBuf),
+    ConfigError(String),
     UnknownMessage
 }
 
@@ -40,6 +47,11 @@ impl std::fmt::Display for ServerError {
             Self::TransportError(e) => write!(f, "error in transport: {}", e),
             Self::IoError(e) => write!(f, "io error: {}", e),
             Self::BadFileId(id) => write!(f, "unrecongized file id: {:?}", id),
+            Self::FileTooLarge { size, max_size } =>
+                write!(f, "file is {} bytes, which is larger than the limit of {} bytes", size, max_size),
+            Self::PathNotAllowed(path) =>
+                write!(f, "{} is outside of the directories this server allows", path.display()),
+            Self::ConfigError(msg) => write!(f, "error in server configuration: {}", msg),
             Self::UnknownMessage => write!(f, "unrecongized message recieved"),
             Self::InternalError => write!(f, "internal error"),
         }
@@ -64,64 +76,161 @@ mod filetype_table {
     #[derive(Deserialize, Debug)]
     pub struct FileType {
         pub name: String,
-        pub ext: Vec<String>
+        pub ext: Vec<String>,
+        /// whole file names, for files like Makefiles that don't have an extension
+        #[serde(default)]
+        pub file_names: Vec<String>,
+        /// programs that can run the file from a `#!` line, without any version number
+        #[serde(default)]
+        pub interpreters: Vec<String>,
+        /// things the first line of the file can start with
+        #[serde(default)]
+        pub first_line: Vec<String>
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Default)]
     pub struct FileTypeTable {
         filetype: Vec<FileType>
     }
 
+    /// the program named in a `#!` line, skipping over `env` and dropping any version number, so
+    /// that `#!/usr/bin/env python3` gives `python`
+    fn interpreter(first_line: &str) -> Option<&str> {
+        let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+        let mut program = words.next()?.rsplit('/').next()?;
+        if program == "env" {
+            program = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+        }
+        Some(program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+    }
+
     impl FileTypeTable {
-        pub fn analyze(&self, path: impl AsRef<Path>) -> super::protocol::FileType {
-            use super::protocol::FileType;
-            match path.as_ref().extension().and_then(|oss| oss.to_str()) {
-                Some(ext) => {
-                    for ft in self.filetype.iter() {
-                        if ft.ext.iter().any(|x| ext == x) {
-                            return FileType::from(ft.name.as_str());
-                        }
-                    }
-                    FileType::default()
-                },
-                None => FileType::default()
-            }
+        /// find the type of a file by its extension or name, or if neither are known, by its
+        /// contents. Files that don't match anything are plain text
+        pub fn analyze(&self, path: impl AsRef<Path>, contents: &str) -> super::protocol::FileType {
+            let path = path.as_ref();
+            let ext = path.extension().and_then(|oss| oss.to_str());
+            let file_name = path.file_name().and_then(|oss| oss.to_str());
+            let first_line = contents.lines().next().unwrap_or("");
+            let interpreter = interpreter(first_line);
+            let tests: [&dyn Fn(&FileType) -> bool; 4] = [
+                &|ft| ext.map_or(false, |ext| ft.ext.iter().any(|x| ext == x)),
+                &|ft| file_name.map_or(false, |n| ft.file_names.iter().any(|x| n == x)),
+                &|ft| interpreter.map_or(false, |i| ft.interpreters.iter().any(|x| i == x)),
+                &|ft| ft.first_line.iter().any(|x| first_line.starts_with(x.as_str())),
+            ];
+            tests.iter()
+                .find_map(|test| self.filetype.iter().find(|ft| test(ft)))
+                .map_or_else(Default::default, |ft| ft.name.as_str().into())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn table() -> FileTypeTable {
+            let ft = |name: &str, ext: &[&str], file_names: &[&str], interpreters: &[&str], first_line: &[&str]| {
+                let strs = |s: &[&str]| s.iter().map(|s| s.to_string()).collect();
+                FileType { name: name.into(), ext: strs(ext), file_names: strs(file_names),
+                    interpreters: strs(interpreters), first_line: strs(first_line) }
+            };
+            FileTypeTable { filetype: vec![
+                ft("rust", &["rs"], &[], &[], &[]),
+                ft("python", &["py"], &[], &["python"], &[]),
+                ft("make", &["mk"], &["Makefile"], &[], &[]),
+                ft("xml ", &["xml"], &[], &[], &["<?xml"]),
+                ft("markdown", &["md"], &[], &[], &["---"]),
+            ] }
+        }
+
+        fn analyze(path: &str, contents: &str) -> String {
+            table().analyze(path, contents).to_string()
+        }
+
+        #[test]
+        fn extension_first() {
+            assert_eq!(analyze("a.rs", "#!/usr/bin/env python\n"), "rust");
+            assert_eq!(analyze("a.py", ""), "pyth");
+        }
+
+        #[test]
+        fn shebang() {
+            assert_eq!(analyze("script", "#!/usr/bin/env python3\nprint()\n"), "pyth");
+            assert_eq!(analyze("script", "#!/usr/bin/env -S PYTHONPATH=. python3.8 -u\n"), "pyth");
+            assert_eq!(analyze("script", "#!/usr/bin/python\n"), "pyth");
+            assert_eq!(analyze("script.unknown", "#!/usr/bin/python2.7"), "pyth");
+            assert_eq!(analyze("script", "#!/bin/sh\n"), "text");
+        }
+
+        #[test]
+        fn file_name_and_markers() {
+            assert_eq!(analyze("dir/Makefile", "all:\n"), "make");
+            assert_eq!(analyze("feed", "<?xml version=\"1.0\"?>\n<rss/>"), "xml ");
+            assert_eq!(analyze("post", "---\ntitle: hi\n---\n"), "mark");
+        }
+
+        #[test]
+        fn plain_text() {
+            assert_eq!(analyze("notes", "just some words\n"), "text");
+            assert_eq!(analyze("notes.txt", ""), "text");
+            assert_eq!(FileTypeTable::default().analyze("a.rs", "#!/bin/python"), crate::protocol::FileType::default());
         }
     }
 }
 
 use filetype_table::FileTypeTable;
 
-
-#[derive(Default)]
+#[derive(Clone)]
 struct File {
     path: Option<PathBuf>,
     contents: String,
     current_version: usize,
     format: protocol::TextFormat,
+    /// the last time a client opened, synced or read the file
+    last_access: Instant
 }
 
 impl File {
 
-    fn from_path<P: AsRef<Path>>(p: P, filetype_table: &FileTypeTable) -> Result<File, ServerError> {
+    /// read a file's contents from disk, treating a file that doesn't exist yet as empty. Files
+    /// larger than `max_size` bytes are refused before reading any of them
+    fn read_contents(path: &Path, max_size: u64) -> Result<String, ServerError> {
+        if let Ok(m) = std::fs::metadata(path) {
+            if m.len() > max_size {
+                return Err(ServerError::FileTooLarge { size: m.len(), max_size });
+            }
+        }
+        match std::fs::read_to_string(path) {
+            Ok(s) => Ok(s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(ServerError::IoError(e))
+        }
+    }
+
+    fn from_contents<P: AsRef<Path>>(p: P, mut contents: String, filetype_table: &FileTypeTable) -> File {
         let path = p.as_ref().to_owned();
-        let mut contents = match std::fs::read_to_string(&path) {
-            Ok(s) => s,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
-            Err(e) => return Err(ServerError::IoError(e))
-        };
+        let line_endings = protocol::LineEnding::count(&contents);
         let fmt = protocol::TextFormat {
-            line_ending: protocol::LineEnding::from_analysis(&contents),
-            stype: filetype_table.analyze(&path)
+            line_ending: line_endings.majority().unwrap_or_default(),
+            stype: filetype_table.analyze(&path, &contents),
+            mixed_line_endings: line_endings.is_mixed()
         };
-        if fmt.line_ending == protocol::LineEnding::CRLF {
+        // mixed line endings get written back as the most common one
+        if fmt.line_ending == protocol::LineEnding::CRLF || fmt.mixed_line_endings {
             contents = contents.replace("\r\n", "\n");
         }
-        Ok(File {
+        File {
             format: fmt,
             path: Some(path), contents,
             current_version: 0,
-        })
+            last_access: Instant::now()
+        }
+    }
+
+    /// true if nothing has touched the file for at least `timeout` as of `now`
+    fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(self.last_access) >= timeout
     }
 
     fn write_to_disk(&self) -> Result<(), ServerError> {
@@ -137,18 +246,411 @@ impl File {
     }
 }
 
+/// the largest file that will be opened if `--max-file-size` isn't given, 256MiB
+const DEFAULT_MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Settings for the server, read from `server.toml`. Options given on the command line take
+/// precedence over the file
+#[derive(Debug, Clone, PartialEq)]
+struct ServerConfig {
+    /// where to load the file type table from
+    filetypes: PathBuf,
+    /// how many requests can be handled at once
+    workers: usize,
+    /// the directories that files can be opened in, or anywhere if this is empty
+    allowed_roots: Vec<PathBuf>,
+    /// how often changed files are written to disk
+    autosave_interval: Duration,
+    /// the size in bytes of the largest file that can be opened
+    max_file_size: u64,
+    /// how long a file can go untouched before it is written out and dropped from memory
+    idle_timeout: Option<Duration>
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            filetypes: PathBuf::from("./filetypes.toml"),
+            workers: 8,
+            allowed_roots: Vec::new(),
+            autosave_interval: Duration::from_secs(1),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            idle_timeout: None
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_toml(val: &toml::Value) -> Result<ServerConfig, ServerError> {
+        let mut cfg = ServerConfig::default();
+        let positive = |key: &str| -> Result<Option<u64>, ServerError> {
+            val.get(key).map(|v| v.as_integer().filter(|n| *n > 0).map(|n| n as u64)
+                .ok_or_else(|| ServerError::ConfigError(format!("expected a positive integer for {}", key))))
+                .transpose()
+        };
+        if let Some(ft) = val.get("filetypes") {
+            cfg.filetypes = ft.as_str().map(PathBuf::from)
+                .ok_or_else(|| ServerError::ConfigError("expected a path for filetypes".into()))?;
+        }
+        if let Some(w) = positive("workers")? { cfg.workers = w as usize; }
+        if let Some(roots) = val.get("allowed-roots") {
+            cfg.allowed_roots = roots.as_array().and_then(|rs| rs.iter().map(|r| r.as_str().map(PathBuf::from)).collect())
+                .ok_or_else(|| ServerError::ConfigError("expected a list of paths for allowed-roots".into()))?;
+        }
+        if let Some(i) = positive("autosave-interval")? { cfg.autosave_interval = Duration::from_secs(i); }
+        if let Some(m) = positive("max-file-size")? { cfg.max_file_size = m; }
+        if let Some(t) = positive("idle-timeout")? { cfg.idle_timeout = Some(Duration::from_secs(t)); }
+        Ok(cfg)
+    }
+
+    /// read the configuration at `path`, using the defaults if there is no file there
+    fn load(path: &Path) -> Result<ServerConfig, ServerError> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => ServerConfig::from_toml(&s.parse::<toml::Value>()
+                .map_err(|e| ServerError::ConfigError(format!("{}", e)))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ServerConfig::default()),
+            Err(e) => Err(ServerError::IoError(e))
+        }
+    }
+}
+
+/// `path` made absolute and with any `.` and `..` components removed, without touching the file
+/// system, so that it works for files that don't exist yet
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut abs = if path.is_absolute() { PathBuf::new() } else { std::env::current_dir().unwrap_or_default() };
+    for c in path.components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => { abs.pop(); },
+            c => abs.push(c)
+        }
+    }
+    abs
+}
+
+/// `path` made absolute with every symlink resolved, so that it can be checked against the allowed
+/// roots without a link leading somewhere else. A file that doesn't exist yet is resolved through
+/// the closest directory above it that does
+fn canonicalize_path(path: &Path) -> PathBuf {
+    let path = normalize_path(path);
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return missing.into_iter().rev().fold(canonical, |p: PathBuf, name| p.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            },
+            _ => return path.clone()
+        }
+    }
+}
+
+/// how many responses are remembered for each connection
+const RECENT_RESPONSES_PER_CONNECTION: usize = 32;
+/// how many connections responses are remembered for before the least recently used is forgotten
+const RECENT_RESPONSE_CONNECTIONS: usize = 64;
+
+/// the id and hash of a request, and the response that was sent for it
+type Exchange = (protocol::MessageId, u64, protocol::Response);
+
+fn request_hash(req: &protocol::Request) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    req.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// the last few responses sent on each connection, so that a request that is sent again after
+/// the client gave up waiting gets the same response instead of being processed twice.
+/// Message ids are only unique within a connection, and a response is only reused if the
+/// request hashes the same as the one it answered
+struct RecentResponses<C> {
+    connections: HashMap<C, (u64, VecDeque<Exchange>)>,
+    /// incremented on every use of a connection, to find the least recently used one
+    clock: u64
+}
+
+impl<C: Hash + Eq + Clone> RecentResponses<C> {
+    fn new() -> Self {
+        RecentResponses { connections: HashMap::new(), clock: 0 }
+    }
+
+    /// true for requests that would change something if they were processed twice, which are
+    /// the only ones worth remembering the responses of
+    fn remembers(req: &protocol::Request) -> bool {
+        matches!(req, protocol::Request::SyncFile { .. } | protocol::Request::CloseFile(_))
+    }
+
+    fn get(&mut self, conn: &C, id: protocol::MessageId, req: &protocol::Request) -> Option<protocol::Response> {
+        self.clock += 1;
+        let (last_used, responses) = self.connections.get_mut(conn)?;
+        *last_used = self.clock;
+        let hash = request_hash(req);
+        responses.iter().find(|(i, h, _)| *i == id && *h == hash).map(|(_, _, resp)| resp.clone())
+    }
+
+    fn insert(&mut self, conn: C, id: protocol::MessageId, req: &protocol::Request, resp: protocol::Response) {
+        self.clock += 1;
+        if !self.connections.contains_key(&conn) && self.connections.len() >= RECENT_RESPONSE_CONNECTIONS {
+            let oldest = self.connections.iter().min_by_key(|(_, (t, _))| *t).map(|(c, _)| c.clone());
+            if let Some(c) = oldest { self.connections.remove(&c); }
+        }
+        let (last_used, responses) = self.connections.entry(conn).or_insert_with(|| (0, VecDeque::new()));
+        *last_used = self.clock;
+        responses.retain(|(i, _, _)| *i != id);
+        if responses.len() >= RECENT_RESPONSES_PER_CONNECTION {
+            responses.pop_front();
+        }
+        responses.push_back((id, request_hash(req), resp));
+    }
+}
+
+/// how long a `WaitForChanges` request is held before it is answered with the unchanged file, so
+/// that waiting clients don't tie up a worker forever
+const WAIT_FOR_CHANGES_TIMEOUT: Duration = Duration::from_secs(30);
+
 struct Server {
     open_files: HashMap<protocol::FileId, File>,
+    /// files that were dropped from memory after being idle, with their path and version, so that
+    /// they can be loaded again under the same id when a client uses them
+    evicted_files: HashMap<protocol::FileId, (PathBuf, usize)>,
     next_file_id: protocol::FileId,
-    filetype_table: FileTypeTable
+    filetype_table: FileTypeTable,
+    /// the size in bytes of the largest file that can be opened
+    max_file_size: u64,
+    /// how long a file can go untouched before it is written out and dropped from memory
+    idle_timeout: Option<Duration>,
+    /// canonical directories that files can be opened in, or anywhere if this is empty
+    allowed_roots: Vec<PathBuf>,
+    recent_responses: RecentResponses<Option<nng::Pipe>>,
+    /// clients waiting for each file to change, which are sent its info once it is synced, by
+    /// the id they were given when they started waiting
+    change_waiters: HashMap<protocol::FileId, Vec<(usize, mpsc::Sender<protocol::Response>)>>,
+    next_waiter_id: usize,
+    /// how long to wait for a change before answering with the file as it is
+    wait_timeout: Duration
 }
 
 impl Server {
     fn new(filetype_table: FileTypeTable) -> Self {
         Server {
             open_files: HashMap::new(),
+            evicted_files: HashMap::new(),
             next_file_id: protocol::FileId(1),
-            filetype_table
+            filetype_table,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            idle_timeout: None,
+            allowed_roots: Vec::new(),
+            recent_responses: RecentResponses::new(),
+            change_waiters: HashMap::new(),
+            next_waiter_id: 0,
+            wait_timeout: WAIT_FOR_CHANGES_TIMEOUT
+        }
+    }
+
+    fn with_config(filetype_table: FileTypeTable, config: &ServerConfig) -> Self {
+        let mut server = Server::new(filetype_table);
+        server.max_file_size = config.max_file_size;
+        server.idle_timeout = config.idle_timeout;
+        server.allowed_roots = config.allowed_roots.iter().map(|r| canonicalize_path(r)).collect();
+        server
+    }
+
+    /// the path to use for a file a client asked for. With allowed roots, this is the canonical
+    /// path, which is refused unless it is inside one of them. The canonical path is what gets
+    /// opened, so that a symlink can't be changed to point elsewhere after it has been checked
+    fn resolve_path(&self, path: PathBuf) -> Result<PathBuf, ServerError> {
+        if self.allowed_roots.is_empty() {
+            return Ok(path);
+        }
+        let resolved = canonicalize_path(&path);
+        if self.allowed_roots.iter().any(|r| resolved.starts_with(r)) {
+            Ok(resolved)
+        } else {
+            Err(ServerError::PathNotAllowed(path))
+        }
+    }
+
+    fn file_info_for_path(&mut self, path: &Path) -> Option<protocol::Response> {
+        self.open_files.iter_mut().find(|b| b.1.path.as_ref().map(|p| *p == path).unwrap_or(false))
+            .map(|(id, buf)| {
+                buf.last_access = Instant::now();
+                protocol::Response::FileInfo {
+                    id: *id,
+                    contents: buf.contents.clone(),
+                    version: buf.current_version,
+                    format: buf.format.clone()
+                }
+            })
+    }
+
+    /// open a file, or return the already open copy. The file is read from disk without holding
+    /// the lock on the server so that other requests can be processed while a large file loads
+    fn open_file(server: &RwLock<Self>, path: PathBuf) -> Result<protocol::Response, ServerError> {
+        Server::open_file_with(server, path, File::read_contents)
+    }
+
+    /// open a file like `open_file`, reading its contents with `read`
+    fn open_file_with(server: &RwLock<Self>, path: PathBuf,
+                      read: impl FnOnce(&Path, u64) -> Result<String, ServerError>) -> Result<protocol::Response, ServerError> {
+        let path = server.read().unwrap().resolve_path(path)?;
+        if let Some(info) = server.write().unwrap().file_info_for_path(&path) {
+            return Ok(info);
+        }
+        let max_size = server.read().unwrap().max_file_size;
+        let contents = read(&path, max_size)?;
+        let mut buf = File::from_contents(&path, contents, &server.read().unwrap().filetype_table);
+        let mut server = server.write().unwrap();
+        // someone else could have opened the same file while we were reading it
+        if let Some(info) = server.file_info_for_path(&path) {
+            return Ok(info);
+        }
+        let evicted = server.evicted_files.iter().find(|(_, (p, _))| *p == path).map(|(id, _)| *id);
+        let id = match evicted {
+            Some(id) => {
+                buf.current_version = server.evicted_files.remove(&id).unwrap().1;
+                id
+            },
+            None => {
+                let id = server.next_file_id;
+                server.next_file_id = protocol::FileId(server.next_file_id.0 + 1);
+                id
+            }
+        };
+        let res = protocol::Response::FileInfo {
+            id,
+            contents: buf.contents.clone(),
+            version: buf.current_version,
+            format: buf.format.clone()
+        };
+        server.open_files.insert(id, buf);
+        Ok(res)
+    }
+
+    fn handle_request(server: &RwLock<Self>, msg: protocol::Request) -> Result<protocol::Response, ServerError> {
+        match msg {
+            protocol::Request::OpenFile { path } => {
+                println!("request = OpenFile {{ path: {:?} }}", path);
+                Server::open_file(server, path)
+            },
+            protocol::Request::Ping => Ok(protocol::Response::Pong),
+            protocol::Request::WaitForChanges { id, since_version } => {
+                // the lock is only held to subscribe, so that the change can be made while waiting
+                let ((waiter, changes), timeout) = {
+                    let mut server = server.write().unwrap();
+                    (server.subscribe(id, since_version)?, server.wait_timeout)
+                };
+                match changes.recv_timeout(timeout) {
+                    Ok(info) => Ok(info),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        let mut server = server.write().unwrap();
+                        server.unsubscribe(id, waiter);
+                        server.process_request(protocol::Request::GetFile(id))
+                    },
+                    // the file was closed while waiting
+                    Err(mpsc::RecvTimeoutError::Disconnected) => Err(ServerError::BadFileId(id))
+                }
+            },
+            msg => server.write().unwrap().process_request(msg)
+        }
+    }
+
+    /// handle a request from the client connected by `conn`, answering a repeated request that
+    /// changes something with the response it got the first time
+    fn handle_message(server: &RwLock<Self>, conn: Option<nng::Pipe>, req: protocol::MsgRequest) -> protocol::MsgResponse {
+        let protocol::MsgRequest { msg_id, msg } = req;
+        if !RecentResponses::<Option<nng::Pipe>>::remembers(&msg) {
+            let resp = Server::handle_request(server, msg)
+                .unwrap_or_else(|err| protocol::Response::Error{message: format!("{}", err)});
+            return protocol::MsgResponse { req_id: msg_id, msg: resp };
+        }
+        // a retry can arrive on another context while the first copy is still being processed, so
+        // the lock is held from looking for a response until it has been remembered
+        let mut server = server.write().unwrap();
+        let cached = server.recent_responses.get(&conn, msg_id, &msg);
+        let resp = cached.unwrap_or_else(|| {
+            let resp = server.process_request(msg.clone())
+                .unwrap_or_else(|err| protocol::Response::Error{message: format!("{}", err)});
+            server.recent_responses.insert(conn, msg_id, &msg, resp.clone());
+            resp
+        });
+        protocol::MsgResponse { req_id: msg_id, msg: resp }
+    }
+
+    /// the open file with `id`, loading it from disk again if it was evicted
+    fn file_mut(&mut self, id: protocol::FileId) -> Result<&mut File, ServerError> {
+        if let Some((path, _)) = self.evicted_files.get(&id) {
+            println!("reloading evicted file {}", path.to_str().unwrap_or(""));
+            // the file stays evicted if it can't be read, so that it can be tried again
+            let contents = File::read_contents(path, self.max_file_size)?;
+            let (path, version) = self.evicted_files.remove(&id).unwrap();
+            let mut file = File::from_contents(&path, contents, &self.filetype_table);
+            file.current_version = version;
+            self.open_files.insert(id, file);
+        }
+        let file = self.open_files.get_mut(&id).ok_or_else(|| ServerError::BadFileId(id))?;
+        file.last_access = Instant::now();
+        Ok(file)
+    }
+
+    /// the info of the file with `id` as a response
+    fn file_info(&mut self, id: protocol::FileId) -> Result<protocol::Response, ServerError> {
+        let file = self.file_mut(id)?;
+        Ok(protocol::Response::FileInfo {
+            id,
+            contents: file.contents.clone(),
+            version: file.current_version,
+            format: file.format.clone()
+        })
+    }
+
+    /// a channel that gets the info of the file with `id` once it is at a newer version than
+    /// `since_version`, which is right away if it already is, along with the id of the waiter to
+    /// unsubscribe with if it stops waiting
+    fn subscribe(&mut self, id: protocol::FileId, since_version: usize) -> Result<(usize, mpsc::Receiver<protocol::Response>), ServerError> {
+        let (tx, rx) = mpsc::channel();
+        let waiter = self.next_waiter_id;
+        self.next_waiter_id += 1;
+        if self.file_mut(id)?.current_version > since_version {
+            tx.send(self.file_info(id)?).map_err(|_| ServerError::InternalError)?;
+        } else {
+            self.change_waiters.entry(id).or_default().push((waiter, tx));
+        }
+        Ok((waiter, rx))
+    }
+
+    /// stop sending changes to the file with `id` to `waiter`, which gave up waiting
+    fn unsubscribe(&mut self, id: protocol::FileId, waiter: usize) {
+        if let Some(waiters) = self.change_waiters.get_mut(&id) {
+            waiters.retain(|(w, _)| *w != waiter);
+            if waiters.is_empty() {
+                self.change_waiters.remove(&id);
+            }
+        }
+    }
+
+    /// send the new info of the file with `id` to every client waiting for it to change
+    fn notify_change_waiters(&mut self, id: protocol::FileId) -> Result<(), ServerError> {
+        if let Some(waiters) = self.change_waiters.remove(&id) {
+            let info = self.file_info(id)?;
+            for (_, w) in waiters {
+                // waiters that timed out have already gone
+                let _ = w.send(info.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// the files that haven't been touched for longer than the idle timeout as of `now`
+    fn idle_files(&self, now: Instant) -> Vec<protocol::FileId> {
+        match self.idle_timeout {
+            Some(timeout) => self.open_files.iter()
+                .filter(|(_, f)| f.path.is_some() && f.is_idle(now, timeout))
+                .map(|(id, _)| *id).collect(),
+            None => Vec::new()
         }
     }
 
@@ -156,28 +658,8 @@ impl Server {
         println!("request = {:?}", msg);
         use protocol::*;
         match msg {
-            Request::OpenFile { path } => {
-                let (id, contents, version, format) = 
-                    if let Some((id, buf)) = self.open_files.iter().find(|b| b.1.path.as_ref().map(|p| *p == path).unwrap_or(false)) {
-                        (*id, buf.contents.clone(), buf.current_version, buf.format.clone())
-                    }
-                    else {
-                        let buf = File::from_path(&path, &self.filetype_table)?;
-                        let id = self.next_file_id;
-                        self.next_file_id = protocol::FileId(self.next_file_id.0 + 1);
-                        let res = (id, buf.contents.clone(), buf.current_version, buf.format.clone());
-                        self.open_files.insert(id, buf);
-                        res
-                    };
-                Ok(Response::FileInfo {
-                    id,
-                    contents,
-                    version,
-                    format
-                })
-            },
-            Request::SyncFile { id, new_text, version } => {
-                let file = self.open_files.get_mut(&id).ok_or_else(|| ServerError::BadFileId(id))?;
+            Request::SyncFile { id, new_text, version, format } => {
+                let file = self.file_mut(id)?;
                 if file.current_version >= version {
                     Ok(Response::VersionConflict {
                         id,
@@ -188,10 +670,27 @@ impl Server {
                 } else {
                     file.current_version = version;
                     file.contents = new_text;
+                    file.format = format;
+                    self.notify_change_waiters(id)?;
                     Ok(Response::Ack)
                 }
             },
+            Request::GetFile(id) => self.file_info(id),
+            Request::ListOpenFiles => {
+                let mut files: Vec<(FileId, PathBuf, usize)> = self.open_files.iter()
+                    .filter_map(|(id, f)| f.path.clone().map(|p| (*id, p, f.current_version)))
+                    .chain(self.evicted_files.iter().map(|(id, (p, v))| (*id, p.clone(), *v)))
+                    .collect();
+                files.sort_by_key(|f| f.0.0);
+                Ok(Response::OpenFiles { files })
+            },
             Request::CloseFile(id) => {
+                // anyone waiting for the file to change never will be
+                self.change_waiters.remove(&id);
+                // evicted files were already written out
+                if self.evicted_files.remove(&id).is_some() {
+                    return Ok(Response::Ack);
+                }
                 self.open_files.remove(&id)
                     .ok_or_else(|| ServerError::BadFileId(id))?
                     .write_to_disk()?;
@@ -201,24 +700,98 @@ impl Server {
         }
     }
 
-    fn callback(server: Arc<RwLock<Self>>, aio: &nng::Aio, cx: &nng::Context, res: nng::AioResult) {
+    fn send_response(aio: &nng::Aio, cx: &nng::Context, resp: &protocol::MsgResponse) {
+        // println!("response = {:?}", resp);
+        let mut msg = nng::Message::new();
+        serde_cbor::to_writer(&mut msg, resp).expect("serialize message");
+        cx.send(aio, msg).unwrap();
+    }
+}
+
+/// the contexts that receive requests and send their responses. A context can only take a new
+/// request once it has answered the last one, so each worker holding a `WaitForChanges` request
+/// is covered for by a spare one until it answers
+struct Workers {
+    socket: nng::Socket,
+    server: Arc<RwLock<Server>>,
+    /// every worker that has been started, so that none of them are dropped
+    workers: Mutex<Vec<(nng::Aio, nng::Context)>>,
+    /// spare workers that aren't needed right now, and so aren't receiving
+    idle: Mutex<Vec<(nng::Aio, nng::Context)>>,
+    /// how many more workers are receiving than were asked for
+    extra: AtomicUsize
+}
+
+impl Workers {
+    fn start(socket: nng::Socket, server: Arc<RwLock<Server>>, count: usize) -> Arc<Workers> {
+        let workers = Arc::new(Workers {
+            socket, server,
+            workers: Mutex::new(Vec::new()),
+            idle: Mutex::new(Vec::new()),
+            extra: AtomicUsize::new(0)
+        });
+        for _ in 0..count {
+            if let Err(e) = workers.start_worker() {
+                println!("error starting worker thread {}", e);
+            }
+        }
+        workers
+    }
+
+    /// start receiving on another worker, reusing an idle one if there is one
+    fn start_worker(self: &Arc<Self>) -> Result<(), nng::Error> {
+        let idle = self.idle.lock().unwrap().pop();
+        let (aio, cx) = match idle {
+            Some(w) => w,
+            None => {
+                let cx = nng::Context::new(&self.socket)?;
+                let (workers, wcx) = (self.clone(), cx.clone());
+                let aio = nng::Aio::new(move |aio, res| workers.callback(&aio, &wcx, res))?;
+                self.workers.lock().unwrap().push((aio.clone(), cx.clone()));
+                (aio, cx)
+            }
+        };
+        cx.recv(&aio)
+    }
+
+    fn callback(self: &Arc<Self>, aio: &nng::Aio, cx: &nng::Context, res: nng::AioResult) {
         match res {
-            nng::AioResult::Send(Ok(_)) => while let Err(e) = cx.recv(aio) { println!("error recieving message {}", e); },
+            nng::AioResult::Send(Ok(_)) => {
+                // a worker that stood in for one holding a long poll goes idle once there is no
+                // need for it anymore
+                if self.extra.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                    self.idle.lock().unwrap().push((aio.clone(), cx.clone()));
+                    return;
+                }
+                while let Err(e) = cx.recv(aio) { println!("error recieving message {}", e); }
+            },
             nng::AioResult::Recv(Ok(raw_msg)) => {
-                let resp = serde_cbor::from_slice(raw_msg.as_slice())
-                    .map(|req: protocol::MsgRequest| protocol::MsgResponse {
-                        req_id: req.msg_id,
-                        msg: server.write().unwrap()
-                                .process_request(req.msg)
-                                .unwrap_or_else(|err| protocol::Response::Error{message: format!("{}", err)})
-                    }).unwrap_or_else(|err| protocol::MsgResponse {
-                        req_id: protocol::MessageId(0),
-                        msg: protocol::Response::Error { message: format!("error decoding request {}", err) }
-                    });
-                // println!("response = {:?}", resp);
-                let mut msg = nng::Message::new();
-                serde_cbor::to_writer(&mut msg, &resp).expect("serialize message");
-                cx.send(aio, msg).unwrap();
+                let pipe = raw_msg.pipe();
+                match serde_cbor::from_slice::<protocol::MsgRequest>(raw_msg.as_slice()) {
+                    // waiting for a change would hold up this worker and the thread the callback
+                    // runs on, so another worker takes requests and the response is sent from
+                    // another thread
+                    Ok(req) if matches!(req.msg, protocol::Request::WaitForChanges { .. }) => {
+                        self.extra.fetch_add(1, Ordering::SeqCst);
+                        if let Err(e) = self.start_worker() {
+                            println!("error starting worker for a long poll {}", e);
+                            self.extra.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        let (server, aio, cx) = (self.server.clone(), aio.clone(), cx.clone());
+                        std::thread::spawn(move || {
+                            let resp = Server::handle_message(&server, pipe, req);
+                            Server::send_response(&aio, &cx, &resp);
+                        });
+                    },
+                    req => {
+                        let resp = req.map(|req| Server::handle_message(&self.server, pipe, req))
+                            .unwrap_or_else(|err| protocol::MsgResponse {
+                                req_id: protocol::MessageId(0),
+                                msg: protocol::Response::Error { message: format!("error decoding request {}", err) }
+                            });
+                        Server::send_response(aio, cx, &resp);
+                    }
+                }
             },
             nng::AioResult::Recv(Err(e)) => { println!("error on recv {}", e); cx.recv(aio).unwrap(); },
             _ => panic!()
@@ -228,69 +801,101 @@ impl Server {
 
 struct AutosaveWorker {
     server: Arc<RwLock<Server>>,
-    disk_versions: HashMap<protocol::FileId, usize>
+    disk_versions: HashMap<protocol::FileId, usize>,
+    interval: Duration
 }
 
 impl AutosaveWorker {
-    fn new(server: Arc<RwLock<Server>>) -> AutosaveWorker {
+    fn new(server: Arc<RwLock<Server>>, interval: Duration) -> AutosaveWorker {
         AutosaveWorker {
-            server, disk_versions: HashMap::new()
+            server, disk_versions: HashMap::new(), interval
+        }
+    }
+
+    fn save(disk_version: &mut usize, file: &File) -> bool {
+        if *disk_version < file.current_version {
+            println!("save v{} < v{} - {:?}", *disk_version,
+                     file.current_version, file.path.as_ref());
+            match file.write_to_disk() {
+                Ok(()) => *disk_version = file.current_version,
+                Err(e) => {
+                    println!("error syncing {} to disk: {}",
+                             file.path.as_ref().and_then(|p| p.to_str()).unwrap_or(""), e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// drop idle files from memory once everything in them is on disk. Copies of the files are
+    /// written out so that the server isn't locked while they are
+    fn evict_idle_files(&mut self, now: Instant) {
+        let idle: Vec<(protocol::FileId, File)> = {
+            let srv = self.server.read().unwrap();
+            srv.idle_files(now).into_iter().map(|id| (id, srv.open_files[&id].clone())).collect()
+        };
+        for (id, copy) in idle {
+            let disk_version = self.disk_versions.entry(id).or_insert(0);
+            if !AutosaveWorker::save(disk_version, &copy) {
+                continue;
+            }
+            let mut srv = self.server.write().unwrap();
+            // a client could have used the file while it was being written
+            match srv.open_files.get(&id) {
+                Some(f) if f.last_access == copy.last_access && f.current_version == copy.current_version => {},
+                _ => continue
+            }
+            let file = srv.open_files.remove(&id).unwrap();
+            println!("evicting idle file {:?}", file.path.as_ref());
+            self.disk_versions.remove(&id);
+            if let Some(path) = file.path {
+                srv.evicted_files.insert(id, (path, file.current_version));
+            }
         }
     }
 
     fn run(&mut self) {
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            let srv = self.server.read().unwrap();
-            for (file_id, file) in srv.open_files.iter() {
-                let disk_version = self.disk_versions.entry(*file_id).or_insert(0);
-                if *disk_version < file.current_version {
-                    println!("save v{} < v{} - {:?}", *disk_version,
-                             file.current_version, file.path.as_ref());
-                    match file.write_to_disk() {
-                        Ok(()) => *disk_version = file.current_version,
-                        Err(e) => {
-                            println!("error syncing {} to disk: {}",
-                                     file.path.as_ref().and_then(|p| p.to_str()).unwrap_or(""), e);
-                        }
-                    }
+            std::thread::sleep(self.interval);
+            {
+                let srv = self.server.read().unwrap();
+                for (file_id, file) in srv.open_files.iter() {
+                    AutosaveWorker::save(self.disk_versions.entry(*file_id).or_insert(0), file);
                 }
             }
+            self.evict_idle_files(Instant::now());
         }
     }
 }
 
 fn main() -> Result<(), ServerError> {
-    let server_address = std::env::args().skip(1).next().expect("require nng url to listen on");
+    let mut args = pico_args::Arguments::from_env();
+    let config_path: PathBuf = args.opt_value_from_str("--config").expect("parse config path")
+        .unwrap_or_else(|| PathBuf::from("./server.toml"));
+    let mut config = ServerConfig::load(&config_path)?;
+    if let Some(max_file_size) = args.opt_value_from_str("--max-file-size").expect("parse max file size") {
+        config.max_file_size = max_file_size;
+    }
+    let idle_timeout: Option<u64> = args.opt_value_from_str("--idle-timeout").expect("parse idle timeout");
+    if let Some(t) = idle_timeout {
+        config.idle_timeout = Some(Duration::from_secs(t));
+    }
+    let server_address = args.free().expect("parse arguments").into_iter().next().expect("require nng url to listen on");
 
     let socket = nng::Socket::new(nng::Protocol::Rep0)?;
 
-    //let pool = threadpool::ThreadPool::new(8);
-    let filetype_table = toml::from_str(&std::fs::read_to_string("./filetypes.toml")?).expect("parse filetype table");
+    let filetype_table = toml::from_str(&std::fs::read_to_string(&config.filetypes)?).expect("parse filetype table");
     //println!("filetypes = {:?}", filetype_table);
-    let server = Arc::new(RwLock::new(Server::new(filetype_table)));
-
-    let ts = (0..8).map(|_| {
-        let cx = nng::Context::new(&socket)?;
-        let mcx = cx.clone();
-        let server = server.clone();
-        let aio = nng::Aio::new(move |aio, res| Server::callback(server.clone(), &aio, &cx, res))?;
-        Ok((aio, mcx))
+    let server = Arc::new(RwLock::new(Server::with_config(filetype_table, &config)));
 
-    }).collect::<Vec<nng::Result<_>>>();
-    
     println!("listening on {}", &server_address);
 
     socket.listen(&server_address)?;
 
-    for w in ts.iter() {
-        match w {
-            Ok((aio, cx)) => cx.recv(aio)?,
-            Err(e) => println!("error starting worker thread {}", e)
-        }
-    }
+    let _workers = Workers::start(socket.clone(), server.clone(), config.workers);
 
-    let mut autosave_worker = AutosaveWorker::new(server.clone());
+    let mut autosave_worker = AutosaveWorker::new(server.clone(), config.autosave_interval);
     std::thread::spawn(move || {
         autosave_worker.run();
     });
@@ -299,3 +904,444 @@ fn main() -> Result<(), ServerError> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_line_endings_written_as_majority() -> Result<(), ServerError> {
+        let path = std::env::temp_dir().join(format!("pk-server-mixed-{}.txt", std::process::id()));
+        let file = File::from_contents(&path, "a\r\nb\r\nc\nd".into(), &FileTypeTable::default());
+        assert_eq!(file.format.line_ending, protocol::LineEnding::CRLF);
+        assert!(file.format.mixed_line_endings);
+        assert_eq!(file.contents, "a\nb\nc\nd");
+        file.write_to_disk()?;
+        let written = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(written, "a\r\nb\r\nc\r\nd");
+        Ok(())
+    }
+
+    #[test]
+    fn sync_changes_line_ending() -> Result<(), ServerError> {
+        let path = std::env::temp_dir().join(format!("pk-server-sync-format-{}.txt", std::process::id()));
+        std::fs::write(&path, "a\nb\n")?;
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        let (id, mut format) = match Server::handle_request(&server, protocol::Request::OpenFile { path: path.clone() })? {
+            protocol::Response::FileInfo { id, format, .. } => (id, format),
+            r => panic!("unexpected response {:?}", r)
+        };
+        assert_eq!(format.line_ending, protocol::LineEnding::LF);
+        format.line_ending = protocol::LineEnding::CRLF;
+        match Server::handle_request(&server, protocol::Request::SyncFile { id, new_text: "a\nb\nc\n".into(), version: 1, format })? {
+            protocol::Response::Ack => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        Server::handle_request(&server, protocol::Request::CloseFile(id))?;
+        let written = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(written, "a\r\nb\r\nc\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn get_file_returns_synced_text() -> Result<(), ServerError> {
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-get-file.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        Server::handle_request(&server, protocol::Request::SyncFile { id, new_text: "a\nb\n".into(), version: 1, format: protocol::TextFormat::default() })?;
+        match Server::handle_request(&server, protocol::Request::GetFile(id))? {
+            protocol::Response::FileInfo { contents, version, .. } => {
+                assert_eq!(contents, "a\nb\n");
+                assert_eq!(version, 1);
+            },
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(matches!(Server::handle_request(&server, protocol::Request::GetFile(protocol::FileId(id.0+1))),
+            Err(ServerError::BadFileId(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn list_open_files() -> Result<(), ServerError> {
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        let mut ids = Vec::new();
+        for name in ["pk-server-list-a.txt", "pk-server-list-b.txt"].iter() {
+            match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from(name) })? {
+                protocol::Response::FileInfo { id, .. } => ids.push(id),
+                r => panic!("unexpected response {:?}", r)
+            }
+        }
+        Server::handle_request(&server, protocol::Request::SyncFile { id: ids[1], new_text: "b".into(), version: 3, format: protocol::TextFormat::default() })?;
+        match Server::handle_request(&server, protocol::Request::ListOpenFiles)? {
+            protocol::Response::OpenFiles { files } => assert_eq!(files, vec![
+                (ids[0], PathBuf::from("pk-server-list-a.txt"), 0),
+                (ids[1], PathBuf::from("pk-server-list-b.txt"), 3)
+            ]),
+            r => panic!("unexpected response {:?}", r)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_message_processed_once() -> Result<(), ServerError> {
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-repeat.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        let sync = |text: &str, version: usize| protocol::Request::SyncFile { id, new_text: text.into(), version, format: protocol::TextFormat::default() };
+        let send = |msg_id: u64, msg: protocol::Request| Server::handle_message(&server, None, protocol::MsgRequest { msg_id: protocol::MessageId(msg_id), msg }).msg;
+        assert_eq!(send(1, sync("a", 1)), protocol::Response::Ack);
+        // a retry gets the same answer, where processing it again would be a version conflict
+        assert_eq!(send(1, sync("a", 1)), protocol::Response::Ack);
+        assert_eq!(server.read().unwrap().open_files[&id].current_version, 1);
+        assert_eq!(send(2, sync("b", 2)), protocol::Response::Ack);
+        // the same id with a different request is processed
+        match send(2, sync("c", 2)) {
+            protocol::Response::VersionConflict { server_version: 2, .. } => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert_eq!(server.read().unwrap().open_files[&id].contents, "b");
+        // requests that don't change anything are always answered as they are now
+        match send(3, protocol::Request::GetFile(id)) {
+            protocol::Response::FileInfo { version: 2, .. } => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert_eq!(send(4, sync("d", 3)), protocol::Response::Ack);
+        match send(3, protocol::Request::GetFile(id)) {
+            protocol::Response::FileInfo { version: 3, .. } => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_retry_processed_once() -> Result<(), ServerError> {
+        let server = Arc::new(RwLock::new(Server::new(FileTypeTable::default())));
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-concurrent-retry.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        for version in 1..=20 {
+            // the original and its retry arrive at the same time on different contexts
+            let barrier = Arc::new(std::sync::Barrier::new(2));
+            let senders: Vec<_> = (0..2).map(|_| {
+                let (server, barrier) = (server.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    let msg = protocol::Request::SyncFile { id, new_text: format!("v{}", version), version, format: protocol::TextFormat::default() };
+                    barrier.wait();
+                    Server::handle_message(&server, None, protocol::MsgRequest { msg_id: protocol::MessageId(version as u64), msg }).msg
+                })
+            }).collect();
+            for s in senders {
+                assert_eq!(s.join().unwrap(), protocol::Response::Ack);
+            }
+            assert_eq!(server.read().unwrap().open_files[&id].current_version, version);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sync_wakes_waiting_client() -> Result<(), ServerError> {
+        let server = Arc::new(RwLock::new(Server::new(FileTypeTable::default())));
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-wait.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        // the second client waits for changes from the version it has
+        let waiter = {
+            let server = server.clone();
+            std::thread::spawn(move || Server::handle_message(&server, None,
+                    protocol::MsgRequest { msg_id: protocol::MessageId(1), msg: protocol::Request::WaitForChanges { id, since_version: 0 } }).msg)
+        };
+        while server.read().unwrap().change_waiters.get(&id).map_or(true, Vec::is_empty) {
+            std::thread::yield_now();
+        }
+        // and the first one syncs
+        Server::handle_request(&server, protocol::Request::SyncFile { id, new_text: "changed".into(), version: 1, format: protocol::TextFormat::default() })?;
+        match waiter.join().unwrap() {
+            protocol::Response::FileInfo { id: wid, contents, version, .. } => {
+                assert_eq!((wid, contents.as_str(), version), (id, "changed", 1));
+            },
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(!server.read().unwrap().change_waiters.contains_key(&id));
+
+        // a client that is already behind gets the newer version right away
+        match Server::handle_request(&server, protocol::Request::WaitForChanges { id, since_version: 0 })? {
+            protocol::Response::FileInfo { version: 1, .. } => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_changes_times_out() -> Result<(), ServerError> {
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        server.write().unwrap().wait_timeout = Duration::from_millis(10);
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-wait-timeout.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        match Server::handle_request(&server, protocol::Request::WaitForChanges { id, since_version: 0 })? {
+            protocol::Response::FileInfo { version: 0, .. } => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        // the waiter that timed out is dropped without getting in the way of the next sync
+        assert!(!server.read().unwrap().change_waiters.contains_key(&id));
+        Server::handle_request(&server, protocol::Request::SyncFile { id, new_text: "a".into(), version: 1, format: protocol::TextFormat::default() })?;
+        assert!(!server.read().unwrap().change_waiters.contains_key(&id));
+        assert!(matches!(Server::handle_request(&server, protocol::Request::WaitForChanges { id: protocol::FileId(id.0+1), since_version: 0 }),
+            Err(ServerError::BadFileId(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn recent_responses_per_connection() {
+        let mut recent = RecentResponses::new();
+        recent.insert(1, protocol::MessageId(1), &protocol::Request::Ping, protocol::Response::Pong);
+        assert_eq!(recent.get(&1, protocol::MessageId(1), &protocol::Request::Ping), Some(protocol::Response::Pong));
+        assert_eq!(recent.get(&2, protocol::MessageId(1), &protocol::Request::Ping), None);
+        for i in 2..=RECENT_RESPONSES_PER_CONNECTION as u64 + 1 {
+            recent.insert(1, protocol::MessageId(i), &protocol::Request::Ping, protocol::Response::Pong);
+        }
+        assert_eq!(recent.get(&1, protocol::MessageId(1), &protocol::Request::Ping), None);
+        assert!(recent.get(&1, protocol::MessageId(2), &protocol::Request::Ping).is_some());
+        for c in 2..=RECENT_RESPONSE_CONNECTIONS as u32 {
+            recent.insert(c, protocol::MessageId(1), &protocol::Request::Ping, protocol::Response::Pong);
+        }
+        recent.get(&1, protocol::MessageId(2), &protocol::Request::Ping);
+        recent.insert(0, protocol::MessageId(1), &protocol::Request::Ping, protocol::Response::Pong);
+        assert!(recent.get(&1, protocol::MessageId(2), &protocol::Request::Ping).is_some());
+        assert_eq!(recent.get(&2, protocol::MessageId(1), &protocol::Request::Ping), None);
+    }
+
+    #[test]
+    fn ping() -> Result<(), ServerError> {
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        assert!(matches!(Server::handle_request(&server, protocol::Request::Ping)?, protocol::Response::Pong));
+        Ok(())
+    }
+
+    #[test]
+    fn idle_files() {
+        let mut server = Server::new(FileTypeTable::default());
+        let start = Instant::now();
+        for (i, age) in [0, 5, 20].iter().enumerate() {
+            let mut file = File::from_contents(format!("{}.txt", i), String::new(), &server.filetype_table);
+            file.last_access = start + Duration::from_secs(*age);
+            server.open_files.insert(protocol::FileId(i as u64), file);
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(server.idle_files(later).is_empty(), "no timeout set");
+        server.idle_timeout = Some(Duration::from_secs(25));
+        let mut idle = server.idle_files(later);
+        idle.sort_by_key(|id| id.0);
+        assert_eq!(idle, vec![protocol::FileId(0), protocol::FileId(1)]);
+        assert!(server.idle_files(start + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn evicted_file_reloads_with_same_id() -> Result<(), ServerError> {
+        let path = std::env::temp_dir().join(format!("pk-server-evict-{}.txt", std::process::id()));
+        let server = Arc::new(RwLock::new(Server::new(FileTypeTable::default())));
+        server.write().unwrap().idle_timeout = Some(Duration::from_secs(60));
+        let id = match Server::handle_request(&server, protocol::Request::OpenFile { path: path.clone() })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+        Server::handle_request(&server, protocol::Request::SyncFile { id, new_text: "evicted\n".into(), version: 1, format: protocol::TextFormat::default() })?;
+        let mut worker = AutosaveWorker::new(server.clone(), Duration::from_secs(1));
+        worker.evict_idle_files(Instant::now());
+        assert_eq!(server.read().unwrap().open_files.len(), 1, "not idle yet");
+        worker.evict_idle_files(Instant::now() + Duration::from_secs(61));
+        assert!(server.read().unwrap().open_files.is_empty());
+        let on_disk = std::fs::read_to_string(&path)?;
+        // a file that can't be read back stays evicted until it can be
+        server.write().unwrap().max_file_size = 4;
+        let too_large = Server::handle_request(&server, protocol::Request::GetFile(id));
+        server.write().unwrap().max_file_size = DEFAULT_MAX_FILE_SIZE;
+        let reloaded = Server::handle_request(&server, protocol::Request::GetFile(id));
+        std::fs::remove_file(&path)?;
+        assert_eq!(on_disk, "evicted\n");
+        assert!(matches!(too_large, Err(ServerError::FileTooLarge { .. })));
+        match reloaded? {
+            protocol::Response::FileInfo { contents, version, .. } => {
+                assert_eq!(contents, "evicted\n");
+                assert_eq!(version, 1);
+            },
+            r => panic!("unexpected response {:?}", r)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_file_size() -> Result<(), ServerError> {
+        let dir = std::env::temp_dir();
+        let under = dir.join(format!("pk-server-under-{}.txt", std::process::id()));
+        let over = dir.join(format!("pk-server-over-{}.txt", std::process::id()));
+        std::fs::write(&under, "0123456789")?;
+        std::fs::write(&over, "0123456789\n")?;
+        let server = RwLock::new(Server::new(FileTypeTable::default()));
+        server.write().unwrap().max_file_size = 10;
+        let opened_under = Server::handle_request(&server, protocol::Request::OpenFile { path: under.clone() });
+        let opened_over = Server::handle_request(&server, protocol::Request::OpenFile { path: over.clone() });
+        std::fs::remove_file(&under)?;
+        std::fs::remove_file(&over)?;
+        match opened_under? {
+            protocol::Response::FileInfo { contents, .. } => assert_eq!(contents, "0123456789"),
+            r => panic!("unexpected response {:?}", r)
+        }
+        match opened_over {
+            Err(ServerError::FileTooLarge { size: 11, max_size: 10 }) => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert_eq!(server.read().unwrap().open_files.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn server_config() -> Result<(), ServerError> {
+        let config = ServerConfig::from_toml(&r#"
+            filetypes = "/etc/pk/filetypes.toml"
+            workers = 2
+            allowed-roots = ["/home/pk/src", "projects/../notes"]
+            autosave-interval = 5
+            max-file-size = 1024
+        "#.parse::<toml::Value>().unwrap())?;
+        assert_eq!(config, ServerConfig {
+            filetypes: PathBuf::from("/etc/pk/filetypes.toml"),
+            workers: 2,
+            allowed_roots: vec![PathBuf::from("/home/pk/src"), PathBuf::from("projects/../notes")],
+            autosave_interval: Duration::from_secs(5),
+            max_file_size: 1024,
+            idle_timeout: None
+        });
+        assert_eq!(ServerConfig::from_toml(&"".parse::<toml::Value>().unwrap())?, ServerConfig::default());
+        assert!(ServerConfig::from_toml(&"workers = 0".parse::<toml::Value>().unwrap()).is_err());
+        assert!(ServerConfig::from_toml(&"allowed-roots = [1]".parse::<toml::Value>().unwrap()).is_err());
+        assert_eq!(ServerConfig::load(Path::new("/nonexistent/pk/server.toml"))?, ServerConfig::default());
+
+        let server = Server::with_config(FileTypeTable::default(), &config);
+        assert_eq!(server.max_file_size, 1024);
+        assert_eq!(server.idle_timeout, None);
+        let allowed = |p: &Path| server.resolve_path(p.to_owned()).is_ok();
+        assert_eq!(server.allowed_roots.len(), 2);
+        assert!(allowed(Path::new("/home/pk/src/main.rs")));
+        assert!(allowed(&std::env::current_dir()?.join("notes").join("todo.md")));
+        assert!(allowed(Path::new("notes/todo.md")), "relative to the current directory");
+        assert!(!allowed(Path::new("/home/pk/src/../secrets")));
+        assert!(!allowed(Path::new("/home/pk/srcfoo")));
+        Ok(())
+    }
+
+    #[test]
+    fn open_outside_allowed_roots() {
+        let config = ServerConfig {
+            allowed_roots: vec![std::env::temp_dir().join("pk-server-allowed")],
+            ..ServerConfig::default()
+        };
+        let server = RwLock::new(Server::with_config(FileTypeTable::default(), &config));
+        let outside = std::env::temp_dir().join("pk-server-allowed").join("..").join("elsewhere.txt");
+        match Server::handle_request(&server, protocol::Request::OpenFile { path: outside }) {
+            Err(ServerError::PathNotAllowed(_)) => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(server.read().unwrap().open_files.is_empty());
+    }
+
+    #[test]
+    fn sandboxed_paths() -> Result<(), ServerError> {
+        let dir = std::env::temp_dir().join(format!("pk-server-sandbox-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub"))?;
+        std::fs::write(root.join("sub").join("inside.txt"), "inside")?;
+        std::fs::write(dir.join("outside.txt"), "outside")?;
+        let config = ServerConfig { allowed_roots: vec![root.clone()], ..ServerConfig::default() };
+        let server = RwLock::new(Server::with_config(FileTypeTable::default(), &config));
+        let open = |path: PathBuf| Server::handle_request(&server, protocol::Request::OpenFile { path });
+
+        let inside = open(root.join("sub").join("inside.txt"));
+        let new_file = open(root.join("sub").join("new.txt"));
+        let dotted = open(root.join("sub").join("..").join("sub").join("inside.txt"));
+        let escape = open(root.join("sub").join("..").join("..").join("outside.txt"));
+        #[cfg(unix)]
+        let link_escape = {
+            std::os::unix::fs::symlink(&dir, root.join("link"))?;
+            open(root.join("link").join("outside.txt"))
+        };
+        std::fs::remove_dir_all(&dir)?;
+
+        match inside? {
+            protocol::Response::FileInfo { id, contents, .. } => {
+                assert_eq!(contents, "inside");
+                match dotted? {
+                    protocol::Response::FileInfo { id: same, .. } => assert_eq!(id, same, "paths are resolved before opening"),
+                    r => panic!("unexpected response {:?}", r)
+                }
+            },
+            r => panic!("unexpected response {:?}", r)
+        }
+        assert!(new_file.is_ok(), "files that don't exist yet can be created inside a root");
+        assert!(matches!(escape, Err(ServerError::PathNotAllowed(_))));
+        #[cfg(unix)]
+        assert!(matches!(link_escape, Err(ServerError::PathNotAllowed(_))), "symlinks out of a root are refused");
+        assert_eq!(server.read().unwrap().open_files.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn open_file_without_blocking() -> Result<(), ServerError> {
+        let path = std::env::temp_dir().join(format!("pk-server-slow-{}.txt", std::process::id()));
+        std::fs::write(&path, "slow file contents\n")?;
+
+        let server = Arc::new(RwLock::new(Server::new(FileTypeTable::default())));
+        let small_id = match Server::handle_request(&server, protocol::Request::OpenFile { path: PathBuf::from("pk-server-does-not-exist.txt") })? {
+            protocol::Response::FileInfo { id, .. } => id,
+            r => panic!("unexpected response {:?}", r)
+        };
+
+        // the read waits until another request has been handled, which deadlocks if the server
+        // is locked while the file is read
+        let (reading_tx, reading_rx) = mpsc::channel();
+        let (handled_tx, handled_rx) = mpsc::channel();
+        let opener = {
+            let server = server.clone();
+            let path = path.clone();
+            std::thread::spawn(move || {
+                Server::open_file_with(&server, path, |path, max_size| {
+                    reading_tx.send(()).unwrap();
+                    handled_rx.recv().unwrap();
+                    File::read_contents(path, max_size)
+                })
+            })
+        };
+
+        reading_rx.recv().unwrap();
+        match Server::handle_request(&server, protocol::Request::SyncFile { id: small_id, new_text: "hello".into(), version: 1, format: protocol::TextFormat::default() })? {
+            protocol::Response::Ack => {},
+            r => panic!("unexpected response {:?}", r)
+        }
+        handled_tx.send(()).unwrap();
+
+        let slow_id = match opener.join().unwrap()? {
+            protocol::Response::FileInfo { id, contents, .. } => {
+                assert_eq!(contents, "slow file contents\n");
+                id
+            },
+            r => panic!("unexpected response {:?}", r)
+        };
+        std::fs::remove_file(&path)?;
+
+        // opening the same file again returns the copy that is already open
+        match Server::handle_request(&server, protocol::Request::OpenFile { path: path.clone() })? {
+            protocol::Response::FileInfo { id, .. } => assert_eq!(id, slow_id),
+            r => panic!("unexpected response {:?}", r)
+        }
+        Ok(())
+    }
+}
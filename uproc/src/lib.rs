@@ -16,40 +16,231 @@
 use std::any::*;
 use std::sync::{Arc, RwLock};
 use crossbeam::channel::{Sender, Receiver};
-use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
 use futures::prelude::*;
 
-pub type Pid = usize;
+/// The id of a process. The `index` of a process that has exited is given to the next one spawned,
+/// so the `generation` is what tells them apart, and messages sent to the old pid won't reach the
+/// new process
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid {
+    index: usize,
+    generation: u32
+}
+
+impl Pid {
+    /// The pid of the main thread's context, which is never reused
+    pub const MAIN: Pid = Pid { index: 0, generation: 0 };
+
+    /// The slot of the process, which is shared with every process that has had it before
+    pub fn index(&self) -> usize { self.index }
+
+    /// How many processes have had this pid's `index` before this one
+    pub fn generation(&self) -> u32 { self.generation }
+}
+
+impl std::fmt::Display for Pid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
 
 /// A message in the system, consisting of the sender PID and the actual message contents
 pub type Msg = (Pid, Box<dyn Any + Send>);
 
+/// The id of a process that only accepts messages of type `M`, so that sending it anything else is
+/// a compile error. Use `pid` to get the raw `Pid` when the type doesn't matter
+///
+/// ```compile_fail
+/// use uproc::*;
+/// let schd = Scheduler::with_threads(1);
+/// let cx = schd.main_context();
+/// let p = cx.spawn_typed(|_: &mut Context, _: Pid, _: &u32| Ok(ProcessState::Finished));
+/// cx.send_typed(p, "not a u32");
+/// ```
+pub struct TypedPid<M> {
+    pid: Pid,
+    msg: std::marker::PhantomData<fn(M)>
+}
+
+impl<M> TypedPid<M> {
+    /// Assume that the process `pid` accepts messages of type `M`. If it doesn't, messages sent
+    /// to it will only be caught at runtime, like with an untyped `Pid`
+    pub fn from_pid(pid: Pid) -> TypedPid<M> {
+        TypedPid { pid, msg: std::marker::PhantomData }
+    }
+
+    pub fn pid(&self) -> Pid { self.pid }
+}
+
+impl<M> Clone for TypedPid<M> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<M> Copy for TypedPid<M> {}
+
+impl<M> PartialEq for TypedPid<M> {
+    fn eq(&self, other: &Self) -> bool { self.pid == other.pid }
+}
+
+impl<M> Eq for TypedPid<M> {}
+
+impl<M> std::fmt::Debug for TypedPid<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TypedPid({})", self.pid)
+    }
+}
+
+impl<M> From<TypedPid<M>> for Pid {
+    fn from(p: TypedPid<M>) -> Pid { p.pid }
+}
+
+/// A message that couldn't be delivered because the process it was sent to is gone, forwarded to
+/// the process set with `Scheduler::set_dead_letter` from the original sender
+pub struct DeadLetter {
+    /// The process the message was sent to
+    pub to: Pid,
+    pub msg: Box<dyn Any + Send>
+}
+
+/// A message that `Context::try_send` couldn't deliver because the process it was sent to has
+/// exited, even if a newer process has its pid's index now
+#[derive(Debug)]
+pub struct ProcessGone {
+    /// The process the message was sent to
+    pub to: Pid,
+    pub msg: Box<dyn Any + Send>
+}
+
+/// The mailbox of every live process, indexed by `Pid::index`. When a process exits its slot goes on
+/// the free list and its generation goes up, so the slot can be reused without the old pid matching
+#[derive(Default)]
+struct ProcessTable {
+    slots: Vec<(u32, Option<Sender<Msg>>)>,
+    free: Vec<usize>
+}
+
+impl ProcessTable {
+    fn insert(&mut self, tx: Sender<Msg>) -> Pid {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.1 = Some(tx);
+                Pid { index, generation: slot.0 }
+            },
+            None => {
+                self.slots.push((0, Some(tx)));
+                Pid { index: self.slots.len() - 1, generation: 0 }
+            }
+        }
+    }
+
+    fn get(&self, pid: Pid) -> Option<&Sender<Msg>> {
+        match self.slots.get(pid.index) {
+            Some((generation, tx)) if *generation == pid.generation => tx.as_ref(),
+            _ => None
+        }
+    }
+
+    fn remove(&mut self, pid: Pid) {
+        if let Some(slot) = self.slots.get_mut(pid.index) {
+            if slot.0 == pid.generation && slot.1.take().is_some() {
+                slot.0 = slot.0.wrapping_add(1);
+                self.free.push(pid.index);
+            }
+        }
+    }
+}
+
+/// Something that happened to a process, reported to the scheduler's tracer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEventKind {
+    /// The process was spawned by `parent`
+    Spawn { parent: Pid },
+    /// The process sent a message to `to`
+    Send { to: Pid },
+    /// The process recieved a message from `from`
+    Recv { from: Pid },
+    /// The process exited with `result` and won't be scheduled again
+    Exit { result: ProcessResult },
+}
+
+/// An event observed by the tracer installed with `Scheduler::set_tracer`
+#[derive(Clone, Debug)]
+pub struct TraceEvent {
+    /// The process the event happened to
+    pub pid: Pid,
+    pub time: std::time::Instant,
+    pub kind: TraceEventKind,
+}
+
+type TraceFn = Box<dyn Fn(TraceEvent) + Send + Sync>;
+
+/// Holds the tracer, if there is one. Checking `enabled` first means that nothing gets locked or
+/// timestamped while tracing is off
+#[derive(Default)]
+struct Tracer {
+    enabled: std::sync::atomic::AtomicBool,
+    f: RwLock<Option<TraceFn>>
+}
+
+impl Tracer {
+    fn trace(&self, pid: Pid, kind: TraceEventKind) {
+        if !self.enabled.load(Ordering::Relaxed) { return; }
+        if let Some(f) = self.f.read().unwrap().as_ref() {
+            f(TraceEvent { pid, time: std::time::Instant::now(), kind });
+        }
+    }
+}
+
 /// A process context from which processes can spawn other processes or send messages
 #[derive(Clone)]
 pub struct Context {
     self_pid: Pid,
     inj: Arc<crossbeam::deque::Injector<ProcessTask>>,
     rx: Receiver<Msg>,
-    next_pid: Arc<AtomicUsize>,
-    process_senders: Arc<RwLock<BTreeMap<Pid, Sender<Msg>>>>
+    process_senders: Arc<RwLock<ProcessTable>>,
+    tracer: Arc<Tracer>,
+    dead_letter: Arc<RwLock<Option<Pid>>>
 }
 
 impl Context {
     /// Spawn a process, returns the process id
-    /// If `supervise` is true, then when this process exits either normally or by an error, the
-    /// `ProcessResult` will be sent back to this process as a message from the spawned process
+    /// If `supervise` is true, then when this process exits either normally or by an error, an
+    /// `Exit` with its `ProcessResult` will be sent back to this process as a message from the
+    /// spawned process
     pub fn spawn_sup(&self, p: impl Process + Send + 'static, supervise: bool) -> Pid {
-        let pid = self.next_pid.fetch_add(1, Ordering::SeqCst); //could this ordering be relaxed?
+        self.spawn_inner(Box::new(p), supervise, None)
+    }
+
+    fn spawn_inner(&self, p: Box<dyn Process + Send>, supervise: bool, initial_msg: Option<Box<dyn Any + Send>>) -> Pid {
+        let (pid, rx) = self.open_mailbox(initial_msg);
+        self.schedule(pid, p, rx, supervise);
+        pid
+    }
+
+    /// Make a new pid and a mailbox that can already be sent to, without scheduling a process for
+    /// it yet
+    fn open_mailbox(&self, initial_msg: Option<Box<dyn Any + Send>>) -> (Pid, Receiver<Msg>) {
         let (tx, rx) = crossbeam::channel::unbounded::<Msg>();
+        let pid = self.process_senders.write().unwrap().insert(tx.clone());
+        if let Some(msg) = initial_msg {
+            // the mailbox is empty and nobody else knows the pid yet, so this is the first message
+            self.tracer.trace(self.self_pid, TraceEventKind::Send { to: pid });
+            tx.send((self.self_pid, msg)).expect("new mailbox is open");
+        }
+        self.tracer.trace(pid, TraceEventKind::Spawn { parent: self.self_pid });
+        (pid, rx)
+    }
+
+    fn schedule(&self, pid: Pid, p: Box<dyn Process + Send>, rx: Receiver<Msg>, supervise: bool) {
         self.inj.push(ProcessTask {
             pid,
-            code: Box::new(p),
+            code: p,
             rx,
-            supv: if supervise { Some(self.self_pid) } else { None }
+            supv: if supervise { Some(self.self_pid) } else { None },
+            initialized: false
         });
-        self.process_senders.write().unwrap().insert(pid, tx.clone());
-        pid
     }
     
     /// Spawn an unsupervised process
@@ -57,52 +248,161 @@ impl Context {
         self.spawn_sup(p, false)
     }
 
-    /// Spawn a future on the scheduler and run it to completion asynchronously
+    /// Spawn a process with `msg` from this process already in its mailbox, supervised like
+    /// `spawn_sup`. Since processes only run when they recieve a message, this starts the process
+    /// without a separate `send`, and no other message can get to it first
+    pub fn spawn_with_sup(&self, p: impl Process + Send + 'static, msg: impl Any + Send, supervise: bool) -> Pid {
+        self.spawn_inner(Box::new(p), supervise, Some(Box::new(msg)))
+    }
+
+    /// Spawn an unsupervised process with `msg` already in its mailbox
+    pub fn spawn_with(&self, p: impl Process + Send + 'static, msg: impl Any + Send) -> Pid {
+        self.spawn_with_sup(p, msg, false)
+    }
+
+    /// Spawn a process built by `builder`, which is given the context of the new process so that
+    /// it can spawn and supervise children or send messages before the process exists. Supervised
+    /// like `spawn_sup`.
+    ///
+    /// `builder` runs right away on the calling thread, so it doesn't need to be `Send` or
+    /// `'static`, but the process isn't scheduled until it returns: messages sent to the new
+    /// process in the meantime, including `Exit`s from its children, wait in its mailbox until
+    /// after `Process::init`. Don't `recv` on the context given to `builder`, since nothing can be
+    /// sent to the process before it is built except by the builder itself
+    pub fn spawn_tree_sup<P, B>(&self, builder: B, supervise: bool) -> Pid
+        where P: Process + Send + 'static, B: FnOnce(&mut Context) -> P
+    {
+        let (pid, rx) = self.open_mailbox(None);
+        let mut cx = Context { self_pid: pid, rx: rx.clone(), ..self.clone() };
+        let p = builder(&mut cx);
+        self.schedule(pid, Box::new(p), rx, supervise);
+        pid
+    }
+
+    /// Spawn an unsupervised process built by `builder` with its own context, like `spawn_tree_sup`
+    pub fn spawn_tree<P, B>(&self, builder: B) -> Pid
+        where P: Process + Send + 'static, B: FnOnce(&mut Context) -> P
+    {
+        self.spawn_tree_sup(builder, false)
+    }
+
+    /// Spawn a process that only accepts messages of type `M`, supervised like `spawn_sup`
+    pub fn spawn_typed_sup<M: Any>(&self, p: impl TypedProcess<M> + Send + 'static, supervise: bool) -> TypedPid<M> {
+        TypedPid::from_pid(self.spawn_sup(Typed { p, msg: std::marker::PhantomData }, supervise))
+    }
+
+    /// Spawn an unsupervised process that only accepts messages of type `M`
+    pub fn spawn_typed<M: Any>(&self, p: impl TypedProcess<M> + Send + 'static) -> TypedPid<M> {
+        self.spawn_typed_sup(p, false)
+    }
+
+    /// Spawn a future on the scheduler and run it to completion asynchronously.
+    /// If `supervise` is true, an `Exit` is sent back to this process when the future's process
+    /// finishes, with `Err(PANICKED)` as the result if the future panics
+    pub fn spawn_future_sup<F>(&self, fut: F, supervise: bool) -> Pid
+        where F: Future<Output=()> + Send + 'static
+    {
+        self.spawn_with_sup(FuturePollOnRecv { fut: Box::pin(fut), send_out: false }, (), supervise)
+    }
+
+    /// Spawn an unsupervised future on the scheduler and run it to completion asynchronously
     pub fn spawn_future<F>(&self, fut: F) -> Pid
         where F: Future<Output=()> + Send + 'static
     {
-        let pid = self.spawn(FuturePollOnRecv { fut, send_out: false });
-        self.send(pid, ());
-        pid
+        self.spawn_future_sup(fut, false)
+    }
+
+    /// Spawn a future on the scheduler that will send a message back of type `Out` when it is
+    /// finished, supervised in the same way as `spawn_future_sup`
+    pub fn future_message_sup<Out: Send + 'static, F>(&self, fut: F, supervise: bool) -> Pid
+        where F: Future<Output=Out> + Send + 'static
+    {
+        self.spawn_with_sup(FuturePollOnRecv { fut: Box::pin(fut), send_out: true }, (), supervise)
     }
 
-    /// Spawn a future on the scheduler that will send a message back of type `Out` when it is finished
+    /// Spawn an unsupervised future on the scheduler that will send a message back of type `Out` when it is finished
     pub fn future_message<Out: Send + 'static, F>(&self, fut: F) -> Pid
         where F: Future<Output=Out> + Send + 'static
     {
-        let pid = self.spawn(FuturePollOnRecv { fut, send_out: true });
-        self.send(pid, ());
-        pid
+        self.future_message_sup(fut, false)
     }
 
-    /// Send a message to a process. Does block, but should finish quickly
+    /// Send a message to a process. Does block, but should finish quickly. If the process is
+    /// gone, the message goes to the dead letter process instead, or panics if there isn't one
     pub fn send(&self, to_pid: Pid, msg: impl Any + Send) {
-        // println!("send {} -> {}", self.self_pid, to_pid);
-        self.process_senders.read().unwrap().get(&to_pid).expect("pid is valid")
-            .send((self.self_pid, Box::new(msg))).unwrap();
+        self.tracer.trace(self.self_pid, TraceEventKind::Send { to: to_pid });
+        self.deliver(self.self_pid, to_pid, Box::new(msg));
+    }
+
+    /// Send a message to a process, or give it back in a `ProcessGone` if the process has exited,
+    /// without going to the dead letter process
+    pub fn try_send(&self, to_pid: Pid, msg: impl Any + Send) -> Result<(), ProcessGone> {
+        self.tracer.trace(self.self_pid, TraceEventKind::Send { to: to_pid });
+        self.try_deliver(self.self_pid, to_pid, Box::new(msg))
+    }
+
+    /// Send a message to a process that accepts messages of type `M`
+    pub fn send_typed<M: Any + Send>(&self, to: TypedPid<M>, msg: M) {
+        self.send(to.pid, msg);
     }
 
     /// Send a message to a process, pretending to be from `from_pid`, blocking 
     fn send_to_self(&self, from_pid: Pid, msg: impl Any + Send) {
-        // println!("send {} -> {}", self.self_pid, to_pid);
-        self.process_senders.read().unwrap().get(&self.self_pid).expect("pid is valid")
-            .send((from_pid, Box::new(msg))).unwrap();
+        self.tracer.trace(from_pid, TraceEventKind::Send { to: self.self_pid });
+        self.deliver(from_pid, self.self_pid, Box::new(msg));
+    }
+
+    fn try_deliver(&self, from_pid: Pid, to_pid: Pid, msg: Box<dyn Any + Send>) -> Result<(), ProcessGone> {
+        match self.process_senders.read().unwrap().get(to_pid) {
+            Some(tx) => tx.send((from_pid, msg))
+                .map_err(|crossbeam::channel::SendError((_, msg))| ProcessGone { to: to_pid, msg }),
+            None => Err(ProcessGone { to: to_pid, msg })
+        }
+    }
+
+    fn deliver(&self, from_pid: Pid, to_pid: Pid, msg: Box<dyn Any + Send>) {
+        let msg = match self.try_deliver(from_pid, to_pid, msg) {
+            Ok(()) => return,
+            Err(ProcessGone { msg, .. }) => msg
+        };
+        let dead_letter = *self.dead_letter.read().unwrap();
+        match dead_letter {
+            Some(dl) if dl != to_pid => self.deliver(from_pid, dl, Box::new(DeadLetter { to: to_pid, msg })),
+            _ => panic!("pid {} is not valid", to_pid)
+        }
     }
 
     /// Recieve a message send to this process, or block until one is sent
     pub fn recv(&self) -> Msg {
-        self.rx.recv().unwrap()
+        let m = self.rx.recv().unwrap();
+        self.tracer.trace(self.self_pid, TraceEventKind::Recv { from: m.0 });
+        m
     }
 
     /// Try to recieve a message send to this process, or return `None`
     pub fn try_recv(&self) -> Option<Msg> {
         match self.rx.try_recv() {
-            Ok(m) => Some(m),
+            Ok(m) => {
+                self.tracer.trace(self.self_pid, TraceEventKind::Recv { from: m.0 });
+                Some(m)
+            },
             Err(crossbeam::channel::TryRecvError::Empty) => None,
             Err(e) => panic!(e)
         }
     }
 
+    /// The number of messages waiting to be recieved by this process. Other processes can send
+    /// messages at any time, so this is only approximate
+    pub fn mailbox_len(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// True if there are no messages waiting to be recieved by this process. Like `mailbox_len`,
+    /// this could be out of date as soon as it returns
+    pub fn is_empty(&self) -> bool {
+        self.rx.is_empty()
+    }
+
     pub fn pid(&self) -> Pid { self.self_pid }
 
     // TODO: it seems reasonable to have an async `recv` function, but you'd need to keep track of
@@ -117,17 +417,43 @@ pub enum ProcessState {
     Waiting,
     /// The process has finished and no longer needs to be scheduled
     Finished,
+    /// The process has more work to do, but is letting other processes run first. It will be sent
+    /// a `Resume` message from itself once the messages already in its mailbox have been
+    /// processed. Any state needed to pick the work back up has to be kept in the process itself
+    Yield,
 }
 
+/// The message a process recieves to continue after returning `ProcessState::Yield`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resume;
+
 pub type ProcessResult = Result<ProcessState, usize>;
 
+/// The message a supervisor recieves when one of the processes it supervises exits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exit {
+    /// The process that exited, which is also the sender of the message
+    pub pid: Pid,
+    pub result: ProcessResult,
+}
+
+/// The error code a process exits with if it panics
+pub const PANICKED: usize = usize::MAX;
+
 /// A process that can be executed
 pub trait Process {
     /// Process a message from `sender` The context `cx` is for this process. This will only be
     /// called if a process recieves messages, so if the process never recieves any messages, it
-    /// will never run.
+    /// will never run. Use `Context::spawn_with` to give a process the message that starts it.
     /// Return the new state of the process after processing the message or an error code
     fn process_message(&mut self, cx: &mut Context, sender: Pid, msg: &dyn Any) -> ProcessResult;
+
+    /// Set up the process, called once when it is first scheduled and before any messages are
+    /// processed, even if it never recieves one. Returns the state of the process like
+    /// `process_message`, so it can also finish or fail straight away. By default this does nothing
+    fn init(&mut self, _cx: &mut Context) -> ProcessResult {
+        Ok(ProcessState::Waiting)
+    }
 }
 
 impl<T> Process for T where T: FnMut(&mut Context, Pid, &dyn Any)->ProcessResult {
@@ -136,8 +462,50 @@ impl<T> Process for T where T: FnMut(&mut Context, Pid, &dyn Any)->ProcessResult
     }
 }
 
+/// A process that expects messages of type `M`
+pub trait TypedProcess<M> {
+    /// Process a message of type `M` from `sender`, like `Process::process_message`
+    fn process(&mut self, cx: &mut Context, sender: Pid, msg: &M) -> ProcessResult;
+
+    /// Process any other message, which can still come from untyped sends, from supervised
+    /// processes or as a `Resume` after yielding. By default these are ignored
+    fn process_other(&mut self, _cx: &mut Context, _sender: Pid, _msg: &dyn Any) -> ProcessResult {
+        Ok(ProcessState::Waiting)
+    }
+
+    /// Set up the process before any messages are processed, like `Process::init`
+    fn init(&mut self, _cx: &mut Context) -> ProcessResult {
+        Ok(ProcessState::Waiting)
+    }
+}
+
+impl<M, T> TypedProcess<M> for T where T: FnMut(&mut Context, Pid, &M)->ProcessResult {
+    fn process(&mut self, cx: &mut Context, sender: Pid, msg: &M) -> ProcessResult {
+        (self)(cx, sender, msg)
+    }
+}
+
+/// Adapts a `TypedProcess` to the untyped `Process` that the scheduler runs
+struct Typed<M, P> {
+    p: P,
+    msg: std::marker::PhantomData<fn(M)>
+}
+
+impl<M: Any, P: TypedProcess<M>> Process for Typed<M, P> {
+    fn process_message(&mut self, cx: &mut Context, sender: Pid, msg: &dyn Any) -> ProcessResult {
+        match msg.downcast_ref::<M>() {
+            Some(m) => self.p.process(cx, sender, m),
+            None => self.p.process_other(cx, sender, msg)
+        }
+    }
+
+    fn init(&mut self, cx: &mut Context) -> ProcessResult {
+        self.p.init(cx)
+    }
+}
+
 struct FuturePollOnRecv<Out: Send + 'static, F: Future<Output=Out>> {
-    fut: F,
+    fut: std::pin::Pin<Box<F>>,
     send_out: bool
 }
 
@@ -156,18 +524,21 @@ impl futures::task::ArcWake for FuturePollOnRecvWaker {
 
 impl <Out: Send + 'static, F: Future<Output=Out>> Process for FuturePollOnRecv<Out, F> {
     fn process_message(&mut self, cx: &mut Context, sender: Pid, msg: &dyn Any) -> ProcessResult {
-        // use futures::task::{Poll, Context};
-        // let fut = &mut self.fut;
-        // let wak = futures::task::waker(msg.downcast_ref::<Arc<FuturePollOnRecvWaker>>()
-        //     .map_or_else(|| Arc::new(FuturePollOnRecvWaker{cx: cx.clone(), target: sender}), Clone::clone));
-        // match Future::poll(std::pin::Pin::new(fut), &mut Context::from_waker(&wak)) {
-        //     Poll::Pending => Ok(ProcessState::Waiting),
-        //     Poll::Ready(v) => {
-        //         if self.send_out { cx.send(sender, v); }
-        //         Ok(ProcessState::Finished)
-        //     }
-        // }
-        unimplemented!();
+        use futures::task::Poll;
+        let fut = self.fut.as_mut();
+        let wak = futures::task::waker(msg.downcast_ref::<Arc<FuturePollOnRecvWaker>>()
+            .map_or_else(|| Arc::new(FuturePollOnRecvWaker{cx: cx.clone(), target: sender}), Clone::clone));
+        // a panicking future only takes down its own process, so that a supervisor can find out
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(||
+            Future::poll(fut, &mut futures::task::Context::from_waker(&wak))));
+        match res {
+            Ok(Poll::Pending) => Ok(ProcessState::Waiting),
+            Ok(Poll::Ready(v)) => {
+                if self.send_out { cx.send(sender, v); }
+                Ok(ProcessState::Finished)
+            },
+            Err(_) => Err(PANICKED)
+        }
     }
 }
 
@@ -175,15 +546,18 @@ struct ProcessTask {
     pid: Pid,
     code: Box<dyn Process + Send>,
     rx: Receiver<Msg>,
-    supv: Option<Pid>
+    supv: Option<Pid>,
+    /// true once `Process::init` has been called
+    initialized: bool
 }
 
 /// The top level process scheduler, which is cooperative
 pub struct Scheduler {
     injector: Arc<crossbeam::deque::Injector<ProcessTask>>,
-    next_pid: Arc<AtomicUsize>,
-    process_senders: Arc<RwLock<BTreeMap<Pid, Sender<Msg>>>>,
+    process_senders: Arc<RwLock<ProcessTable>>,
     main_rx: Receiver<Msg>,
+    tracer: Arc<Tracer>,
+    dead_letter: Arc<RwLock<Option<Pid>>>,
 }
 
 fn find_task(global: &crossbeam::deque::Injector<ProcessTask>,
@@ -204,77 +578,218 @@ fn find_task(global: &crossbeam::deque::Injector<ProcessTask>,
     })
 }
 
-impl Scheduler {
-    /// Create a new scheduler that can run `count` processes in parallel
-    pub fn with_threads(count: usize) -> Scheduler {
+/// Initialize `task` if it hasn't been yet, or else process the next message waiting for it, if
+/// there is one. Returns the task if it should be scheduled again, and whether it ran
+fn run_task(mut task: ProcessTask,
+            inj: &Arc<crossbeam::deque::Injector<ProcessTask>>,
+            psen: &Arc<RwLock<ProcessTable>>,
+            tracer: &Arc<Tracer>,
+            dead_letter: &Arc<RwLock<Option<Pid>>>) -> (Option<ProcessTask>, bool)
+{
+    let mut cx = Context {
+        self_pid: task.pid,
+        inj: inj.clone(),
+        rx: task.rx.clone(),
+        process_senders: psen.clone(),
+        tracer: tracer.clone(),
+        dead_letter: dead_letter.clone()
+    };
+    let result = if !task.initialized {
+        task.initialized = true;
+        task.code.init(&mut cx)
+    } else {
+        match task.rx.try_recv() {
+            Ok((pid, msg)) => {
+                tracer.trace(task.pid, TraceEventKind::Recv { from: pid });
+                task.code.process_message(&mut cx, pid, msg.as_ref())
+            },
+            Err(crossbeam::channel::TryRecvError::Empty) => return (Some(task), false),
+            Err(_) => {
+                psen.write().unwrap().remove(task.pid);
+                return (None, false)
+            }
+        }
+    };
+    match result {
+        Ok(ProcessState::Waiting) => (Some(task), true),
+        Ok(ProcessState::Yield) => {
+            // going to the back of the mailbox means waiting messages get handled first
+            cx.send_to_self(task.pid, Resume);
+            (Some(task), true)
+        },
+        state => {
+            tracer.trace(task.pid, TraceEventKind::Exit { result: state.clone() });
+            // anything sent from now on is to a process that is gone, even once the slot is reused
+            psen.write().unwrap().remove(task.pid);
+            if let Some(spid) = task.supv {
+                cx.send(spid, Exit { pid: task.pid, result: state });
+            }
+            (None, true)
+        }
+    }
+}
+
+/// Configures a `Scheduler` before starting its threads
+#[derive(Debug, Clone)]
+pub struct SchedulerBuilder {
+    thread_count: usize,
+    thread_name_prefix: String,
+    stack_size: Option<usize>
+}
+
+impl Default for SchedulerBuilder {
+    fn default() -> SchedulerBuilder {
+        SchedulerBuilder {
+            thread_count: num_cpus::get(),
+            thread_name_prefix: "pk-worker".into(),
+            stack_size: None
+        }
+    }
+}
+
+impl SchedulerBuilder {
+    /// Start with one thread per CPU, named `pk-worker-0`, `pk-worker-1` and so on, with the
+    /// default stack size
+    pub fn new() -> SchedulerBuilder {
+        SchedulerBuilder::default()
+    }
+
+    /// How many processes can run in parallel. With zero threads, processes only run during calls
+    /// to `run_until_idle`
+    pub fn thread_count(mut self, count: usize) -> SchedulerBuilder {
+        self.thread_count = count;
+        self
+    }
+
+    /// Name the worker threads `<prefix>-<n>`, which shows up in debuggers and panic messages
+    pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> SchedulerBuilder {
+        self.thread_name_prefix = prefix.into();
+        self
+    }
+
+    /// The stack size of each worker thread in bytes, instead of the standard library default
+    pub fn stack_size(mut self, size: usize) -> SchedulerBuilder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Start the worker threads and create the scheduler
+    pub fn build(self) -> Scheduler {
         let injector = Arc::new(crossbeam::deque::Injector::new());
-        let process_senders = Arc::new(RwLock::new(BTreeMap::new()));
-        let next_pid = Arc::new(AtomicUsize::new(1));
+        let process_senders = Arc::new(RwLock::new(ProcessTable::default()));
         let (main_tx, main_rx) = crossbeam::channel::unbounded();
-        process_senders.write().unwrap().insert(0, main_tx);
+        let tracer = Arc::new(Tracer::default());
+        let dead_letter = Arc::new(RwLock::new(None));
+        let main_pid = process_senders.write().unwrap().insert(main_tx);
+        debug_assert_eq!(main_pid, Pid::MAIN);
         use crossbeam::deque::{Worker, Stealer};
-        let (mut work_qus, stealers): (Vec<Option<Worker<ProcessTask>>>, Vec<Stealer<ProcessTask>>) = (0..count).map(|_| {
+        let (mut work_qus, stealers): (Vec<Option<Worker<ProcessTask>>>, Vec<Stealer<ProcessTask>>) = (0..self.thread_count).map(|_| {
             let wk = Worker::new_fifo();
             let st = wk.stealer();
             (Some(wk), st)
         }).unzip();
-        for wq in work_qus.iter_mut() {
+        for (i, wq) in work_qus.iter_mut().enumerate() {
             let wrk_qu = wq.take().unwrap();
             let inj = injector.clone();
             let stl = stealers.clone();
-            let npid = next_pid.clone();
             let psen = process_senders.clone();
-            std::thread::spawn(move || {
+            let trc = tracer.clone();
+            let dl = dead_letter.clone();
+            let mut thread = std::thread::Builder::new().name(format!("{}-{}", self.thread_name_prefix, i));
+            if let Some(size) = self.stack_size {
+                thread = thread.stack_size(size);
+            }
+            thread.spawn(move || {
                 loop {
-                    if let Some(mut task) = find_task(inj.as_ref(), &wrk_qu, &stl) {
-                        match task.rx.try_recv() {
-                            Ok((pid, msg)) => {
-                                let mut cx = Context {
-                                    self_pid: task.pid,
-                                    inj: inj.clone(),
-                                    rx: task.rx.clone(),
-                                    next_pid: npid.clone(),
-                                    process_senders: psen.clone()
-                                };
-                                match task.code.process_message(&mut cx, pid, msg.as_ref()) {
-                                    Ok(ProcessState::Waiting) => wrk_qu.push(task),
-                                    state => {
-                                        if let Some(spid) = task.supv {
-                                            cx.send(spid, state);
-                                        }
-                                    }
-                                }
-                            },
-                            Err(crossbeam::channel::TryRecvError::Empty) => {
-                                wrk_qu.push(task);
-                            },
-                            Err(_) => {}
+                    if let Some(task) = find_task(inj.as_ref(), &wrk_qu, &stl) {
+                        if let (Some(task), _) = run_task(task, &inj, &psen, &trc, &dl) {
+                            wrk_qu.push(task);
                         }
                     }
                 }
-            });
+            }).expect("failed to spawn scheduler thread");
         }
         Scheduler {
             injector,
             main_rx,
             process_senders,
-            next_pid
+            tracer,
+            dead_letter
+        }
+    }
+}
+
+impl Scheduler {
+    /// Configure a new scheduler before starting it
+    pub fn builder() -> SchedulerBuilder {
+        SchedulerBuilder::new()
+    }
+
+    /// Create a new scheduler that can run `count` processes in parallel. With zero threads,
+    /// processes only run during calls to `run_until_idle`
+    pub fn with_threads(count: usize) -> Scheduler {
+        SchedulerBuilder::new().thread_count(count).build()
+    }
+
+    /// Run processes on the current thread until none of them have any messages left to process.
+    /// Processes are run one message at a time in the order they were spawned, so with a scheduler
+    /// that has no other threads, messages are delivered in the same order every time
+    pub fn run_until_idle(&self) {
+        let mut tasks = std::collections::VecDeque::new();
+        loop {
+            // pick up any newly spawned processes
+            while let Some(task) = self.injector.steal().success() {
+                tasks.push_back(task);
+            }
+            let mut progress = false;
+            for _ in 0..tasks.len() {
+                let (task, ran) = run_task(tasks.pop_front().unwrap(), &self.injector, &self.process_senders, &self.tracer, &self.dead_letter);
+                progress |= ran;
+                if let Some(task) = task {
+                    tasks.push_back(task);
+                }
+            }
+            if !progress && self.injector.is_empty() { break; }
+        }
+        // hand the waiting processes back so that they can run later
+        for task in tasks {
+            self.injector.push(task);
         }
     }
 
     /// Create a new scheduler with one worker thread per logical CPU
-    pub fn new() -> Scheduler { Scheduler::with_threads(num_cpus::get()) }
+    pub fn new() -> Scheduler { SchedulerBuilder::new().build() }
 
     /// Get the context for the main thread, so that it can send/recv messages and spawn processes
     pub fn main_context(&self) -> Context {
         Context {
-            self_pid: 0,
+            self_pid: Pid::MAIN,
             inj: self.injector.clone(),
             rx: self.main_rx.clone(),
-            next_pid: self.next_pid.clone(),
-            process_senders: self.process_senders.clone()
+            process_senders: self.process_senders.clone(),
+            tracer: self.tracer.clone(),
+            dead_letter: self.dead_letter.clone()
         }
     }
+
+    /// Forward messages sent to processes that are gone to `pid`, wrapped in a `DeadLetter`,
+    /// instead of panicking. If `pid` is gone too, sending panics again
+    pub fn set_dead_letter(&self, pid: Option<Pid>) {
+        *self.dead_letter.write().unwrap() = pid;
+    }
+
+    /// Call `f` with every spawn, send, recieve and exit from now on, in every process, until
+    /// `clear_tracer` is called. `f` runs on whichever thread the event happened on
+    pub fn set_tracer(&self, f: impl Fn(TraceEvent) + Send + Sync + 'static) {
+        *self.tracer.f.write().unwrap() = Some(Box::new(f));
+        self.tracer.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop tracing events
+    pub fn clear_tracer(&self) {
+        self.tracer.enabled.store(false, Ordering::Relaxed);
+        *self.tracer.f.write().unwrap() = None;
+    }
 }
 
 #[cfg(test)]
@@ -285,9 +800,9 @@ mod tests {
     fn simple() {
         let schd = Scheduler::with_threads(1);
         let cx = schd.main_context();
-        let p = cx.spawn(move |cx: &mut Context, _, msg: &dyn Any| {
+        let p = cx.spawn_sup(move |cx: &mut Context, _, msg: &dyn Any| {
             assert_eq!(msg.downcast_ref::<u32>(), Some(&42));
-            cx.send(0, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
+            cx.send(Pid::MAIN, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
             Ok(ProcessState::Finished)
         }, false);
         cx.send(p, 42u32);
@@ -298,42 +813,66 @@ mod tests {
     fn simple_supv() {
         let schd = Scheduler::with_threads(1);
         let cx = schd.main_context();
-        let p = cx.spawn(move |cx: &mut Context, _, msg: &dyn Any| {
+        let p = cx.spawn_sup(move |cx: &mut Context, _, msg: &dyn Any| {
             assert_eq!(msg.downcast_ref::<u32>(), Some(&42));
-            cx.send(0, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
+            cx.send(Pid::MAIN, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
             Ok(ProcessState::Finished)
         }, true);
         cx.send(p, 42u32);
         assert_eq!(cx.recv().1.downcast_ref::<u32>().cloned(), Some(45));
-        assert_eq!(cx.recv().1.downcast_ref::<ProcessResult>(), Some(&Ok(ProcessState::Finished)));
+        assert_eq!(cx.recv().1.downcast_ref::<Exit>(), Some(&Exit { pid: p, result: Ok(ProcessState::Finished) }));
     }
 
     #[test]
     fn simple_err_supv() {
         let schd = Scheduler::with_threads(1);
         let cx = schd.main_context();
-        let p = cx.spawn(move |cx: &mut Context, _, msg: &dyn Any| {
+        let p = cx.spawn_sup(move |cx: &mut Context, _, msg: &dyn Any| {
             assert_eq!(msg.downcast_ref::<u32>(), Some(&42));
-            cx.send(0, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
+            cx.send(Pid::MAIN, msg.downcast_ref::<u32>().cloned().map(|i| i + 3).unwrap());
             Err(53)
         }, true);
         cx.send(p, 42u32);
         assert_eq!(cx.recv().1.downcast_ref::<u32>().cloned(), Some(45));
-        assert_eq!(cx.recv().1.downcast_ref::<ProcessResult>(), Some(&Err(53)));
+        assert_eq!(cx.recv().1.downcast_ref::<Exit>(), Some(&Exit { pid: p, result: Err(53) }));
+    }
+
+    #[test]
+    fn supervise_two_children() {
+        let schd = Scheduler::with_threads(2);
+        let cx = schd.main_context();
+        let child = |code: usize| move |_: &mut Context, _: Pid, _: &dyn Any| {
+            if code == 0 { Ok(ProcessState::Finished) } else { Err(code) }
+        };
+        let ok = cx.spawn_sup(child(0), true);
+        let failed = cx.spawn_sup(child(7), true);
+        cx.send(failed, ());
+        cx.send(ok, ());
+        let mut exits: Vec<Exit> = (0..2).map(|_| {
+            let (sender, m) = cx.recv();
+            let exit = m.downcast_ref::<Exit>().cloned().unwrap();
+            assert_eq!(sender, exit.pid);
+            exit
+        }).collect();
+        exits.sort_by_key(|e| e.pid);
+        assert_eq!(exits, vec![
+            Exit { pid: ok, result: Ok(ProcessState::Finished) },
+            Exit { pid: failed, result: Err(7) }
+        ]);
     }
 
     #[test]
     fn two_procs() {
         let schd = Scheduler::with_threads(2);
         let cx = schd.main_context();
-        let proc1 = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+        let proc1 = cx.spawn_sup(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
             let i = msg.downcast_ref::<u32>().cloned().unwrap();
             // println!("1: {}", i);
-            if i > 10 { cx.send(0, i); return Ok(ProcessState::Finished); }
+            if i > 10 { cx.send(Pid::MAIN, i); return Ok(ProcessState::Finished); }
             cx.send(sender, i + 1);
             Ok(ProcessState::Waiting)
         }, false);
-        let proc2 = cx.spawn(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+        let proc2 = cx.spawn_sup(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
             let i = msg.downcast_ref::<u32>().cloned().unwrap();
             // println!("2: {}", i);
             if i > 10 { return Ok(ProcessState::Finished); }
@@ -351,7 +890,7 @@ mod tests {
         let mut processes = Vec::new();
         for _ in 0..100 {
             let mut sum = 0;
-            processes.push(cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            processes.push(cx.spawn_sup(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
                 match msg.downcast_ref::<u32>() {
                     Some(0) => {
                         //println!("exit!");
@@ -398,13 +937,14 @@ mod tests {
         let cx = schd.main_context();
         let future_ran = Arc::new(std::sync::atomic::AtomicBool::new(false));
         let fr = future_ran.clone();
-        cx.spawn_future(futures::future::lazy(move |_| {
+        cx.spawn_future_sup(futures::future::lazy(move |_| {
             fr.store(true, std::sync::atomic::Ordering::Relaxed);
         }), false);
         for _ in 0..100000 {
             if future_ran.load(std::sync::atomic::Ordering::Relaxed) {
                 return;
             }
+            std::thread::yield_now();
         }
         panic!("future never ran!");
     }
@@ -413,11 +953,448 @@ mod tests {
     fn run_future_message() {
         let schd = Scheduler::with_threads(2);
         let cx = schd.main_context();
-        let pid = cx.future_message(futures::future::lazy(|_| {
+        let pid = cx.future_message_sup(futures::future::lazy(|_| {
             42u32
         }), false);
         let (rpid, m) = cx.recv();
         assert_eq!(rpid, pid);
         assert_eq!(m.downcast_ref::<u32>(), Some(&42));
     }
+
+    #[test]
+    fn supervised_future_panics() {
+        let schd = Scheduler::with_threads(2);
+        let cx = schd.main_context();
+        let pid = cx.spawn_future_sup(futures::future::lazy(|_| {
+            panic!("future failed");
+        }), true);
+        let (rpid, m) = cx.recv();
+        assert_eq!(rpid, pid);
+        assert_eq!(m.downcast_ref::<Exit>(), Some(&Exit { pid, result: Err(PANICKED) }));
+    }
+
+    #[test]
+    fn supervised_future_finishes() {
+        let schd = Scheduler::with_threads(2);
+        let cx = schd.main_context();
+        let pid = cx.future_message_sup(futures::future::lazy(|_| 7u32), true);
+        let (rpid, m) = cx.recv();
+        assert_eq!(rpid, pid);
+        assert_eq!(m.downcast_ref::<u32>(), Some(&7));
+        let (rpid, m) = cx.recv();
+        assert_eq!(rpid, pid);
+        assert_eq!(m.downcast_ref::<Exit>(), Some(&Exit { pid, result: Ok(ProcessState::Finished) }));
+    }
+
+    #[test]
+    fn mailbox_len() {
+        let schd = Scheduler::with_threads(1);
+        let cx = schd.main_context();
+        let all_sent = Arc::new(std::sync::Barrier::new(2));
+        let als = all_sent.clone();
+        let p = cx.spawn(move |cx: &mut Context, sender: Pid, _: &dyn Any| {
+            als.wait();
+            cx.send(sender, cx.mailbox_len());
+            let mut drained = 0usize;
+            while cx.try_recv().is_some() { drained += 1; }
+            cx.send(sender, (drained, cx.is_empty()));
+            Ok(ProcessState::Finished)
+        });
+        for i in 0..5u32 {
+            cx.send(p, i);
+        }
+        all_sent.wait();
+        assert_eq!(cx.recv().1.downcast_ref::<usize>(), Some(&4));
+        assert_eq!(cx.recv().1.downcast_ref::<(usize, bool)>(), Some(&(4, true)));
+        assert!(cx.is_empty());
+    }
+
+    #[test]
+    fn run_until_idle_order() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let procs: Vec<Pid> = (0..3u32).map(|i| cx.spawn(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+            let n = msg.downcast_ref::<u32>().cloned().unwrap();
+            cx.send(Pid::MAIN, (i, n));
+            if n > 0 {
+                // bounce to the next process
+                cx.send(Pid { index: cx.pid().index % 3 + 1, generation: 0 }, n - 1);
+            }
+            Ok(ProcessState::Waiting)
+        })).collect();
+        for p in procs.iter() {
+            cx.send(*p, 2u32);
+        }
+        schd.run_until_idle();
+        let mut order = Vec::new();
+        while let Some((_, m)) = cx.try_recv() {
+            order.push(m.downcast_ref::<(u32, u32)>().cloned().unwrap());
+        }
+        assert_eq!(order, vec![(0, 2), (1, 2), (2, 2),
+                               (0, 1), (1, 1), (2, 1),
+                               (0, 0), (1, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn yield_interleaves() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        // sums 0..12 four numbers at a time, reporting progress after each chunk
+        let mut next = 0u32;
+        let mut sum = 0u32;
+        let summer = cx.spawn(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+            assert!(msg.is::<()>() || msg.is::<Resume>());
+            for i in next..next+4 { sum += i; }
+            next += 4;
+            cx.send(Pid::MAIN, ('s', sum));
+            Ok(if next < 12 { ProcessState::Yield } else { ProcessState::Finished })
+        });
+        let echo = cx.spawn(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+            cx.send(Pid::MAIN, ('e', msg.downcast_ref::<u32>().cloned().unwrap()));
+            Ok(ProcessState::Waiting)
+        });
+        cx.send(summer, ());
+        for i in 0..3u32 {
+            cx.send(echo, i);
+        }
+        schd.run_until_idle();
+        let msgs: Vec<(char, u32)> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<(char, u32)>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![('s', 6), ('e', 0), ('s', 28), ('e', 1), ('s', 66), ('e', 2)]);
+    }
+
+    #[test]
+    fn yield_after_waiting_messages() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let mut resumes = 0u32;
+        let p = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            if msg.is::<Resume>() {
+                assert_eq!(sender, cx.pid());
+                resumes += 1;
+                cx.send(Pid::MAIN, resumes);
+                return Ok(if resumes < 2 { ProcessState::Yield } else { ProcessState::Waiting });
+            }
+            cx.send(Pid::MAIN, 100 + msg.downcast_ref::<u32>().cloned().unwrap());
+            Ok(if msg.downcast_ref::<u32>() == Some(&0) { ProcessState::Yield } else { ProcessState::Waiting })
+        });
+        cx.send(p, 0u32);
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![100, 101, 1, 2]);
+    }
+
+    #[test]
+    fn builder_names_threads() {
+        let schd = Scheduler::builder()
+            .thread_count(2)
+            .thread_name_prefix("test-worker")
+            .stack_size(256 * 1024)
+            .build();
+        let cx = schd.main_context();
+        let p = cx.spawn(move |cx: &mut Context, sender: Pid, _: &dyn Any| {
+            cx.send(sender, std::thread::current().name().map(String::from));
+            Ok(ProcessState::Finished)
+        });
+        cx.send(p, ());
+        let name = cx.recv().1.downcast_ref::<Option<String>>().cloned().unwrap().unwrap();
+        assert!(name == "test-worker-0" || name == "test-worker-1", "unexpected thread name {}", name);
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let evs = events.clone();
+        schd.set_tracer(move |e| evs.lock().unwrap().push(e));
+        let p = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            cx.send(sender, msg.downcast_ref::<u32>().cloned().unwrap());
+            Ok(ProcessState::Finished)
+        });
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        assert_eq!(cx.recv().1.downcast_ref::<u32>(), Some(&1));
+        schd.clear_tracer();
+        cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Finished));
+        let events = events.lock().unwrap();
+        assert_eq!(events.iter().map(|e| (e.pid, e.kind.clone())).collect::<Vec<_>>(), vec![
+            (p, TraceEventKind::Spawn { parent: Pid::MAIN }),
+            (Pid::MAIN, TraceEventKind::Send { to: p }),
+            (p, TraceEventKind::Recv { from: Pid::MAIN }),
+            (p, TraceEventKind::Send { to: Pid::MAIN }),
+            (p, TraceEventKind::Exit { result: Ok(ProcessState::Finished) }),
+            (Pid::MAIN, TraceEventKind::Recv { from: p }),
+        ]);
+        assert!(events.windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    #[test]
+    fn dead_letter() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        schd.set_dead_letter(Some(Pid::MAIN));
+        let p = cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Finished));
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        cx.send(p, 2u32);
+        let (sender, m) = cx.try_recv().unwrap();
+        assert_eq!(sender, Pid::MAIN);
+        let dl = m.downcast_ref::<DeadLetter>().unwrap();
+        assert_eq!(dl.to, p);
+        assert_eq!(dl.msg.downcast_ref::<u32>(), Some(&2));
+        assert!(cx.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn no_dead_letter() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let p = cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Finished));
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        cx.send(p, 2u32);
+    }
+
+    #[test]
+    fn stale_pid_after_reuse() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let old = cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Finished));
+        cx.send(old, 1u32);
+        schd.run_until_idle();
+        let new = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            cx.send(sender, msg.downcast_ref::<u32>().cloned().unwrap());
+            Ok(ProcessState::Waiting)
+        });
+        assert_eq!(new.index(), old.index(), "the slot is reused");
+        assert_eq!(new.generation(), old.generation() + 1);
+        assert_ne!(new, old);
+        let gone = cx.try_send(old, 2u32).unwrap_err();
+        assert_eq!(gone.to, old);
+        assert_eq!(gone.msg.downcast_ref::<u32>(), Some(&2));
+        assert!(cx.try_send(new, 3u32).is_ok());
+        schd.run_until_idle();
+        assert_eq!(cx.try_recv().map(|(from, m)| (from, m.downcast_ref::<u32>().cloned())), Some((new, Some(3))));
+        assert!(cx.try_recv().is_none(), "the new process never sees the message sent to the old pid");
+    }
+
+    #[test]
+    fn stale_pid_dead_letter() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        schd.set_dead_letter(Some(Pid::MAIN));
+        let old = cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Finished));
+        cx.send(old, 1u32);
+        schd.run_until_idle();
+        let new = cx.spawn(move |_: &mut Context, _: Pid, _: &dyn Any| Ok(ProcessState::Waiting));
+        assert_eq!(new.index(), old.index());
+        cx.send(old, 2u32);
+        let (_, m) = cx.try_recv().unwrap();
+        assert_eq!(m.downcast_ref::<DeadLetter>().map(|dl| dl.to), Some(old));
+        assert!(cx.try_recv().is_none());
+    }
+
+    #[test]
+    fn typed_process() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let mut total = 0u32;
+        let adder: TypedPid<u32> = cx.spawn_typed(move |cx: &mut Context, sender: Pid, n: &u32| {
+            total += n;
+            cx.send(sender, total);
+            Ok(ProcessState::Waiting)
+        });
+        cx.send_typed(adder, 2);
+        cx.send_typed(adder, 3);
+        // other messages are ignored by default
+        cx.send(adder.pid(), "not a number");
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![2, 5]);
+        // raw pids convert both ways
+        let raw: Pid = adder.into();
+        cx.send(raw, 10u32);
+        cx.send_typed(TypedPid::<u32>::from_pid(raw), 20);
+        assert_eq!(TypedPid::<u32>::from_pid(raw), adder);
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![15, 35]);
+    }
+
+    struct Echo;
+
+    impl TypedProcess<String> for Echo {
+        fn process(&mut self, cx: &mut Context, sender: Pid, msg: &String) -> ProcessResult {
+            cx.send(sender, msg.clone());
+            Ok(ProcessState::Waiting)
+        }
+
+        fn process_other(&mut self, _: &mut Context, _: Pid, msg: &dyn Any) -> ProcessResult {
+            assert!(msg.is::<Exit>());
+            Ok(ProcessState::Finished)
+        }
+    }
+
+    #[test]
+    fn typed_process_other() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let echo = cx.spawn_typed_sup(Echo, true);
+        cx.send_typed(echo, "hi".to_string());
+        cx.send(echo.pid(), Exit { pid: Pid::MAIN, result: Ok(ProcessState::Waiting) });
+        schd.run_until_idle();
+        assert_eq!(cx.recv().1.downcast_ref::<String>().map(String::as_str), Some("hi"));
+        assert_eq!(cx.recv().1.downcast_ref::<Exit>(), Some(&Exit { pid: echo.pid(), result: Ok(ProcessState::Finished) }));
+    }
+
+    #[test]
+    fn spawn_with_initial_message() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let p = cx.spawn_with(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            cx.send(sender, msg.downcast_ref::<u32>().cloned().unwrap() + 1);
+            Ok(ProcessState::Waiting)
+        }, 7u32);
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![8]);
+        // later messages are recieved after it
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        assert_eq!(cx.try_recv().map(|(_, m)| m.downcast_ref::<u32>().cloned()), Some(Some(2)));
+        assert!(cx.try_recv().is_none());
+    }
+
+    struct Parent {
+        child: Option<Pid>
+    }
+
+    impl Process for Parent {
+        fn init(&mut self, cx: &mut Context) -> ProcessResult {
+            let child = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+                cx.send(sender, msg.downcast_ref::<u32>().cloned().unwrap() * 2);
+                Ok(ProcessState::Waiting)
+            });
+            self.child = Some(child);
+            // there is no name registry, so tell the main process who to talk to
+            cx.send(Pid::MAIN, (cx.pid(), child));
+            Ok(ProcessState::Waiting)
+        }
+
+        fn process_message(&mut self, cx: &mut Context, sender: Pid, msg: &dyn Any) -> ProcessResult {
+            let child = self.child.expect("initialized first");
+            let n = msg.downcast_ref::<u32>().cloned().unwrap();
+            cx.send(if sender == child { Pid::MAIN } else { child }, n);
+            Ok(ProcessState::Waiting)
+        }
+    }
+
+    #[test]
+    fn init_before_messages() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let p = cx.spawn(Parent { child: None });
+        schd.run_until_idle();
+        let (from, m) = cx.try_recv().expect("init runs without any messages");
+        let (parent, child) = m.downcast_ref::<(Pid, Pid)>().cloned().unwrap();
+        assert_eq!((from, parent), (p, p));
+        assert!(cx.try_recv().is_none());
+        cx.send(child, 4u32);
+        schd.run_until_idle();
+        assert_eq!(cx.try_recv().map(|(_, m)| m.downcast_ref::<u32>().cloned()), Some(Some(8)));
+        // init happens only once, and the parent passes the child's answer on
+        cx.send(p, 1u32);
+        schd.run_until_idle();
+        assert_eq!(cx.try_recv().map(|(from, m)| (from, m.downcast_ref::<u32>().cloned())), Some((p, Some(2))));
+        assert!(cx.try_recv().is_none());
+    }
+
+    #[test]
+    fn init_can_finish() {
+        struct Quit;
+        impl Process for Quit {
+            fn init(&mut self, _: &mut Context) -> ProcessResult { Err(3) }
+            fn process_message(&mut self, _: &mut Context, _: Pid, _: &dyn Any) -> ProcessResult {
+                panic!("never gets a message")
+            }
+        }
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let p = cx.spawn_with_sup(Quit, 1u32, true);
+        schd.run_until_idle();
+        assert_eq!(cx.recv().1.downcast_ref::<Exit>(), Some(&Exit { pid: p, result: Err(3) }));
+    }
+
+    #[test]
+    fn spawn_tree() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let root = cx.spawn_tree(|cx: &mut Context| {
+            let children: Vec<Pid> = (0..3u32).map(|i| cx.spawn_sup(move |cx: &mut Context, sender: Pid, _: &dyn Any| {
+                cx.send(sender, i);
+                Ok(ProcessState::Finished)
+            }, true)).collect();
+            cx.send(Pid::MAIN, children.clone());
+            let mut exited = Vec::new();
+            move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+                let exit = msg.downcast_ref::<Exit>().expect("only children send to the root");
+                assert!(children.contains(&exit.pid));
+                exited.push(exit.pid);
+                if exited.len() < children.len() { return Ok(ProcessState::Waiting); }
+                cx.send(Pid::MAIN, exited.clone());
+                Ok(ProcessState::Finished)
+            }
+        });
+        // the children were spawned by the root before it was scheduled
+        let (from, m) = cx.try_recv().expect("builder runs at spawn");
+        let children = m.downcast_ref::<Vec<Pid>>().cloned().unwrap();
+        assert_eq!(from, root);
+        assert!(children.iter().all(|c| *c > root));
+        for c in children.iter() {
+            cx.send(*c, ());
+        }
+        schd.run_until_idle();
+        let mut answers: Vec<_> = std::iter::from_fn(|| cx.try_recv()).collect();
+        let (from, exited) = answers.pop().unwrap();
+        assert_eq!(from, root);
+        assert_eq!(exited.downcast_ref::<Vec<Pid>>(), Some(&children), "the root supervises every child");
+        assert_eq!(answers.iter().map(|(from, m)| (*from, m.downcast_ref::<u32>().cloned().unwrap())).collect::<Vec<_>>(),
+            children.iter().cloned().zip(0..3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn run_until_idle_spawn() {
+        let schd = Scheduler::with_threads(0);
+        let cx = schd.main_context();
+        let p = cx.spawn(move |cx: &mut Context, sender: Pid, msg: &dyn Any| {
+            let n = msg.downcast_ref::<u32>().cloned().unwrap();
+            let child = cx.spawn(move |cx: &mut Context, _: Pid, msg: &dyn Any| {
+                cx.send(sender, msg.downcast_ref::<u32>().cloned().unwrap() * 10);
+                Ok(ProcessState::Finished)
+            });
+            cx.send(child, n);
+            cx.send(sender, n);
+            Ok(ProcessState::Waiting)
+        });
+        cx.send(p, 1u32);
+        cx.send(p, 2u32);
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![1, 2, 10, 20]);
+        // waiting processes can still be sent messages and run again
+        cx.send(p, 3u32);
+        assert!(cx.is_empty());
+        schd.run_until_idle();
+        let msgs: Vec<u32> = std::iter::from_fn(|| cx.try_recv())
+            .map(|(_, m)| m.downcast_ref::<u32>().cloned().unwrap()).collect();
+        assert_eq!(msgs, vec![3, 30]);
+    }
 }